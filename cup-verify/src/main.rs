@@ -0,0 +1,296 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Verifies a captured CUPv2 exchange offline, printing every intermediate value.
+
+use anyhow::{bail, Context as _, Error};
+use argh::FromArgs;
+use http::Response;
+use omaha_client::cup_ecdsa::{
+    make_transaction_hash, parse_etag, Cup2Key, CupProofHeader, Nonce, PublicKeys, RequestMetadata,
+    StandardCupv2Handler,
+};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::PathBuf;
+
+#[derive(FromArgs)]
+/// Verify a captured CUPv2 exchange offline, and print each intermediate hash.
+struct Args {
+    #[argh(option, description = "path to the exact request body that was sent")]
+    request: PathBuf,
+
+    #[argh(
+        option,
+        description = "path to the exact, uncompressed response body that was received"
+    )]
+    response: PathBuf,
+
+    #[argh(
+        option,
+        description = "value of the request's cup2key query parameter, '<key id>:<nonce>'"
+    )]
+    cup2key: String,
+
+    #[argh(option, description = "value of the response's ETag header")]
+    etag: Option<String>,
+
+    #[argh(
+        option,
+        description = "value of the response's X-Cup-Server-Proof header"
+    )]
+    server_proof: Option<String>,
+
+    #[argh(option, description = "path to the JSON bundle of public keys")]
+    keys: PathBuf,
+}
+
+/// Everything that's needed to verify an exchange.
+struct Exchange {
+    request_body: Vec<u8>,
+    response_body: Vec<u8>,
+    cup2key: String,
+    etag: Option<String>,
+    server_proof: Option<String>,
+    public_keys: PublicKeys,
+}
+
+fn main() {
+    let args: Args = argh::from_env();
+    match load(args).and_then(|exchange| verify(&exchange, &mut std::io::stdout())) {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Error: {e:#}");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn load(args: Args) -> Result<Exchange, Error> {
+    if args.etag.is_none() && args.server_proof.is_none() {
+        bail!("one of --etag or --server-proof is required");
+    }
+    Ok(Exchange {
+        request_body: std::fs::read(&args.request)
+            .with_context(|| format!("reading {}", args.request.display()))?,
+        response_body: std::fs::read(&args.response)
+            .with_context(|| format!("reading {}", args.response.display()))?,
+        cup2key: args.cup2key,
+        etag: args.etag,
+        server_proof: args.server_proof,
+        public_keys: PublicKeys::load_bundle(&args.keys)
+            .with_context(|| format!("loading {}", args.keys.display()))?,
+    })
+}
+
+/// Verify `exchange` and print the intermediate values to `out`.  Returns whether the exchange
+/// is authentic.
+fn verify(exchange: &Exchange, out: &mut impl Write) -> Result<bool, Error> {
+    let cup2key: Cup2Key = exchange.cup2key.parse().context("parsing cup2key")?;
+    let nonce: Nonce = cup2key.nonce.parse().context("parsing cup2key")?;
+    writeln!(out, "cup2key:            {cup2key}")?;
+
+    let public_keys = &exchange.public_keys;
+    let key_status = if public_keys.latest.id == cup2key.public_key_id {
+        "latest"
+    } else if public_keys
+        .historical
+        .iter()
+        .any(|k| k.id == cup2key.public_key_id)
+    {
+        "historical"
+    } else {
+        "not in the key bundle"
+    };
+    writeln!(
+        out,
+        "public key:         {} ({key_status})",
+        cup2key.public_key_id
+    )?;
+
+    let request_hash = Sha256::digest(&exchange.request_body);
+    writeln!(out, "request hash:       {}", hex::encode(request_hash))?;
+    writeln!(
+        out,
+        "response hash:      {}",
+        hex::encode(Sha256::digest(&exchange.response_body))
+    )?;
+    writeln!(
+        out,
+        "transaction hash:   {}",
+        hex::encode(make_transaction_hash(
+            &exchange.request_body,
+            &exchange.response_body,
+            cup2key.public_key_id,
+            &nonce,
+        ))
+    )?;
+
+    // The server proof takes precedence over the etag, as it does for the handler.
+    let (header, proof) = match (&exchange.server_proof, &exchange.etag) {
+        (Some(proof), _) => (CupProofHeader::ServerProof, proof),
+        (None, Some(etag)) => (CupProofHeader::Etag, etag),
+        (None, None) => bail!("no proof header"),
+    };
+    writeln!(out, "proof header:       {header}")?;
+    let unquoted = match header {
+        CupProofHeader::ServerProof => proof.as_str(),
+        CupProofHeader::Etag => parse_etag(proof),
+    };
+    if let Some((signature, claimed_request_hash)) = unquoted.split_once(':') {
+        writeln!(out, "  signature:        {signature}")?;
+        let matches = hex::decode(claimed_request_hash)
+            .map(|hash| hash == request_hash.as_slice())
+            .unwrap_or(false);
+        writeln!(
+            out,
+            "  request hash:     {claimed_request_hash} ({})",
+            if matches { "matches" } else { "does not match" }
+        )?;
+    }
+
+    let mut response = Response::new(exchange.response_body.clone());
+    response.headers_mut().insert(
+        header.name(),
+        proof.parse().context("parsing the proof header")?,
+    );
+    let request_metadata = RequestMetadata {
+        request_body: exchange.request_body.clone(),
+        public_key_id: cup2key.public_key_id,
+        nonce,
+    };
+    let result = StandardCupv2Handler::new(public_keys).verify_proof(
+        &request_metadata,
+        &response,
+        cup2key.public_key_id,
+    );
+    match result {
+        Ok(_) => {
+            writeln!(out, "result:             OK")?;
+            Ok(true)
+        }
+        Err(e) => {
+            writeln!(out, "result:             FAILED: {e}")?;
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use omaha_client::cup_ecdsa::{
+        test_support::{
+            make_default_private_key_for_test, make_default_public_key_id_for_test,
+            make_default_public_keys_for_test,
+        },
+        Cupv2Signer, PrivateKeyAndId, PrivateKeys,
+    };
+
+    const REQUEST: &[u8] = br#"{"request":{"protocol":"3.0"}}"#;
+    const RESPONSE: &[u8] = br#"{"response":{"protocol":"3.0"}}"#;
+
+    fn make_exchange() -> Exchange {
+        let cup2key = format!("{}:{}", make_default_public_key_id_for_test(), Nonce::new());
+        let signer = Cupv2Signer::new(PrivateKeys {
+            latest: PrivateKeyAndId {
+                id: make_default_public_key_id_for_test(),
+                key: make_default_private_key_for_test(),
+            },
+            historical: vec![],
+        });
+        let signature = signer
+            .sign(
+                &format!("/?cup2key={cup2key}").parse().unwrap(),
+                REQUEST,
+                RESPONSE,
+            )
+            .unwrap();
+        Exchange {
+            request_body: REQUEST.to_vec(),
+            response_body: RESPONSE.to_vec(),
+            cup2key,
+            etag: Some(format!("\"{}\"", signature.etag())),
+            server_proof: None,
+            public_keys: make_default_public_keys_for_test(),
+        }
+    }
+
+    fn run(exchange: &Exchange) -> (bool, String) {
+        let mut out = vec![];
+        let verified = verify(exchange, &mut out).unwrap();
+        (verified, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_verify_valid_exchange() {
+        let (verified, out) = run(&make_exchange());
+        assert!(verified, "{out}");
+        assert!(out.contains(&format!(
+            "request hash:       {}",
+            hex::encode(Sha256::digest(REQUEST))
+        )));
+        assert!(out.contains("(latest)"));
+        assert!(out.contains("proof header:       etag"));
+        assert!(out.contains("(matches)"));
+        assert!(out.ends_with("result:             OK\n"));
+    }
+
+    #[test]
+    fn test_verify_modified_request() {
+        let mut exchange = make_exchange();
+        exchange.request_body = br#"{"request":{"protocol":"3.1"}}"#.to_vec();
+        let (verified, out) = run(&exchange);
+        assert!(!verified);
+        assert!(out.contains("(does not match)"));
+        assert!(out
+            .ends_with("result:             FAILED: etag header's request hash doesn't match.\n"));
+    }
+
+    #[test]
+    fn test_verify_modified_response() {
+        let mut exchange = make_exchange();
+        exchange.response_body = br#"{"response":{"protocol":"3.1"}}"#.to_vec();
+        let (verified, out) = run(&exchange);
+        assert!(!verified);
+        assert!(out.contains("(matches)"));
+        assert!(
+            out.ends_with("result:             FAILED: could not verify the response signature.\n")
+        );
+    }
+
+    #[test]
+    fn test_verify_server_proof_takes_precedence() {
+        let mut exchange = make_exchange();
+        exchange.server_proof = Some("garbage".to_string());
+        let (verified, out) = run(&exchange);
+        assert!(!verified);
+        assert!(out.contains("proof header:       X-Cup-Server-Proof"));
+        assert!(
+            out.ends_with("result:             FAILED: X-Cup-Server-Proof header is malformed.\n")
+        );
+    }
+
+    #[test]
+    fn test_verify_unknown_key() {
+        let mut exchange = make_exchange();
+        let (_, nonce) = exchange.cup2key.split_once(':').unwrap();
+        exchange.cup2key = format!("1:{nonce}");
+        let (verified, out) = run(&exchange);
+        assert!(!verified);
+        assert!(out.contains("public key:         1 (not in the key bundle)"));
+    }
+
+    #[test]
+    fn test_verify_malformed_cup2key() {
+        let mut exchange = make_exchange();
+        exchange.cup2key = "123456789:nonce".to_string();
+        assert!(verify(&exchange, &mut vec![]).is_err());
+    }
+}
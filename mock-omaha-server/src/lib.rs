@@ -9,7 +9,6 @@
 use anyhow::Error;
 use derive_builder::Builder;
 use futures::prelude::*;
-use hyper::body::Bytes;
 use hyper::server::accept::from_stream;
 use hyper::server::Server;
 use hyper::service::{make_service_fn, service_fn};
@@ -17,17 +16,17 @@ use hyper::{header, Body, Method, Request, Response, StatusCode};
 use omaha_client::cup_ecdsa::test_support::{
     make_default_private_key_for_test, make_default_public_key_id_for_test,
 };
-use omaha_client::cup_ecdsa::PublicKeyId;
+use omaha_client::cup_ecdsa::{CupSigningError, Cupv2Signer, X_CUP_SERVER_PROOF};
+pub use omaha_client::cup_ecdsa::{PrivateKey, PrivateKeyAndId, PrivateKeys};
 use serde::Deserialize;
 use serde_json::json;
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
 #[cfg(not(target_os = "fuchsia"))]
 use tokio::net::{TcpListener, TcpStream};
-use url::Url;
 
 #[cfg(not(target_os = "fuchsia"))]
 use {
@@ -81,36 +80,6 @@ impl Default for ResponseAndMetadata {
     }
 }
 
-// The corresponding private key to lib/omaha-client's PublicKey. For testing
-// only, since omaha-client never needs to hold a private key.
-pub type PrivateKey = p256::ecdsa::SigningKey;
-
-#[derive(Clone, Debug)]
-pub struct PrivateKeyAndId {
-    pub id: PublicKeyId,
-    pub key: PrivateKey,
-}
-
-#[derive(Clone, Debug)]
-pub struct PrivateKeys {
-    pub latest: PrivateKeyAndId,
-    pub historical: Vec<PrivateKeyAndId>,
-}
-
-impl PrivateKeys {
-    pub fn find(&self, id: PublicKeyId) -> Option<&PrivateKey> {
-        if self.latest.id == id {
-            return Some(&self.latest.key);
-        }
-        for pair in &self.historical {
-            if pair.id == id {
-                return Some(&pair.key);
-            }
-        }
-        None
-    }
-}
-
 pub fn make_default_private_keys_for_test() -> PrivateKeys {
     PrivateKeys {
         latest: PrivateKeyAndId {
@@ -123,6 +92,40 @@ pub fn make_default_private_keys_for_test() -> PrivateKeys {
 
 pub type ResponseMap = HashMap<String, ResponseAndMetadata>;
 
+/// Which response headers carry the server's CUP proof.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CupProofHeaders {
+    #[default]
+    Etag,
+    ServerProof,
+    Both,
+}
+
+impl CupProofHeaders {
+    fn etag(&self) -> bool {
+        matches!(self, CupProofHeaders::Etag | CupProofHeaders::Both)
+    }
+
+    fn server_proof(&self) -> bool {
+        matches!(self, CupProofHeaders::ServerProof | CupProofHeaders::Both)
+    }
+}
+
+impl FromStr for CupProofHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "etag" => Ok(CupProofHeaders::Etag),
+            "server-proof" => Ok(CupProofHeaders::ServerProof),
+            "both" => Ok(CupProofHeaders::Both),
+            _ => Err(format!(
+                "unknown CUP proof headers {s:?}, expected 'etag', 'server-proof' or 'both'"
+            )),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Deserialize)]
 pub enum UpdateCheckAssertion {
     UpdatesEnabled,
@@ -201,6 +204,8 @@ pub struct OmahaServer {
     #[builder(default = "None")]
     pub etag_override: Option<String>,
     #[builder(default)]
+    pub cup_proof_headers: CupProofHeaders,
+    #[builder(default)]
     pub require_cup: bool,
 }
 
@@ -356,55 +361,6 @@ impl OmahaServer {
     }
 }
 
-fn make_etag(
-    request_body: &Bytes,
-    uri: &str,
-    private_keys: &PrivateKeys,
-    response_data: &[u8],
-) -> Option<String> {
-    use p256::ecdsa::signature::Signer;
-
-    if uri == "/" {
-        return None;
-    }
-
-    let parsed_uri = Url::parse(&format!("https://example.com{uri}")).unwrap();
-    let mut query_pairs = parsed_uri.query_pairs();
-
-    let (cup2key_key, cup2key_val) = query_pairs.next().unwrap();
-    assert_eq!(cup2key_key, "cup2key");
-
-    let (public_key_id_str, _nonce_str) = cup2key_val.split_once(':').unwrap();
-    let public_key_id: PublicKeyId = public_key_id_str.parse().unwrap();
-    let private_key: &PrivateKey = match private_keys.find(public_key_id) {
-        Some(pk) => Some(pk),
-        None => {
-            tracing::error!(
-                "Could not find public_key_id {:?} in the private_keys map, which only knows about the latest key_id {:?} and the historical key_ids {:?}",
-                public_key_id,
-                private_keys.latest.id,
-                private_keys.historical.iter().map(|pkid| pkid.id).collect::<Vec<_>>(),
-                );
-            None
-        }
-    }?;
-
-    let request_hash = Sha256::digest(request_body);
-    let response_hash = Sha256::digest(response_data);
-
-    let mut hasher = Sha256::new();
-    hasher.update(request_hash);
-    hasher.update(response_hash);
-    hasher.update(&*cup2key_val);
-    let transaction_hash = hasher.finalize();
-
-    Some(format!(
-        "{}:{}",
-        hex::encode(private_key.sign(&transaction_hash).to_der()),
-        hex::encode(request_hash)
-    ))
-}
-
 pub async fn handle_request(
     req: Request<Body>,
     omaha_server: &Mutex<OmahaServer>,
@@ -458,7 +414,7 @@ pub async fn handle_omaha_request(
         return Ok(builder.body(Body::empty()).unwrap());
     }
 
-    let uri_string = req.uri().to_string();
+    let uri = req.uri().clone();
 
     let req_body = hyper::body::to_bytes(req).await?;
     let req_json: serde_json::Value = serde_json::from_slice(&req_body).expect("parse json");
@@ -660,21 +616,36 @@ pub async fn handle_omaha_request(
         .status(StatusCode::OK)
         .header(header::CONTENT_LENGTH, response_data.len());
 
-    // It is only possible to calculate an induced etag if the incoming request
-    // had a valid cup2key query argument.
-    let induced_etag: Option<String> = make_etag(
+    // It is only possible to calculate an induced signature if the incoming
+    // request had a valid cup2key query argument.
+    let induced_signature = match Cupv2Signer::new(omaha_server.private_keys.clone()).sign(
+        &uri,
         &req_body,
-        &uri_string,
-        &omaha_server.private_keys,
         &response_data,
-    );
+    ) {
+        Ok(signature) => Some(signature),
+        Err(CupSigningError::Cup2KeyMissing) => None,
+        Err(e) => {
+            tracing::error!(
+                "Could not sign the response to {uri}, with the latest key_id {:?} and the historical key_ids {:?}: {:#}",
+                omaha_server.private_keys.latest.id,
+                omaha_server.private_keys.historical.iter().map(|pkid| pkid.id).collect::<Vec<_>>(),
+                anyhow::Error::from(e),
+            );
+            None
+        }
+    };
 
-    if omaha_server.require_cup && induced_etag.is_none() {
+    if omaha_server.require_cup && induced_signature.is_none() {
         panic!(
             "mock-omaha-server was configured to expect CUP, but we received a request without it."
         );
     }
 
+    let induced_etag = induced_signature
+        .as_ref()
+        .filter(|_| omaha_server.cup_proof_headers.etag())
+        .map(|signature| signature.etag());
     if let Some(etag) = omaha_server
         .etag_override
         .as_ref()
@@ -683,6 +654,13 @@ pub async fn handle_omaha_request(
         builder = builder.header(header::ETAG, etag);
     }
 
+    if let Some(signature) = induced_signature
+        .as_ref()
+        .filter(|_| omaha_server.cup_proof_headers.server_proof())
+    {
+        builder = builder.header(X_CUP_SERVER_PROOF, signature.server_proof());
+    }
+
     Ok(builder.body(Body::from(response_data)).unwrap())
 }
 
@@ -1032,4 +1010,73 @@ mod tests {
             .unwrap();
         let _response = client.request(request).await.unwrap();
     }
+
+    #[cfg_attr(fasync, fasync::run_singlethreaded(test))]
+    #[cfg_attr(feature = "tokio", tokio::test)]
+    async fn test_server_cup_proof_headers() -> Result<(), Error> {
+        for (cup_proof_headers, expect_etag, expect_server_proof) in [
+            (CupProofHeaders::Etag, true, false),
+            (CupProofHeaders::ServerProof, false, true),
+            (CupProofHeaders::Both, true, true),
+        ] {
+            let server_url = OmahaServer::start_and_detach(
+                Arc::new(Mutex::new(
+                    OmahaServerBuilder::default()
+                        .responses_by_appid([(
+                            "integration-test-appid-1".to_string(),
+                            ResponseAndMetadata {
+                                response: OmahaResponse::NoUpdate,
+                                version: Some("0.0.0.1".to_string()),
+                                ..Default::default()
+                            },
+                        )])
+                        .cup_proof_headers(cup_proof_headers)
+                        .build()
+                        .unwrap(),
+                )),
+                None,
+            )
+            .await
+            .context("starting server")?;
+
+            let client = new_http_client().await;
+            let body = json!({
+                "request": {
+                    "app": [
+                        {
+                            "appid": "integration-test-appid-1",
+                            "version": "0.0.0.1",
+                            "updatecheck": { "updatedisabled": false }
+                        },
+                    ]
+                }
+            });
+            let request = Request::post(format!(
+                "{}?cup2key={}:nonce",
+                &server_url,
+                make_default_public_key_id_for_test()
+            ))
+            .body(Body::from(body.to_string()))
+            .unwrap();
+
+            let response = client.request(request).await?;
+            assert_eq!(response.status(), StatusCode::OK);
+            let etag = response.headers().get(header::ETAG);
+            let server_proof = response.headers().get(X_CUP_SERVER_PROOF);
+            assert_eq!(etag.is_some(), expect_etag);
+            assert_eq!(server_proof.is_some(), expect_server_proof);
+            if let (Some(etag), Some(server_proof)) = (etag, server_proof) {
+                assert_eq!(etag, server_proof);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cup_proof_headers() {
+        assert_eq!("etag".parse(), Ok(CupProofHeaders::Etag));
+        assert_eq!("server-proof".parse(), Ok(CupProofHeaders::ServerProof));
+        assert_eq!("both".parse(), Ok(CupProofHeaders::Both));
+        assert!("neither".parse::<CupProofHeaders>().is_err());
+    }
 }
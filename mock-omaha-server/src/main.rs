@@ -8,7 +8,8 @@
 
 use argh::FromArgs;
 use mock_omaha_server::{
-    OmahaServer, OmahaServerBuilder, PrivateKeyAndId, PrivateKeys, ResponseAndMetadata,
+    CupProofHeaders, OmahaServer, OmahaServerBuilder, PrivateKeyAndId, PrivateKeys,
+    ResponseAndMetadata,
 };
 use std::collections::HashMap;
 use std::net::{Ipv6Addr, SocketAddr};
@@ -72,6 +73,13 @@ struct Args {
         description = "if 'true', will only accept requests with CUP enabled."
     )]
     require_cup: bool,
+
+    #[argh(
+        option,
+        description = "which headers carry the CUP proof: 'etag', 'server-proof' or 'both'.",
+        default = "CupProofHeaders::Etag"
+    )]
+    cup_proof_headers: CupProofHeaders,
 }
 
 fn parse_responses_by_appid(value: &str) -> Result<HashMap<String, ResponseAndMetadata>, String> {
@@ -113,6 +121,7 @@ async fn main() -> Result<(), anyhow::Error> {
                     historical: vec![],
                 })
                 .require_cup(args.require_cup)
+                .cup_proof_headers(args.cup_proof_headers)
                 .build()
                 .expect("omaha server build"),
         )),
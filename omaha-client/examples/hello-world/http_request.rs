@@ -19,6 +19,8 @@ use {
 // This implements the minimal trait requirements for a http request for the omaha state machine.
 // For a detailed implementation, see
 // https://cs.opensource.google/fuchsia/fuchsia/+/main:src/sys/pkg/lib/omaha-client-fuchsia/src/http_request.rs
+// It ignores the `ProxyRoute` on each request and always connects directly, so it must only be
+// used with a `Config` that has no `proxy_config`.
 pub struct MinimalHttpRequest {
     timeout: Duration,
     client: Client<hyper_rustls::HttpsConnector<HttpConnector>, Body>,
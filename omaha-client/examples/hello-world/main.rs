@@ -16,7 +16,7 @@ use {
     omaha_client::{
         common::App,
         configuration::{Config, Updater},
-        cup_ecdsa::StandardCupv2Handler,
+        cup_ecdsa::{PublicKeys, StandardCupv2Handler},
         protocol::{request::OS, Cohort},
         state_machine::StateMachineBuilder,
         time::StandardTimeSource,
@@ -46,6 +46,13 @@ struct Args {
         description = "URL of the omaha service to connect to, e.g. 'http://[::]:1234'. This service must accept connections."
     )]
     url: String,
+
+    #[argh(
+        option,
+        short = 'k',
+        description = "path to a JSON bundle of the omaha service's CUP public keys. If omitted, responses are not verified."
+    )]
+    public_keys: Option<std::path::PathBuf>,
 }
 
 #[tokio::main]
@@ -86,7 +93,9 @@ async fn main_inner() -> Result<(), Error> {
             arch: "aarch64".to_string(),
         },
         service_url: args.url,
-        omaha_public_keys: None,
+        omaha_public_keys: args.public_keys.map(PublicKeys::load_bundle).transpose()?,
+        proxy_config: None,
+        compression: Default::default(),
     };
 
     // The cup handler is required for the state machine, but does not require explicit
@@ -12,6 +12,7 @@ use futures::{future::BoxFuture, FutureExt as _};
 use omaha_client::{
     common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
     policy::{CheckDecision, Policy, PolicyEngine, UpdateDecision},
+    protocol::response::Urgency,
     request_builder::RequestParams,
     time::{PartialComplexTime, TimeSource},
 };
@@ -64,6 +65,7 @@ impl Policy for MinimalPolicy {
     fn update_can_start(
         _policy_data: &Self::UpdateCanStartPolicyData,
         _proposed_install_plan: &Self::InstallPlan,
+        _urgency: Urgency,
     ) -> UpdateDecision {
         UpdateDecision::Ok
     }
@@ -72,6 +74,7 @@ impl Policy for MinimalPolicy {
     fn reboot_allowed(
         _policy_data: &Self::RebootPolicyData,
         _check_options: &CheckOptions,
+        _urgency: Urgency,
     ) -> bool {
         true
     }
@@ -144,10 +147,11 @@ where
     fn update_can_start<'a>(
         &'a mut self,
         proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
     ) -> BoxFuture<'a, UpdateDecision> {
         async move {
             let policy_data = UnusedPolicyData {};
-            MinimalPolicy::update_can_start(&policy_data, proposed_install_plan)
+            MinimalPolicy::update_can_start(&policy_data, proposed_install_plan, urgency)
         }
         .boxed()
     }
@@ -156,10 +160,11 @@ where
         &'a mut self,
         check_options: &'a CheckOptions,
         _install_result: &'a Self::InstallResult,
+        urgency: Urgency,
     ) -> BoxFuture<'a, bool> {
         async move {
             let policy_data = UnusedPolicyData {};
-            MinimalPolicy::reboot_allowed(&policy_data, check_options)
+            MinimalPolicy::reboot_allowed(&policy_data, check_options, urgency)
         }
         .boxed()
     }
@@ -7,15 +7,78 @@
 // those terms.
 
 use {
-    crate::{common::App, state_machine::update_check::AppResponse, storage::Storage},
+    crate::{
+        channel_config::ChannelConfigs, common::App, state_machine::update_check::AppResponse,
+        storage::Storage, version::Version,
+    },
     futures::{future::LocalBoxFuture, prelude::*},
+    serde::{Deserialize, Serialize},
+    std::collections::HashMap,
+    thiserror::Error,
+    tracing::{error, warn},
 };
 
+/// The storage key of the apps that were added to the app set at runtime.
+pub const REGISTERED_APPS: &str = "registered_apps";
+
+/// Errors from changing which apps are in an `AppSet`.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum AppSetError {
+    #[error("this app set doesn't support adding or removing apps")]
+    Unsupported,
+
+    #[error("app {0} is already in the app set")]
+    AlreadyExists(String),
+
+    #[error("app {0} is not in the app set")]
+    NotFound(String),
+
+    #[error("app {0} is the system app, which can't be removed")]
+    SystemApp(String),
+
+    #[error("app {0:?} is not valid")]
+    InvalidApp(String),
+}
+
 /// The trait for the platform-specific AppSet to implement.
 pub trait AppSet {
     fn get_apps(&self) -> Vec<App>;
     fn iter_mut_apps(&mut self) -> Box<dyn Iterator<Item = &mut App> + '_>;
     fn get_system_app_id(&self) -> &str;
+
+    /// Add |app| to the set, failing if an app with the same id is already in it.  App sets that
+    /// are fixed at construction don't need to implement this.
+    fn add_app(&mut self, _app: App) -> Result<(), AppSetError> {
+        Err(AppSetError::Unsupported)
+    }
+
+    /// Remove the app with |id| from the set, returning it.  The system app can't be removed.
+    fn remove_app(&mut self, _id: &str) -> Result<App, AppSetError> {
+        Err(AppSetError::Unsupported)
+    }
+}
+
+/// Changes to make to an app that is already in an `AppSet`.  Fields that are `None` are left as
+/// they are.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AppUpdate {
+    pub version: Option<Version>,
+    pub cohort_hint: Option<String>,
+    pub extra_fields: Option<HashMap<String, String>>,
+}
+
+impl AppUpdate {
+    fn apply(self, app: &mut App) {
+        if let Some(version) = self.version {
+            app.version = version;
+        }
+        if let Some(cohort_hint) = self.cohort_hint {
+            app.cohort.hint = Some(cohort_hint);
+        }
+        if let Some(extra_fields) = self.extra_fields {
+            app.extra_fields = extra_fields;
+        }
+    }
 }
 
 pub trait AppSetExt: AppSet {
@@ -37,10 +100,41 @@ pub trait AppSetExt: AppSet {
         }
     }
 
-    /// Load data from |storage|, only overwrite existing fields if data exists.
+    /// Apply |update| to the app with |id|, returning the updated app.  The app is left unchanged
+    /// if the update would make it invalid.
+    fn update_app(&mut self, id: &str, update: AppUpdate) -> Result<App, AppSetError> {
+        let app = self
+            .iter_mut_apps()
+            .find(|app| app.id == id)
+            .ok_or_else(|| AppSetError::NotFound(id.to_string()))?;
+        let mut updated = app.clone();
+        update.apply(&mut updated);
+        if !updated.valid() {
+            return Err(AppSetError::InvalidApp(id.to_string()));
+        }
+        *app = updated.clone();
+        Ok(updated)
+    }
+
+    /// Put the system app on its channel from |configs|, see `ChannelConfigs::apply_to_app`.
+    fn apply_channel_configs(&mut self, configs: &ChannelConfigs) {
+        let system_app_id = self.get_system_app_id().to_string();
+        if let Some(app) = self.iter_mut_apps().find(|app| app.id == system_app_id) {
+            configs.apply_to_app(app);
+        }
+    }
+
+    /// Load data from |storage|, only overwrite existing fields if data exists.  Apps that were
+    /// added at runtime are added again first.
     #[must_use]
     fn load<'a>(&'a mut self, storage: &'a impl Storage) -> LocalBoxFuture<'a, ()> {
         async move {
+            for app in RegisteredApps::load(storage).await.apps() {
+                match self.add_app(app) {
+                    Ok(()) | Err(AppSetError::AlreadyExists(_)) => {}
+                    Err(e) => warn!("Unable to add registered app: {}", e),
+                }
+            }
             for app in self.iter_mut_apps() {
                 app.load(storage).await;
             }
@@ -64,6 +158,98 @@ pub trait AppSetExt: AppSet {
 
 impl<T> AppSetExt for T where T: AppSet {}
 
+/// An app that was added to the app set at runtime, with the fields that aren't persisted by
+/// `App::persist`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct RegisteredApp {
+    id: String,
+    version: Version,
+    fingerprint: Option<String>,
+    #[serde(default)]
+    extra_fields: HashMap<String, String>,
+}
+
+impl From<&App> for RegisteredApp {
+    fn from(app: &App) -> Self {
+        Self {
+            id: app.id.clone(),
+            version: app.version.clone(),
+            fingerprint: app.fingerprint.clone(),
+            extra_fields: app.extra_fields.clone(),
+        }
+    }
+}
+
+/// The apps that were added to the app set at runtime, persisted under `REGISTERED_APPS` so that
+/// `AppSetExt::load` can add them again.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct RegisteredApps(Vec<RegisteredApp>);
+
+impl RegisteredApps {
+    pub(crate) async fn load(storage: &impl Storage) -> Self {
+        let Some(json) = storage.get_string(REGISTERED_APPS).await else {
+            return Self::default();
+        };
+        match serde_json::from_str(&json) {
+            Ok(apps) => Self(apps),
+            Err(e) => {
+                error!(
+                    "Unable to deserialize registered apps from json {}: {}",
+                    json, e
+                );
+                Self::default()
+            }
+        }
+    }
+
+    /// It will NOT call commit() on |storage|, caller is responsible to call commit().
+    pub(crate) async fn persist(&self, storage: &mut impl Storage) {
+        let result = if self.0.is_empty() {
+            storage.remove(REGISTERED_APPS).await
+        } else {
+            match serde_json::to_string(&self.0) {
+                Ok(json) => storage.set_string(REGISTERED_APPS, &json).await,
+                Err(e) => {
+                    error!("Unable to serialize registered apps: {}", e);
+                    return;
+                }
+            }
+        };
+        if let Err(e) = result {
+            error!("Unable to persist registered apps: {}", e);
+        }
+    }
+
+    /// The apps, without the fields that are loaded by `App::load`.
+    pub(crate) fn apps(&self) -> impl Iterator<Item = App> + '_ {
+        self.0.iter().map(|app| App {
+            fingerprint: app.fingerprint.clone(),
+            extra_fields: app.extra_fields.clone(),
+            ..App::builder()
+                .id(app.id.clone())
+                .version(app.version.clone())
+                .build()
+        })
+    }
+
+    /// Add |app|, or replace the registered app with the same id.
+    pub(crate) fn insert(&mut self, app: &App) {
+        self.remove(&app.id);
+        self.0.push(app.into());
+    }
+
+    /// Replace the registered app with |id| by |app|, if there is one.
+    pub(crate) fn replace(&mut self, id: &str, app: &App) {
+        if let Some(registered) = self.0.iter_mut().find(|registered| registered.id == id) {
+            *registered = app.into();
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: &str) {
+        self.0.retain(|registered| registered.id != id);
+    }
+}
+
 /// An AppSet implementation based on Vec, the first app will be treated as the system app.
 pub struct VecAppSet {
     pub apps: Vec<App>,
@@ -87,12 +273,34 @@ impl AppSet for VecAppSet {
     fn get_system_app_id(&self) -> &str {
         &self.apps[0].id
     }
+    fn add_app(&mut self, app: App) -> Result<(), AppSetError> {
+        if self.apps.iter().any(|existing| existing.id == app.id) {
+            return Err(AppSetError::AlreadyExists(app.id));
+        }
+        self.apps.push(app);
+        Ok(())
+    }
+    fn remove_app(&mut self, id: &str) -> Result<App, AppSetError> {
+        if id == self.get_system_app_id() {
+            return Err(AppSetError::SystemApp(id.to_string()));
+        }
+        let index = self
+            .apps
+            .iter()
+            .position(|app| app.id == id)
+            .ok_or_else(|| AppSetError::NotFound(id.to_string()))?;
+        Ok(self.apps.remove(index))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{common::UserCounting, protocol::Cohort, state_machine::update_check::Action};
+    use crate::{
+        common::UserCounting, protocol::Cohort, state_machine::update_check::Action,
+        storage::MemStorage,
+    };
+    use futures::executor::block_on;
 
     #[test]
     fn test_appsetext_update_from_omaha() {
@@ -161,6 +369,65 @@ mod tests {
         assert!(!app_set.all_valid());
     }
 
+    #[test]
+    fn test_appsetext_load_registered_apps() {
+        let mut storage = MemStorage::new();
+        let registered = App::builder()
+            .id("id2")
+            .version([2])
+            .fingerprint("fp")
+            .extra_fields([("key".to_string(), "value".to_string())])
+            .build();
+        block_on(async {
+            let mut registered_apps = RegisteredApps::default();
+            registered_apps.insert(&App::builder().id("id1").version([3]).build());
+            registered_apps.insert(&registered);
+            registered_apps.persist(&mut storage).await;
+        });
+
+        let mut app_set = VecAppSet::new(vec![App::builder().id("id1").version([1]).build()]);
+        block_on(app_set.load(&storage));
+        assert_eq!(
+            app_set.get_apps(),
+            vec![App::builder().id("id1").version([1]).build(), registered]
+        );
+
+        block_on(async {
+            let mut registered_apps = RegisteredApps::load(&storage).await;
+            registered_apps.remove("id1");
+            registered_apps.remove("id2");
+            registered_apps.persist(&mut storage).await;
+        });
+        assert_eq!(block_on(storage.get_string(REGISTERED_APPS)), None);
+    }
+
+    #[test]
+    fn test_appsetext_apply_channel_configs() {
+        let configs = ChannelConfigs::from_json(
+            br#"{
+                "default_channel": "beta",
+                "known_channels": [{ "name": "beta", "appid": "beta-app-id" }]
+            }"#,
+        )
+        .unwrap();
+        let mut app_set = VecAppSet::new(vec![
+            App::builder().id("id1").version([1]).build(),
+            App::builder().id("id2").version([2]).build(),
+        ]);
+        app_set.apply_channel_configs(&configs);
+        assert_eq!(
+            app_set.get_apps(),
+            vec![
+                App::builder()
+                    .id("beta-app-id")
+                    .version([1])
+                    .cohort(Cohort::from_hint("beta"))
+                    .build(),
+                App::builder().id("id2").version([2]).build(),
+            ]
+        );
+    }
+
     #[test]
     fn test_get_apps() {
         let apps = vec![App::builder().id("some_id").version([0, 1]).build()];
@@ -196,4 +463,69 @@ mod tests {
         let app_set = VecAppSet::new(apps);
         assert_eq!(app_set.get_system_app_id(), "id1");
     }
+
+    #[test]
+    fn test_add_app() {
+        let mut app_set = VecAppSet::new(vec![App::builder().id("id1").version([1]).build()]);
+        let app = App::builder().id("id2").version([2]).build();
+        assert_eq!(app_set.add_app(app.clone()), Ok(()));
+        assert_eq!(
+            app_set.add_app(App::builder().id("id2").version([3]).build()),
+            Err(AppSetError::AlreadyExists("id2".to_string()))
+        );
+        assert_eq!(app_set.get_apps()[1], app);
+    }
+
+    #[test]
+    fn test_remove_app() {
+        let mut app_set = VecAppSet::new(vec![
+            App::builder().id("id1").version([1]).build(),
+            App::builder().id("id2").version([2]).build(),
+        ]);
+        assert_eq!(
+            app_set.remove_app("id1"),
+            Err(AppSetError::SystemApp("id1".to_string()))
+        );
+        assert_eq!(
+            app_set.remove_app("id3"),
+            Err(AppSetError::NotFound("id3".to_string()))
+        );
+        assert_eq!(
+            app_set.remove_app("id2"),
+            Ok(App::builder().id("id2").version([2]).build())
+        );
+        assert_eq!(app_set.get_apps().len(), 1);
+    }
+
+    #[test]
+    fn test_appsetext_update_app() {
+        let mut app_set = VecAppSet::new(vec![App::builder().id("id1").version([1]).build()]);
+        let update = AppUpdate {
+            version: Some([1, 2].into()),
+            cohort_hint: Some("beta-channel".to_string()),
+            extra_fields: Some([("key".to_string(), "value".to_string())].into()),
+        };
+        let expected = App::builder()
+            .id("id1")
+            .version([1, 2])
+            .cohort(Cohort::from_hint("beta-channel"))
+            .extra_fields([("key".to_string(), "value".to_string())])
+            .build();
+        assert_eq!(app_set.update_app("id1", update), Ok(expected.clone()));
+        assert_eq!(app_set.get_apps(), vec![expected.clone()]);
+
+        let update = AppUpdate {
+            version: Some([0].into()),
+            ..AppUpdate::default()
+        };
+        assert_eq!(
+            app_set.update_app("id1", update),
+            Err(AppSetError::InvalidApp("id1".to_string()))
+        );
+        assert_eq!(
+            app_set.update_app("id2", AppUpdate::default()),
+            Err(AppSetError::NotFound("id2".to_string()))
+        );
+        assert_eq!(app_set.get_apps(), vec![expected]);
+    }
 }
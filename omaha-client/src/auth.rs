@@ -0,0 +1,280 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Bearer-token authentication for Omaha requests, for deployments that sit behind an auth
+//! gateway.
+//!
+//! The state machine asks its [`AuthProvider`] for a token, caches it in memory for as long as
+//! the provider says it's valid, and attaches it to every request as an `Authorization: Bearer`
+//! header.  If the server rejects a request with 401 or 403, the cached token is dropped, a fresh
+//! one is fetched, and the request is retried once.
+
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use http::{header::AUTHORIZATION, HeaderValue, StatusCode};
+use hyper::{Body, Request};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// A token fetched from an [`AuthProvider`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AuthToken {
+    /// The bearer token itself.
+    pub token: String,
+
+    /// How long the token is valid for, from when it was fetched.  `None` means that the token
+    /// is used until the server rejects it.
+    pub expires_in: Option<Duration>,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("Unable to fetch an auth token")]
+    Fetch(#[source] anyhow::Error),
+
+    #[error("Auth token is not a valid header value")]
+    InvalidToken(#[from] http::header::InvalidHeaderValue),
+
+    #[error("Request was rejected with {0} after refreshing the auth token")]
+    Rejected(StatusCode),
+}
+
+/// A source of bearer tokens for Omaha requests.
+pub trait AuthProvider {
+    /// Fetch a new token.  This is only called when there's no valid cached token, either because
+    /// none was fetched yet, because it expired, or because the server rejected it.
+    ///
+    /// Returns `Ok(None)` if requests should be sent without authentication.
+    fn fetch_token(&mut self) -> BoxFuture<'_, Result<Option<AuthToken>, AuthError>>;
+}
+
+/// An AuthProvider that never provides a token, so requests are sent unauthenticated.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StubAuthProvider;
+
+impl AuthProvider for StubAuthProvider {
+    fn fetch_token(&mut self) -> BoxFuture<'_, Result<Option<AuthToken>, AuthError>> {
+        future::ok(None).boxed()
+    }
+}
+
+/// Returns true if the status code means that the request's credentials were rejected.
+pub(crate) fn is_auth_rejection(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    header: HeaderValue,
+    valid_until: Option<Instant>,
+}
+
+/// Wraps an [`AuthProvider`] with an in-memory cache of its last token.
+#[derive(Debug)]
+pub(crate) struct TokenCache<AP> {
+    provider: AP,
+    cached: Option<CachedToken>,
+}
+
+impl<AP: AuthProvider> TokenCache<AP> {
+    pub(crate) fn new(provider: AP) -> Self {
+        Self {
+            provider,
+            cached: None,
+        }
+    }
+
+    /// Drop the cached token, so that the next request fetches a new one.
+    pub(crate) fn invalidate(&mut self) {
+        self.cached = None;
+    }
+
+    /// Attach the current token to `request`, fetching one if there's no valid cached token.
+    ///
+    /// Returns true if a token was attached.
+    pub(crate) async fn authorize(
+        &mut self,
+        request: &mut Request<Body>,
+        now: Instant,
+    ) -> Result<bool, AuthError> {
+        let expired = matches!(
+            &self.cached,
+            Some(CachedToken { valid_until: Some(valid_until), .. }) if *valid_until <= now
+        );
+        if expired || self.cached.is_none() {
+            self.cached = match self.provider.fetch_token().await? {
+                Some(AuthToken { token, expires_in }) => {
+                    let mut header = HeaderValue::from_str(&format!("Bearer {token}"))?;
+                    // Keep the token out of logs and HPACK tables.
+                    header.set_sensitive(true);
+                    Some(CachedToken {
+                        header,
+                        valid_until: expires_in.map(|d| now + d),
+                    })
+                }
+                None => None,
+            };
+        }
+        match &self.cached {
+            Some(cached) => {
+                request
+                    .headers_mut()
+                    .insert(AUTHORIZATION, cached.header.clone());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+    /// An AuthProvider that hands out a queue of tokens, and counts how many were fetched.
+    #[derive(Debug, Default)]
+    pub struct MockAuthProvider {
+        tokens: VecDeque<Result<Option<AuthToken>, AuthError>>,
+        fetch_count: Rc<RefCell<usize>>,
+    }
+
+    impl MockAuthProvider {
+        pub fn new(tokens: impl IntoIterator<Item = &'static str>) -> Self {
+            Self {
+                tokens: tokens
+                    .into_iter()
+                    .map(|token| {
+                        Ok(Some(AuthToken {
+                            token: token.to_string(),
+                            expires_in: None,
+                        }))
+                    })
+                    .collect(),
+                ..Default::default()
+            }
+        }
+
+        pub fn add_result(&mut self, result: Result<Option<AuthToken>, AuthError>) {
+            self.tokens.push_back(result);
+        }
+
+        pub fn fetch_count(&self) -> Rc<RefCell<usize>> {
+            Rc::clone(&self.fetch_count)
+        }
+    }
+
+    impl AuthProvider for MockAuthProvider {
+        fn fetch_token(&mut self) -> BoxFuture<'_, Result<Option<AuthToken>, AuthError>> {
+            *self.fetch_count.borrow_mut() += 1;
+            future::ready(self.tokens.pop_front().expect("no more tokens")).boxed()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::MockAuthProvider;
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+
+    fn authorization(request: &Request<Body>) -> Option<&str> {
+        request
+            .headers()
+            .get(AUTHORIZATION)
+            .map(|v| v.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_stub_provider_attaches_nothing() {
+        let mut cache = TokenCache::new(StubAuthProvider);
+        let mut request = Request::default();
+        assert!(!block_on(cache.authorize(&mut request, Instant::now())).unwrap());
+        assert_eq!(authorization(&request), None);
+    }
+
+    #[test]
+    fn test_token_is_cached() {
+        let provider = MockAuthProvider::new(["first", "second"]);
+        let fetch_count = provider.fetch_count();
+        let mut cache = TokenCache::new(provider);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            let mut request = Request::default();
+            assert!(block_on(cache.authorize(&mut request, now)).unwrap());
+            assert_eq!(authorization(&request), Some("Bearer first"));
+            assert!(request.headers()[AUTHORIZATION].is_sensitive());
+        }
+        assert_eq!(*fetch_count.borrow(), 1);
+
+        cache.invalidate();
+        let mut request = Request::default();
+        assert!(block_on(cache.authorize(&mut request, now)).unwrap());
+        assert_eq!(authorization(&request), Some("Bearer second"));
+        assert_eq!(*fetch_count.borrow(), 2);
+    }
+
+    #[test]
+    fn test_token_expires() {
+        let mut provider = MockAuthProvider::default();
+        for token in ["first", "second"] {
+            provider.add_result(Ok(Some(AuthToken {
+                token: token.to_string(),
+                expires_in: Some(Duration::from_secs(60)),
+            })));
+        }
+        let mut cache = TokenCache::new(provider);
+        let now = Instant::now();
+
+        let mut request = Request::default();
+        block_on(cache.authorize(&mut request, now)).unwrap();
+        assert_eq!(authorization(&request), Some("Bearer first"));
+
+        let mut request = Request::default();
+        block_on(cache.authorize(&mut request, now + Duration::from_secs(59))).unwrap();
+        assert_eq!(authorization(&request), Some("Bearer first"));
+
+        let mut request = Request::default();
+        block_on(cache.authorize(&mut request, now + Duration::from_secs(60))).unwrap();
+        assert_eq!(authorization(&request), Some("Bearer second"));
+    }
+
+    #[test]
+    fn test_fetch_error() {
+        let mut provider = MockAuthProvider::default();
+        provider.add_result(Err(AuthError::Fetch(anyhow::anyhow!("no network"))));
+        let mut cache = TokenCache::new(provider);
+
+        let mut request = Request::default();
+        assert_matches!(
+            block_on(cache.authorize(&mut request, Instant::now())),
+            Err(AuthError::Fetch(_))
+        );
+        assert_eq!(authorization(&request), None);
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        let mut cache = TokenCache::new(MockAuthProvider::new(["bad\ntoken"]));
+        let mut request = Request::default();
+        assert_matches!(
+            block_on(cache.authorize(&mut request, Instant::now())),
+            Err(AuthError::InvalidToken(_))
+        );
+    }
+
+    #[test]
+    fn test_is_auth_rejection() {
+        assert!(is_auth_rejection(StatusCode::UNAUTHORIZED));
+        assert!(is_auth_rejection(StatusCode::FORBIDDEN));
+        assert!(!is_auth_rejection(StatusCode::OK));
+        assert!(!is_auth_rejection(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! The channels that the system app can be on, and the app id that Omaha knows it by on each of
+//! them.
+
+use crate::{common::App, protocol::Cohort};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ChannelConfigError {
+    #[error("could not read {}.", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not parse channel configs.")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid channel name {0:?}.")]
+    InvalidChannelName(String),
+    #[error("channel {0} is listed more than once.")]
+    DuplicateChannel(String),
+    #[error("channel {0} has an empty app id.")]
+    EmptyAppId(String),
+    #[error("the default app id is empty.")]
+    EmptyDefaultAppId,
+    #[error("channel {0} has no app id, and there is no default app id to move back to.")]
+    MissingAppId(String),
+    #[error("channel {0} has a check interval of zero.")]
+    ZeroCheckInterval(String),
+    #[error("default channel {0} is not one of the channels.")]
+    UnknownDefaultChannel(String),
+}
+
+/// The configuration of a single channel.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfig {
+    pub name: String,
+
+    /// The app id that Omaha knows the app by on this channel, if it's not the default one.
+    pub appid: Option<String>,
+
+    /// The interval between update checks on this channel.
+    #[serde(
+        default,
+        rename = "check_interval_secs",
+        with = "crate::common::optional_secs"
+    )]
+    pub check_interval: Option<Duration>,
+}
+
+/// The channels that the system app can be on, loaded from a JSON document such as:
+///
+/// ```json
+/// {
+///     "default_channel": "stable",
+///     "default_appid": "stable-app-id",
+///     "known_channels": [
+///         { "name": "stable" },
+///         { "name": "beta", "appid": "beta-app-id", "check_interval_secs": 3600 }
+///     ]
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelConfigs {
+    /// The channel that the app is on until it's moved to another one.
+    pub default_channel: Option<String>,
+
+    /// The app id of the channels that don't have their own.  It's required if any channel has
+    /// one, so that the app can be moved back from that channel.
+    pub default_appid: Option<String>,
+
+    #[serde(default)]
+    pub known_channels: Vec<ChannelConfig>,
+}
+
+impl ChannelConfigs {
+    /// Load and validate the channel configs from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ChannelConfigError> {
+        let path = path.as_ref();
+        let json = fs::read(path).map_err(|source| ChannelConfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        Self::from_json(&json)
+    }
+
+    /// Parse and validate the channel configs.
+    pub fn from_json(json: &[u8]) -> Result<Self, ChannelConfigError> {
+        let configs: Self = serde_json::from_slice(json)?;
+        configs.validate()?;
+        Ok(configs)
+    }
+
+    /// Check that the channel names are valid and unique, that the app ids and check intervals
+    /// aren't empty, that every channel has an app id if any does, and that the default channel
+    /// is one of the channels.
+    pub fn validate(&self) -> Result<(), ChannelConfigError> {
+        if self.default_appid.as_deref() == Some("") {
+            return Err(ChannelConfigError::EmptyDefaultAppId);
+        }
+        let has_appids = self.default_appid.is_some()
+            || self
+                .known_channels
+                .iter()
+                .any(|channel| channel.appid.is_some());
+        let mut names = HashSet::new();
+        for channel in &self.known_channels {
+            if !Cohort::validate_name(&channel.name) {
+                return Err(ChannelConfigError::InvalidChannelName(channel.name.clone()));
+            }
+            if !names.insert(channel.name.as_str()) {
+                return Err(ChannelConfigError::DuplicateChannel(channel.name.clone()));
+            }
+            if channel.appid.as_deref() == Some("") {
+                return Err(ChannelConfigError::EmptyAppId(channel.name.clone()));
+            }
+            if has_appids && self.get_appid(channel).is_none() {
+                return Err(ChannelConfigError::MissingAppId(channel.name.clone()));
+            }
+            if channel.check_interval == Some(Duration::ZERO) {
+                return Err(ChannelConfigError::ZeroCheckInterval(channel.name.clone()));
+            }
+        }
+        match &self.default_channel {
+            Some(default_channel) if !names.contains(default_channel.as_str()) => Err(
+                ChannelConfigError::UnknownDefaultChannel(default_channel.clone()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_channel(&self, name: &str) -> Option<&ChannelConfig> {
+        self.known_channels
+            .iter()
+            .find(|channel| channel.name == name)
+    }
+
+    pub fn get_default_channel(&self) -> Option<&ChannelConfig> {
+        self.get_channel(self.default_channel.as_deref()?)
+    }
+
+    /// The app id of |channel|: its own, or else the default one.
+    pub fn get_appid<'a>(&'a self, channel: &'a ChannelConfig) -> Option<&'a str> {
+        channel.appid.as_deref().or(self.default_appid.as_deref())
+    }
+
+    /// The app id of each channel, for `StateMachineBuilder::channel_app_ids`.  This is empty if
+    /// the app id doesn't depend on the channel.
+    pub fn channel_app_ids(&self) -> HashMap<String, String> {
+        self.known_channels
+            .iter()
+            .filter_map(|channel| {
+                Some((channel.name.clone(), self.get_appid(channel)?.to_string()))
+            })
+            .collect()
+    }
+
+    /// The check interval of the channel that |app| targets, if it has one.
+    pub fn check_interval(&self, app: &App) -> Option<Duration> {
+        self.get_channel(app.get_target_channel())?.check_interval
+    }
+
+    /// Put |app| on the default channel if it doesn't target one yet, and switch it to the app id
+    /// of the channel that it targets.
+    pub fn apply_to_app(&self, app: &mut App) {
+        if app.get_target_channel().is_empty() {
+            if let Some(default_channel) = &self.default_channel {
+                app.cohort.hint = Some(default_channel.clone());
+            }
+        }
+        if let Some(appid) = self
+            .get_channel(app.get_target_channel())
+            .and_then(|channel| self.get_appid(channel))
+        {
+            app.id = appid.to_string();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use assert_matches::assert_matches;
+    use serde_json::json;
+
+    fn make_configs() -> ChannelConfigs {
+        ChannelConfigs::from_json(
+            json!({
+                "default_channel": "stable",
+                "default_appid": "app-id",
+                "known_channels": [
+                    { "name": "stable" },
+                    { "name": "beta", "appid": "beta-app-id", "check_interval_secs": 3600 },
+                ],
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_from_json() {
+        let configs = make_configs();
+        assert_eq!(
+            configs,
+            ChannelConfigs {
+                default_channel: Some("stable".to_string()),
+                default_appid: Some("app-id".to_string()),
+                known_channels: vec![
+                    ChannelConfig {
+                        name: "stable".to_string(),
+                        appid: None,
+                        check_interval: None,
+                    },
+                    ChannelConfig {
+                        name: "beta".to_string(),
+                        appid: Some("beta-app-id".to_string()),
+                        check_interval: Some(Duration::from_secs(3600)),
+                    },
+                ],
+            }
+        );
+        assert_eq!(configs.get_default_channel(), configs.get_channel("stable"));
+        assert_eq!(configs.get_channel("alpha"), None);
+        assert_eq!(
+            configs.channel_app_ids(),
+            HashMap::from([
+                ("stable".to_string(), "app-id".to_string()),
+                ("beta".to_string(), "beta-app-id".to_string()),
+            ])
+        );
+        let configs =
+            ChannelConfigs::from_json(br#"{"known_channels": [{ "name": "stable" }]}"#).unwrap();
+        assert_eq!(configs.channel_app_ids(), HashMap::new());
+        assert_eq!(
+            ChannelConfigs::from_json(b"{}").unwrap(),
+            ChannelConfigs::default()
+        );
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        let from_json =
+            |json: serde_json::Value| ChannelConfigs::from_json(json.to_string().as_bytes());
+
+        assert_matches!(
+            from_json(json!({ "known_channels": [{ "name": "stable", "repo": "x" }] })),
+            Err(ChannelConfigError::Json(_))
+        );
+        assert_matches!(
+            from_json(json!({ "known_channels": [{ "name": "" }] })),
+            Err(ChannelConfigError::InvalidChannelName(name)) if name.is_empty()
+        );
+        assert_matches!(
+            from_json(json!({ "known_channels": [{ "name": "stable" }, { "name": "stable" }] })),
+            Err(ChannelConfigError::DuplicateChannel(name)) if name == "stable"
+        );
+        assert_matches!(
+            from_json(json!({ "known_channels": [{ "name": "stable", "appid": "" }] })),
+            Err(ChannelConfigError::EmptyAppId(name)) if name == "stable"
+        );
+        assert_matches!(
+            from_json(json!({ "default_appid": "", "known_channels": [{ "name": "stable" }] })),
+            Err(ChannelConfigError::EmptyDefaultAppId)
+        );
+        assert_matches!(
+            from_json(json!({
+                "known_channels": [{ "name": "stable" }, { "name": "beta", "appid": "beta-app-id" }]
+            })),
+            Err(ChannelConfigError::MissingAppId(name)) if name == "stable"
+        );
+        assert_matches!(
+            from_json(json!({ "known_channels": [{ "name": "stable", "check_interval_secs": 0 }] })),
+            Err(ChannelConfigError::ZeroCheckInterval(name)) if name == "stable"
+        );
+        assert_matches!(
+            from_json(json!({ "default_channel": "beta", "known_channels": [{ "name": "stable" }] })),
+            Err(ChannelConfigError::UnknownDefaultChannel(name)) if name == "beta"
+        );
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let dir = TempDir::new();
+        let path = dir.join("channel-config.json");
+        assert_matches!(
+            ChannelConfigs::load(&path),
+            Err(ChannelConfigError::Io { path: error_path, .. }) if error_path == path
+        );
+    }
+
+    #[test]
+    fn test_apply_to_app() {
+        let configs = make_configs();
+
+        let mut app = App::builder().id("some-id").version([1]).build();
+        configs.apply_to_app(&mut app);
+        assert_eq!(app.id, "app-id");
+        assert_eq!(app.get_target_channel(), "stable");
+        assert_eq!(configs.check_interval(&app), None);
+
+        let mut app = App::builder()
+            .id("app-id")
+            .version([1])
+            .cohort(Cohort::from_hint("beta"))
+            .build();
+        configs.apply_to_app(&mut app);
+        assert_eq!(app.id, "beta-app-id");
+        assert_eq!(app.get_target_channel(), "beta");
+        assert_eq!(
+            configs.check_interval(&app),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn test_channel_round_trip() {
+        let configs = make_configs();
+        let channel_app_ids = configs.channel_app_ids();
+        let mut app = App::builder().id("app-id").version([1]).build();
+        configs.apply_to_app(&mut app);
+
+        for (channel, appid) in [
+            ("beta", "beta-app-id"),
+            ("stable", "app-id"),
+            ("beta", "beta-app-id"),
+        ] {
+            app.set_target_channel(
+                Some(channel.to_string()),
+                channel_app_ids.get(channel).cloned(),
+            );
+            assert_eq!(app.id, appid);
+            assert_eq!(app.get_target_channel(), channel);
+
+            let mut reloaded = App::builder()
+                .id("app-id")
+                .version([1])
+                .cohort(Cohort::from_hint(channel))
+                .build();
+            configs.apply_to_app(&mut reloaded);
+            assert_eq!(reloaded.id, appid);
+        }
+    }
+}
@@ -295,11 +295,23 @@ pub struct ProtocolState {
     /// The number of consecutive failed update checks.  Used to perform backoffs.
     pub consecutive_failed_update_checks: u32,
 
-    /// The number of consecutive proxied requests.  Used to periodically not use
+    /// The number of consecutive proxied update checks.  Used to periodically not use
     /// proxies, in the case of an invalid proxy configuration.
     pub consecutive_proxied_requests: u32,
 }
 
+/// Deserialize an optional number of seconds as an `Option<Duration>`, for configuration files.
+pub(crate) mod optional_secs {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
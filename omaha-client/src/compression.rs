@@ -0,0 +1,311 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! HTTP content-encoding support for Omaha request and response bodies.
+//!
+//! CUP always hashes the uncompressed bytes: request bodies are compressed after the request has
+//! been decorated, and response bodies are decompressed before the response is verified.
+
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use http::{header::CONTENT_ENCODING, Response};
+use std::{
+    fmt,
+    io::{Read, Write},
+    str::FromStr,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CompressionError {
+    #[error("Unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("Unable to encode or decode body")]
+    Io(#[from] std::io::Error),
+
+    #[error("Decoded body is larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+/// The supported values of the `Content-Encoding` header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ContentEncoding {
+    Gzip,
+    /// The zlib format, as HTTP's "deflate" encoding is defined.
+    Deflate,
+}
+
+impl ContentEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentEncoding::Gzip => "gzip",
+            ContentEncoding::Deflate => "deflate",
+        }
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Result<Vec<u8>, CompressionError> {
+        Ok(match self {
+            ContentEncoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            ContentEncoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        })
+    }
+
+    /// Decode |data|, failing with `TooLarge` if it decodes to more than |max_size| bytes.
+    pub fn decode(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>, CompressionError> {
+        // Read one byte past the limit, so that a body of exactly |max_size| bytes can be told
+        // apart from a larger one.
+        let limit = (max_size as u64).saturating_add(1);
+        let mut decoded = vec![];
+        match self {
+            ContentEncoding::Gzip => GzDecoder::new(data).take(limit).read_to_end(&mut decoded)?,
+            ContentEncoding::Deflate => ZlibDecoder::new(data)
+                .take(limit)
+                .read_to_end(&mut decoded)?,
+        };
+        if decoded.len() > max_size {
+            return Err(CompressionError::TooLarge(max_size));
+        }
+        Ok(decoded)
+    }
+}
+
+impl fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = CompressionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Ok(ContentEncoding::Gzip),
+            "deflate" => Ok(ContentEncoding::Deflate),
+            _ => Err(CompressionError::UnsupportedEncoding(s.to_string())),
+        }
+    }
+}
+
+/// Compression settings for Omaha requests and responses.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CompressionConfig {
+    /// The encoding to compress request bodies with, or None to never compress them.
+    pub request_encoding: Option<ContentEncoding>,
+
+    /// Only request bodies larger than this many bytes are compressed.
+    pub request_threshold: usize,
+
+    /// Whether to advertise support for compressed responses with `Accept-Encoding`, and decode
+    /// responses that have a `Content-Encoding`.
+    pub decompress_responses: bool,
+
+    /// The largest size in bytes that a response body may decode to.
+    pub max_decoded_size: usize,
+}
+
+impl Default for CompressionConfig {
+    /// Compression is disabled in both directions by default.
+    fn default() -> Self {
+        Self {
+            request_encoding: None,
+            request_threshold: 4096,
+            decompress_responses: false,
+            max_decoded_size: 16 << 20,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// Returns the encoding to use for a request body of the given length, if any.
+    pub fn request_encoding_for(&self, len: usize) -> Option<ContentEncoding> {
+        self.request_encoding
+            .filter(|_| len > self.request_threshold)
+    }
+}
+
+/// Decode the body of a response according to its `Content-Encoding` header(s), and remove them.
+///
+/// Multiple encodings are undone in the reverse of the order in which they were applied, and each
+/// decoded body is limited to |max_size| bytes.
+pub fn decode_response(
+    response: &mut Response<Vec<u8>>,
+    max_size: usize,
+) -> Result<(), CompressionError> {
+    let encodings = response
+        .headers()
+        .get_all(CONTENT_ENCODING)
+        .iter()
+        .map(|value| {
+            value
+                .to_str()
+                .map_err(|_| CompressionError::UnsupportedEncoding(format!("{value:?}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|encoding| !encoding.is_empty() && !encoding.eq_ignore_ascii_case("identity"))
+        .map(ContentEncoding::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for encoding in encodings.iter().rev() {
+        *response.body_mut() = encoding.decode(response.body(), max_size)?;
+    }
+    response.headers_mut().remove(CONTENT_ENCODING);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    const DATA: &[u8] = br#"{"response":{"protocol":"3.0","app":[{"appid":"{some-app}"}]}}"#;
+
+    #[test]
+    fn test_round_trip() {
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let encoded = encoding.encode(DATA).unwrap();
+            assert_ne!(encoded, DATA);
+            assert_eq!(encoding.decode(&encoded, DATA.len()).unwrap(), DATA);
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid_data() {
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            assert_matches!(encoding.decode(DATA, 1024), Err(CompressionError::Io(_)));
+        }
+    }
+
+    #[test]
+    fn test_decode_too_large() {
+        let data = vec![0; 1 << 20];
+        for encoding in [ContentEncoding::Gzip, ContentEncoding::Deflate] {
+            let encoded = encoding.encode(&data).unwrap();
+            assert!(encoded.len() < 4096);
+            assert_matches!(
+                encoding.decode(&encoded, data.len() - 1),
+                Err(CompressionError::TooLarge(size)) if size == data.len() - 1
+            );
+            assert_eq!(encoding.decode(&encoded, data.len()).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_parse_encoding() {
+        assert_eq!(
+            "gzip".parse::<ContentEncoding>().unwrap(),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            "X-Gzip".parse::<ContentEncoding>().unwrap(),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            " deflate ".parse::<ContentEncoding>().unwrap(),
+            ContentEncoding::Deflate
+        );
+        assert_matches!(
+            "br".parse::<ContentEncoding>(),
+            Err(CompressionError::UnsupportedEncoding(e)) if e == "br"
+        );
+    }
+
+    #[test]
+    fn test_request_encoding_for() {
+        let config = CompressionConfig {
+            request_encoding: Some(ContentEncoding::Gzip),
+            request_threshold: 10,
+            decompress_responses: false,
+            max_decoded_size: 1024,
+        };
+        assert_eq!(config.request_encoding_for(10), None);
+        assert_eq!(config.request_encoding_for(11), Some(ContentEncoding::Gzip));
+        assert_eq!(
+            CompressionConfig::default().request_encoding_for(1 << 20),
+            None
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let mut response = Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(ContentEncoding::Gzip.encode(DATA).unwrap())
+            .unwrap();
+        decode_response(&mut response, 1024).unwrap();
+        assert_eq!(response.body(), DATA);
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn test_decode_response_multiple_encodings() {
+        let encoded = ContentEncoding::Gzip
+            .encode(&ContentEncoding::Deflate.encode(DATA).unwrap())
+            .unwrap();
+        let mut response = Response::builder()
+            .header(CONTENT_ENCODING, "deflate, identity")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(encoded)
+            .unwrap();
+        decode_response(&mut response, 1024).unwrap();
+        assert_eq!(response.body(), DATA);
+    }
+
+    #[test]
+    fn test_decode_response_without_encoding() {
+        let mut response = Response::new(DATA.to_vec());
+        decode_response(&mut response, 1024).unwrap();
+        assert_eq!(response.body(), DATA);
+
+        let mut response = Response::builder()
+            .header(CONTENT_ENCODING, "identity")
+            .body(DATA.to_vec())
+            .unwrap();
+        decode_response(&mut response, 1024).unwrap();
+        assert_eq!(response.body(), DATA);
+    }
+
+    #[test]
+    fn test_decode_response_too_large() {
+        let mut response = Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(ContentEncoding::Gzip.encode(&[0; 4096]).unwrap())
+            .unwrap();
+        assert_matches!(
+            decode_response(&mut response, 1024),
+            Err(CompressionError::TooLarge(1024))
+        );
+    }
+
+    #[test]
+    fn test_decode_response_unsupported_encoding() {
+        let mut response = Response::builder()
+            .header(CONTENT_ENCODING, "br")
+            .body(DATA.to_vec())
+            .unwrap();
+        assert_matches!(
+            decode_response(&mut response, 1024),
+            Err(CompressionError::UnsupportedEncoding(_))
+        );
+    }
+}
@@ -6,15 +6,22 @@
 // This file may not be copied, modified, or distributed except according to
 // those terms.
 
-use crate::cup_ecdsa::PublicKeys;
+use crate::compression::CompressionConfig;
+use crate::cup_ecdsa::{PublicKeys, PublicKeysError};
+use crate::http_request::proxy::ProxyConfig;
 use crate::protocol::request::OS;
 use crate::version::Version;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 /// This is the name and version of the updater binary that is built using this crate.
 ///
 /// This is how the updater identifies itself with the Omaha service.
 ///
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct Updater {
     /// The string identifying the updater itself.  (e.g. 'Omaha', 'Fuchsia/Rust')
     pub name: String,
@@ -36,6 +43,93 @@ pub struct Config {
 
     /// These are the public keys to use when communicating with the Omaha server.
     pub omaha_public_keys: Option<PublicKeys>,
+
+    /// The proxies to use when policy allows it (see `RequestParams::use_configured_proxies`).
+    /// If None, all requests are made directly.
+    pub proxy_config: Option<ProxyConfig>,
+
+    /// How request and response bodies are compressed.
+    pub compression: CompressionConfig,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read {}.", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not parse config.")]
+    Json(#[from] serde_json::Error),
+    #[error("could not load public keys from {}.", .path.display())]
+    PublicKeys {
+        path: PathBuf,
+        #[source]
+        source: PublicKeysError,
+    },
+}
+
+/// The JSON form of a [`Config`], e.g.:
+///
+/// ```json
+/// {
+///     "updater": { "name": "updater", "version": "1.2.3.4" },
+///     "os": { "platform": "Fuchsia", "version": "1.0", "sp": "", "arch": "x86-64" },
+///     "service_url": "https://example.com/service/update2/json",
+///     "omaha_public_keys": "omaha_public_keys.json"
+/// }
+/// ```
+///
+/// `omaha_public_keys` is the path of a JSON key bundle (see [`PublicKeys::from_json`]),
+/// relative to the directory of the config file.  It may be omitted to disable CUP.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    updater: Updater,
+    os: OS,
+    service_url: String,
+    omaha_public_keys: Option<PathBuf>,
+}
+
+impl Config {
+    /// Load a config from a JSON file, along with the public key bundle it references.
+    ///
+    /// Proxies and compression are not part of the file, and are left disabled.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let json = fs::read(path).map_err(|source| ConfigError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::from_json(&json, base_dir)
+    }
+
+    /// Parse a config from JSON, resolving relative paths in it against `base_dir`.
+    pub fn from_json(json: &[u8], base_dir: &Path) -> Result<Self, ConfigError> {
+        let ConfigFile {
+            updater,
+            os,
+            service_url,
+            omaha_public_keys,
+        } = serde_json::from_slice(json)?;
+        let omaha_public_keys = omaha_public_keys
+            .map(|path| {
+                let path = base_dir.join(path);
+                PublicKeys::load_bundle(&path)
+                    .map_err(|source| ConfigError::PublicKeys { path, source })
+            })
+            .transpose()?;
+        Ok(Config {
+            updater,
+            os,
+            service_url,
+            omaha_public_keys,
+            proxy_config: None,
+            compression: CompressionConfig::default(),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -71,6 +165,95 @@ pub mod test_support {
             },
             service_url: "http://example.com/".to_string(),
             omaha_public_keys: Some(omaha_public_keys),
+            proxy_config: None,
+            compression: CompressionConfig::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cup_ecdsa::test_support::{
+            make_default_json_public_keys_for_test, make_default_public_keys_for_test,
+        },
+        test_support::TempDir,
+    };
+    use assert_matches::assert_matches;
+
+    fn make_config_json(omaha_public_keys: Option<&str>) -> String {
+        let mut json = serde_json::json!({
+            "updater": {"name": "updater", "version": "1.2.3.4"},
+            "os": {"platform": "platform", "version": "0.1.2.3", "sp": "sp", "arch": "test_arch"},
+            "service_url": "http://example.com/",
+        });
+        if let Some(path) = omaha_public_keys {
+            json["omaha_public_keys"] = path.into();
+        }
+        json.to_string()
+    }
+
+    #[test]
+    fn test_config_from_json() {
+        let config = Config::from_json(make_config_json(None).as_bytes(), Path::new("")).unwrap();
+        assert_eq!(config.updater.name, "updater");
+        assert_eq!(config.updater.version, Version::from([1, 2, 3, 4]));
+        assert_eq!(
+            config.os,
+            OS {
+                platform: "platform".to_string(),
+                version: "0.1.2.3".to_string(),
+                service_pack: "sp".to_string(),
+                arch: "test_arch".to_string(),
+            }
+        );
+        assert_eq!(config.service_url, "http://example.com/");
+        assert_eq!(config.omaha_public_keys, None);
+        assert_eq!(config.proxy_config, None);
+        assert_eq!(config.compression, CompressionConfig::default());
+    }
+
+    #[test]
+    fn test_config_load_with_public_keys() {
+        let dir = TempDir::new();
+        dir.write(
+            "keys/bundle.json",
+            make_default_json_public_keys_for_test().to_string(),
+        );
+        dir.write("config.json", make_config_json(Some("keys/bundle.json")));
+        dir.write(
+            "bad_config.json",
+            make_config_json(Some("keys/missing.json")),
+        );
+
+        let config = Config::load(dir.join("config.json"));
+        let bad_config = Config::load(dir.join("bad_config.json"));
+        let missing_config = Config::load(dir.join("missing.json"));
+
+        assert_eq!(
+            config.unwrap().omaha_public_keys,
+            Some(make_default_public_keys_for_test())
+        );
+        assert_matches!(
+            bad_config,
+            Err(ConfigError::PublicKeys { path, source: PublicKeysError::Io { .. } })
+                if path == dir.join("keys/missing.json")
+        );
+        assert_matches!(missing_config, Err(ConfigError::Io { .. }));
+    }
+
+    #[test]
+    fn test_config_from_json_errors() {
+        assert_matches!(
+            Config::from_json(b"{}", Path::new("")),
+            Err(ConfigError::Json(_))
+        );
+        let mut json: serde_json::Value = serde_json::from_str(&make_config_json(None)).unwrap();
+        json["unknown_field"] = 1.into();
+        assert_matches!(
+            Config::from_json(json.to_string().as_bytes(), Path::new("")),
+            Err(ConfigError::Json(_))
+        );
+    }
+}
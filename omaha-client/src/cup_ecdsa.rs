@@ -14,7 +14,15 @@ use rand::{thread_rng, Rng};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::{digest, Digest, Sha256};
 use signature::Signature;
-use std::{collections::HashMap, convert::TryInto, fmt, fmt::Debug};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    fmt,
+    fmt::Debug,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 /// Error enum listing different kinds of CUPv2 decoration errors.
 #[derive(Debug, thiserror::Error)]
@@ -27,27 +35,118 @@ pub enum CupDecorationError {
     AppendQueryParameterError(#[from] crate::http_uri_ext::Error),
 }
 
+/// The response headers which can carry the server's CUPv2 proof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CupProofHeader {
+    /// `X-Cup-Server-Proof`, which is checked first.
+    ServerProof,
+    /// `ETag`, which is only checked if there's no `X-Cup-Server-Proof` header, since caches may
+    /// rewrite or strip it.
+    Etag,
+}
+
+impl CupProofHeader {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CupProofHeader::ServerProof => X_CUP_SERVER_PROOF,
+            CupProofHeader::Etag => "etag",
+        }
+    }
+}
+
+impl fmt::Display for CupProofHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 /// Error enum listing different kinds of CUPv2 verification errors.
 #[derive(Debug, thiserror::Error)]
 pub enum CupVerificationError {
+    #[error("X-Cup-Server-Proof and etag headers both missing.")]
+    ProofHeaderMissing,
+    #[error("{0} header is not a string.")]
+    HeaderNotString(CupProofHeader, #[source] hyper::header::ToStrError),
+    #[error("{0} header is malformed.")]
+    HeaderMalformed(CupProofHeader),
+    #[error("{0} header's request hash is malformed.")]
+    ProofRequestHashMalformed(CupProofHeader),
+    #[error("{0} header's request hash doesn't match.")]
+    ProofRequestHashMismatch(CupProofHeader),
+    #[error("{0} header's signature is malformed.")]
+    ProofSignatureMalformed(CupProofHeader),
+    #[error("specified public key ID not found in internal map.")]
+    SpecifiedPublicKeyIdMissing,
+    #[error("response does not answer an outstanding request.")]
+    NonceNotOutstanding,
+    #[error("could not verify the response signature.")]
+    SignatureError(#[from] ecdsa::Error),
+
+    // The variants below are no longer returned, now that the proof may come from either the
+    // X-Cup-Server-Proof or the ETag header.  They're kept so that existing matches still compile.
+    #[deprecated(note = "use ProofHeaderMissing")]
     #[error("etag header missing.")]
     EtagHeaderMissing,
+    #[deprecated(note = "use HeaderNotString")]
     #[error("etag header is not a string.")]
     EtagNotString(hyper::header::ToStrError),
+    #[deprecated(note = "use HeaderMalformed")]
     #[error("etag header is malformed.")]
     EtagMalformed,
+    #[deprecated(note = "use ProofRequestHashMalformed")]
     #[error("etag header's request hash is malformed.")]
     RequestHashMalformed,
+    #[deprecated(note = "use ProofRequestHashMismatch")]
     #[error("etag header's request hash doesn't match.")]
     RequestHashMismatch,
+    #[deprecated(note = "use ProofSignatureMalformed")]
     #[error("etag header's signature is malformed.")]
     SignatureMalformed,
-    #[error("specified public key ID not found in internal map.")]
-    SpecifiedPublicKeyIdMissing,
-    #[error("could not verify etag header's signature.")]
+}
+
+/// Error enum listing different kinds of CUPv2 signing errors.
+#[derive(Debug, thiserror::Error)]
+pub enum CupSigningError {
+    #[error("request has no cup2key query parameter.")]
+    Cup2KeyMissing,
+    #[error("cup2key query parameter is malformed.")]
+    Cup2KeyMalformed,
+    #[error("no private key for public key ID {0}.")]
+    SpecifiedPublicKeyIdMissing(PublicKeyId),
+    #[error("could not sign transaction hash.")]
     SignatureError(#[from] ecdsa::Error),
 }
 
+/// Error enum listing different kinds of errors when loading public keys.
+#[derive(Debug, thiserror::Error)]
+pub enum PublicKeysError {
+    #[error("could not read {}.", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not parse public key bundle.")]
+    Json(#[from] serde_json::Error),
+    #[error("public key {id} is not a valid PEM-encoded public key: {reason}.")]
+    InvalidKey {
+        id: PublicKeyId,
+        reason: pkcs8::spki::Error,
+    },
+    #[error("public key {id} is not an elliptic curve key.")]
+    UnsupportedAlgorithm { id: PublicKeyId },
+    #[error("public key {id} is not a P-256 key.")]
+    UnsupportedCurve { id: PublicKeyId },
+    #[error("file name {} is not of the form <id>.pem.", .0.display())]
+    InvalidFileName(PathBuf),
+    #[error("public key ID {0} is used by more than one key.")]
+    DuplicateId(PublicKeyId),
+    #[error("public key bundle has no latest key.")]
+    NoLatestKey,
+    #[error("latest public key {0} not found.")]
+    LatestKeyMissing(PublicKeyId),
+}
+
 /// By convention, this is always the u64 hash of the public key
 /// value.
 pub type PublicKeyId = u64;
@@ -90,6 +189,134 @@ pub struct PublicKeys {
     pub historical: Vec<PublicKeyAndId>,
 }
 
+/// The JSON form of a `PublicKeyAndId`, with the key left as PEM so that it can be validated
+/// with a descriptive error.
+#[derive(Deserialize)]
+struct PemPublicKeyAndId {
+    key: String,
+    id: PublicKeyId,
+}
+
+#[derive(Deserialize)]
+struct PemPublicKeys {
+    latest: Option<PemPublicKeyAndId>,
+    #[serde(default)]
+    historical: Vec<PemPublicKeyAndId>,
+}
+
+/// Parse a PEM-encoded SubjectPublicKeyInfo, which must hold a P-256 key.
+fn parse_pem_public_key(id: PublicKeyId, pem: &str) -> Result<PublicKey, PublicKeysError> {
+    use pkcs8::AssociatedOid;
+    use pkcs8::DecodePublicKey;
+    match elliptic_curve::PublicKey::<p256::NistP256>::from_public_key_pem(pem) {
+        Ok(key) => Ok(key.into()),
+        // The unknown OID is the one that was expected, which tells whether the key's algorithm
+        // or its curve didn't match.
+        Err(pkcs8::spki::Error::OidUnknown { oid }) if oid == elliptic_curve::ALGORITHM_OID => {
+            Err(PublicKeysError::UnsupportedAlgorithm { id })
+        }
+        Err(pkcs8::spki::Error::OidUnknown { oid }) if oid == p256::NistP256::OID => {
+            Err(PublicKeysError::UnsupportedCurve { id })
+        }
+        Err(reason) => Err(PublicKeysError::InvalidKey { id, reason }),
+    }
+}
+
+impl PublicKeys {
+    /// Parse a JSON key bundle, of the same form that `PublicKeys` serializes to:
+    ///
+    /// ```json
+    /// {
+    ///     "latest": { "id": 2, "key": "-----BEGIN PUBLIC KEY-----\n..." },
+    ///     "historical": [{ "id": 1, "key": "-----BEGIN PUBLIC KEY-----\n..." }]
+    /// }
+    /// ```
+    ///
+    /// `historical` may be omitted.
+    pub fn from_json(json: &[u8]) -> Result<Self, PublicKeysError> {
+        let bundle: PemPublicKeys = serde_json::from_slice(json)?;
+        let parse = |k: PemPublicKeyAndId| -> Result<PublicKeyAndId, PublicKeysError> {
+            Ok(PublicKeyAndId {
+                key: parse_pem_public_key(k.id, &k.key)?,
+                id: k.id,
+            })
+        };
+        let public_keys = PublicKeys {
+            latest: parse(bundle.latest.ok_or(PublicKeysError::NoLatestKey)?)?,
+            historical: bundle
+                .historical
+                .into_iter()
+                .map(parse)
+                .collect::<Result<_, _>>()?,
+        };
+        public_keys.validate()?;
+        Ok(public_keys)
+    }
+
+    /// Load a JSON key bundle from a file, see [`PublicKeys::from_json`].
+    pub fn load_bundle(path: impl AsRef<Path>) -> Result<Self, PublicKeysError> {
+        let path = path.as_ref();
+        let json = fs::read(path).map_err(|source| PublicKeysError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        Self::from_json(&json)
+    }
+
+    /// Load every `<id>.pem` file in `dir`, each holding a PEM-encoded public key.  The key with
+    /// ID `latest` becomes the latest key, and all others are historical, ordered by ID.
+    ///
+    /// Files without a `.pem` extension are ignored.
+    pub fn load_dir(dir: impl AsRef<Path>, latest: PublicKeyId) -> Result<Self, PublicKeysError> {
+        let dir = dir.as_ref();
+        let io_error = |path: &Path| {
+            let path = path.to_owned();
+            |source| PublicKeysError::Io { path, source }
+        };
+
+        let mut keys = vec![];
+        for entry in fs::read_dir(dir).map_err(io_error(dir))? {
+            let path = entry.map_err(io_error(dir))?.path();
+            if path.extension() != Some("pem".as_ref()) {
+                continue;
+            }
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<PublicKeyId>().ok())
+                .ok_or_else(|| PublicKeysError::InvalidFileName(path.clone()))?;
+            let pem = fs::read_to_string(&path).map_err(io_error(&path))?;
+            keys.push(PublicKeyAndId {
+                key: parse_pem_public_key(id, &pem)?,
+                id,
+            });
+        }
+        keys.sort_by_key(|k| k.id);
+
+        let latest_index = keys
+            .iter()
+            .position(|k| k.id == latest)
+            .ok_or(PublicKeysError::LatestKeyMissing(latest))?;
+        let public_keys = PublicKeys {
+            latest: keys.remove(latest_index),
+            historical: keys,
+        };
+        public_keys.validate()?;
+        Ok(public_keys)
+    }
+
+    /// Check that no two keys share an ID.
+    pub fn validate(&self) -> Result<(), PublicKeysError> {
+        let mut ids = HashSet::new();
+        for key in std::iter::once(&self.latest).chain(&self.historical) {
+            if !ids.insert(key.id) {
+                return Err(PublicKeysError::DuplicateId(key.id));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub struct Nonce([u8; 32]);
 
@@ -125,6 +352,20 @@ impl Nonce {
     }
 }
 
+/// Error returned when parsing a `Nonce` that isn't 32 hex-encoded bytes.
+#[derive(Debug, thiserror::Error)]
+#[error("nonce is not 64 hex digits.")]
+pub struct NonceParseError;
+
+impl std::str::FromStr for Nonce {
+    type Err = NonceParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| NonceParseError)?;
+        Ok(Nonce(bytes.try_into().map_err(|_| NonceParseError)?))
+    }
+}
+
 impl fmt::Display for Nonce {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", hex::encode(self.0))
@@ -164,14 +405,18 @@ pub trait Cupv2RequestHandler {
         request: &mut impl CupRequest,
     ) -> Result<RequestMetadata, CupDecorationError>;
 
-    /// Examines an incoming client request with an ETag HTTP Header. Returns an
-    /// error if the response is not authentic.
+    /// Examines an incoming server response with an X-Cup-Server-Proof or ETag
+    /// HTTP Header. Returns an error if the response is not authentic.
     fn verify_response(
         &self,
         request_metadata: &RequestMetadata,
         resp: &Response<Vec<u8>>,
         public_key_id: PublicKeyId,
     ) -> Result<DerSignature, CupVerificationError>;
+
+    /// Forget a decorated request whose response will never be verified, e.g. because it failed
+    /// or is being retried, so that no response can be verified against it later.
+    fn abandon_request(&self, _request_metadata: &RequestMetadata) {}
 }
 
 // General trait for something which can verify CUPv2 signatures.
@@ -192,6 +437,10 @@ pub trait Cupv2Handler: Cupv2RequestHandler + Cupv2Verifier {}
 
 impl<T> Cupv2Handler for T where T: Cupv2RequestHandler + Cupv2Verifier {}
 
+/// The number of decorated requests that can await verification at the same time. When more
+/// requests are decorated, the oldest ones are forgotten.
+const MAX_OUTSTANDING_NONCES: usize = 8;
+
 // Default Cupv2Handler.
 #[derive(Debug)]
 pub struct StandardCupv2Handler {
@@ -199,6 +448,10 @@ pub struct StandardCupv2Handler {
     /// startup. This map should never be empty.
     parameters_by_id: HashMap<PublicKeyId, PublicKey>,
     latest_public_key_id: PublicKeyId,
+    /// Nonces of the requests that were decorated, but whose responses haven't been verified
+    /// yet, oldest first. Each nonce can only be used to verify one response, so a signed
+    /// response can't be replayed to a later request, or to a retry of the same request.
+    outstanding_nonces: Mutex<VecDeque<Nonce>>,
 }
 
 impl StandardCupv2Handler {
@@ -210,7 +463,78 @@ impl StandardCupv2Handler {
                 .map(|k| (k.id, k.key))
                 .collect(),
             latest_public_key_id: public_keys.latest.id,
+            outstanding_nonces: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn outstanding_nonces(&self) -> std::sync::MutexGuard<'_, VecDeque<Nonce>> {
+        // The nonces are always left in a consistent state, so a poisoned lock is still usable.
+        self.outstanding_nonces
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Verify the proof header of a response to the request described by `request_metadata`,
+    /// without checking that the request is still awaiting a response. This is for verifying
+    /// captured or stored exchanges offline: responses to live requests must be checked with
+    /// `verify_response`, which can't be fooled by replayed responses.
+    pub fn verify_proof(
+        &self,
+        request_metadata: &RequestMetadata,
+        resp: &Response<Vec<u8>>,
+        public_key_id: PublicKeyId,
+    ) -> Result<DerSignature, CupVerificationError> {
+        let (header, proof) = match resp.headers().get(X_CUP_SERVER_PROOF) {
+            Some(value) => (CupProofHeader::ServerProof, value),
+            None => (
+                CupProofHeader::Etag,
+                resp.headers()
+                    .get(ETAG)
+                    .ok_or(CupVerificationError::ProofHeaderMissing)?,
+            ),
+        };
+        let proof = proof
+            .to_str()
+            .map_err(|e| CupVerificationError::HeaderNotString(header, e))?;
+        let proof = match header {
+            CupProofHeader::ServerProof => proof,
+            CupProofHeader::Etag => parse_etag(proof),
+        };
+
+        let (encoded_signature, hex_hash): (&str, &str) = proof
+            .split_once(':')
+            .ok_or(CupVerificationError::HeaderMalformed(header))?;
+
+        let actual_hash = &hex::decode(hex_hash)
+            .map_err(|_| CupVerificationError::ProofRequestHashMalformed(header))?;
+
+        let request_body_hash = Sha256::digest(&request_metadata.request_body);
+        if *request_body_hash != *actual_hash {
+            return Err(CupVerificationError::ProofRequestHashMismatch(header));
         }
+
+        let signature = DerSignature::from_bytes(
+            &hex::decode(encoded_signature)
+                .map_err(|_| CupVerificationError::ProofSignatureMalformed(header))?,
+        )?;
+
+        let () = self.verify_response_with_signature(
+            &signature,
+            &request_metadata.request_body,
+            resp.body(),
+            public_key_id,
+            &request_metadata.nonce,
+        )?;
+
+        Ok(signature)
+    }
+
+    fn is_outstanding(&self, nonce: &Nonce) -> bool {
+        self.outstanding_nonces().contains(nonce)
+    }
+
+    fn remove_outstanding(&self, nonce: &Nonce) {
+        self.outstanding_nonces().retain(|n| n != nonce);
     }
 }
 
@@ -233,9 +557,16 @@ impl Cupv2RequestHandler for StandardCupv2Handler {
         let uri: Uri = request.get_uri().parse()?;
         let uri = uri.append_query_parameter("cup2key", &format!("{public_key_id}:{nonce}"))?;
         request.set_uri(uri.to_string());
+        let request_body = request.get_serialized_body()?;
+
+        let mut outstanding_nonces = self.outstanding_nonces();
+        if outstanding_nonces.len() >= MAX_OUTSTANDING_NONCES {
+            outstanding_nonces.pop_front();
+        }
+        outstanding_nonces.push_back(nonce);
 
         Ok(RequestMetadata {
-            request_body: request.get_serialized_body()?,
+            request_body,
             public_key_id,
             nonce,
         })
@@ -261,42 +592,23 @@ impl Cupv2RequestHandler for StandardCupv2Handler {
         // original hash of the request. If the hashes do not match, the client
         // recognizes that the request has been tampered in transit, and rejects
         // the exchange.
+        //
+        // Additionally, the response must answer a request that is still waiting
+        // for one, so that a response that was already accepted can't be replayed.
 
-        let etag_header = resp
-            .headers()
-            .get(ETAG)
-            .ok_or(CupVerificationError::EtagHeaderMissing)?
-            .to_str()
-            .map_err(CupVerificationError::EtagNotString)
-            .map(parse_etag)?;
-
-        let (encoded_signature, hex_hash): (&str, &str) = etag_header
-            .split_once(':')
-            .ok_or(CupVerificationError::EtagMalformed)?;
-
-        let actual_hash =
-            &hex::decode(hex_hash).map_err(|_| CupVerificationError::RequestHashMalformed)?;
-
-        let request_body_hash = Sha256::digest(&request_metadata.request_body);
-        if *request_body_hash != *actual_hash {
-            return Err(CupVerificationError::RequestHashMismatch);
+        if !self.is_outstanding(&request_metadata.nonce) {
+            return Err(CupVerificationError::NonceNotOutstanding);
         }
 
-        let signature = DerSignature::from_bytes(
-            &hex::decode(encoded_signature)
-                .map_err(|_| CupVerificationError::SignatureMalformed)?,
-        )?;
-
-        let () = self.verify_response_with_signature(
-            &signature,
-            &request_metadata.request_body,
-            resp.body(),
-            public_key_id,
-            &request_metadata.nonce,
-        )?;
+        let signature = self.verify_proof(request_metadata, resp, public_key_id)?;
 
+        self.remove_outstanding(&request_metadata.nonce);
         Ok(signature)
     }
+
+    fn abandon_request(&self, request_metadata: &RequestMetadata) {
+        self.remove_outstanding(&request_metadata.nonce);
+    }
 }
 
 pub fn make_transaction_hash(
@@ -304,10 +616,22 @@ pub fn make_transaction_hash(
     response_body: &[u8],
     public_key_id: PublicKeyId,
     nonce: &Nonce,
+) -> digest::Output<Sha256> {
+    hash_transaction(
+        request_body,
+        response_body,
+        &format!("{public_key_id}:{nonce}"),
+    )
+}
+
+/// The transaction hash, given the value of the `cup2key` query parameter exactly as it was sent.
+fn hash_transaction(
+    request_body: &[u8],
+    response_body: &[u8],
+    cup2_urlparam: &str,
 ) -> digest::Output<Sha256> {
     let request_hash = Sha256::digest(request_body);
     let response_hash = Sha256::digest(response_body);
-    let cup2_urlparam = format!("{public_key_id}:{nonce}");
 
     let mut hasher = Sha256::new();
     hasher.update(request_hash);
@@ -343,7 +667,167 @@ impl Cupv2Verifier for StandardCupv2Handler {
     }
 }
 
-fn parse_etag(etag: &str) -> &str {
+/// The private half of a `PublicKey`. Only servers hold these, so the client never needs them.
+pub type PrivateKey = p256::ecdsa::SigningKey;
+
+#[derive(Clone, Debug)]
+pub struct PrivateKeyAndId {
+    pub id: PublicKeyId,
+    pub key: PrivateKey,
+}
+
+#[derive(Clone, Debug)]
+pub struct PrivateKeys {
+    /// The private key matching the clients' latest public key.
+    pub latest: PrivateKeyAndId,
+    /// Private keys for older public keys that clients may still use. May be empty.
+    pub historical: Vec<PrivateKeyAndId>,
+}
+
+impl PrivateKeys {
+    pub fn find(&self, id: PublicKeyId) -> Option<&PrivateKey> {
+        std::iter::once(&self.latest)
+            .chain(&self.historical)
+            .find(|pair| pair.id == id)
+            .map(|pair| &pair.key)
+    }
+}
+
+/// The parsed value of a request's `cup2key` query parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cup2Key {
+    pub public_key_id: PublicKeyId,
+    /// The nonce, exactly as the client sent it. This is normally the hex form of a `Nonce`, but
+    /// the server signs whatever it was given.
+    pub nonce: String,
+}
+
+impl Cup2Key {
+    /// Find and parse the `cup2key` query parameter of a request URI.
+    pub fn from_uri(uri: &Uri) -> Result<Self, CupSigningError> {
+        let value = uri
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("cup2key="))
+            .ok_or(CupSigningError::Cup2KeyMissing)?;
+        value.parse()
+    }
+}
+
+impl std::str::FromStr for Cup2Key {
+    type Err = CupSigningError;
+
+    /// Parse a `<public key id>:<nonce>` pair. The separator may be percent-encoded.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (public_key_id, nonce) = s
+            .split_once(':')
+            .or_else(|| s.split_once("%3A"))
+            .or_else(|| s.split_once("%3a"))
+            .ok_or(CupSigningError::Cup2KeyMalformed)?;
+        Ok(Cup2Key {
+            public_key_id: public_key_id
+                .parse()
+                .map_err(|_| CupSigningError::Cup2KeyMalformed)?,
+            nonce: nonce.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Cup2Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.public_key_id, self.nonce)
+    }
+}
+
+/// The server's signature over a CUPv2 transaction.
+#[derive(Debug)]
+pub struct CupSignature {
+    pub signature: DerSignature,
+    pub request_hash: digest::Output<Sha256>,
+}
+
+impl CupSignature {
+    /// The value of the `ETag` header carrying this signature.
+    pub fn etag(&self) -> String {
+        format!(
+            "{}:{}",
+            hex::encode(self.signature.as_bytes()),
+            hex::encode(self.request_hash)
+        )
+    }
+
+    /// The value of the `X-Cup-Server-Proof` header carrying this signature, which has the same
+    /// form as the ETag, but can't be mangled by caches along the way.
+    pub fn server_proof(&self) -> String {
+        self.etag()
+    }
+}
+
+/// The name of the header that carries the server's proof when ETags are unsuitable.
+pub const X_CUP_SERVER_PROOF: &str = "X-Cup-Server-Proof";
+
+/// The server side of CUPv2, the mirror of `StandardCupv2Handler`: signs responses with the
+/// private key that the request's `cup2key` names.
+#[derive(Clone, Debug)]
+pub struct Cupv2Signer {
+    private_keys: PrivateKeys,
+}
+
+impl Cupv2Signer {
+    pub fn new(private_keys: PrivateKeys) -> Self {
+        Self { private_keys }
+    }
+
+    /// Sign the response to a request, given the request's URI and the exact request and response
+    /// bodies.
+    pub fn sign(
+        &self,
+        request_uri: &Uri,
+        request_body: &[u8],
+        response_body: &[u8],
+    ) -> Result<CupSignature, CupSigningError> {
+        use p256::ecdsa::signature::Signer as _;
+
+        let cup2key = Cup2Key::from_uri(request_uri)?;
+        let private_key = self.private_keys.find(cup2key.public_key_id).ok_or(
+            CupSigningError::SpecifiedPublicKeyIdMissing(cup2key.public_key_id),
+        )?;
+        let transaction_hash = hash_transaction(request_body, response_body, &cup2key.to_string());
+        let signature: ecdsa::Signature<p256::NistP256> =
+            private_key.try_sign(&transaction_hash)?;
+        Ok(CupSignature {
+            signature: signature.to_der(),
+            request_hash: Sha256::digest(request_body),
+        })
+    }
+
+    /// Sign `response` and attach the signature as its ETag, and optionally also as its
+    /// `X-Cup-Server-Proof` header.
+    pub fn sign_response(
+        &self,
+        request_uri: &Uri,
+        request_body: &[u8],
+        response: &mut Response<Vec<u8>>,
+        server_proof: bool,
+    ) -> Result<(), CupSigningError> {
+        let signature = self.sign(request_uri, request_body, response.body())?;
+        let headers = response.headers_mut();
+        // Hex digits and ':' are always valid header characters.
+        headers.insert(ETAG, signature.etag().parse().unwrap());
+        if server_proof {
+            headers.insert(
+                X_CUP_SERVER_PROOF,
+                signature.server_proof().parse().unwrap(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Strips the quotes, and the weak validator prefix if any, from an ETag header value, to get the
+/// CUPv2 proof that it carries.
+pub fn parse_etag(etag: &str) -> &str {
     // ETag headers are wrapped in double quotes, and can optionally have a W/
     // prefix. Examples:
     //     ETag: "33a64df551425fcc55e4d42a148795d9f25f89d4"
@@ -539,6 +1023,7 @@ pub mod test_support {
             uri: "http://fuchsia.dev".to_string(),
             headers: [].into(),
             body: RequestWrapper { request },
+            content_encoding: None,
         }
     }
 }
@@ -549,6 +1034,7 @@ mod tests {
     use crate::{
         protocol::request::{Request, RequestWrapper},
         request_builder::Intermediate,
+        test_support::TempDir,
     };
     use assert_matches::assert_matches;
     use p256::ecdsa::SigningKey;
@@ -602,6 +1088,7 @@ mod tests {
             body: RequestWrapper {
                 request: Request::default(),
             },
+            content_encoding: None,
         };
 
         let request_metadata = cup_handler.decorate_request(&mut intermediate)?;
@@ -638,7 +1125,7 @@ mod tests {
 
         assert_matches!(
             cup_handler.verify_response(&request_metadata, &response, public_key_id),
-            Err(CupVerificationError::EtagHeaderMissing)
+            Err(CupVerificationError::ProofHeaderMissing)
         );
         Ok(())
     }
@@ -661,7 +1148,230 @@ mod tests {
 
         assert_matches!(
             cup_handler.verify_response(&request_metadata, &response, public_key_id),
-            Err(CupVerificationError::EtagNotString(_))
+            Err(CupVerificationError::HeaderNotString(
+                CupProofHeader::Etag,
+                _
+            ))
+        );
+
+        let response: Response<Vec<u8>> = hyper::Response::builder()
+            .status(200)
+            .header(X_CUP_SERVER_PROOF, "\u{FEFF}")
+            .body("foo".as_bytes().to_vec())?;
+
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Err(CupVerificationError::HeaderNotString(
+                CupProofHeader::ServerProof,
+                _
+            ))
+        );
+        Ok(())
+    }
+
+    fn decorate_and_sign_for_test(
+        cup_handler: &StandardCupv2Handler,
+        response_body: &[u8],
+    ) -> Result<(RequestMetadata, Response<Vec<u8>>), anyhow::Error> {
+        let mut intermediate =
+            test_support::make_standard_intermediate_for_test(Request::default());
+        let request_metadata = cup_handler.decorate_request(&mut intermediate)?;
+        let mut response = Response::new(response_body.to_vec());
+        make_signer_for_test().sign_response(
+            &intermediate.uri.parse()?,
+            &request_metadata.request_body,
+            &mut response,
+            false,
+        )?;
+        Ok((request_metadata, response))
+    }
+
+    #[test]
+    fn test_verify_response_rejects_replay() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let (request_metadata, response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+        let public_key_id = request_metadata.public_key_id;
+
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Ok(_)
+        );
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Err(CupVerificationError::NonceNotOutstanding)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_response_after_failure() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let (request_metadata, response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+        let public_key_id = request_metadata.public_key_id;
+
+        // A tampered response doesn't use up the request's nonce, so the genuine response is
+        // still accepted.
+        let mut tampered = Response::new(b"bar".to_vec());
+        *tampered.headers_mut() = response.headers().clone();
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &tampered, public_key_id),
+            Err(CupVerificationError::SignatureError(_))
+        );
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Ok(_)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_response_abandoned_request() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let (first_metadata, first_response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+        let public_key_id = first_metadata.public_key_id;
+
+        // The first attempt is abandoned and retried, so only the retry's response is accepted.
+        cup_handler.abandon_request(&first_metadata);
+        let (retry_metadata, retry_response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+
+        assert_matches!(
+            cup_handler.verify_response(&first_metadata, &first_response, public_key_id),
+            Err(CupVerificationError::NonceNotOutstanding)
+        );
+        assert_matches!(
+            cup_handler.verify_response(&retry_metadata, &first_response, public_key_id),
+            Err(_)
+        );
+        assert_matches!(
+            cup_handler.verify_response(&retry_metadata, &retry_response, public_key_id),
+            Ok(_)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_proof_ignores_outstanding_nonces() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let (request_metadata, response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+        let public_key_id = request_metadata.public_key_id;
+
+        cup_handler.verify_response(&request_metadata, &response, public_key_id)?;
+        // A captured exchange can still be checked offline, with a different handler.
+        let offline_handler = test_support::make_cup_handler_for_test();
+        assert_matches!(
+            offline_handler.verify_proof(&request_metadata, &response, public_key_id),
+            Ok(_)
+        );
+        assert_matches!(
+            offline_handler.verify_proof(
+                &request_metadata,
+                &Response::new(b"bar".to_vec()),
+                public_key_id
+            ),
+            Err(CupVerificationError::ProofHeaderMissing)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_nonce() {
+        let nonce = Nonce::new();
+        assert_eq!(nonce.to_string().parse::<Nonce>().unwrap(), nonce);
+        assert_matches!("abcd".parse::<Nonce>(), Err(NonceParseError));
+        assert_matches!("zz".repeat(32).parse::<Nonce>(), Err(NonceParseError));
+    }
+
+    #[test]
+    fn test_outstanding_nonces_are_bounded() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let requests = (0..=MAX_OUTSTANDING_NONCES)
+            .map(|_| decorate_and_sign_for_test(&cup_handler, b"foo"))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (oldest_metadata, oldest_response) = &requests[0];
+        assert_matches!(
+            cup_handler.verify_response(
+                oldest_metadata,
+                oldest_response,
+                oldest_metadata.public_key_id
+            ),
+            Err(CupVerificationError::NonceNotOutstanding)
+        );
+        for (request_metadata, response) in &requests[1..] {
+            assert_matches!(
+                cup_handler.verify_response(
+                    request_metadata,
+                    response,
+                    request_metadata.public_key_id
+                ),
+                Ok(_)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_response_prefers_server_proof() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let signer = make_signer_for_test();
+        let decorate_and_sign = || -> Result<(RequestMetadata, String), anyhow::Error> {
+            let mut intermediate =
+                test_support::make_standard_intermediate_for_test(Request::default());
+            let request_metadata = cup_handler.decorate_request(&mut intermediate)?;
+            let proof = signer
+                .sign(
+                    &intermediate.uri.parse()?,
+                    &request_metadata.request_body,
+                    b"foo",
+                )?
+                .server_proof();
+            Ok((request_metadata, proof))
+        };
+
+        // A valid server proof is used even if a cache rewrote the etag.
+        let (request_metadata, proof) = decorate_and_sign()?;
+        let response: Response<Vec<u8>> = hyper::Response::builder()
+            .header(X_CUP_SERVER_PROOF, &proof)
+            .header(ETAG, "W/\"rewritten-by-cache\"")
+            .body(b"foo".to_vec())?;
+        assert_matches!(
+            cup_handler.verify_response(
+                &request_metadata,
+                &response,
+                request_metadata.public_key_id
+            ),
+            Ok(_)
+        );
+
+        // Without a server proof, the etag is used.
+        let (request_metadata, proof) = decorate_and_sign()?;
+        let response: Response<Vec<u8>> = hyper::Response::builder()
+            .header(ETAG, format!("\"{proof}\""))
+            .body(b"foo".to_vec())?;
+        assert_matches!(
+            cup_handler.verify_response(
+                &request_metadata,
+                &response,
+                request_metadata.public_key_id
+            ),
+            Ok(_)
+        );
+
+        // A bad server proof isn't rescued by a valid etag.
+        let (request_metadata, proof) = decorate_and_sign()?;
+        let response: Response<Vec<u8>> = hyper::Response::builder()
+            .header(X_CUP_SERVER_PROOF, "garbage")
+            .header(ETAG, &proof)
+            .body(b"foo".to_vec())?;
+        assert_matches!(
+            cup_handler.verify_response(
+                &request_metadata,
+                &response,
+                request_metadata.public_key_id
+            ),
+            Err(CupVerificationError::HeaderMalformed(
+                CupProofHeader::ServerProof
+            ))
         );
         Ok(())
     }
@@ -694,65 +1404,100 @@ mod tests {
             response_body.as_bytes(),
         ));
 
-        for (etag, public_key_id, expected_err) in vec![
-            // This etag doesn't even have the form foo:bar.
-            (
-                "bar",
-                correct_public_key_id,
-                Some(CupVerificationError::EtagMalformed),
-            ),
-            // This etag has the form foo:bar, but the latter isn't a real hash.
-            (
-                "foo:bar",
-                correct_public_key_id,
-                Some(CupVerificationError::RequestHashMalformed),
-            ),
-            // This hash is the right length, but doesn't decode to the right value.
-            (
-                &format!("foo:{}", hex::encode([1; 32])),
-                correct_public_key_id,
-                Some(CupVerificationError::RequestHashMismatch),
-            ),
-            // The hash is the right length and the right value.
-            // But the signature is malformed.
-            (
-                &format!("foo:{expected_hash_hex}"),
-                correct_public_key_id,
-                Some(CupVerificationError::SignatureMalformed),
-            ),
-            // The hash is the right length and the right value.
-            // But the signature decodes to the wrong value.
-            (
-                &format!("{}:{}", hex::encode([1; 64]), expected_hash_hex),
-                correct_public_key_id,
-                Some(CupVerificationError::SignatureError(ecdsa::Error::new())),
-            ),
-            // Wrong public key ID.
-            (
-                &format!("{expected_signature}:{expected_hash_hex}",),
-                wrong_public_key_id,
-                Some(CupVerificationError::SpecifiedPublicKeyIdMissing),
-            ),
-            // Finally, the happy path.
-            (
-                &format!("{expected_signature}:{expected_hash_hex}",),
-                correct_public_key_id,
-                None,
-            ),
-        ] {
+        let verify = |header: CupProofHeader, proof: &str, public_key_id| {
             let response: Response<Vec<u8>> = hyper::Response::builder()
                 .status(200)
-                .header(ETAG, etag)
-                .body(response_body.as_bytes().to_vec())?;
-            let actual_err = cup_handler
+                .header(header.name(), proof)
+                .body(response_body.as_bytes().to_vec())
+                .unwrap();
+            cup_handler
                 .verify_response(&request_metadata, &response, public_key_id)
-                .err();
-            assert_eq!(
-                actual_err, expected_err,
-                "Received error {actual_err:?}, expected error {expected_err:?}"
-            );
+                .err()
+        };
+
+        for header in [CupProofHeader::ServerProof, CupProofHeader::Etag] {
+            for (proof, public_key_id, expected_err) in vec![
+                // This proof doesn't even have the form foo:bar.
+                (
+                    "bar",
+                    correct_public_key_id,
+                    Some(CupVerificationError::HeaderMalformed(header)),
+                ),
+                // This proof has the form foo:bar, but the latter isn't a real hash.
+                (
+                    "foo:bar",
+                    correct_public_key_id,
+                    Some(CupVerificationError::ProofRequestHashMalformed(header)),
+                ),
+                // This hash is the right length, but doesn't decode to the right value.
+                (
+                    &format!("foo:{}", hex::encode([1; 32])),
+                    correct_public_key_id,
+                    Some(CupVerificationError::ProofRequestHashMismatch(header)),
+                ),
+                // The hash is the right length and the right value.
+                // But the signature is malformed.
+                (
+                    &format!("foo:{expected_hash_hex}"),
+                    correct_public_key_id,
+                    Some(CupVerificationError::ProofSignatureMalformed(header)),
+                ),
+                // The hash is the right length and the right value.
+                // But the signature decodes to the wrong value.
+                (
+                    &format!("{}:{}", hex::encode([1; 64]), expected_hash_hex),
+                    correct_public_key_id,
+                    Some(CupVerificationError::SignatureError(ecdsa::Error::new())),
+                ),
+                // Wrong public key ID.
+                (
+                    &format!("{expected_signature}:{expected_hash_hex}",),
+                    wrong_public_key_id,
+                    Some(CupVerificationError::SpecifiedPublicKeyIdMissing),
+                ),
+            ] {
+                let actual_err = verify(header, proof, public_key_id);
+                assert_eq!(
+                    actual_err, expected_err,
+                    "Received error {actual_err:?}, expected error {expected_err:?}"
+                );
+            }
         }
 
+        // Finally, the happy path.
+        assert_eq!(
+            verify(
+                CupProofHeader::ServerProof,
+                &format!("{expected_signature}:{expected_hash_hex}"),
+                correct_public_key_id
+            ),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_response_once_across_proof_headers() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let (request_metadata, response) = decorate_and_sign_for_test(&cup_handler, b"foo")?;
+        let public_key_id = request_metadata.public_key_id;
+        let proof = parse_etag(response.headers()[ETAG].to_str()?).to_string();
+
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Ok(_)
+        );
+
+        // The same proof moved to the X-Cup-Server-Proof header is still a replay of a verified
+        // response.
+        let response: Response<Vec<u8>> = hyper::Response::builder()
+            .header(X_CUP_SERVER_PROOF, proof)
+            .body(b"foo".to_vec())?;
+        assert_matches!(
+            cup_handler.verify_response(&request_metadata, &response, public_key_id),
+            Err(CupVerificationError::NonceNotOutstanding)
+        );
         Ok(())
     }
 
@@ -803,10 +1548,8 @@ mod tests {
         let mut cup_handler = StandardCupv2Handler::new(&public_keys);
         let (request_metadata_a, response_a) =
             make_verify_response_arguments(&cup_handler, private_key_a, response_body_a)?;
-        assert_matches!(
-            cup_handler.verify_response(&request_metadata_a, &response_a, public_key_id_a),
-            Ok(_)
-        );
+        let signature_a =
+            cup_handler.verify_response(&request_metadata_a, &response_a, public_key_id_a)?;
 
         // Now introduce a new set of keys,
         let (private_key_b, public_key_b) = test_support::make_keys_for_test();
@@ -835,21 +1578,51 @@ mod tests {
         );
 
         // as well as a response which has already been generated and stored.
+        assert_matches!(
+            cup_handler.verify_response_with_signature(
+                &signature_a,
+                &request_metadata_a.request_body,
+                response_a.body(),
+                public_key_id_a,
+                &request_metadata_a.nonce,
+            ),
+            Ok(())
+        );
+
+        // A stored response can't be passed off as the response to a live request, though.
         assert_matches!(
             cup_handler.verify_response(&request_metadata_a, &response_a, public_key_id_a),
-            Ok(_)
+            Err(CupVerificationError::NonceNotOutstanding)
         );
 
-        // finally, assert that verification fails if either (1) the hash, (2)
+        // finally, assert that verification fails if either (1) the request, (2)
         // the stored response, or (3) the key ID itself is wrong.
         assert!(cup_handler
-            .verify_response(&request_metadata_a, &response_a, public_key_id_b)
+            .verify_response_with_signature(
+                &signature_a,
+                &request_metadata_b.request_body,
+                response_a.body(),
+                public_key_id_a,
+                &request_metadata_b.nonce,
+            )
             .is_err());
         assert!(cup_handler
-            .verify_response(&request_metadata_a, &response_b, public_key_id_a)
+            .verify_response_with_signature(
+                &signature_a,
+                &request_metadata_a.request_body,
+                response_b.body(),
+                public_key_id_a,
+                &request_metadata_a.nonce,
+            )
             .is_err());
         assert!(cup_handler
-            .verify_response(&request_metadata_b, &response_a, public_key_id_a)
+            .verify_response_with_signature(
+                &signature_a,
+                &request_metadata_a.request_body,
+                response_a.body(),
+                public_key_id_b,
+                &request_metadata_a.nonce,
+            )
             .is_err());
 
         Ok(())
@@ -913,4 +1686,319 @@ mod tests {
             assert_eq!(parse_etag(v), v);
         }
     }
+
+    fn make_signer_for_test() -> Cupv2Signer {
+        Cupv2Signer::new(PrivateKeys {
+            latest: PrivateKeyAndId {
+                id: test_support::make_default_public_key_id_for_test(),
+                key: test_support::make_default_private_key_for_test(),
+            },
+            historical: vec![PrivateKeyAndId {
+                id: 1,
+                key: SigningKey::random(&mut rand::thread_rng()),
+            }],
+        })
+    }
+
+    #[test]
+    fn test_signer_round_trip() -> Result<(), anyhow::Error> {
+        let cup_handler = test_support::make_cup_handler_for_test();
+        let signer = make_signer_for_test();
+
+        let mut intermediate =
+            test_support::make_standard_intermediate_for_test(Request::default());
+        let request_metadata = cup_handler.decorate_request(&mut intermediate)?;
+        let uri: Uri = intermediate.uri.parse()?;
+
+        let mut response = Response::new(b"response body".to_vec());
+        signer.sign_response(&uri, &request_metadata.request_body, &mut response, true)?;
+
+        assert_eq!(
+            response.headers()[ETAG],
+            response.headers()[X_CUP_SERVER_PROOF]
+        );
+        let signature = cup_handler.verify_response(
+            &request_metadata,
+            &response,
+            request_metadata.public_key_id,
+        )?;
+        assert_eq!(
+            signature.as_bytes(),
+            test_support::make_expected_signature_for_test(
+                &test_support::make_default_private_key_for_test(),
+                &request_metadata,
+                response.body(),
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_signer_without_server_proof() -> Result<(), anyhow::Error> {
+        let uri: Uri = "https://example.com/?cup2key=123456789:abcd".parse()?;
+        let mut response = Response::new(b"response body".to_vec());
+        make_signer_for_test().sign_response(&uri, b"request", &mut response, false)?;
+        assert!(response.headers().contains_key(ETAG));
+        assert!(!response.headers().contains_key(X_CUP_SERVER_PROOF));
+        Ok(())
+    }
+
+    #[test]
+    fn test_signer_uses_historical_key() -> Result<(), anyhow::Error> {
+        let signer = make_signer_for_test();
+        let uri: Uri = "https://example.com/?foo=bar&cup2key=1:abcd".parse()?;
+        let signature = signer.sign(&uri, b"request", b"response")?;
+        assert_eq!(
+            signature.request_hash,
+            Sha256::digest(b"request".as_slice())
+        );
+
+        let public_key = PublicKey::from(signer.private_keys.find(1).unwrap());
+        let transaction_hash = hash_transaction(b"request", b"response", "1:abcd");
+        let signature: ecdsa::Signature<p256::NistP256> = signature.signature.try_into()?;
+        public_key.verify(&transaction_hash, &signature)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_signer_errors() {
+        let signer = make_signer_for_test();
+        let sign = |uri: &str| signer.sign(&uri.parse().unwrap(), b"request", b"response");
+        assert_matches!(
+            sign("https://example.com/"),
+            Err(CupSigningError::Cup2KeyMissing)
+        );
+        assert_matches!(
+            sign("https://example.com/?foo=bar"),
+            Err(CupSigningError::Cup2KeyMissing)
+        );
+        assert_matches!(
+            sign("https://example.com/?cup2key=123456789"),
+            Err(CupSigningError::Cup2KeyMalformed)
+        );
+        assert_matches!(
+            sign("https://example.com/?cup2key=notanumber:abcd"),
+            Err(CupSigningError::Cup2KeyMalformed)
+        );
+        assert_matches!(
+            sign("https://example.com/?cup2key=2:abcd"),
+            Err(CupSigningError::SpecifiedPublicKeyIdMissing(2))
+        );
+    }
+
+    #[test]
+    fn test_parse_cup2key() {
+        let expected = Cup2Key {
+            public_key_id: 42,
+            nonce: "abcd".to_string(),
+        };
+        assert_eq!("42:abcd".parse::<Cup2Key>().unwrap(), expected);
+        assert_eq!("42%3Aabcd".parse::<Cup2Key>().unwrap(), expected);
+        assert_eq!(expected.to_string(), "42:abcd");
+        assert_eq!(
+            Cup2Key::from_uri(&"/?cup2key=42:abcd&x=y".parse().unwrap()).unwrap(),
+            expected
+        );
+    }
+
+    const P384_PUBLIC_KEY_FOR_TEST: &str = "-----BEGIN PUBLIC KEY-----
+MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAEIn0QtOm0Y4Z06XdG9WCuR7A9sRg7CQpG
+TDwEq3ZyfynBLMzPx4twWyc71IyYosPbSejmEPNuYrBW0FlzzvhBOph/gxLxRxSW
+YKtgeOCOKU1Vz780aGx8LI98ierpQzxL
+-----END PUBLIC KEY-----
+";
+
+    const ED25519_PUBLIC_KEY_FOR_TEST: &str = "-----BEGIN PUBLIC KEY-----
+MCowBQYDK2VwAyEAGb9ECWmEzf6FQbrBZ9w7lshQhqowtrbLDFw4rXAxZuE=
+-----END PUBLIC KEY-----
+";
+
+    fn make_random_public_key_pem() -> (PublicKey, String) {
+        use pkcs8::EncodePublicKey;
+        let public_key = PublicKey::from(&SigningKey::random(&mut rand::thread_rng()));
+        let pem = elliptic_curve::PublicKey::from(&public_key)
+            .to_public_key_pem(pkcs8::LineEnding::LF)
+            .unwrap();
+        (public_key, pem)
+    }
+
+    #[test]
+    fn test_public_keys_from_json() {
+        let (historical_key, historical_pem) = make_random_public_key_pem();
+        let json = serde_json::json!({
+            "latest": {"id": 2, "key": test_support::RAW_PUBLIC_KEY_FOR_TEST},
+            "historical": [{"id": 1, "key": historical_pem}],
+        });
+        let public_keys = PublicKeys::from_json(json.to_string().as_bytes()).unwrap();
+        assert_eq!(
+            public_keys,
+            PublicKeys {
+                latest: PublicKeyAndId {
+                    key: test_support::make_default_public_key_for_test(),
+                    id: 2,
+                },
+                historical: vec![PublicKeyAndId {
+                    key: historical_key,
+                    id: 1,
+                }],
+            }
+        );
+
+        // The bundle format matches the serialized form of PublicKeys.
+        let default_keys = test_support::make_default_json_public_keys_for_test();
+        assert_eq!(
+            PublicKeys::from_json(default_keys.to_string().as_bytes()).unwrap(),
+            test_support::make_default_public_keys_for_test()
+        );
+    }
+
+    #[test]
+    fn test_public_keys_from_json_historical_optional() {
+        let json = serde_json::json!({
+            "latest": {"id": 2, "key": test_support::RAW_PUBLIC_KEY_FOR_TEST},
+        });
+        let public_keys = PublicKeys::from_json(json.to_string().as_bytes()).unwrap();
+        assert_eq!(public_keys.latest.id, 2);
+        assert_eq!(public_keys.historical, vec![]);
+    }
+
+    #[test]
+    fn test_public_keys_from_json_errors() {
+        let key = test_support::RAW_PUBLIC_KEY_FOR_TEST;
+        assert_matches!(
+            PublicKeys::from_json(b"not json"),
+            Err(PublicKeysError::Json(_))
+        );
+        assert_matches!(
+            PublicKeys::from_json(br#"{"historical": []}"#),
+            Err(PublicKeysError::NoLatestKey)
+        );
+        assert_matches!(
+            PublicKeys::from_json(
+                serde_json::json!({
+                    "latest": {"id": 1, "key": key},
+                    "historical": [{"id": 2, "key": "garbage"}],
+                })
+                .to_string()
+                .as_bytes()
+            ),
+            Err(PublicKeysError::InvalidKey { id: 2, .. })
+        );
+        assert_matches!(
+            PublicKeys::from_json(
+                serde_json::json!({
+                    "latest": {"id": 1, "key": P384_PUBLIC_KEY_FOR_TEST},
+                })
+                .to_string()
+                .as_bytes()
+            ),
+            Err(PublicKeysError::UnsupportedCurve { id: 1 })
+        );
+        assert_matches!(
+            PublicKeys::from_json(
+                serde_json::json!({
+                    "latest": {"id": 1, "key": ED25519_PUBLIC_KEY_FOR_TEST},
+                })
+                .to_string()
+                .as_bytes()
+            ),
+            Err(PublicKeysError::UnsupportedAlgorithm { id: 1 })
+        );
+        assert_matches!(
+            PublicKeys::from_json(
+                serde_json::json!({
+                    "latest": {"id": 1, "key": key},
+                    "historical": [{"id": 3, "key": key}, {"id": 1, "key": key}],
+                })
+                .to_string()
+                .as_bytes()
+            ),
+            Err(PublicKeysError::DuplicateId(1))
+        );
+    }
+
+    #[test]
+    fn test_public_keys_load_bundle() {
+        let dir = TempDir::new();
+        dir.write(
+            "keys.json",
+            test_support::make_default_json_public_keys_for_test().to_string(),
+        );
+        assert_eq!(
+            PublicKeys::load_bundle(dir.join("keys.json")).unwrap(),
+            test_support::make_default_public_keys_for_test()
+        );
+        assert_matches!(
+            PublicKeys::load_bundle(dir.join("missing.json")),
+            Err(PublicKeysError::Io { path, .. }) if path == dir.join("missing.json")
+        );
+    }
+
+    #[test]
+    fn test_public_keys_load_dir() {
+        let (key_1, pem_1) = make_random_public_key_pem();
+        let (key_3, pem_3) = make_random_public_key_pem();
+        let dir = TempDir::new();
+        dir.write("3.pem", &pem_3);
+        dir.write("2.pem", test_support::RAW_PUBLIC_KEY_FOR_TEST);
+        dir.write("1.pem", &pem_1);
+        dir.write("README", "not a key");
+
+        let public_keys = PublicKeys::load_dir(dir.path(), 2).unwrap();
+        assert_eq!(
+            public_keys,
+            PublicKeys {
+                latest: PublicKeyAndId {
+                    key: test_support::make_default_public_key_for_test(),
+                    id: 2,
+                },
+                historical: vec![
+                    PublicKeyAndId { key: key_1, id: 1 },
+                    PublicKeyAndId { key: key_3, id: 3 },
+                ],
+            }
+        );
+
+        assert_matches!(
+            PublicKeys::load_dir(dir.path(), 4),
+            Err(PublicKeysError::LatestKeyMissing(4))
+        );
+    }
+
+    #[test]
+    fn test_public_keys_load_dir_errors() {
+        let key = test_support::RAW_PUBLIC_KEY_FOR_TEST;
+
+        let dir = TempDir::new();
+        dir.write("latest.pem", key);
+        assert_matches!(
+            PublicKeys::load_dir(dir.path(), 1),
+            Err(PublicKeysError::InvalidFileName(path)) if path == dir.join("latest.pem")
+        );
+
+        let dir = TempDir::new();
+        dir.write("1.pem", key);
+        dir.write("01.pem", key);
+        assert_matches!(
+            PublicKeys::load_dir(dir.path(), 1),
+            Err(PublicKeysError::DuplicateId(1))
+        );
+
+        let dir = TempDir::new();
+        dir.write("1.pem", P384_PUBLIC_KEY_FOR_TEST);
+        assert_matches!(
+            PublicKeys::load_dir(dir.path(), 1),
+            Err(PublicKeysError::UnsupportedCurve { id: 1 })
+        );
+
+        let dir = TempDir::new();
+        assert_matches!(
+            PublicKeys::load_dir(dir.path(), 1),
+            Err(PublicKeysError::LatestKeyMissing(1))
+        );
+        assert_matches!(
+            PublicKeys::load_dir(dir.join("missing"), 1),
+            Err(PublicKeysError::Io { .. })
+        );
+    }
 }
@@ -12,7 +12,11 @@ use {
     hyper::{Body, Request, Response},
 };
 
+pub mod cassette;
+pub mod fault;
+pub mod middleware;
 pub mod mock;
+pub mod proxy;
 
 /// A trait for providing HTTP capabilities to the StateMachine.
 ///
@@ -20,6 +24,11 @@ pub mod mock;
 /// the state machine to use.
 ///
 /// In particular, it's meant to be easy to mock for tests.
+///
+/// This crate never connects through a proxy itself: it only attaches a [`proxy::ProxyRoute`] to
+/// each request.  Implementations must read it with [`proxy::route_for`] and connect through the
+/// given proxy (or directly), otherwise the configured proxies are silently ignored.  Failures to
+/// reach or authenticate with a proxy should be reported using [`Error::new_proxy`].
 pub trait HttpRequest {
     /// Make a request, and return an Response, as the header Parts and collect the entire collected
     /// Body as a Vec of bytes.
@@ -43,6 +52,12 @@ enum ErrorKind {
     User,
     Transport,
     Timeout,
+    Proxy,
+    Dns,
+    ConnectionRefused,
+    Tls,
+    ConnectionReset,
+    BodyTruncated,
 }
 
 impl Error {
@@ -57,6 +72,57 @@ impl Error {
         }
     }
 
+    /// Create a proxy error
+    ///
+    /// Implementations of the trait should use this when the request could not be made because
+    /// the proxy it was routed through was unreachable or refused it.
+    pub fn new_proxy() -> Self {
+        Self {
+            kind: ErrorKind::Proxy,
+            source: None,
+        }
+    }
+
+    /// Create a DNS resolution error
+    pub fn new_dns() -> Self {
+        Self {
+            kind: ErrorKind::Dns,
+            source: None,
+        }
+    }
+
+    /// Create an error for a connection that was refused by the remote host
+    pub fn new_connection_refused() -> Self {
+        Self {
+            kind: ErrorKind::ConnectionRefused,
+            source: None,
+        }
+    }
+
+    /// Create an error for a failed TLS handshake, including certificate validation failures
+    pub fn new_tls() -> Self {
+        Self {
+            kind: ErrorKind::Tls,
+            source: None,
+        }
+    }
+
+    /// Create an error for a connection that was reset or aborted by the remote host
+    pub fn new_connection_reset() -> Self {
+        Self {
+            kind: ErrorKind::ConnectionReset,
+            source: None,
+        }
+    }
+
+    /// Create an error for a response body that ended before it was completely read
+    pub fn new_body_truncated() -> Self {
+        Self {
+            kind: ErrorKind::BodyTruncated,
+            source: None,
+        }
+    }
+
     /// Returns true if this error the result of the Hyper API being incorrectly used (a "user"
     /// error in Hyper)
     pub fn is_user(&self) -> bool {
@@ -70,6 +136,79 @@ impl Error {
     pub fn is_timeout(&self) -> bool {
         self.kind == ErrorKind::Timeout
     }
+
+    /// Returns true if this error is the result of failing to communicate through a proxy.
+    pub fn is_proxy(&self) -> bool {
+        self.kind == ErrorKind::Proxy
+    }
+
+    /// Returns true if this error is the result of failing to resolve the host name.
+    pub fn is_dns(&self) -> bool {
+        self.kind == ErrorKind::Dns
+    }
+
+    /// Returns true if this error is the result of the remote host refusing the connection.
+    pub fn is_connection_refused(&self) -> bool {
+        self.kind == ErrorKind::ConnectionRefused
+    }
+
+    /// Returns true if this error is the result of a failed TLS handshake, which includes
+    /// certificate validation failures.
+    pub fn is_tls(&self) -> bool {
+        self.kind == ErrorKind::Tls
+    }
+
+    /// Returns true if this error is the result of the connection being reset or aborted.
+    pub fn is_connection_reset(&self) -> bool {
+        self.kind == ErrorKind::ConnectionReset
+    }
+
+    /// Returns true if this error is the result of the connection closing before the entire
+    /// response body was read.
+    pub fn is_body_truncated(&self) -> bool {
+        self.kind == ErrorKind::BodyTruncated
+    }
+}
+
+impl ErrorKind {
+    /// Classify a non-user hyper error by walking its chain of sources.
+    ///
+    /// Only hyper's own predicates and the `std::io::Error`s that the connectors produce are
+    /// used, anything else is reported as a generic transport error.  Hyper doesn't expose its
+    /// resolver errors as types, so `HttpRequest` implementations that can tell that name
+    /// resolution failed should return [`Error::new_dns()`] themselves.
+    fn classify(error: &hyper::Error) -> Self {
+        if error.is_timeout() {
+            return ErrorKind::Timeout;
+        }
+        if error.is_incomplete_message() {
+            return ErrorKind::BodyTruncated;
+        }
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+        while let Some(e) = source {
+            if let Some(kind) = Self::from_source(e, error.is_connect()) {
+                return kind;
+            }
+            source = e.source();
+        }
+        ErrorKind::Transport
+    }
+
+    fn from_source(error: &(dyn std::error::Error + 'static), is_connect: bool) -> Option<Self> {
+        let io_error = error.downcast_ref::<std::io::Error>()?;
+        match io_error.kind() {
+            std::io::ErrorKind::ConnectionRefused => Some(ErrorKind::ConnectionRefused),
+            std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+            | std::io::ErrorKind::BrokenPipe => Some(ErrorKind::ConnectionReset),
+            std::io::ErrorKind::UnexpectedEof if !is_connect => Some(ErrorKind::BodyTruncated),
+            std::io::ErrorKind::TimedOut => Some(ErrorKind::Timeout),
+            // TLS implementations (e.g. tokio-rustls) surface handshake and certificate
+            // failures as `InvalidData` io errors while connecting.
+            std::io::ErrorKind::InvalidData if is_connect => Some(ErrorKind::Tls),
+            _ => None,
+        }
+    }
 }
 
 impl From<hyper::Error> for Error {
@@ -77,7 +216,7 @@ impl From<hyper::Error> for Error {
         let kind = if error.is_user() {
             ErrorKind::User
         } else {
-            ErrorKind::Transport
+            ErrorKind::classify(&error)
         };
         Error {
             kind,
@@ -102,6 +241,30 @@ pub mod mock_errors {
             source: None,
         }
     }
+
+    pub fn make_proxy_error() -> Error {
+        Error::new_proxy()
+    }
+
+    pub fn make_dns_error() -> Error {
+        Error::new_dns()
+    }
+
+    pub fn make_connection_refused_error() -> Error {
+        Error::new_connection_refused()
+    }
+
+    pub fn make_tls_error() -> Error {
+        Error::new_tls()
+    }
+
+    pub fn make_connection_reset_error() -> Error {
+        Error::new_connection_reset()
+    }
+
+    pub fn make_body_truncated_error() -> Error {
+        Error::new_body_truncated()
+    }
 }
 
 /// A stub HttpRequest that does nothing and returns an empty response immediately.
@@ -112,3 +275,76 @@ impl HttpRequest for StubHttpRequest {
         future::ok(Response::default()).boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("{0}")]
+    struct MessageError(&'static str);
+
+    #[test]
+    fn test_classify_io_errors() {
+        let classify =
+            |kind, is_connect| ErrorKind::from_source(&io::Error::new(kind, "test"), is_connect);
+        assert_eq!(
+            classify(io::ErrorKind::ConnectionRefused, true),
+            Some(ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(
+            classify(io::ErrorKind::ConnectionReset, false),
+            Some(ErrorKind::ConnectionReset)
+        );
+        assert_eq!(
+            classify(io::ErrorKind::ConnectionAborted, false),
+            Some(ErrorKind::ConnectionReset)
+        );
+        assert_eq!(
+            classify(io::ErrorKind::UnexpectedEof, false),
+            Some(ErrorKind::BodyTruncated)
+        );
+        assert_eq!(classify(io::ErrorKind::UnexpectedEof, true), None);
+        assert_eq!(
+            classify(io::ErrorKind::InvalidData, true),
+            Some(ErrorKind::Tls)
+        );
+        assert_eq!(classify(io::ErrorKind::InvalidData, false), None);
+        assert_eq!(
+            classify(io::ErrorKind::TimedOut, true),
+            Some(ErrorKind::Timeout)
+        );
+        assert_eq!(classify(io::ErrorKind::Other, true), None);
+    }
+
+    #[test]
+    fn test_classify_ignores_messages() {
+        // Errors are only classified by type, never by their message.
+        for message in [
+            "dns error: failed to lookup address information",
+            "error reading a body from connection: end of file",
+            "tcp connect error",
+        ] {
+            assert_eq!(ErrorKind::from_source(&MessageError(message), true), None);
+            assert_eq!(ErrorKind::from_source(&MessageError(message), false), None);
+        }
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert!(Error::new_timeout().is_timeout());
+        assert!(Error::new_proxy().is_proxy());
+        assert!(Error::new_dns().is_dns());
+        assert!(Error::new_connection_refused().is_connection_refused());
+        assert!(Error::new_tls().is_tls());
+        assert!(Error::new_connection_reset().is_connection_reset());
+        assert!(Error::new_body_truncated().is_body_truncated());
+
+        let error = Error::new_dns();
+        assert!(!error.is_timeout());
+        assert!(!error.is_connection_refused());
+        assert!(!error.is_tls());
+        assert!(!error.is_user());
+    }
+}
@@ -0,0 +1,699 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Record-and-replay support for [`HttpRequest`].
+//!
+//! [`RecordingHttpRequest`] wraps another `HttpRequest` and captures every request/response pair
+//! that goes through it into a [`Cassette`], which can be saved as a JSON file.
+//! [`ReplayHttpRequest`] serves the responses from a cassette back, without any network access,
+//! which allows an interaction captured against a real (or mock) Omaha server to be turned into a
+//! deterministic test.
+//!
+//! Requests are matched on their method, their path and their normalized body.  The query (which
+//! holds the CUP parameters) is recorded but not matched, and the `requestid` and `sessionid`
+//! fields of JSON bodies are ignored, as they're random for every request.
+//!
+//! Credentials are never written to a cassette: the values of sensitive headers (see
+//! [`http::HeaderValue::is_sensitive`]) and of the `Authorization`, `Cookie`,
+//! `Proxy-Authorization` and `Set-Cookie` headers are replaced by `[redacted]`.
+//!
+//! Note that the CUP signatures in a recorded response are bound to the nonce of the recorded
+//! request, so replayed responses will only pass verification with a CUP handler that doesn't
+//! check them.
+
+use crate::http_request::{Error, HttpRequest};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use http::StatusCode;
+use hyper::{Body, Request, Response};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::{error, warn};
+
+/// Fields of a JSON request body which are expected to differ between a recording and its replay.
+const VOLATILE_BODY_FIELDS: &[&str] = &["requestid", "sessionid"];
+
+/// Headers that carry credentials, whose values are never recorded.
+const SENSITIVE_HEADERS: &[http::header::HeaderName] = &[
+    http::header::AUTHORIZATION,
+    http::header::COOKIE,
+    http::header::PROXY_AUTHORIZATION,
+    http::header::SET_COOKIE,
+];
+
+/// What the values of sensitive headers are recorded as.
+const REDACTED_HEADER_VALUE: &str = "[redacted]";
+
+#[derive(Debug, thiserror::Error)]
+pub enum CassetteError {
+    #[error("unable to access cassette file")]
+    Io(#[from] std::io::Error),
+
+    #[error("unable to (de)serialize cassette")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The body of a recorded request or response.
+///
+/// Bodies are stored as text when they are valid UTF-8, so that cassettes are easy to read and
+/// edit, and as hex otherwise.  Either way, the exact bytes are preserved.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteBody {
+    Text(String),
+    Hex(String),
+}
+
+impl CassetteBody {
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => CassetteBody::Text(text.to_string()),
+            Err(_) => CassetteBody::Hex(hex::encode(bytes)),
+        }
+    }
+
+    /// Returns the bytes of the body, or None if a hex body is malformed.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            CassetteBody::Text(text) => Some(text.as_bytes().to_vec()),
+            CassetteBody::Hex(hex) => hex::decode(hex).ok(),
+        }
+    }
+}
+
+/// A single recorded request.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// The full uri of the request, including the query.
+    pub uri: String,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: CassetteBody,
+}
+
+/// A single recorded response.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    pub body: CassetteBody,
+}
+
+/// A request and the response that was returned for it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+/// An ordered list of interactions, which is (de)serialized as JSON.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+impl Cassette {
+    pub fn from_json(json: &[u8]) -> Result<Self, CassetteError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    pub fn to_json(&self) -> Result<Vec<u8>, CassetteError> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CassetteError> {
+        Self::from_json(&fs::read(path)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), CassetteError> {
+        Ok(fs::write(path, self.to_json()?)?)
+    }
+}
+
+fn headers_to_vec(headers: &http::HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if value.is_sensitive() || SENSITIVE_HEADERS.contains(name) {
+                REDACTED_HEADER_VALUE.to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            (name.to_string(), value)
+        })
+        .collect()
+}
+
+/// Normalize a request body for matching, see the module documentation.
+fn normalize_body(body: &[u8]) -> Result<serde_json::Value, Vec<u8>> {
+    fn strip_volatile_fields(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for field in VOLATILE_BODY_FIELDS {
+                    map.remove(*field);
+                }
+                map.values_mut().for_each(strip_volatile_fields);
+            }
+            serde_json::Value::Array(values) => values.iter_mut().for_each(strip_volatile_fields),
+            _ => {}
+        }
+    }
+
+    match serde_json::from_slice(body) {
+        Ok(mut value) => {
+            strip_volatile_fields(&mut value);
+            Ok(value)
+        }
+        Err(_) => Err(body.to_vec()),
+    }
+}
+
+/// An HttpRequest that forwards requests to another HttpRequest, and records every successful
+/// request/response pair into a [`Cassette`].
+///
+/// Transport errors are passed through without being recorded.
+pub struct RecordingHttpRequest<H> {
+    inner: H,
+    cassette: Arc<Mutex<Cassette>>,
+    path: Option<PathBuf>,
+}
+
+impl<H> RecordingHttpRequest<H>
+where
+    H: HttpRequest,
+{
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            cassette: Default::default(),
+            path: None,
+        }
+    }
+
+    /// Record into the cassette file at `path`, which is rewritten after every interaction.
+    pub fn with_path(inner: H, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Returns a handle to the cassette that's being recorded into.
+    pub fn cassette(&self) -> Arc<Mutex<Cassette>> {
+        Arc::clone(&self.cassette)
+    }
+
+    fn record(&mut self, req: Request<Body>) -> BoxFuture<'_, Result<Response<Vec<u8>>, Error>> {
+        let (parts, body) = req.into_parts();
+        let method = parts.method.to_string();
+        let uri = parts.uri.to_string();
+        let headers = headers_to_vec(&parts.headers);
+
+        // The inner request is started right away, so that the returned future doesn't need to
+        // hold on to `inner`, which doesn't have to be Send.  Bodies that are already in memory,
+        // like the state machine's, are forwarded as they are, so that their length is still
+        // known.  Any other body is passed along once it has been read and recorded.
+        let mut read_body = hyper::body::to_bytes(body).boxed();
+        let (body, forwarded_body, body_sender) = match (&mut read_body).now_or_never() {
+            Some(Ok(body)) => (Some(body.clone()), Body::from(body), None),
+            Some(Err(e)) => return future::err(e.into()).boxed(),
+            None => {
+                let (body_sender, forwarded_body) = Body::channel();
+                (None, forwarded_body, Some(body_sender))
+            }
+        };
+        let response = self
+            .inner
+            .request(Request::from_parts(parts, forwarded_body));
+        let cassette = Arc::clone(&self.cassette);
+        let path = self.path.clone();
+
+        async move {
+            let body = match body {
+                Some(body) => body,
+                None => read_body.await?,
+            };
+            let recorded_request = RecordedRequest {
+                method,
+                uri,
+                headers,
+                body: CassetteBody::from_bytes(&body),
+            };
+            if let Some(mut body_sender) = body_sender {
+                // The inner request may not read the body at all, in which case there's no one
+                // to send it to.
+                let _ = body_sender.send_data(body).await;
+            }
+
+            let response = response.await?;
+
+            let interaction = Interaction {
+                request: recorded_request,
+                response: RecordedResponse {
+                    status: response.status().as_u16(),
+                    headers: headers_to_vec(response.headers()),
+                    body: CassetteBody::from_bytes(response.body()),
+                },
+            };
+            let mut cassette = cassette.lock().unwrap();
+            cassette.interactions.push(interaction);
+            if let Some(path) = &path {
+                if let Err(e) = cassette.save(path) {
+                    error!("Unable to save cassette to {:?}: {:?}", path, e);
+                }
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+impl<H> HttpRequest for RecordingHttpRequest<H>
+where
+    H: HttpRequest,
+{
+    fn request(&mut self, req: Request<Body>) -> BoxFuture<'_, Result<Response<Vec<u8>>, Error>> {
+        self.record(req)
+    }
+}
+
+/// An HttpRequest that serves responses from a [`Cassette`].
+///
+/// Each request is answered with the response of the first interaction, in recording order, that
+/// matches it and hasn't been used yet.  If there is no such interaction, an internal server error
+/// response is returned, as with [`crate::http_request::mock::MockHttpRequest`].
+#[derive(Debug)]
+pub struct ReplayHttpRequest {
+    interactions: Vec<(Interaction, bool)>,
+}
+
+impl ReplayHttpRequest {
+    pub fn new(cassette: Cassette) -> Self {
+        Self {
+            interactions: cassette
+                .interactions
+                .into_iter()
+                .map(|interaction| (interaction, false))
+                .collect(),
+        }
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, CassetteError> {
+        Ok(Self::new(Cassette::load(path)?))
+    }
+
+    /// Returns the number of interactions that haven't been replayed yet.
+    pub fn unused_interactions(&self) -> usize {
+        self.interactions.iter().filter(|(_, used)| !used).count()
+    }
+
+    fn find_response(
+        &mut self,
+        method: &str,
+        path: &str,
+        body: &[u8],
+    ) -> Option<Response<Vec<u8>>> {
+        let body = normalize_body(body);
+        let (interaction, used) = self.interactions.iter_mut().find(|(interaction, used)| {
+            let request = &interaction.request;
+            !*used
+                && request.method == method
+                && request
+                    .uri
+                    .parse::<http::Uri>()
+                    .ok()
+                    .as_ref()
+                    .map(http::Uri::path)
+                    == Some(path)
+                && request
+                    .body
+                    .to_bytes()
+                    .map(|bytes| normalize_body(&bytes))
+                    .as_ref()
+                    == Some(&body)
+        })?;
+        *used = true;
+
+        let recorded = &interaction.response;
+        let mut builder = Response::builder().status(recorded.status);
+        for (name, value) in &recorded.headers {
+            builder = builder.header(name, value);
+        }
+        builder
+            .body(recorded.body.to_bytes().unwrap_or_default())
+            .map_err(|e| warn!("Unable to replay recorded response: {}", e))
+            .ok()
+    }
+
+    async fn replay(&mut self, req: Request<Body>) -> Result<Response<Vec<u8>>, Error> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        Ok(self
+            .find_response(parts.method.as_str(), parts.uri.path(), &body)
+            .unwrap_or_else(|| {
+                warn!(
+                    "No recorded interaction matches {} {}",
+                    parts.method, parts.uri
+                );
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(vec![])
+                    .unwrap()
+            }))
+    }
+}
+
+impl HttpRequest for ReplayHttpRequest {
+    fn request(&mut self, req: Request<Body>) -> BoxFuture<'_, Result<Response<Vec<u8>>, Error>> {
+        self.replay(req).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_request::mock::MockHttpRequest, test_support::TempDir};
+    use futures::executor::block_on;
+    use serde_json::json;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// The bodies of the requests that reached the inner HttpRequest, in order.
+    fn forwarded_bodies(requests: &Rc<RefCell<Vec<Request<Body>>>>) -> Vec<Vec<u8>> {
+        block_on(future::join_all(
+            requests
+                .borrow_mut()
+                .drain(..)
+                .map(|request| hyper::body::to_bytes(request.into_body())),
+        ))
+        .into_iter()
+        .map(|body| body.unwrap().to_vec())
+        .collect()
+    }
+
+    fn make_request(uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header("content-type", "application/json")
+            .body(serde_json::to_vec(&body).unwrap().into())
+            .unwrap()
+    }
+
+    fn make_cassette() -> Cassette {
+        Cassette::from_json(
+            br#"{
+              "interactions": [
+                {
+                  "request": {
+                    "method": "POST",
+                    "uri": "https://omaha.example.com/update?cup2key=1:nonce&cup2hreq=abc",
+                    "body": {"text": "{\"request\":{\"requestid\":\"a\",\"app\":[{\"appid\":\"1\"}]}}"}
+                  },
+                  "response": {
+                    "status": 200,
+                    "headers": [["etag", "signature:hash"]],
+                    "body": {"text": "first"}
+                  }
+                },
+                {
+                  "request": {
+                    "method": "POST",
+                    "uri": "https://omaha.example.com/update",
+                    "body": {"text": "{\"request\":{\"requestid\":\"b\",\"app\":[{\"appid\":\"1\"}]}}"}
+                  },
+                  "response": {
+                    "status": 200,
+                    "body": {"hex": "00ff"}
+                  }
+                }
+              ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cassette_body_round_trip() {
+        for bytes in [&b"{\"a\": 1}"[..], &[0x00, 0xff, 0x80][..], &[][..]] {
+            assert_eq!(
+                CassetteBody::from_bytes(bytes).to_bytes(),
+                Some(bytes.to_vec())
+            );
+        }
+        assert_eq!(
+            CassetteBody::from_bytes(&[0xff]),
+            CassetteBody::Hex("ff".to_string())
+        );
+        assert_eq!(CassetteBody::Hex("zz".to_string()).to_bytes(), None);
+    }
+
+    #[test]
+    fn test_normalize_body_ignores_volatile_fields_and_formatting() {
+        let a = br#"{"request": {"requestid": "1", "sessionid": "2", "app": [{"appid": "x"}]}}"#;
+        let b = br#"{"request":{"app":[{"appid":"x"}],"sessionid":"3","requestid":"4"}}"#;
+        assert_eq!(normalize_body(a), normalize_body(b));
+
+        let c = br#"{"request":{"app":[{"appid":"y"}]}}"#;
+        assert_ne!(normalize_body(a), normalize_body(c));
+
+        assert_eq!(normalize_body(b"not json"), Err(b"not json".to_vec()));
+    }
+
+    #[test]
+    fn test_record() {
+        let mut inner = MockHttpRequest::new(
+            Response::builder()
+                .status(200)
+                .header("etag", "sig:hash")
+                .body(b"response".to_vec())
+                .unwrap(),
+        );
+        inner.add_error(crate::http_request::mock_errors::make_transport_error());
+        let requests = inner.get_request_cell();
+        let mut recorder = RecordingHttpRequest::new(inner);
+        let cassette = recorder.cassette();
+
+        block_on(async {
+            let uri = "https://omaha.example.com/update?cup2key=1:2";
+            let response = recorder
+                .request(make_request(uri, json!({"request": {}})))
+                .await
+                .unwrap();
+            assert_eq!(response.body(), b"response");
+
+            // Errors are passed through, but not recorded.
+            assert!(recorder
+                .request(make_request(uri, json!({})))
+                .await
+                .is_err());
+        });
+
+        assert_eq!(
+            *cassette.lock().unwrap(),
+            Cassette {
+                interactions: vec![Interaction {
+                    request: RecordedRequest {
+                        method: "POST".to_string(),
+                        uri: "https://omaha.example.com/update?cup2key=1:2".to_string(),
+                        headers: vec![("content-type".to_string(), "application/json".to_string())],
+                        body: CassetteBody::Text(r#"{"request":{}}"#.to_string()),
+                    },
+                    response: RecordedResponse {
+                        status: 200,
+                        headers: vec![("etag".to_string(), "sig:hash".to_string())],
+                        body: CassetteBody::Text("response".to_string()),
+                    },
+                }],
+            }
+        );
+        assert_eq!(
+            forwarded_bodies(&requests),
+            vec![br#"{"request":{}}"#.to_vec(), b"{}".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_record_redacts_sensitive_headers() {
+        let inner = MockHttpRequest::new(
+            Response::builder()
+                .header("set-cookie", "session=secret")
+                .body(b"response".to_vec())
+                .unwrap(),
+        );
+        let requests = inner.get_request_cell();
+        let mut recorder = RecordingHttpRequest::new(inner);
+        let cassette = recorder.cassette();
+
+        let mut token = http::HeaderValue::from_static("secret");
+        token.set_sensitive(true);
+        let request = Request::post("https://omaha.example.com/update")
+            .header("authorization", "Bearer secret")
+            .header("cookie", "session=secret")
+            .header("proxy-authorization", "Basic secret")
+            .header("x-goog-update-token", token)
+            .header("x-goog-update-appid", "some-app")
+            .body(Body::empty())
+            .unwrap();
+        block_on(recorder.request(request)).unwrap();
+
+        let redacted = |name: &str| (name.to_string(), REDACTED_HEADER_VALUE.to_string());
+        let interaction = &cassette.lock().unwrap().interactions[0];
+        assert_eq!(
+            interaction.request.headers,
+            vec![
+                redacted("authorization"),
+                redacted("cookie"),
+                redacted("proxy-authorization"),
+                redacted("x-goog-update-token"),
+                ("x-goog-update-appid".to_string(), "some-app".to_string()),
+            ]
+        );
+        assert_eq!(interaction.response.headers, vec![redacted("set-cookie")]);
+
+        // The inner HttpRequest still gets the credentials.
+        assert_eq!(
+            requests.borrow()[0].headers()["authorization"],
+            "Bearer secret"
+        );
+    }
+
+    #[test]
+    fn test_record_streamed_body() {
+        let inner = MockHttpRequest::new(Response::new(b"response".to_vec()));
+        let requests = inner.get_request_cell();
+        let mut recorder = RecordingHttpRequest::new(inner);
+        let cassette = recorder.cassette();
+
+        let (mut body_sender, body) = Body::channel();
+        let request = Request::post("https://omaha.example.com/update")
+            .body(body)
+            .unwrap();
+        block_on(async {
+            let (response, ()) = future::join(recorder.request(request), async move {
+                body_sender.send_data("streamed".into()).await.unwrap();
+            })
+            .await;
+            assert_eq!(response.unwrap().body(), b"response");
+        });
+
+        assert_eq!(
+            cassette.lock().unwrap().interactions[0].request.body,
+            CassetteBody::Text("streamed".to_string())
+        );
+        assert_eq!(forwarded_bodies(&requests), vec![b"streamed".to_vec()]);
+    }
+
+    #[test]
+    fn test_record_to_file_and_replay() {
+        let dir = TempDir::new();
+        let path = dir.join("cassette.json");
+        let inner = MockHttpRequest::new(Response::new(b"recorded".to_vec()));
+        let mut recorder = RecordingHttpRequest::with_path(inner, &path);
+        let request_body = json!({"request": {"requestid": "1", "app": []}});
+
+        block_on(async {
+            recorder
+                .request(make_request(
+                    "https://omaha.example.com/update?cup2key=1:2",
+                    request_body.clone(),
+                ))
+                .await
+                .unwrap();
+
+            let mut replay = ReplayHttpRequest::from_file(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let response = replay
+                .request(make_request(
+                    "https://omaha.example.com/update?cup2key=1:3",
+                    json!({"request": {"requestid": "2", "app": []}}),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.body(), b"recorded");
+        });
+    }
+
+    #[test]
+    fn test_replay_matches_in_order() {
+        let mut replay = ReplayHttpRequest::new(make_cassette());
+        assert_eq!(replay.unused_interactions(), 2);
+        let body = json!({"request": {"requestid": "new", "app": [{"appid": "1"}]}});
+
+        block_on(async {
+            let response = replay
+                .request(make_request(
+                    "https://omaha.example.com/update?cup2key=2:other",
+                    body.clone(),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.body(), b"first");
+            assert_eq!(response.headers()["etag"], "signature:hash");
+            assert_eq!(replay.unused_interactions(), 1);
+
+            let response = replay
+                .request(make_request(
+                    "https://omaha.example.com/update",
+                    body.clone(),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.body(), &[0x00, 0xff]);
+            assert_eq!(replay.unused_interactions(), 0);
+
+            let response = replay
+                .request(make_request("https://omaha.example.com/update", body))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        });
+    }
+
+    #[test]
+    fn test_replay_no_match() {
+        let mut replay = ReplayHttpRequest::new(make_cassette());
+        let body = json!({"request": {"requestid": "new", "app": [{"appid": "1"}]}});
+
+        block_on(async {
+            // Different path.
+            let response = replay
+                .request(make_request("https://omaha.example.com/other", body))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+            // Different body.
+            let response = replay
+                .request(make_request(
+                    "https://omaha.example.com/update",
+                    json!({"request": {"app": [{"appid": "2"}]}}),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+            // Different method.
+            let response = replay
+                .request(
+                    Request::get("https://omaha.example.com/update")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        });
+        assert_eq!(replay.unused_interactions(), 2);
+    }
+}
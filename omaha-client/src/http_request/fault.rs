@@ -0,0 +1,389 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! An [`HttpRequest`] wrapper that injects faults into the requests made through it, to exercise
+//! the failure handling paths of the state machine without a misbehaving server.
+//!
+//! Faults are injected according to a list of rules, each of which pairs a [`Fault`] with a
+//! [`Trigger`] that says for which requests it applies.  All randomness (probabilistic triggers,
+//! truncation lengths, corrupted bytes, etc.) is derived from a seed, so a sequence of requests
+//! always sees the same faults for the same seed.
+
+use crate::{
+    http_request::{Error, HttpRequest},
+    time::Timer,
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use http::{header::ETAG, HeaderValue, StatusCode};
+use hyper::{Body, Request, Response};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::time::Duration;
+use tracing::info;
+
+/// A fault to inject into a request.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Wait for the given duration before making the request.
+    Delay(Duration),
+
+    /// Fail the request with a timeout error, without making it.
+    Timeout,
+
+    /// Fail the request with the error returned by the given function, without making it, e.g.
+    /// `Fault::Error(Error::new_connection_reset)`.
+    Error(fn() -> Error),
+
+    /// Respond with the given status code and an empty body, without making the request.
+    Status(StatusCode),
+
+    /// Add an `X-Retry-After` header with the given number of seconds to the response.
+    RetryAfter(u64),
+
+    /// Truncate the response body to a random length shorter than the original.
+    TruncateBody,
+
+    /// Flip a random bit in the given number of randomly chosen bytes of the response body.
+    CorruptBytes(usize),
+
+    /// Flip a random bit in a randomly chosen byte of the response's ETag header, if it has one.
+    TamperEtag,
+}
+
+/// When to inject a fault.
+#[derive(Clone, Debug)]
+pub enum Trigger {
+    /// Inject the fault into every request.
+    Always,
+
+    /// Inject the fault into the requests with these indices (the first request is 0).
+    OnRequests(Vec<usize>),
+
+    /// Inject the fault into each request with the given probability.
+    Probability(f64),
+}
+
+/// An HttpRequest that forwards requests to another HttpRequest, injecting faults along the way.
+///
+/// Faults whose triggers match a request are applied in the order in which they were added.  A
+/// `Timeout`, `Error` or `Status` fault ends the request without forwarding it, so any faults
+/// after it have no effect.
+#[derive(Debug)]
+pub struct FaultInjectingHttpRequest<H, T> {
+    inner: H,
+    timer: T,
+    rng: StdRng,
+    rules: Vec<(Trigger, Fault)>,
+    request_count: usize,
+}
+
+impl<H, T> FaultInjectingHttpRequest<H, T>
+where
+    H: HttpRequest,
+    T: Timer,
+{
+    /// Create a new wrapper around `inner`, with no faults.  `timer` is used for `Delay` faults.
+    pub fn new(inner: H, timer: T, seed: u64) -> Self {
+        Self {
+            inner,
+            timer,
+            rng: StdRng::seed_from_u64(seed),
+            rules: vec![],
+            request_count: 0,
+        }
+    }
+
+    /// Add a fault, which will be injected into the requests that match `trigger`.
+    pub fn inject(mut self, trigger: Trigger, fault: Fault) -> Self {
+        self.rules.push((trigger, fault));
+        self
+    }
+
+    /// Returns the faults to inject into the next request.
+    fn next_faults(&mut self) -> Vec<Fault> {
+        let index = self.request_count;
+        self.request_count += 1;
+
+        let rng = &mut self.rng;
+        self.rules
+            .iter()
+            .filter(|(trigger, _)| match trigger {
+                Trigger::Always => true,
+                Trigger::OnRequests(indices) => indices.contains(&index),
+                // Always draw a number, so that the sequence of draws doesn't depend on the
+                // results of earlier rules.
+                Trigger::Probability(p) => rng.gen::<f64>() < *p,
+            })
+            .map(|(_, fault)| fault.clone())
+            .collect()
+    }
+}
+
+impl<H, T> HttpRequest for FaultInjectingHttpRequest<H, T>
+where
+    H: HttpRequest,
+    T: Timer,
+{
+    fn request(&mut self, req: Request<Body>) -> BoxFuture<'_, Result<Response<Vec<u8>>, Error>> {
+        let faults = self.next_faults();
+        if !faults.is_empty() {
+            info!(
+                "Injecting faults into request to {}: {:?}",
+                req.uri(),
+                faults
+            );
+        }
+        let mut rng = StdRng::seed_from_u64(self.rng.gen());
+
+        let mut delays = vec![];
+        for fault in &faults {
+            let early_result = match fault {
+                Fault::Delay(duration) => {
+                    delays.push(self.timer.wait_for(*duration));
+                    continue;
+                }
+                Fault::Timeout => Err(Error::new_timeout()),
+                Fault::Error(make_error) => Err(make_error()),
+                Fault::Status(status) => {
+                    Ok(Response::builder().status(*status).body(vec![]).unwrap())
+                }
+                _ => continue,
+            };
+            return async move {
+                future::join_all(delays).await;
+                early_result
+            }
+            .boxed();
+        }
+
+        let response = self.inner.request(req);
+        async move {
+            future::join_all(delays).await;
+            let mut response = response.await?;
+            for fault in faults {
+                apply_response_fault(&fault, &mut response, &mut rng);
+            }
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+fn apply_response_fault(fault: &Fault, response: &mut Response<Vec<u8>>, rng: &mut StdRng) {
+    match fault {
+        Fault::RetryAfter(seconds) => {
+            response
+                .headers_mut()
+                .insert("X-Retry-After", HeaderValue::from(*seconds));
+        }
+        Fault::TruncateBody => {
+            let body = response.body_mut();
+            if !body.is_empty() {
+                let len = rng.gen_range(0..body.len());
+                body.truncate(len);
+            }
+        }
+        Fault::CorruptBytes(count) => {
+            let body = response.body_mut();
+            if !body.is_empty() {
+                for _ in 0..*count {
+                    let index = rng.gen_range(0..body.len());
+                    body[index] ^= 1 << rng.gen_range(0..8);
+                }
+            }
+        }
+        Fault::TamperEtag => {
+            if let Some(etag) = response.headers().get(ETAG) {
+                let mut etag = etag.as_bytes().to_vec();
+                if !etag.is_empty() {
+                    let index = rng.gen_range(0..etag.len());
+                    // Only flip the low bits of the byte, so that the header stays visible ASCII.
+                    etag[index] ^= 1 << rng.gen_range(0..4);
+                    if let Ok(value) = HeaderValue::from_bytes(&etag) {
+                        response.headers_mut().insert(ETAG, value);
+                    }
+                }
+            }
+        }
+        Fault::Delay(_) | Fault::Timeout | Fault::Error(_) | Fault::Status(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        http_request::mock::MockHttpRequest,
+        time::timers::{MockTimer, StubTimer},
+    };
+    use futures::executor::block_on;
+
+    const BODY: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    fn make_response() -> Response<Vec<u8>> {
+        Response::builder()
+            .header(ETAG, "0123456789abcdef:fedcba9876543210")
+            .body(BODY.to_vec())
+            .unwrap()
+    }
+
+    fn make_inner(count: usize) -> MockHttpRequest {
+        let mut inner = MockHttpRequest::empty();
+        for _ in 0..count {
+            inner.add_response(make_response());
+        }
+        inner
+    }
+
+    #[test]
+    fn test_no_faults() {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), StubTimer, 0);
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.body(), BODY);
+        assert_eq!(
+            response.headers()[ETAG],
+            "0123456789abcdef:fedcba9876543210"
+        );
+    }
+
+    #[test]
+    fn test_errors_on_scheduled_requests() {
+        let inner = make_inner(2);
+        let requests = inner.get_request_cell();
+        let mut http = FaultInjectingHttpRequest::new(inner, StubTimer, 0)
+            .inject(Trigger::OnRequests(vec![0]), Fault::Timeout)
+            .inject(
+                Trigger::OnRequests(vec![1]),
+                Fault::Error(Error::new_connection_reset),
+            )
+            .inject(
+                Trigger::OnRequests(vec![2]),
+                Fault::Status(StatusCode::SERVICE_UNAVAILABLE),
+            );
+
+        block_on(async {
+            assert!(http
+                .request(Request::default())
+                .await
+                .unwrap_err()
+                .is_timeout());
+            assert!(http
+                .request(Request::default())
+                .await
+                .unwrap_err()
+                .is_connection_reset());
+            let response = http.request(Request::default()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+            assert!(response.body().is_empty());
+
+            // None of those requests were forwarded.
+            assert!(requests.borrow().is_empty());
+
+            let response = http.request(Request::default()).await.unwrap();
+            assert_eq!(response.body(), BODY);
+            assert_eq!(requests.borrow().len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut timer = MockTimer::new();
+        timer.expect_for(Duration::from_secs(3));
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), timer, 0)
+            .inject(Trigger::Always, Fault::Delay(Duration::from_secs(3)));
+
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert_eq!(response.body(), BODY);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), StubTimer, 0)
+            .inject(Trigger::Always, Fault::RetryAfter(60));
+
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert_eq!(response.headers()["X-Retry-After"], "60");
+    }
+
+    #[test]
+    fn test_truncate_body() {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), StubTimer, 0)
+            .inject(Trigger::Always, Fault::TruncateBody);
+
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert!(response.body().len() < BODY.len());
+        assert!(BODY.starts_with(response.body()));
+    }
+
+    #[test]
+    fn test_corrupt_bytes() {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), StubTimer, 0)
+            .inject(Trigger::Always, Fault::CorruptBytes(1));
+
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert_eq!(response.body().len(), BODY.len());
+        let differences: Vec<u8> = response
+            .body()
+            .iter()
+            .zip(BODY)
+            .map(|(a, b)| a ^ b)
+            .filter(|x| *x != 0)
+            .collect();
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].count_ones(), 1);
+    }
+
+    #[test]
+    fn test_tamper_etag() {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(1), StubTimer, 0)
+            .inject(Trigger::Always, Fault::TamperEtag);
+
+        let response = block_on(http.request(Request::default())).unwrap();
+        assert_eq!(response.body(), BODY);
+        let etag = response.headers()[ETAG].as_bytes();
+        assert_ne!(etag, b"0123456789abcdef:fedcba9876543210");
+        assert_eq!(etag.len(), "0123456789abcdef:fedcba9876543210".len());
+    }
+
+    fn run_with_seed(seed: u64) -> Vec<Result<Vec<u8>, ()>> {
+        let mut http = FaultInjectingHttpRequest::new(make_inner(20), StubTimer, seed)
+            .inject(Trigger::Probability(0.3), Fault::Timeout)
+            .inject(Trigger::Probability(0.5), Fault::CorruptBytes(2))
+            .inject(Trigger::Probability(0.5), Fault::TruncateBody);
+        block_on(async {
+            let mut results = vec![];
+            for _ in 0..20 {
+                results.push(
+                    http.request(Request::default())
+                        .await
+                        .map(Response::into_body)
+                        .map_err(|_| ()),
+                );
+            }
+            results
+        })
+    }
+
+    #[test]
+    fn test_probabilistic_faults_are_reproducible() {
+        let results = run_with_seed(1234);
+        assert_eq!(results, run_with_seed(1234));
+        assert_ne!(results, run_with_seed(4321));
+
+        // With these probabilities, some requests should have failed, some should have been
+        // modified, and some should have been left alone.
+        assert!(results.iter().any(Result::is_err));
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Ok(body) if body != BODY)));
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Ok(body) if body == BODY)));
+    }
+}
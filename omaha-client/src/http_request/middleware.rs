@@ -0,0 +1,255 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Request/response middleware for [`HttpRequest`].
+//!
+//! A [`Middleware`] gets to modify every request after it has been built (and decorated for CUP)
+//! by the state machine, just before it's handed to the transport, and to inspect every response
+//! before the state machine verifies and parses it.  Middleware is layered around an
+//! `HttpRequest` with [`HttpRequestExt::layer`], or with
+//! [`crate::state_machine::StateMachineBuilder::http_middleware`].
+//!
+//! Middleware only has access to the request's head (uri, headers and extensions), never to its
+//! body, so the CUP request hash always covers the exact bytes that are sent.  CUP doesn't sign
+//! the request headers, so adding auth, attestation or tracing headers doesn't affect it.  The CUP
+//! query parameters are part of the uri, and must be left as they are.
+//!
+//! Responses are passed through the middleware before CUP verification, so any change to the
+//! response body or ETag will be caught by it.
+
+use crate::http_request::{Error, HttpRequest};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use http::{request::Parts, HeaderMap};
+use hyper::{Body, Request, Response};
+
+/// A hook into every request made and every response received through an `HttpRequest`.
+pub trait Middleware: Send {
+    /// Called with the head of every request, before it's sent.
+    fn on_request(&mut self, _request: &mut Parts) {}
+
+    /// Called with every response that was successfully received, before it's returned to the
+    /// state machine.
+    fn on_response(&mut self, _response: &mut Response<Vec<u8>>) {}
+}
+
+/// An `HttpRequest` that runs its requests and responses through a [`Middleware`].
+///
+/// When several layers are stacked, the outermost layer (the last one added) sees requests first
+/// and responses last.
+#[derive(Debug)]
+pub struct Layered<M, H> {
+    middleware: M,
+    inner: H,
+}
+
+impl<M, H> Layered<M, H>
+where
+    M: Middleware,
+    H: HttpRequest,
+{
+    pub fn new(middleware: M, inner: H) -> Self {
+        Self { middleware, inner }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.inner
+    }
+}
+
+impl<M, H> HttpRequest for Layered<M, H>
+where
+    M: Middleware,
+    H: HttpRequest,
+{
+    fn request(&mut self, req: Request<Body>) -> BoxFuture<'_, Result<Response<Vec<u8>>, Error>> {
+        let (mut parts, body) = req.into_parts();
+        self.middleware.on_request(&mut parts);
+        let response = self.inner.request(Request::from_parts(parts, body));
+        let middleware = &mut self.middleware;
+        async move {
+            let mut response = response.await?;
+            middleware.on_response(&mut response);
+            Ok(response)
+        }
+        .boxed()
+    }
+}
+
+/// Extension trait for composing middleware around an [`HttpRequest`].
+pub trait HttpRequestExt: HttpRequest + Sized {
+    /// Wrap this `HttpRequest` in a layer that uses `middleware`.
+    fn layer<M: Middleware>(self, middleware: M) -> Layered<M, Self> {
+        Layered::new(middleware, self)
+    }
+}
+
+impl<H: HttpRequest> HttpRequestExt for H {}
+
+/// A middleware that sets a fixed set of headers on every request, replacing any existing values
+/// for them, e.g. for custom `X-Goog-Update-*` headers.
+#[derive(Clone, Debug, Default)]
+pub struct StaticHeaders {
+    headers: HeaderMap,
+}
+
+impl StaticHeaders {
+    pub fn new(headers: HeaderMap) -> Self {
+        Self { headers }
+    }
+}
+
+impl Middleware for StaticHeaders {
+    fn on_request(&mut self, request: &mut Parts) {
+        for (name, value) in &self.headers {
+            request.headers.insert(name, value.clone());
+        }
+    }
+}
+
+/// Use a closure that gets the request head as a middleware that only modifies requests.
+impl<F> Middleware for F
+where
+    F: FnMut(&mut Parts) + Send,
+{
+    fn on_request(&mut self, request: &mut Parts) {
+        self(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_request::mock::MockHttpRequest;
+    use futures::executor::block_on;
+    use http::HeaderValue;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the order in which its hooks are called into a shared log.
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Middleware for Recorder {
+        fn on_request(&mut self, request: &mut Parts) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("request {}", self.name));
+            request
+                .headers
+                .append("X-Layers", HeaderValue::from_static(self.name));
+        }
+
+        fn on_response(&mut self, response: &mut Response<Vec<u8>>) {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("response {}", self.name));
+            response.body_mut().extend_from_slice(self.name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_layer_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mock = MockHttpRequest::new(Response::new(b"body".to_vec()));
+        let requests = mock.get_request_cell();
+        let mut http = mock
+            .layer(Recorder {
+                name: "inner",
+                log: Arc::clone(&log),
+            })
+            .layer(Recorder {
+                name: "outer",
+                log: Arc::clone(&log),
+            });
+
+        let response = block_on(http.request(Request::new(Body::from("request")))).unwrap();
+
+        assert_eq!(response.body(), b"bodyinnerouter");
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "request outer",
+                "request inner",
+                "response inner",
+                "response outer"
+            ]
+        );
+        let requests = requests.borrow();
+        let layers: Vec<_> = requests[0].headers().get_all("X-Layers").iter().collect();
+        assert_eq!(layers, vec!["outer", "inner"]);
+    }
+
+    #[test]
+    fn test_body_is_untouched() {
+        let mock = MockHttpRequest::new(Response::new(vec![]));
+        let mut http = mock.layer(|request: &mut Parts| {
+            request
+                .headers
+                .insert("Authorization", HeaderValue::from_static("Bearer token"));
+        });
+
+        block_on(async {
+            let uri = "https://example.com/?cup2key=1:2&cup2hreq=abcd";
+            http.request(Request::post(uri).body(Body::from("request")).unwrap())
+                .await
+                .unwrap();
+
+            let mock = http.into_inner();
+            mock.assert_uri(uri);
+            mock.assert_header("Authorization", "Bearer token");
+            mock.assert_body_str("request").await;
+        });
+    }
+
+    #[test]
+    fn test_static_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Goog-Update-Custom", HeaderValue::from_static("value"));
+        headers.insert(
+            "X-Goog-Update-Updater",
+            HeaderValue::from_static("override"),
+        );
+        let mut http =
+            MockHttpRequest::new(Response::new(vec![])).layer(StaticHeaders::new(headers));
+
+        block_on(async {
+            http.request(
+                Request::post("https://example.com/")
+                    .header("X-Goog-Update-Updater", "original")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        });
+
+        let mock = http.into_inner();
+        mock.assert_header("X-Goog-Update-Custom", "value");
+        mock.assert_header("X-Goog-Update-Updater", "override");
+    }
+
+    #[test]
+    fn test_errors_skip_response_hooks() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut mock = MockHttpRequest::empty();
+        mock.add_error(Error::new_timeout());
+        let mut http = mock.layer(Recorder {
+            name: "layer",
+            log: Arc::clone(&log),
+        });
+
+        assert!(block_on(http.request(Request::default()))
+            .unwrap_err()
+            .is_timeout());
+        assert_eq!(*log.lock().unwrap(), vec!["request layer"]);
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! The proxy configuration model used by the state machine and honored by [`HttpRequest`]
+//! implementations.
+//!
+//! The state machine resolves the [`ProxyConfig`] from the [`Config`] against the request URI and
+//! the policy's [`RequestParams::use_configured_proxies`], and attaches the result to each outgoing
+//! request as a [`ProxyRoute`] extension.  Nothing in this crate acts on that route: every
+//! `HttpRequest` implementation must read it with [`route_for`] and connect through the given
+//! proxy, or directly.
+//!
+//! Proxy auto-config (PAC) scripts are not supported.
+//!
+//! [`HttpRequest`]: crate::http_request::HttpRequest
+//! [`Config`]: crate::configuration::Config
+//! [`RequestParams::use_configured_proxies`]: crate::request_builder::RequestParams
+
+use http::{uri::Scheme, Request, Uri};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// Error enum listing the ways a proxy specification can be invalid.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ProxyParseError {
+    #[error("proxy uri is malformed.")]
+    InvalidUri,
+    #[error("proxy uri has no host.")]
+    MissingHost,
+    #[error("unsupported proxy scheme: {0}")]
+    UnsupportedScheme(String),
+}
+
+/// The protocol used to talk to a proxy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyScheme {
+    /// An HTTP proxy, using `CONNECT` for https requests.
+    Http,
+    /// An HTTP proxy that is itself reached over TLS.
+    Https,
+    /// A SOCKS5 proxy, with hostnames resolved locally.
+    Socks5,
+    /// A SOCKS5 proxy, with hostnames resolved by the proxy.
+    Socks5h,
+}
+
+impl ProxyScheme {
+    fn default_port(self) -> u16 {
+        match self {
+            ProxyScheme::Http => 80,
+            ProxyScheme::Https => 443,
+            ProxyScheme::Socks5 | ProxyScheme::Socks5h => 1080,
+        }
+    }
+}
+
+impl fmt::Display for ProxyScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProxyScheme::Http => "http",
+            ProxyScheme::Https => "https",
+            ProxyScheme::Socks5 => "socks5",
+            ProxyScheme::Socks5h => "socks5h",
+        })
+    }
+}
+
+/// A single proxy server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proxy {
+    pub scheme: ProxyScheme,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Proxy {
+    /// The `scheme://host:port` form of this proxy, suitable for handing to a connector.
+    pub fn uri(&self) -> String {
+        format!("{self}")
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", self.scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", self.scheme, self.host, self.port)
+        }
+    }
+}
+
+/// Parses proxies in the forms accepted by the `*_PROXY` environment variables, e.g.
+/// `proxy.example.com:3128`, `http://proxy.example.com` or `socks5h://[::1]:1080`.  A missing
+/// scheme means an HTTP proxy.
+impl FromStr for Proxy {
+    type Err = ProxyParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let with_scheme = if s.contains("://") {
+            s.to_string()
+        } else {
+            format!("http://{s}")
+        };
+        let uri: Uri = with_scheme
+            .parse()
+            .map_err(|_| ProxyParseError::InvalidUri)?;
+        let scheme = match uri.scheme_str().map(str::to_ascii_lowercase).as_deref() {
+            Some("http") => ProxyScheme::Http,
+            Some("https") => ProxyScheme::Https,
+            Some("socks5") => ProxyScheme::Socks5,
+            Some("socks5h") => ProxyScheme::Socks5h,
+            Some(other) => return Err(ProxyParseError::UnsupportedScheme(other.to_string())),
+            None => return Err(ProxyParseError::InvalidUri),
+        };
+        let host = uri
+            .host()
+            .filter(|h| !h.is_empty())
+            .ok_or(ProxyParseError::MissingHost)?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or_else(|| scheme.default_port());
+        Ok(Proxy { scheme, host, port })
+    }
+}
+
+/// The set of hosts that must never be reached through a proxy, in the format of the `NO_PROXY`
+/// environment variable: a comma-separated list of hostnames, domain suffixes (with or without a
+/// leading `.`), IP addresses, or `*` to disable proxies entirely.  Ports in entries are ignored.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NoProxy {
+    all: bool,
+    entries: Vec<String>,
+}
+
+impl NoProxy {
+    /// Returns true if requests to `host` must bypass the proxy.
+    pub fn matches(&self, host: &str) -> bool {
+        if self.all {
+            return true;
+        }
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        let host_ip = host.parse::<IpAddr>().ok();
+        self.entries.iter().any(|entry| {
+            if let Ok(ip) = entry.parse::<IpAddr>() {
+                return Some(ip) == host_ip;
+            }
+            let suffix = entry.trim_start_matches('.');
+            host == suffix
+                || (host.len() > suffix.len()
+                    && host.ends_with(suffix)
+                    && host.as_bytes()[host.len() - suffix.len() - 1] == b'.')
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.entries.is_empty()
+    }
+}
+
+impl From<&str> for NoProxy {
+    fn from(s: &str) -> Self {
+        let mut no_proxy = NoProxy::default();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            if entry == "*" {
+                no_proxy.all = true;
+                continue;
+            }
+            let entry = entry.to_ascii_lowercase();
+            // Strip a trailing port, taking care not to mangle bare IPv6 addresses.
+            let entry = if entry.parse::<IpAddr>().is_ok() {
+                entry
+            } else if let Some(bracketed) = entry.strip_prefix('[') {
+                bracketed
+                    .split_once(']')
+                    .map(|(ip, _)| ip.to_string())
+                    .unwrap_or(entry.clone())
+            } else {
+                match entry.rsplit_once(':') {
+                    Some((host, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+                    _ => entry,
+                }
+            };
+            no_proxy.entries.push(entry);
+        }
+        no_proxy
+    }
+}
+
+/// The proxies to use for requests to the Omaha service.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    /// The proxy to use for `http://` requests.
+    pub http: Option<Proxy>,
+
+    /// The proxy to use for `https://` requests.
+    pub https: Option<Proxy>,
+
+    /// Hosts that are always connected to directly.
+    pub no_proxy: NoProxy,
+}
+
+impl ProxyConfig {
+    /// Creates a config that sends all requests through `proxy`.
+    pub fn all(proxy: Proxy) -> Self {
+        ProxyConfig {
+            http: Some(proxy.clone()),
+            https: Some(proxy),
+            no_proxy: NoProxy::default(),
+        }
+    }
+
+    /// Reads the configuration from the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and `NO_PROXY`
+    /// environment variables (and their lowercase forms, which take precedence).
+    pub fn from_env() -> Result<Self, ProxyParseError> {
+        Self::from_vars(std::env::vars())
+    }
+
+    /// The same as [`ProxyConfig::from_env`], but reading from the given variables, which allows
+    /// callers to supply their own environment.
+    pub fn from_vars(
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ProxyParseError> {
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(_, value)| !value.trim().is_empty())
+            .collect();
+        let get = |name: &str| {
+            let lower = name.to_ascii_lowercase();
+            vars.iter()
+                .find(|(k, _)| *k == lower)
+                .or_else(|| vars.iter().find(|(k, _)| k == name))
+                .map(|(_, v)| v.as_str())
+        };
+
+        let all = get("ALL_PROXY").map(str::parse).transpose()?;
+        // `HTTP_PROXY` is only honored in its lowercase form, as the uppercase form can be set by
+        // the `Proxy:` header of CGI requests ("httpoxy").
+        let http = vars
+            .iter()
+            .find(|(k, _)| k == "http_proxy")
+            .map(|(_, v)| v.parse())
+            .transpose()?
+            .or_else(|| all.clone());
+        let https = get("HTTPS_PROXY")
+            .map(str::parse)
+            .transpose()?
+            .or_else(|| all.clone());
+        let no_proxy = get("NO_PROXY").map(NoProxy::from).unwrap_or_default();
+
+        Ok(ProxyConfig {
+            http,
+            https,
+            no_proxy,
+        })
+    }
+
+    /// Returns true if no proxy is configured.
+    pub fn is_empty(&self) -> bool {
+        self.http.is_none() && self.https.is_none()
+    }
+
+    /// Determine how a request to `uri` should be routed.
+    pub fn route(&self, uri: &Uri) -> ProxyRoute {
+        let proxy = if uri.scheme() == Some(&Scheme::HTTPS) {
+            self.https.as_ref()
+        } else {
+            self.http.as_ref()
+        };
+        match (proxy, uri.host()) {
+            (Some(proxy), Some(host)) if !self.no_proxy.matches(host) => {
+                ProxyRoute::Proxied(proxy.clone())
+            }
+            _ => ProxyRoute::Direct,
+        }
+    }
+}
+
+/// How a single request should reach its destination.
+///
+/// This is attached as an extension to every request made by the state machine.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ProxyRoute {
+    /// Connect directly to the destination.
+    #[default]
+    Direct,
+    /// Connect through the given proxy.
+    Proxied(Proxy),
+}
+
+impl ProxyRoute {
+    /// Resolve the route for a request to `uri`, given the configured proxies and whether policy
+    /// allows using them for this request.
+    pub fn resolve(config: Option<&ProxyConfig>, use_configured_proxies: bool, uri: &str) -> Self {
+        match (config, use_configured_proxies) {
+            (Some(config), true) => uri
+                .parse::<Uri>()
+                .map(|uri| config.route(&uri))
+                .unwrap_or_default(),
+            _ => ProxyRoute::Direct,
+        }
+    }
+
+    pub fn is_proxied(&self) -> bool {
+        matches!(self, ProxyRoute::Proxied(_))
+    }
+}
+
+/// Returns the route an `HttpRequest` implementation should use for `req`.  Requests that weren't
+/// built by the state machine are sent directly.
+pub fn route_for<B>(req: &Request<B>) -> ProxyRoute {
+    req.extensions()
+        .get::<ProxyRoute>()
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_proxy() {
+        assert_eq!(
+            "proxy.example.com:3128".parse::<Proxy>().unwrap(),
+            Proxy {
+                scheme: ProxyScheme::Http,
+                host: "proxy.example.com".to_string(),
+                port: 3128
+            }
+        );
+        assert_eq!(
+            "https://proxy.example.com".parse::<Proxy>().unwrap(),
+            Proxy {
+                scheme: ProxyScheme::Https,
+                host: "proxy.example.com".to_string(),
+                port: 443
+            }
+        );
+        assert_eq!(
+            "socks5h://[::1]".parse::<Proxy>().unwrap(),
+            Proxy {
+                scheme: ProxyScheme::Socks5h,
+                host: "::1".to_string(),
+                port: 1080
+            }
+        );
+        assert_eq!(
+            "SOCKS5://10.0.0.1:9050/".parse::<Proxy>().unwrap().uri(),
+            "socks5://10.0.0.1:9050"
+        );
+    }
+
+    #[test]
+    fn test_parse_proxy_error() {
+        assert_eq!(
+            "ftp://proxy:21".parse::<Proxy>(),
+            Err(ProxyParseError::UnsupportedScheme("ftp".to_string()))
+        );
+        assert_eq!("http://".parse::<Proxy>(), Err(ProxyParseError::InvalidUri));
+        assert_eq!(
+            "not a proxy".parse::<Proxy>(),
+            Err(ProxyParseError::InvalidUri)
+        );
+    }
+
+    #[test]
+    fn test_no_proxy_matches() {
+        let no_proxy =
+            NoProxy::from("localhost, .internal.example.com,example.org:8080,10.1.2.3,[::1]:80");
+        assert!(no_proxy.matches("localhost"));
+        assert!(no_proxy.matches("internal.example.com"));
+        assert!(no_proxy.matches("omaha.internal.example.com"));
+        assert!(no_proxy.matches("example.org"));
+        assert!(no_proxy.matches("Sub.Example.Org."));
+        assert!(no_proxy.matches("10.1.2.3"));
+        assert!(no_proxy.matches("[::1]"));
+        assert!(!no_proxy.matches("notexample.org"));
+        assert!(!no_proxy.matches("example.com"));
+        assert!(!no_proxy.matches("10.1.2.4"));
+
+        assert!(NoProxy::from("*").matches("anything.example.com"));
+        assert!(NoProxy::from("").is_empty());
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let config = ProxyConfig::from_vars(vars(&[
+            ("http_proxy", "http://http-proxy:3128"),
+            ("HTTPS_PROXY", "socks5://socks-proxy"),
+            ("NO_PROXY", "localhost"),
+        ]))
+        .unwrap();
+        assert_eq!(config.http, Some("http-proxy:3128".parse().unwrap()));
+        assert_eq!(config.https, Some("socks5://socks-proxy".parse().unwrap()));
+        assert!(config.no_proxy.matches("localhost"));
+    }
+
+    #[test]
+    fn test_from_vars_lowercase_takes_precedence() {
+        let config = ProxyConfig::from_vars(vars(&[
+            ("HTTPS_PROXY", "upper:1"),
+            ("https_proxy", "lower:2"),
+        ]))
+        .unwrap();
+        assert_eq!(config.https, Some("lower:2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_from_vars_ignores_uppercase_http_proxy() {
+        let config = ProxyConfig::from_vars(vars(&[("HTTP_PROXY", "evil:80")])).unwrap();
+        assert_eq!(config, ProxyConfig::default());
+    }
+
+    #[test]
+    fn test_from_vars_all_proxy() {
+        let config = ProxyConfig::from_vars(vars(&[
+            ("ALL_PROXY", "socks5h://all:1080"),
+            ("https_proxy", "https-only:3128"),
+        ]))
+        .unwrap();
+        assert_eq!(config.http, Some("socks5h://all:1080".parse().unwrap()));
+        assert_eq!(config.https, Some("https-only:3128".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_from_vars_invalid() {
+        assert_eq!(
+            ProxyConfig::from_vars(vars(&[("https_proxy", "gopher://x")])),
+            Err(ProxyParseError::UnsupportedScheme("gopher".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_route() {
+        let mut config = ProxyConfig {
+            https: Some("secure-proxy:443".parse().unwrap()),
+            ..ProxyConfig::default()
+        };
+        config.no_proxy = NoProxy::from("internal");
+
+        assert_eq!(
+            config.route(&"https://omaha.example.com/service".parse().unwrap()),
+            ProxyRoute::Proxied("secure-proxy:443".parse().unwrap())
+        );
+        assert_eq!(
+            config.route(&"http://omaha.example.com/".parse().unwrap()),
+            ProxyRoute::Direct
+        );
+        assert_eq!(
+            config.route(&"https://internal/".parse().unwrap()),
+            ProxyRoute::Direct
+        );
+    }
+
+    #[test]
+    fn test_resolve_follows_policy() {
+        let config = ProxyConfig::all("proxy:3128".parse().unwrap());
+        assert!(ProxyRoute::resolve(Some(&config), true, "http://example.com/").is_proxied());
+        assert!(!ProxyRoute::resolve(Some(&config), false, "http://example.com/").is_proxied());
+        assert!(!ProxyRoute::resolve(None, true, "http://example.com/").is_proxied());
+    }
+
+    #[test]
+    fn test_route_for_request() {
+        let mut req = Request::get("http://example.com/").body(()).unwrap();
+        assert_eq!(route_for(&req), ProxyRoute::Direct);
+
+        let route = ProxyRoute::Proxied("proxy:3128".parse().unwrap());
+        req.extensions_mut().insert(route.clone());
+        assert_eq!(route_for(&req), route);
+    }
+}
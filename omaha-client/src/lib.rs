@@ -13,8 +13,11 @@
 
 pub mod app_set;
 pub mod async_generator;
+pub mod auth;
+pub mod channel_config;
 pub mod clock;
 pub mod common;
+pub mod compression;
 pub mod configuration;
 pub mod cup_ecdsa;
 pub mod http_request;
@@ -26,6 +29,10 @@ pub mod protocol;
 pub mod request_builder;
 pub mod state_machine;
 pub mod storage;
+pub mod system_conditions;
 pub mod time;
 pub mod unless;
 pub mod version;
+
+#[cfg(test)]
+pub(crate) mod test_support;
@@ -47,6 +47,9 @@ pub enum Metrics {
     RequestsPerCheck { count: u64, successful: bool },
     /// Number of update check attempts to get an update check to succeed.
     AttemptsToSuccessfulCheck(u64),
+    /// An update that the server marked as urgent was installed, with a bool to hold whether that
+    /// was a success or a failure.
+    UrgentUpdateInstall { successful: bool },
     /// Number of install attempts to get an update to succeed.
     AttemptsToSuccessfulInstall { count: u64, successful: bool },
     /// Elapsed time from having finished applying the update to when finally
@@ -66,6 +69,18 @@ pub enum UpdateCheckFailureReason {
     Proxy = 2,
     Configuration = 3,
     Internal = 4,
+    /// The Omaha server's host name could not be resolved.
+    Dns = 5,
+    /// The connection to the Omaha server was refused.
+    ConnectionRefused = 6,
+    /// The TLS handshake failed, including certificate validation failures.
+    Tls = 7,
+    /// The connection was reset or aborted while making the request.
+    ConnectionReset = 8,
+    /// The connection was closed before the whole response body was read.
+    BodyTruncated = 9,
+    /// The request couldn't be authenticated.
+    Auth = 10,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -9,19 +9,39 @@
 use crate::{
     common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
     installer::Plan,
+    protocol::response::Urgency,
     request_builder::RequestParams,
-    time::{ComplexTime, TimeSource},
+    time::{ComplexTime, PartialComplexTime, TimeSource},
 };
 use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::time::Duration;
 
 #[cfg(test)]
 mod mock;
 #[cfg(test)]
 pub use mock::MockPolicyEngine;
+mod admin_overrides;
+pub use admin_overrides::{
+    AdminOverrides, AdminOverridesError, AdminOverridesPolicyEngine, AppOverrides,
+};
+mod layered;
+pub use layered::{Layered, Named, PolicyLayer, PolicyQuestion, TimingMerge};
+mod maintenance_window;
+pub use maintenance_window::{MaintenanceWindow, MaintenanceWindows};
+mod standard;
+pub use standard::{
+    MaintenanceWindowPolicyData, StandardPolicy, StandardPolicyConfig, StandardPolicyData,
+    StandardPolicyEngine,
+};
 mod stub;
 pub use stub::StubPolicy;
 pub use stub::StubPolicyEngine;
+mod system_conditions;
+pub use system_conditions::{SystemConditionsPolicyEngine, SystemConditionsRules};
+mod version_rules;
 use typed_builder::TypedBuilder;
+pub use version_rules::{VersionRules, VersionRulesPolicyEngine};
 
 /// Data about the local system that's needed to fulfill Policy questions
 #[derive(Clone, Debug, TypedBuilder)]
@@ -31,6 +51,32 @@ pub struct PolicyData {
     pub current_time: ComplexTime,
 }
 
+/// Why a Policy declined an update check or an update.
+#[derive(Clone, Debug, PartialEq, Eq, TypedBuilder)]
+pub struct PolicyReason {
+    /// Identifies the rule that made the decision.  This is reported to Omaha as the `extracode1`
+    /// of the event for the decision, so it should be stable across releases.
+    pub rule_id: u32,
+
+    /// A human-readable explanation of the decision.
+    #[builder(setter(into))]
+    pub message: String,
+
+    /// How long until the decision is expected to change, if that's known.
+    #[builder(default, setter(strip_option))]
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for PolicyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rule {}: {}", self.rule_id, self.message)?;
+        if let Some(retry_after) = self.retry_after {
+            write!(f, " (retry after {retry_after:?})")?;
+        }
+        Ok(())
+    }
+}
+
 /// Reasons why a check can/cannot be performed at this time
 #[derive(Clone, Debug, PartialEq)]
 pub enum CheckDecision {
@@ -40,9 +86,21 @@ pub enum CheckDecision {
     OkUpdateDeferred(RequestParams),
 
     /// negative responses
-    TooSoon,
-    ThrottledByPolicy,
-    DeniedByPolicy,
+    TooSoon(PolicyReason),
+    ThrottledByPolicy(PolicyReason),
+    DeniedByPolicy(PolicyReason),
+}
+
+impl CheckDecision {
+    /// The reason for a negative decision by Policy.
+    pub fn reason(&self) -> Option<&PolicyReason> {
+        match self {
+            CheckDecision::TooSoon(reason)
+            | CheckDecision::ThrottledByPolicy(reason)
+            | CheckDecision::DeniedByPolicy(reason) => Some(reason),
+            CheckDecision::Ok(_) | CheckDecision::OkUpdateDeferred(_) => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -58,9 +116,21 @@ pub enum UpdateDecision {
     /// Update can be performed.
     Ok,
     /// Update is deferred by Policy.
-    DeferredByPolicy,
+    DeferredByPolicy(PolicyReason),
     /// Update is rejected by Policy.
-    DeniedByPolicy,
+    DeniedByPolicy(PolicyReason),
+}
+
+impl UpdateDecision {
+    /// The reason for a negative decision by Policy.
+    pub fn reason(&self) -> Option<&PolicyReason> {
+        match self {
+            UpdateDecision::Ok => None,
+            UpdateDecision::DeferredByPolicy(reason) | UpdateDecision::DeniedByPolicy(reason) => {
+                Some(reason)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -103,14 +173,20 @@ pub trait Policy {
     ) -> CheckDecision;
 
     /// Given the current State, the current PolicyData, can the proposed InstallPlan
-    /// be executed at this time.
+    /// be executed at this time.  `urgency` is how urgently the server wants it applied.
     fn update_can_start(
         policy_data: &Self::UpdateCanStartPolicyData,
         proposed_install_plan: &Self::InstallPlan,
+        urgency: Urgency,
     ) -> UpdateDecision;
 
-    /// Given the current PolicyData, is reboot allowed right now.
-    fn reboot_allowed(policy_data: &Self::RebootPolicyData, check_options: &CheckOptions) -> bool;
+    /// Given the current PolicyData, is reboot allowed right now.  `urgency` is how urgently the
+    /// server wanted the installed update applied.
+    fn reboot_allowed(
+        policy_data: &Self::RebootPolicyData,
+        check_options: &CheckOptions,
+        urgency: Urgency,
+    ) -> bool;
 
     /// Given the InstallPlan, is reboot needed after update has been installed.
     fn reboot_needed(install_plan: &Self::InstallPlan) -> bool;
@@ -124,6 +200,12 @@ pub trait PolicyEngine {
     /// Provides the time source used by the PolicyEngine to the state machine.
     fn time_source(&self) -> &Self::TimeSource;
 
+    /// The administrator's overrides that are currently in effect, if any.  The state machine
+    /// reports changes to them, and moves apps to the channels that they target.
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        None
+    }
+
     /// When should the next update happen?
     fn compute_next_update_time<'a>(
         &'a mut self,
@@ -144,20 +226,43 @@ pub trait PolicyEngine {
         check_options: &'a CheckOptions,
     ) -> BoxFuture<'a, CheckDecision>;
 
+    /// Can `app` be updated to the `offered_version` from the server's response, if the response
+    /// has one.  This is asked for each app with an update, before `update_can_start`.
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        _app: &'a App,
+        _offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        future::ready(UpdateDecision::Ok).boxed()
+    }
+
     /// Given the current State, the current PolicyData, can the proposed InstallPlan
-    /// be executed at this time.
+    /// be executed at this time.  `urgency` is how urgently the server wants it applied.
     fn update_can_start<'a>(
         &'a mut self,
         proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
     ) -> BoxFuture<'a, UpdateDecision>;
 
-    /// Is reboot allowed right now.
+    /// Is reboot allowed right now.  `urgency` is how urgently the server wanted the installed
+    /// update applied.
     fn reboot_allowed<'a>(
         &'a mut self,
         check_options: &'a CheckOptions,
         install_result: &'a Self::InstallResult,
+        urgency: Urgency,
     ) -> BoxFuture<'a, bool>;
 
+    /// When reboot is next expected to be allowed, if that's known.  While reboot isn't allowed,
+    /// the state machine waits until this time before asking again, instead of polling.
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        _check_options: &'a CheckOptions,
+        _install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        future::ready(None).boxed()
+    }
+
     /// Given the InstallPlan, is reboot needed after update has been installed.
     fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool>;
 }
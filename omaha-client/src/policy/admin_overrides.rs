@@ -0,0 +1,725 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use crate::{
+    common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
+    policy::{
+        version_rules::matches_prefix, CheckDecision, PolicyEngine, PolicyReason, UpdateDecision,
+    },
+    protocol::response::Urgency,
+    request_builder::RequestParams,
+    time::PartialComplexTime,
+    version::Version,
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+use tracing::{info, warn};
+
+#[derive(Debug, Error)]
+pub enum AdminOverridesError {
+    #[error("could not read {}.", .path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("could not parse admin overrides.")]
+    Json(#[from] serde_json::Error),
+    #[error("app {0} has a check interval of zero.")]
+    ZeroCheckInterval(String),
+}
+
+/// The overrides of a single app.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AppOverrides {
+    /// Check for updates, but don't take any.
+    #[serde(default)]
+    pub updates_disabled: bool,
+
+    /// The channel to move the app to.
+    pub target_channel: Option<String>,
+
+    /// Only take updates whose version starts with this prefix.
+    pub target_version_prefix: Option<String>,
+
+    /// The interval between update checks.
+    #[serde(
+        default,
+        rename = "check_interval_secs",
+        with = "crate::common::optional_secs"
+    )]
+    pub check_interval: Option<Duration>,
+}
+
+/// Overrides of the policy that are managed by the administrator of a device, loaded from a JSON
+/// document such as:
+///
+/// ```json
+/// {
+///     "apps": {
+///         "{00000000-0000-0000-0000-000000000001}": {
+///             "updates_disabled": false,
+///             "target_channel": "beta",
+///             "target_version_prefix": "1.2.",
+///             "check_interval_secs": 3600
+///         }
+///     },
+///     "reboot_allowed": false
+/// }
+/// ```
+///
+/// All fields are optional.  Reboots aren't specific to an app, so the reboot rule is for the
+/// whole device: `true` always allows a reboot after an update, and `false` never does.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminOverrides {
+    #[serde(default)]
+    pub apps: HashMap<String, AppOverrides>,
+
+    pub reboot_allowed: Option<bool>,
+}
+
+impl AdminOverrides {
+    /// The [`PolicyReason::rule_id`] of updates to apps whose updates are disabled.
+    pub const UPDATES_DISABLED: u32 = 7;
+    /// The [`PolicyReason::rule_id`] of offered versions that don't match the target version
+    /// prefix.
+    pub const TARGET_VERSION_PREFIX: u32 = 8;
+
+    /// Load the overrides from a JSON file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AdminOverridesError> {
+        let path = path.as_ref();
+        let json = fs::read(path).map_err(|source| AdminOverridesError::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        Self::from_json(&json)
+    }
+
+    /// Parse the overrides, and check that none of the check intervals are zero.
+    pub fn from_json(json: &[u8]) -> Result<Self, AdminOverridesError> {
+        let overrides: Self = serde_json::from_slice(json)?;
+        for (app_id, app_overrides) in &overrides.apps {
+            if app_overrides.check_interval == Some(Duration::ZERO) {
+                return Err(AdminOverridesError::ZeroCheckInterval(app_id.clone()));
+            }
+        }
+        Ok(overrides)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// The shortest of the check intervals of `apps`, if any of them is overridden.
+    fn check_interval(&self, apps: &[App]) -> Option<Duration> {
+        apps.iter()
+            .filter_map(|app| self.apps.get(&app.id)?.check_interval)
+            .min()
+    }
+
+    /// Add the per-app overrides to `params`.
+    fn request_params(&self, mut params: RequestParams) -> RequestParams {
+        for (app_id, overrides) in &self.apps {
+            if !overrides.updates_disabled && overrides.target_version_prefix.is_none() {
+                continue;
+            }
+            let app_params = params.app_params.entry(app_id.clone()).or_default();
+            app_params.disable_updates |= overrides.updates_disabled;
+            if overrides.target_version_prefix.is_some() {
+                app_params.target_version_prefix = overrides.target_version_prefix.clone();
+            }
+        }
+        params
+    }
+
+    /// Check whether `app` may be updated to `offered_version`, in case the server ignored the
+    /// overrides that were sent in the request.
+    fn check(&self, app: &App, offered_version: Option<&str>) -> Result<(), PolicyReason> {
+        let Some(overrides) = self.apps.get(&app.id) else {
+            return Ok(());
+        };
+        let reason = |rule_id, message: String| {
+            PolicyReason::builder()
+                .rule_id(rule_id)
+                .message(format!("{}: {}", app.id, message))
+                .build()
+        };
+        if overrides.updates_disabled {
+            return Err(reason(
+                Self::UPDATES_DISABLED,
+                "updates are disabled by the administrator".to_string(),
+            ));
+        }
+        if let Some(prefix) = &overrides.target_version_prefix {
+            let version = offered_version.and_then(|version| version.parse::<Version>().ok());
+            if !version.is_some_and(|version| matches_prefix(&version, prefix)) {
+                return Err(reason(
+                    Self::TARGET_VERSION_PREFIX,
+                    format!("offered version {offered_version:?} isn't target prefix {prefix}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A PolicyEngine that applies the [`AdminOverrides`] in a file on top of the wrapped engine.  The
+/// file is read again whenever it changes, and may be missing, in which case nothing is
+/// overridden.
+///
+///   * `updates_disabled` and `target_version_prefix` are added to the `RequestParams` of the
+///     allowed update checks, and are also enforced locally: updates of a disabled app, or to a
+///     version that doesn't match the prefix, are denied.  This is done per app in
+///     [`PolicyEngine::update_version_allowed`], as the install plan doesn't say which apps it
+///     updates.
+///   * `check_interval` replaces the wrapped engine's interval between update checks, counting
+///     from the start of the last one, so it can make checks more or less frequent.  It is never
+///     shorter than the server's poll interval, and doesn't apply to the first check or while
+///     backing off after failed checks.
+///   * `reboot_allowed` replaces the wrapped engine's decision about rebooting.
+///   * `target_channel` is applied by the state machine, which gets the current overrides from
+///     [`PolicyEngine::admin_overrides`].
+#[derive(Debug)]
+pub struct AdminOverridesPolicyEngine<E> {
+    engine: E,
+    path: PathBuf,
+    overrides: AdminOverrides,
+    /// The modification time of the file that the overrides were loaded from.
+    loaded_modified: Option<SystemTime>,
+}
+
+impl<E: PolicyEngine> AdminOverridesPolicyEngine<E> {
+    pub fn new(engine: E, path: impl Into<PathBuf>) -> Self {
+        let mut engine = Self {
+            engine,
+            path: path.into(),
+            overrides: AdminOverrides::default(),
+            loaded_modified: None,
+        };
+        engine.reload_if_changed();
+        engine
+    }
+
+    /// Read the overrides again if the file has changed since they were loaded.  If it can't be
+    /// parsed, the previous overrides are kept.
+    fn reload_if_changed(&mut self) {
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(_) => {
+                if !self.overrides.is_empty() {
+                    info!("Admin overrides removed from {}", self.path.display());
+                }
+                self.overrides = AdminOverrides::default();
+                self.loaded_modified = None;
+                return;
+            }
+        };
+        if modified.is_some() && modified == self.loaded_modified {
+            return;
+        }
+        self.loaded_modified = modified;
+        match AdminOverrides::load(&self.path) {
+            Ok(overrides) => {
+                info!("Loaded admin overrides: {:?}", overrides);
+                self.overrides = overrides;
+            }
+            Err(e) => warn!(
+                "Unable to load admin overrides, keeping the previous ones: {:#}",
+                anyhow::anyhow!(e)
+            ),
+        }
+    }
+}
+
+impl<E: PolicyEngine> PolicyEngine for AdminOverridesPolicyEngine<E> {
+    type TimeSource = E::TimeSource;
+    type InstallResult = E::InstallResult;
+    type InstallPlan = E::InstallPlan;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        self.engine.time_source()
+    }
+
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        self.reload_if_changed();
+        Some(self.overrides.clone()).filter(|overrides| !overrides.is_empty())
+    }
+
+    fn compute_next_update_time<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+    ) -> BoxFuture<'a, CheckTiming> {
+        self.reload_if_changed();
+        let Some(interval) = self.overrides.check_interval(apps) else {
+            return self
+                .engine
+                .compute_next_update_time(apps, scheduling, protocol_state);
+        };
+        let Some(last_update_check_time) = scheduling.last_update_check_time else {
+            return self
+                .engine
+                .compute_next_update_time(apps, scheduling, protocol_state);
+        };
+        if protocol_state.consecutive_failed_update_checks > 0 {
+            return self
+                .engine
+                .compute_next_update_time(apps, scheduling, protocol_state);
+        }
+        let interval = protocol_state
+            .server_dictated_poll_interval
+            .map_or(interval, |server_interval| server_interval.max(interval));
+        self.engine
+            .compute_next_update_time(apps, scheduling, protocol_state)
+            .map(move |timing| CheckTiming {
+                time: last_update_check_time + interval,
+                ..timing
+            })
+            .boxed()
+    }
+
+    fn update_check_allowed<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+        check_options: &'a CheckOptions,
+    ) -> BoxFuture<'a, CheckDecision> {
+        self.reload_if_changed();
+        let overrides = &self.overrides;
+        self.engine
+            .update_check_allowed(apps, scheduling, protocol_state, check_options)
+            .map(move |decision| match decision {
+                CheckDecision::Ok(params) => CheckDecision::Ok(overrides.request_params(params)),
+                CheckDecision::OkUpdateDeferred(params) => {
+                    CheckDecision::OkUpdateDeferred(overrides.request_params(params))
+                }
+                decision => decision,
+            })
+            .boxed()
+    }
+
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        app: &'a App,
+        offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.reload_if_changed();
+        match self.overrides.check(app, offered_version) {
+            Ok(()) => self.engine.update_version_allowed(app, offered_version),
+            Err(reason) => future::ready(UpdateDecision::DeniedByPolicy(reason)).boxed(),
+        }
+    }
+
+    fn update_can_start<'a>(
+        &'a mut self,
+        proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.engine.update_can_start(proposed_install_plan, urgency)
+    }
+
+    fn reboot_allowed<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, bool> {
+        self.reload_if_changed();
+        match self.overrides.reboot_allowed {
+            Some(reboot_allowed) => future::ready(reboot_allowed).boxed(),
+            None => self
+                .engine
+                .reboot_allowed(check_options, install_result, urgency),
+        }
+    }
+
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        match self.overrides.reboot_allowed {
+            // Only a change of the overrides can allow it.
+            Some(_) => future::ready(None).boxed(),
+            None => self
+                .engine
+                .next_reboot_allowed_time(check_options, install_result),
+        }
+    }
+
+    fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool> {
+        self.engine.reboot_needed(install_plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        installer::stub::StubPlan,
+        policy::{StandardPolicyConfig, StandardPolicyEngine, StubPolicyEngine},
+        protocol::request::InstallSource,
+        request_builder::AppRequestParams,
+        test_support::TempDir,
+        time::{MockTimeSource, TimeSource},
+    };
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    const APP_ID: &str = "{00000000-0000-0000-0000-000000000001}";
+
+    /// A file for the overrides, in a directory that is removed when dropped.
+    struct OverridesFile {
+        path: PathBuf,
+        _dir: TempDir,
+    }
+
+    impl OverridesFile {
+        fn new() -> Self {
+            let dir = TempDir::new();
+            Self {
+                path: dir.join("admin-overrides.json"),
+                _dir: dir,
+            }
+        }
+
+        fn write(&self, json: serde_json::Value) {
+            self.write_raw(&json.to_string());
+        }
+
+        fn write_raw(&self, contents: &str) {
+            // Make sure that the modification time changes, even on filesystems with a coarse
+            // timestamp resolution.
+            let previous = fs::metadata(&self.path)
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            fs::write(&self.path, contents).unwrap();
+            if let Some(previous) = previous {
+                let file = fs::File::options().write(true).open(&self.path).unwrap();
+                file.set_modified(previous + Duration::from_secs(1))
+                    .unwrap();
+            }
+        }
+    }
+
+    fn make_engine(
+        file: &OverridesFile,
+    ) -> AdminOverridesPolicyEngine<StubPolicyEngine<StubPlan, MockTimeSource>> {
+        AdminOverridesPolicyEngine::new(
+            StubPolicyEngine::new(MockTimeSource::new_from_now()),
+            &file.path,
+        )
+    }
+
+    fn app() -> App {
+        App::builder().id(APP_ID).version([1, 2, 3, 4]).build()
+    }
+
+    fn update_check_allowed(engine: &mut impl PolicyEngine) -> CheckDecision {
+        block_on(engine.update_check_allowed(
+            &[app()],
+            &UpdateCheckSchedule::default(),
+            &ProtocolState::default(),
+            &CheckOptions {
+                source: InstallSource::OnDemand,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_from_json() {
+        let overrides = AdminOverrides::from_json(
+            json!({
+                "apps": {
+                    APP_ID: {
+                        "updates_disabled": true,
+                        "target_channel": "beta",
+                        "target_version_prefix": "1.2.",
+                        "check_interval_secs": 3600,
+                    },
+                    "other-app": {},
+                },
+                "reboot_allowed": false,
+            })
+            .to_string()
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            overrides,
+            AdminOverrides {
+                apps: HashMap::from([
+                    (
+                        APP_ID.to_string(),
+                        AppOverrides {
+                            updates_disabled: true,
+                            target_channel: Some("beta".to_string()),
+                            target_version_prefix: Some("1.2.".to_string()),
+                            check_interval: Some(Duration::from_secs(3600)),
+                        }
+                    ),
+                    ("other-app".to_string(), AppOverrides::default()),
+                ]),
+                reboot_allowed: Some(false),
+            }
+        );
+        assert!(AdminOverrides::from_json(b"{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_from_json_errors() {
+        assert_matches!(
+            AdminOverrides::from_json(br#"{"reboot": true}"#),
+            Err(AdminOverridesError::Json(_))
+        );
+        assert_matches!(
+            AdminOverrides::from_json(br#"{"apps": {"app": {"check_interval_secs": 0}}}"#),
+            Err(AdminOverridesError::ZeroCheckInterval(app_id)) if app_id == "app"
+        );
+        assert_matches!(
+            AdminOverrides::load("/does/not/exist"),
+            Err(AdminOverridesError::Io { .. })
+        );
+    }
+
+    #[test]
+    fn test_missing_file_overrides_nothing() {
+        let file = OverridesFile::new();
+        let mut engine = make_engine(&file);
+        assert_eq!(engine.admin_overrides(), None);
+        assert_eq!(
+            update_check_allowed(&mut engine),
+            CheckDecision::Ok(RequestParams {
+                source: InstallSource::OnDemand,
+                use_configured_proxies: true,
+                ..RequestParams::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_request_params() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {APP_ID: {
+            "updates_disabled": true,
+            "target_version_prefix": "1.2.",
+        }}}));
+        let mut engine = make_engine(&file);
+        assert_matches!(
+            update_check_allowed(&mut engine),
+            CheckDecision::Ok(RequestParams { app_params, .. }) if app_params == HashMap::from([(
+                APP_ID.to_string(),
+                AppRequestParams {
+                    disable_updates: true,
+                    target_version_prefix: Some("1.2.".to_string()),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_updates_disabled_denies_update() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {APP_ID: {"updates_disabled": true}}}));
+        let mut engine = make_engine(&file);
+        assert_matches!(
+            block_on(engine.update_version_allowed(&app(), Some("1.2.3.5"))),
+            UpdateDecision::DeniedByPolicy(PolicyReason {
+                rule_id: AdminOverrides::UPDATES_DISABLED,
+                ..
+            })
+        );
+
+        let other_app = App::builder().id("other-app").version([1, 2, 3, 4]).build();
+        assert_eq!(
+            block_on(engine.update_version_allowed(&other_app, Some("1.2.3.5"))),
+            UpdateDecision::Ok
+        );
+    }
+
+    #[test]
+    fn test_target_version_prefix_denies_other_versions() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {APP_ID: {"target_version_prefix": "1.2."}}}));
+        let mut engine = make_engine(&file);
+        assert_eq!(
+            block_on(engine.update_version_allowed(&app(), Some("1.2.3.5"))),
+            UpdateDecision::Ok
+        );
+        for offered_version in [Some("1.20.0.0"), Some("2.0"), Some("not a version"), None] {
+            assert_matches!(
+                block_on(engine.update_version_allowed(&app(), offered_version)),
+                UpdateDecision::DeniedByPolicy(PolicyReason {
+                    rule_id: AdminOverrides::TARGET_VERSION_PREFIX,
+                    ..
+                }),
+                "{offered_version:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reloads_on_change() {
+        let file = OverridesFile::new();
+        file.write(json!({"reboot_allowed": false}));
+        let mut engine = make_engine(&file);
+        let options = CheckOptions::default();
+        assert!(!block_on(engine.reboot_allowed(
+            &options,
+            &(),
+            Urgency::Normal
+        )));
+
+        file.write(json!({"apps": {APP_ID: {"target_channel": "beta"}}}));
+        assert!(block_on(engine.reboot_allowed(
+            &options,
+            &(),
+            Urgency::Normal
+        )));
+        assert_eq!(
+            engine
+                .admin_overrides()
+                .unwrap()
+                .apps
+                .get(APP_ID)
+                .unwrap()
+                .target_channel
+                .as_deref(),
+            Some("beta")
+        );
+
+        // A broken file keeps the previous overrides.
+        file.write_raw("{");
+        assert!(engine.admin_overrides().is_some());
+
+        fs::remove_file(&file.path).unwrap();
+        assert_eq!(engine.admin_overrides(), None);
+    }
+
+    #[test]
+    fn test_check_interval() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {
+            APP_ID: {"check_interval_secs": 600},
+            "other-app": {"check_interval_secs": 60},
+        }}));
+        let mut engine = make_engine(&file);
+        let last_update_check_time = engine.time_source().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_check_time(last_update_check_time)
+            .build();
+        let timing = block_on(engine.compute_next_update_time(
+            &[app()],
+            &scheduling,
+            &ProtocolState::default(),
+        ));
+        assert_eq!(
+            timing.time,
+            PartialComplexTime::from(last_update_check_time + Duration::from_secs(600))
+        );
+
+        // The server's poll interval is longer, so it wins.
+        let protocol_state = ProtocolState {
+            server_dictated_poll_interval: Some(Duration::from_secs(3600)),
+            ..ProtocolState::default()
+        };
+        let timing =
+            block_on(engine.compute_next_update_time(&[app()], &scheduling, &protocol_state));
+        assert_eq!(
+            timing.time,
+            PartialComplexTime::from(last_update_check_time + Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn test_check_interval_not_applied_after_failed_checks() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {APP_ID: {"check_interval_secs": 600}}}));
+        let mut engine = make_engine(&file);
+        // The last successful check was long ago, and the device has been offline since.
+        let now = engine.time_source().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now - Duration::from_secs(24 * 60 * 60))
+            .last_update_check_time(now)
+            .build();
+        let protocol_state = ProtocolState {
+            consecutive_failed_update_checks: 3,
+            ..ProtocolState::default()
+        };
+        let wrapped = block_on(
+            StubPolicyEngine::<StubPlan, _>::new(engine.time_source().clone())
+                .compute_next_update_time(&[app()], &scheduling, &protocol_state),
+        );
+        let timing =
+            block_on(engine.compute_next_update_time(&[app()], &scheduling, &protocol_state));
+        assert_eq!(timing, wrapped);
+    }
+
+    #[test]
+    fn test_check_interval_replaces_wrapped_interval() {
+        let file = OverridesFile::new();
+        file.write(json!({"apps": {APP_ID: {"check_interval_secs": 600}}}));
+        // The wrapped engine checks every few hours.
+        let mut engine = AdminOverridesPolicyEngine::new(
+            StandardPolicyEngine::<StubPlan, _>::new(
+                StandardPolicyConfig::default(),
+                MockTimeSource::new_from_now(),
+            ),
+            &file.path,
+        );
+        let now = engine.time_source().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now)
+            .last_update_check_time(now)
+            .build();
+        let timing = block_on(engine.compute_next_update_time(
+            &[app()],
+            &scheduling,
+            &ProtocolState::default(),
+        ));
+        assert_eq!(
+            timing.time,
+            PartialComplexTime::from(now + Duration::from_secs(600))
+        );
+
+        // The first check is left to the wrapped engine.
+        let scheduling = UpdateCheckSchedule::default();
+        let timing = block_on(engine.compute_next_update_time(
+            &[app()],
+            &scheduling,
+            &ProtocolState::default(),
+        ));
+        assert_eq!(
+            timing,
+            block_on(
+                StandardPolicyEngine::<StubPlan, _>::new(
+                    StandardPolicyConfig::default(),
+                    engine.time_source().clone(),
+                )
+                .compute_next_update_time(
+                    &[app()],
+                    &scheduling,
+                    &ProtocolState::default()
+                )
+            )
+        );
+    }
+}
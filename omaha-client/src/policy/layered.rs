@@ -0,0 +1,845 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Combinators for composing a [`PolicyEngine`] out of several smaller ones.
+//!
+//! Each rule is wrapped in [`Named`], and rules are stacked with [`Layered`], which can itself be
+//! layered further:
+//!
+//! ```ignore
+//! let engine = Layered::new(
+//!     Named::new("schedule", schedule_engine),
+//!     Layered::new(
+//!         Named::new("battery", battery_engine),
+//!         Named::new("admin", admin_engine),
+//!         TimingMerge::Latest,
+//!     ),
+//!     TimingMerge::Latest,
+//! );
+//! ```
+//!
+//! After each decision, [`PolicyLayer::decided_by`] returns the name of the rule that made it.
+
+use crate::{
+    common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
+    policy::{AdminOverrides, CheckDecision, PolicyEngine, UpdateDecision},
+    protocol::response::Urgency,
+    time::{ComplexTime, PartialComplexTime, TimeSource},
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::{collections::HashMap, time::Duration};
+
+/// The questions that a [`PolicyEngine`] answers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PolicyQuestion {
+    NextUpdateTime,
+    UpdateCheckAllowed,
+    UpdateVersionAllowed,
+    UpdateCanStart,
+    RebootAllowed,
+    RebootNeeded,
+}
+
+/// A [`PolicyEngine`] that can report which of its rules made its last decisions.
+pub trait PolicyLayer: PolicyEngine {
+    /// The name of the rule that made the last decision for `question`, or None if it hasn't
+    /// been asked yet.
+    fn decided_by(&self, question: PolicyQuestion) -> Option<&str>;
+}
+
+/// A single, named, rule.
+#[derive(Debug)]
+pub struct Named<E> {
+    name: String,
+    engine: E,
+}
+
+impl<E: PolicyEngine> Named<E> {
+    pub fn new(name: impl Into<String>, engine: E) -> Self {
+        Self {
+            name: name.into(),
+            engine,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+}
+
+impl<E: PolicyEngine> PolicyLayer for Named<E> {
+    fn decided_by(&self, _question: PolicyQuestion) -> Option<&str> {
+        Some(&self.name)
+    }
+}
+
+impl<E: PolicyEngine> PolicyEngine for Named<E> {
+    type TimeSource = E::TimeSource;
+    type InstallResult = E::InstallResult;
+    type InstallPlan = E::InstallPlan;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        self.engine.time_source()
+    }
+
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        self.engine.admin_overrides()
+    }
+
+    fn compute_next_update_time<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+    ) -> BoxFuture<'a, CheckTiming> {
+        self.engine
+            .compute_next_update_time(apps, scheduling, protocol_state)
+    }
+
+    fn update_check_allowed<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+        check_options: &'a CheckOptions,
+    ) -> BoxFuture<'a, CheckDecision> {
+        self.engine
+            .update_check_allowed(apps, scheduling, protocol_state, check_options)
+    }
+
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        app: &'a App,
+        offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.engine.update_version_allowed(app, offered_version)
+    }
+
+    fn update_can_start<'a>(
+        &'a mut self,
+        proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.engine.update_can_start(proposed_install_plan, urgency)
+    }
+
+    fn reboot_allowed<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, bool> {
+        self.engine
+            .reboot_allowed(check_options, install_result, urgency)
+    }
+
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        self.engine
+            .next_reboot_allowed_time(check_options, install_result)
+    }
+
+    fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool> {
+        self.engine.reboot_needed(install_plan)
+    }
+}
+
+/// How [`Layered`] merges the `CheckTiming`s of its two layers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimingMerge {
+    /// Check when the first of the two layers wants to.
+    Earliest,
+    /// Check when both of the layers want to.
+    Latest,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Side {
+    First,
+    Second,
+}
+
+/// Two policy layers, combined:
+///   * `update_check_allowed`, `update_version_allowed` and `update_can_start` return the first
+///     negative decision, without asking the second layer if the first one declines.  Otherwise,
+///     the first `OkUpdateDeferred` wins over `Ok`, and the second layer's `Ok` is used when both
+///     allow it.
+///   * `compute_next_update_time` picks the earliest or the latest `CheckTiming`, according to
+///     the `TimingMerge`.
+///   * `reboot_allowed` is only true if both layers allow it, and the second layer isn't asked if
+///     the first one doesn't.  `reboot_needed` is true if either layer needs it.
+///   * `next_reboot_allowed_time` is the later of the two, if both layers know it.
+///   * `admin_overrides` are the first layer's, if it has any.
+///
+/// The time source is the first layer's.
+#[derive(Debug)]
+pub struct Layered<A, B> {
+    first: A,
+    second: B,
+    timing_merge: TimingMerge,
+    decided_by: HashMap<PolicyQuestion, Side>,
+}
+
+impl<A, B> Layered<A, B>
+where
+    A: PolicyLayer,
+    B: PolicyLayer<InstallPlan = A::InstallPlan, InstallResult = A::InstallResult>,
+{
+    pub fn new(first: A, second: B, timing_merge: TimingMerge) -> Self {
+        Self {
+            first,
+            second,
+            timing_merge,
+            decided_by: HashMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+impl<A, B> PolicyLayer for Layered<A, B>
+where
+    A: PolicyLayer + Send,
+    B: PolicyLayer<InstallPlan = A::InstallPlan, InstallResult = A::InstallResult> + Send,
+    A::InstallResult: Sync,
+{
+    fn decided_by(&self, question: PolicyQuestion) -> Option<&str> {
+        match self.decided_by.get(&question)? {
+            Side::First => self.first.decided_by(question),
+            Side::Second => self.second.decided_by(question),
+        }
+    }
+}
+
+/// How long from `now` until `timing` is reached.
+fn time_until(timing: &CheckTiming, now: ComplexTime) -> Duration {
+    let (wall, mono) = timing.time.destructure();
+    let wall = wall.map(|wall| wall.duration_since(now.wall).unwrap_or_default());
+    let mono = mono.map(|mono| mono.saturating_duration_since(now.mono));
+    // A complex time is reached when either of its times is.
+    let until_time = wall.into_iter().chain(mono).min().unwrap_or_default();
+    until_time.max(timing.minimum_wait.unwrap_or_default())
+}
+
+fn is_negative(decision: &CheckDecision) -> bool {
+    !matches!(
+        decision,
+        CheckDecision::Ok(_) | CheckDecision::OkUpdateDeferred(_)
+    )
+}
+
+impl<A, B> PolicyEngine for Layered<A, B>
+where
+    A: PolicyLayer + Send,
+    B: PolicyLayer<InstallPlan = A::InstallPlan, InstallResult = A::InstallResult> + Send,
+    A::InstallResult: Sync,
+{
+    type TimeSource = A::TimeSource;
+    type InstallResult = A::InstallResult;
+    type InstallPlan = A::InstallPlan;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        self.first.time_source()
+    }
+
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        self.first
+            .admin_overrides()
+            .or_else(|| self.second.admin_overrides())
+    }
+
+    fn compute_next_update_time<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+    ) -> BoxFuture<'a, CheckTiming> {
+        async move {
+            let first = self
+                .first
+                .compute_next_update_time(apps, scheduling, protocol_state)
+                .await;
+            let second = self
+                .second
+                .compute_next_update_time(apps, scheduling, protocol_state)
+                .await;
+            let now = self.first.time_source().now();
+            let second_is_later = time_until(&second, now) > time_until(&first, now);
+            let side = match (self.timing_merge, second_is_later) {
+                (TimingMerge::Earliest, false) | (TimingMerge::Latest, true) => Side::Second,
+                (TimingMerge::Earliest, true) | (TimingMerge::Latest, false) => Side::First,
+            };
+            // Prefer the first layer when they're the same.
+            let side = if first == second { Side::First } else { side };
+            self.decided_by.insert(PolicyQuestion::NextUpdateTime, side);
+            match side {
+                Side::First => first,
+                Side::Second => second,
+            }
+        }
+        .boxed()
+    }
+
+    fn update_check_allowed<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+        check_options: &'a CheckOptions,
+    ) -> BoxFuture<'a, CheckDecision> {
+        async move {
+            let first = self
+                .first
+                .update_check_allowed(apps, scheduling, protocol_state, check_options)
+                .await;
+            let (side, decision) = if is_negative(&first) {
+                (Side::First, first)
+            } else {
+                let second = self
+                    .second
+                    .update_check_allowed(apps, scheduling, protocol_state, check_options)
+                    .await;
+                match (first, second) {
+                    (_, second) if is_negative(&second) => (Side::Second, second),
+                    (first @ CheckDecision::OkUpdateDeferred(_), _) => (Side::First, first),
+                    (_, second) => (Side::Second, second),
+                }
+            };
+            self.decided_by
+                .insert(PolicyQuestion::UpdateCheckAllowed, side);
+            decision
+        }
+        .boxed()
+    }
+
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        app: &'a App,
+        offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        async move {
+            let first = self
+                .first
+                .update_version_allowed(app, offered_version)
+                .await;
+            let (side, decision) = if first != UpdateDecision::Ok {
+                (Side::First, first)
+            } else {
+                let second = self
+                    .second
+                    .update_version_allowed(app, offered_version)
+                    .await;
+                (Side::Second, second)
+            };
+            self.decided_by
+                .insert(PolicyQuestion::UpdateVersionAllowed, side);
+            decision
+        }
+        .boxed()
+    }
+
+    fn update_can_start<'a>(
+        &'a mut self,
+        proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        async move {
+            let first = self
+                .first
+                .update_can_start(proposed_install_plan, urgency)
+                .await;
+            let (side, decision) = if first != UpdateDecision::Ok {
+                (Side::First, first)
+            } else {
+                let second = self
+                    .second
+                    .update_can_start(proposed_install_plan, urgency)
+                    .await;
+                (Side::Second, second)
+            };
+            self.decided_by.insert(PolicyQuestion::UpdateCanStart, side);
+            decision
+        }
+        .boxed()
+    }
+
+    fn reboot_allowed<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, bool> {
+        async move {
+            let (side, allowed) = if !self
+                .first
+                .reboot_allowed(check_options, install_result, urgency)
+                .await
+            {
+                (Side::First, false)
+            } else {
+                let allowed = self
+                    .second
+                    .reboot_allowed(check_options, install_result, urgency)
+                    .await;
+                (Side::Second, allowed)
+            };
+            self.decided_by.insert(PolicyQuestion::RebootAllowed, side);
+            allowed
+        }
+        .boxed()
+    }
+
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        async move {
+            let first = self
+                .first
+                .next_reboot_allowed_time(check_options, install_result)
+                .await?;
+            let second = self
+                .second
+                .next_reboot_allowed_time(check_options, install_result)
+                .await?;
+            let now = self.first.time_source().now();
+            let until = |time| time_until(&CheckTiming::builder().time(time).build(), now);
+            Some(if until(second) > until(first) {
+                second
+            } else {
+                first
+            })
+        }
+        .boxed()
+    }
+
+    fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool> {
+        async move {
+            let (side, needed) = if self.first.reboot_needed(install_plan).await {
+                (Side::First, true)
+            } else {
+                (Side::Second, self.second.reboot_needed(install_plan).await)
+            };
+            self.decided_by.insert(PolicyQuestion::RebootNeeded, side);
+            needed
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        installer::stub::StubPlan, policy::PolicyReason, protocol::request::InstallSource,
+        request_builder::RequestParams, time::MockTimeSource,
+    };
+    use futures::executor::block_on;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// A rule with fixed answers, that counts how often it's asked anything.
+    #[derive(Debug)]
+    struct FixedRule {
+        time_source: MockTimeSource,
+        check_timing: CheckTiming,
+        check_decision: CheckDecision,
+        update_decision: UpdateDecision,
+        reboot_allowed: bool,
+        reboot_needed: bool,
+        next_reboot_allowed_time: Option<PartialComplexTime>,
+        asked: Arc<AtomicUsize>,
+    }
+
+    impl FixedRule {
+        fn new(time_source: &MockTimeSource) -> Self {
+            Self {
+                time_source: time_source.clone(),
+                check_timing: CheckTiming::builder().time(time_source.now()).build(),
+                check_decision: CheckDecision::Ok(RequestParams::default()),
+                update_decision: UpdateDecision::Ok,
+                reboot_allowed: true,
+                reboot_needed: false,
+                next_reboot_allowed_time: None,
+                asked: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+
+        fn ask<T>(&self, answer: T) -> BoxFuture<'static, T>
+        where
+            T: Send + 'static,
+        {
+            self.asked.fetch_add(1, Ordering::SeqCst);
+            future::ready(answer).boxed()
+        }
+    }
+
+    impl PolicyEngine for FixedRule {
+        type TimeSource = MockTimeSource;
+        type InstallResult = ();
+        type InstallPlan = StubPlan;
+
+        fn time_source(&self) -> &Self::TimeSource {
+            &self.time_source
+        }
+
+        fn compute_next_update_time(
+            &mut self,
+            _apps: &[App],
+            _scheduling: &UpdateCheckSchedule,
+            _protocol_state: &ProtocolState,
+        ) -> BoxFuture<'_, CheckTiming> {
+            self.ask(self.check_timing)
+        }
+
+        fn update_check_allowed(
+            &mut self,
+            _apps: &[App],
+            _scheduling: &UpdateCheckSchedule,
+            _protocol_state: &ProtocolState,
+            _check_options: &CheckOptions,
+        ) -> BoxFuture<'_, CheckDecision> {
+            self.ask(self.check_decision.clone())
+        }
+
+        fn update_can_start<'p>(
+            &mut self,
+            _proposed_install_plan: &'p Self::InstallPlan,
+            _urgency: Urgency,
+        ) -> BoxFuture<'p, UpdateDecision> {
+            self.ask(self.update_decision.clone())
+        }
+
+        fn reboot_allowed(
+            &mut self,
+            _check_options: &CheckOptions,
+            _install_result: &Self::InstallResult,
+            _urgency: Urgency,
+        ) -> BoxFuture<'_, bool> {
+            self.ask(self.reboot_allowed)
+        }
+
+        fn next_reboot_allowed_time(
+            &mut self,
+            _check_options: &CheckOptions,
+            _install_result: &Self::InstallResult,
+        ) -> BoxFuture<'_, Option<PartialComplexTime>> {
+            self.ask(self.next_reboot_allowed_time)
+        }
+
+        fn reboot_needed(&mut self, _install_plan: &Self::InstallPlan) -> BoxFuture<'_, bool> {
+            self.ask(self.reboot_needed)
+        }
+    }
+
+    fn reason(rule_id: u32) -> PolicyReason {
+        PolicyReason::builder()
+            .rule_id(rule_id)
+            .message("declined")
+            .build()
+    }
+
+    fn layered(
+        first: FixedRule,
+        second: FixedRule,
+        timing_merge: TimingMerge,
+    ) -> Layered<Named<FixedRule>, Named<FixedRule>> {
+        Layered::new(
+            Named::new("first", first),
+            Named::new("second", second),
+            timing_merge,
+        )
+    }
+
+    fn update_check_allowed(engine: &mut impl PolicyEngine) -> CheckDecision {
+        block_on(engine.update_check_allowed(
+            &[],
+            &UpdateCheckSchedule::default(),
+            &ProtocolState::default(),
+            &CheckOptions::default(),
+        ))
+    }
+
+    #[test]
+    fn test_update_check_allowed_first_deny_wins() {
+        let mock_time = MockTimeSource::new_from_now();
+        let mut first = FixedRule::new(&mock_time);
+        first.check_decision = CheckDecision::ThrottledByPolicy(reason(1));
+        let mut second = FixedRule::new(&mock_time);
+        second.check_decision = CheckDecision::DeniedByPolicy(reason(2));
+        let second_asked = Arc::clone(&second.asked);
+        let mut engine = layered(first, second, TimingMerge::Latest);
+
+        assert_eq!(engine.decided_by(PolicyQuestion::UpdateCheckAllowed), None);
+        assert_eq!(
+            update_check_allowed(&mut engine),
+            CheckDecision::ThrottledByPolicy(reason(1))
+        );
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCheckAllowed),
+            Some("first")
+        );
+        assert_eq!(second_asked.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_update_check_allowed_combines_positive_decisions() {
+        let mock_time = MockTimeSource::new_from_now();
+        let deferred = CheckDecision::OkUpdateDeferred(RequestParams::default());
+        let on_demand = CheckDecision::Ok(RequestParams {
+            source: InstallSource::OnDemand,
+            ..RequestParams::default()
+        });
+
+        // The second layer can still deny the check.
+        let mut second = FixedRule::new(&mock_time);
+        second.check_decision = CheckDecision::TooSoon(reason(2));
+        let mut engine = layered(FixedRule::new(&mock_time), second, TimingMerge::Latest);
+        assert_eq!(
+            update_check_allowed(&mut engine),
+            CheckDecision::TooSoon(reason(2))
+        );
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCheckAllowed),
+            Some("second")
+        );
+
+        // A deferral wins over an Ok.
+        let mut first = FixedRule::new(&mock_time);
+        first.check_decision = deferred.clone();
+        let mut engine = layered(first, FixedRule::new(&mock_time), TimingMerge::Latest);
+        assert_eq!(update_check_allowed(&mut engine), deferred);
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCheckAllowed),
+            Some("first")
+        );
+
+        // If both allow it, the second layer's decision is used.
+        let mut second = FixedRule::new(&mock_time);
+        second.check_decision = on_demand.clone();
+        let mut engine = layered(FixedRule::new(&mock_time), second, TimingMerge::Latest);
+        assert_eq!(update_check_allowed(&mut engine), on_demand);
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCheckAllowed),
+            Some("second")
+        );
+    }
+
+    #[test]
+    fn test_update_can_start_first_deny_wins() {
+        let mock_time = MockTimeSource::new_from_now();
+        let mut second = FixedRule::new(&mock_time);
+        second.update_decision = UpdateDecision::DeferredByPolicy(reason(3));
+        let mut engine = layered(FixedRule::new(&mock_time), second, TimingMerge::Latest);
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::DeferredByPolicy(reason(3))
+        );
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCanStart),
+            Some("second")
+        );
+
+        let mut first = FixedRule::new(&mock_time);
+        first.update_decision = UpdateDecision::DeniedByPolicy(reason(4));
+        let mut second = FixedRule::new(&mock_time);
+        second.update_decision = UpdateDecision::DeferredByPolicy(reason(3));
+        let mut engine = layered(first, second, TimingMerge::Latest);
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::DeniedByPolicy(reason(4))
+        );
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCanStart),
+            Some("first")
+        );
+    }
+
+    #[test]
+    fn test_compute_next_update_time_merge() {
+        let mock_time = MockTimeSource::new_from_now();
+        let now = mock_time.now();
+        let soon = CheckTiming::builder()
+            .time(now + Duration::from_secs(60))
+            .build();
+        // Only a wall time, but later.
+        let later = CheckTiming::builder()
+            .time(PartialComplexTime::Wall(
+                now.wall + Duration::from_secs(600),
+            ))
+            .build();
+
+        for (timing_merge, expected, expected_rule) in [
+            (TimingMerge::Earliest, soon, "first"),
+            (TimingMerge::Latest, later, "second"),
+        ] {
+            let mut first = FixedRule::new(&mock_time);
+            first.check_timing = soon;
+            let mut second = FixedRule::new(&mock_time);
+            second.check_timing = later;
+            let mut engine = layered(first, second, timing_merge);
+            assert_eq!(
+                block_on(engine.compute_next_update_time(
+                    &[],
+                    &UpdateCheckSchedule::default(),
+                    &ProtocolState::default(),
+                )),
+                expected
+            );
+            assert_eq!(
+                engine.decided_by(PolicyQuestion::NextUpdateTime),
+                Some(expected_rule)
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_next_update_time_includes_minimum_wait() {
+        let mock_time = MockTimeSource::new_from_now();
+        let now = mock_time.now();
+        let mut first = FixedRule::new(&mock_time);
+        first.check_timing = CheckTiming::builder()
+            .time(now)
+            .minimum_wait(Duration::from_secs(600))
+            .build();
+        let mut second = FixedRule::new(&mock_time);
+        second.check_timing = CheckTiming::builder()
+            .time(now + Duration::from_secs(60))
+            .build();
+        let expected = second.check_timing;
+        let mut engine = layered(first, second, TimingMerge::Earliest);
+        assert_eq!(
+            block_on(engine.compute_next_update_time(
+                &[],
+                &UpdateCheckSchedule::default(),
+                &ProtocolState::default(),
+            )),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_reboot_allowed_short_circuits() {
+        let mock_time = MockTimeSource::new_from_now();
+        let mut first = FixedRule::new(&mock_time);
+        first.reboot_allowed = false;
+        let second = FixedRule::new(&mock_time);
+        let second_asked = Arc::clone(&second.asked);
+        let mut engine = layered(first, second, TimingMerge::Latest);
+        let options = CheckOptions::default();
+
+        assert!(!block_on(engine.reboot_allowed(
+            &options,
+            &(),
+            Urgency::Normal
+        )));
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::RebootAllowed),
+            Some("first")
+        );
+        assert_eq!(second_asked.load(Ordering::SeqCst), 0);
+
+        let mut second = FixedRule::new(&mock_time);
+        second.reboot_allowed = false;
+        let mut engine = layered(FixedRule::new(&mock_time), second, TimingMerge::Latest);
+        assert!(!block_on(engine.reboot_allowed(
+            &options,
+            &(),
+            Urgency::Normal
+        )));
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::RebootAllowed),
+            Some("second")
+        );
+    }
+
+    #[test]
+    fn test_reboot_needed_if_either_needs_it() {
+        let mock_time = MockTimeSource::new_from_now();
+        let mut second = FixedRule::new(&mock_time);
+        second.reboot_needed = true;
+        let mut engine = layered(FixedRule::new(&mock_time), second, TimingMerge::Latest);
+        assert!(block_on(engine.reboot_needed(&StubPlan)));
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::RebootNeeded),
+            Some("second")
+        );
+
+        let mut engine = layered(
+            FixedRule::new(&mock_time),
+            FixedRule::new(&mock_time),
+            TimingMerge::Latest,
+        );
+        assert!(!block_on(engine.reboot_needed(&StubPlan)));
+    }
+
+    #[test]
+    fn test_next_reboot_allowed_time() {
+        let mock_time = MockTimeSource::new_from_now();
+        let now = mock_time.now();
+        let soon = PartialComplexTime::from(now + Duration::from_secs(60));
+        let later = PartialComplexTime::Wall(now.wall + Duration::from_secs(600));
+        let options = CheckOptions::default();
+
+        let mut first = FixedRule::new(&mock_time);
+        first.next_reboot_allowed_time = Some(later);
+        let mut second = FixedRule::new(&mock_time);
+        second.next_reboot_allowed_time = Some(soon);
+        let mut engine = layered(first, second, TimingMerge::Earliest);
+        assert_eq!(
+            block_on(engine.next_reboot_allowed_time(&options, &())),
+            Some(later)
+        );
+
+        // Unknown if either layer doesn't know.
+        let mut first = FixedRule::new(&mock_time);
+        first.next_reboot_allowed_time = Some(soon);
+        let mut engine = layered(first, FixedRule::new(&mock_time), TimingMerge::Earliest);
+        assert_eq!(
+            block_on(engine.next_reboot_allowed_time(&options, &())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_nested_layers_report_innermost_rule() {
+        let mock_time = MockTimeSource::new_from_now();
+        let mut denying = FixedRule::new(&mock_time);
+        denying.update_decision = UpdateDecision::DeniedByPolicy(reason(4));
+        let mut engine = Layered::new(
+            Named::new("schedule", FixedRule::new(&mock_time)),
+            Layered::new(
+                Named::new("battery", FixedRule::new(&mock_time)),
+                Named::new("admin", denying),
+                TimingMerge::Latest,
+            ),
+            TimingMerge::Latest,
+        );
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::DeniedByPolicy(reason(4))
+        );
+        assert_eq!(
+            engine.decided_by(PolicyQuestion::UpdateCanStart),
+            Some("admin")
+        );
+    }
+}
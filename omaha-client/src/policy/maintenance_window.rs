@@ -0,0 +1,395 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! Time-of-day and day-of-week maintenance windows, in local time.
+//!
+//! Windows are evaluated against a `chrono::TimeZone`, so that they follow the local clock across
+//! daylight saving time transitions:
+//!   * A window that opens or closes in the hour that's skipped when the clocks go forward opens
+//!     or closes that much later (e.g. a window that opens at 02:30 opens at 03:30 instead).
+//!   * A window that opens or closes in the hour that's repeated when the clocks go back opens or
+//!     closes the first time that local time occurs.
+
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, LocalResult, NaiveDate, NaiveDateTime,
+    NaiveTime, Offset, TimeZone, Utc, Weekday,
+};
+use std::time::SystemTime;
+
+/// A window of local time, that opens at the same time on some (or all) days of the week.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// The days of the week on which the window opens, or every day if empty.
+    pub days: Vec<Weekday>,
+
+    /// The local time at which the window opens.
+    pub start: NaiveTime,
+
+    /// The local time at which the window closes.  If this isn't after `start`, the window closes
+    /// on the following day.
+    pub end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    /// A window that opens every day.
+    pub fn daily(start: NaiveTime, end: NaiveTime) -> Self {
+        Self {
+            days: vec![],
+            start,
+            end,
+        }
+    }
+
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// The times at which the window that opens on local date `date` opens and closes, if it
+    /// opens on that date at all.
+    fn on_date<Tz: TimeZone>(
+        &self,
+        time_zone: &Tz,
+        date: NaiveDate,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.opens_on(date.weekday()) {
+            return None;
+        }
+        let end_date = if self.end > self.start {
+            date
+        } else {
+            date.succ_opt()?
+        };
+        let open = resolve_local(time_zone, date.and_time(self.start));
+        let close = resolve_local(time_zone, end_date.and_time(self.end));
+        // Moving the opening time past the skipped hour can leave nothing of the window.
+        (open < close).then_some((open, close))
+    }
+}
+
+/// Convert a local time to UTC, following the rules in the module docs for local times that are
+/// skipped or repeated.
+fn resolve_local<Tz: TimeZone>(time_zone: &Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    match time_zone.from_local_datetime(&local) {
+        LocalResult::Single(time) | LocalResult::Ambiguous(time, _) => time.with_timezone(&Utc),
+        LocalResult::None => {
+            // Use the offset from before the clocks went forward.  Transitions are never less
+            // than a day apart.
+            let offset = time_zone
+                .offset_from_utc_datetime(&(local - ChronoDuration::days(1)))
+                .fix();
+            DateTime::from_naive_utc_and_offset(local - offset, Utc)
+        }
+    }
+}
+
+/// A set of maintenance windows.  An empty set places no restriction, so it's always open.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MaintenanceWindows(pub Vec<MaintenanceWindow>);
+
+impl From<Vec<MaintenanceWindow>> for MaintenanceWindows {
+    fn from(windows: Vec<MaintenanceWindow>) -> Self {
+        Self(windows)
+    }
+}
+
+impl MaintenanceWindows {
+    /// Returns true if there are no windows, so that any time is allowed.
+    pub fn is_unrestricted(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The (open, close) times of all windows that could contain, or open after, `time`, in no
+    /// particular order.
+    fn windows_around<Tz: TimeZone>(
+        &self,
+        time_zone: &Tz,
+        time: DateTime<Utc>,
+    ) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let today = time.with_timezone(time_zone).date_naive();
+        // A window can have opened the day before and still be open, and every window opens at
+        // least once a week.
+        (-1..=7)
+            .filter_map(|days| today.checked_add_signed(ChronoDuration::days(days)))
+            .flat_map(|date| {
+                self.0
+                    .iter()
+                    .filter_map(move |window| window.on_date(time_zone, date))
+            })
+            .collect()
+    }
+
+    /// Returns true if `time` falls within one of the windows.
+    pub fn contains<Tz: TimeZone>(&self, time_zone: &Tz, time: SystemTime) -> bool {
+        if self.is_unrestricted() {
+            return true;
+        }
+        let time = DateTime::<Utc>::from(time);
+        self.windows_around(time_zone, time)
+            .into_iter()
+            .any(|(open, close)| open <= time && time < close)
+    }
+
+    /// The first time at or after `time` that falls within one of the windows, which is `time`
+    /// itself if a window is open then.  Returns `None` if none of the windows ever open.
+    pub fn next_opening<Tz: TimeZone>(
+        &self,
+        time_zone: &Tz,
+        time: SystemTime,
+    ) -> Option<SystemTime> {
+        if self.contains(time_zone, time) {
+            return Some(time);
+        }
+        let time = DateTime::<Utc>::from(time);
+        self.windows_around(time_zone, time)
+            .into_iter()
+            .map(|(open, _)| open)
+            .filter(|open| *open > time)
+            .min()
+            .map(SystemTime::from)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::*;
+    use chrono::FixedOffset;
+
+    /// US Pacific time in 2024: the clocks go forward at 02:00 on March 10th, and back at 02:00
+    /// on November 3rd.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Pacific2024;
+
+    impl Pacific2024 {
+        fn standard() -> FixedOffset {
+            FixedOffset::west_opt(8 * 3600).unwrap()
+        }
+
+        fn daylight() -> FixedOffset {
+            FixedOffset::west_opt(7 * 3600).unwrap()
+        }
+    }
+
+    impl TimeZone for Pacific2024 {
+        type Offset = FixedOffset;
+
+        fn from_offset(_offset: &FixedOffset) -> Self {
+            Pacific2024
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            // An offset is valid for a local time if converting with it is consistent.  The
+            // daylight offset comes first, as it's the earlier of two ambiguous times.
+            let valid: Vec<_> = [Self::daylight(), Self::standard()]
+                .into_iter()
+                .filter(|offset| self.offset_from_utc_datetime(&(*local - *offset)) == *offset)
+                .collect();
+            match valid[..] {
+                [] => LocalResult::None,
+                [offset] => LocalResult::Single(offset),
+                [earliest, latest] => LocalResult::Ambiguous(earliest, latest),
+                _ => unreachable!(),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let daylight_start = NaiveDate::from_ymd_opt(2024, 3, 10)
+                .unwrap()
+                .and_hms_opt(10, 0, 0)
+                .unwrap();
+            let daylight_end = NaiveDate::from_ymd_opt(2024, 11, 3)
+                .unwrap()
+                .and_hms_opt(9, 0, 0)
+                .unwrap();
+            if daylight_start <= *utc && *utc < daylight_end {
+                Self::daylight()
+            } else {
+                Self::standard()
+            }
+        }
+    }
+
+    /// The SystemTime of the given local time in `Pacific2024`, which must be unambiguous.
+    pub fn pacific(month: u32, day: u32, hour: u32, min: u32) -> SystemTime {
+        Pacific2024
+            .with_ymd_and_hms(2024, month, day, hour, min, 0)
+            .single()
+            .unwrap()
+            .into()
+    }
+
+    pub fn time(hour: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, min, 0).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::*;
+    use super::*;
+    use chrono::FixedOffset;
+    use std::time::Duration;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+
+    #[test]
+    fn test_unrestricted() {
+        let windows = MaintenanceWindows::default();
+        let now = SystemTime::now();
+        assert!(windows.is_unrestricted());
+        assert!(windows.contains(&Utc, now));
+        assert_eq!(windows.next_opening(&Utc, now), Some(now));
+    }
+
+    #[test]
+    fn test_daily_window() {
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(2, 0), time(4, 0))]);
+        // Monday, June 3rd.
+        assert!(!windows.contains(&Pacific2024, pacific(6, 3, 1, 59)));
+        assert!(windows.contains(&Pacific2024, pacific(6, 3, 2, 0)));
+        assert!(windows.contains(&Pacific2024, pacific(6, 3, 3, 59)));
+        assert!(!windows.contains(&Pacific2024, pacific(6, 3, 4, 0)));
+
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 3, 1, 0)),
+            Some(pacific(6, 3, 2, 0))
+        );
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 3, 3, 0)),
+            Some(pacific(6, 3, 3, 0))
+        );
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 3, 5, 0)),
+            Some(pacific(6, 4, 2, 0))
+        );
+    }
+
+    #[test]
+    fn test_window_across_midnight() {
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(23, 0), time(1, 0))]);
+        assert!(windows.contains(&Pacific2024, pacific(6, 3, 23, 30)));
+        assert!(windows.contains(&Pacific2024, pacific(6, 4, 0, 30)));
+        assert!(!windows.contains(&Pacific2024, pacific(6, 4, 1, 0)));
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 4, 1, 0)),
+            Some(pacific(6, 4, 23, 0))
+        );
+    }
+
+    #[test]
+    fn test_days_of_week() {
+        let windows = MaintenanceWindows::from(vec![
+            MaintenanceWindow {
+                days: vec![Weekday::Sat, Weekday::Sun],
+                start: time(10, 0),
+                end: time(12, 0),
+            },
+            MaintenanceWindow {
+                days: vec![Weekday::Wed],
+                start: time(3, 0),
+                end: time(4, 0),
+            },
+        ]);
+        // Monday, June 3rd.
+        assert!(!windows.contains(&Pacific2024, pacific(6, 3, 11, 0)));
+        assert!(windows.contains(&Pacific2024, pacific(6, 8, 11, 0)));
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 3, 11, 0)),
+            Some(pacific(6, 5, 3, 0))
+        );
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 5, 4, 0)),
+            Some(pacific(6, 8, 10, 0))
+        );
+        // Sunday to the next Wednesday.
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(6, 9, 12, 0)),
+            Some(pacific(6, 12, 3, 0))
+        );
+    }
+
+    #[test]
+    fn test_fixed_offset() {
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(2, 0), time(4, 0))]);
+        let tokyo = FixedOffset::east_opt(9 * 3600).unwrap();
+        let open: SystemTime = tokyo.with_ymd_and_hms(2024, 6, 3, 2, 0, 0).unwrap().into();
+        assert!(windows.contains(&tokyo, open));
+        assert!(!windows.contains(&Utc, open));
+        assert_eq!(
+            windows.next_opening(&tokyo, open - HOUR),
+            Some(open),
+            "opening in Tokyo"
+        );
+    }
+
+    #[test]
+    fn test_clocks_go_forward() {
+        // On March 10th, 02:00 to 03:00 doesn't exist.
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(2, 30), time(4, 0))]);
+        // The window opens at 03:30, which is the time 02:30 would have been.
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(3, 10, 1, 0)),
+            Some(pacific(3, 10, 3, 30))
+        );
+        assert!(!windows.contains(&Pacific2024, pacific(3, 10, 3, 29)));
+        assert!(windows.contains(&Pacific2024, pacific(3, 10, 3, 30)));
+        // And it's back to normal the next day.
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(3, 10, 5, 0)),
+            Some(pacific(3, 11, 2, 30))
+        );
+
+        // A window that's entirely within the skipped hour moves along with both ends.
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(2, 0), time(2, 30))]);
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(3, 10, 1, 0)),
+            Some(pacific(3, 10, 3, 0))
+        );
+
+        // A window that would close before it opens doesn't open that day.
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(2, 30), time(3, 15))]);
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(3, 10, 1, 0)),
+            Some(pacific(3, 11, 2, 30))
+        );
+    }
+
+    #[test]
+    fn test_clocks_go_back() {
+        // On November 3rd, 01:00 to 02:00 happens twice, so the window is open for an hour longer.
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(0, 30), time(3, 0))]);
+        let open = windows
+            .next_opening(&Pacific2024, pacific(11, 3, 0, 0))
+            .unwrap();
+        assert_eq!(open, pacific(11, 3, 0, 30));
+        assert!(windows.contains(&Pacific2024, open + 3 * HOUR));
+        assert!(!windows.contains(&Pacific2024, open + 3 * HOUR + HOUR / 2));
+
+        // A window that opens during the repeated hour opens the first time around.
+        let windows =
+            MaintenanceWindows::from(vec![MaintenanceWindow::daily(time(1, 30), time(3, 0))]);
+        assert_eq!(
+            windows.next_opening(&Pacific2024, pacific(11, 3, 0, 0)),
+            Some(pacific(11, 3, 0, 30) + HOUR)
+        );
+    }
+}
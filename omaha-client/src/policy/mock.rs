@@ -10,7 +10,8 @@ use crate::{
     common::{App, CheckOptions, ProtocolState, UpdateCheckSchedule},
     installer::stub::StubPlan,
     policy::{CheckDecision, CheckTiming, PolicyEngine, UpdateDecision},
-    time::MockTimeSource,
+    protocol::response::Urgency,
+    time::{MockTimeSource, PartialComplexTime},
 };
 use futures::future::BoxFuture;
 use futures::prelude::*;
@@ -26,6 +27,9 @@ pub struct MockPolicyEngine {
     pub reboot_allowed: Rc<RefCell<bool>>,
     pub reboot_needed: Rc<RefCell<bool>>,
     pub reboot_check_options_received: Rc<RefCell<Vec<CheckOptions>>>,
+    pub next_reboot_allowed_time: Option<PartialComplexTime>,
+    pub update_urgency_received: Rc<RefCell<Vec<Urgency>>>,
+    pub reboot_urgency_received: Rc<RefCell<Vec<Urgency>>>,
 }
 
 impl Default for MockPolicyEngine {
@@ -38,6 +42,9 @@ impl Default for MockPolicyEngine {
             reboot_allowed: Rc::new(RefCell::new(true)),
             reboot_needed: Rc::new(RefCell::new(true)),
             reboot_check_options_received: Rc::new(RefCell::new(vec![])),
+            next_reboot_allowed_time: None,
+            update_urgency_received: Rc::new(RefCell::new(vec![])),
+            reboot_urgency_received: Rc::new(RefCell::new(vec![])),
         }
     }
 }
@@ -73,7 +80,9 @@ impl PolicyEngine for MockPolicyEngine {
     fn update_can_start<'p>(
         &mut self,
         _proposed_install_plan: &'p Self::InstallPlan,
+        urgency: Urgency,
     ) -> BoxFuture<'p, UpdateDecision> {
+        self.update_urgency_received.borrow_mut().push(urgency);
         future::ready(self.update_decision.clone()).boxed()
     }
 
@@ -81,11 +90,21 @@ impl PolicyEngine for MockPolicyEngine {
         &mut self,
         check_options: &CheckOptions,
         _install_result: &Self::InstallResult,
+        urgency: Urgency,
     ) -> BoxFuture<'_, bool> {
         (*self.reboot_check_options_received.borrow_mut()).push(check_options.clone());
+        self.reboot_urgency_received.borrow_mut().push(urgency);
         future::ready(*self.reboot_allowed.borrow()).boxed()
     }
 
+    fn next_reboot_allowed_time(
+        &mut self,
+        _check_options: &CheckOptions,
+        _install_result: &Self::InstallResult,
+    ) -> BoxFuture<'_, Option<PartialComplexTime>> {
+        future::ready(self.next_reboot_allowed_time).boxed()
+    }
+
     fn reboot_needed(&mut self, _install_plan: &Self::InstallPlan) -> BoxFuture<'_, bool> {
         future::ready(*self.reboot_needed.borrow()).boxed()
     }
@@ -0,0 +1,889 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use crate::{
+    common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
+    installer::Plan,
+    policy::{
+        CheckDecision, MaintenanceWindows, Policy, PolicyEngine, PolicyReason, UpdateDecision,
+    },
+    protocol::{request::InstallSource, response::Urgency},
+    request_builder::RequestParams,
+    time::{ComplexTime, PartialComplexTime, TimeSource},
+};
+use chrono::{Local, TimeZone};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+use typed_builder::TypedBuilder;
+
+/// The configuration of the [`StandardPolicy`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StandardPolicyConfig {
+    /// The interval between successful update checks.
+    pub periodic_interval: Duration,
+
+    /// How far, as a percentage of the interval, the next check is randomly moved earlier or
+    /// later, so that a fleet of devices doesn't check in lockstep.  Values over 100 are treated
+    /// as 100.
+    pub fuzz_percentage_range: u32,
+
+    /// The delay before retrying after the first failed update check.  It's doubled for every
+    /// further consecutive failure.
+    pub retry_delay: Duration,
+
+    /// The upper bound on the backed-off retry delay.
+    pub max_retry_delay: Duration,
+
+    /// The minimum time to wait after startup before the first update check.
+    pub startup_delay: Duration,
+
+    /// The local times at which updates may be installed.  Updates found outside of these windows
+    /// are deferred.
+    pub update_windows: MaintenanceWindows,
+
+    /// The local times at which the device may reboot after an update, unless the update was
+    /// started on demand.
+    pub reboot_windows: MaintenanceWindows,
+
+    /// After this many consecutive update checks failed while going through a proxy, the next
+    /// check connects directly, in case the proxy configuration is broken.  Zero means that the
+    /// configured proxies are always used.
+    pub direct_fallback_after: u32,
+}
+
+impl Default for StandardPolicyConfig {
+    fn default() -> Self {
+        Self {
+            periodic_interval: Duration::from_secs(5 * 60 * 60),
+            fuzz_percentage_range: 25,
+            retry_delay: Duration::from_secs(5 * 60),
+            max_retry_delay: Duration::from_secs(5 * 60 * 60),
+            startup_delay: Duration::from_secs(60),
+            update_windows: MaintenanceWindows::default(),
+            reboot_windows: MaintenanceWindows::default(),
+            direct_fallback_after: 1,
+        }
+    }
+}
+
+impl StandardPolicyConfig {
+    /// The delay before the next check after `consecutive_failures` failed checks in a row.
+    fn backoff(&self, consecutive_failures: u32) -> Duration {
+        2u32.checked_pow(consecutive_failures.saturating_sub(1))
+            .and_then(|factor| self.retry_delay.checked_mul(factor))
+            .map_or(self.max_retry_delay, |delay| {
+                delay.min(self.max_retry_delay)
+            })
+    }
+
+    /// Whether the last checks failed through the proxy often enough to try a direct connection.
+    /// Both counters are of consecutive checks, so if both reach the threshold, then all of the
+    /// last `direct_fallback_after` checks were proxied and failed.
+    fn falls_back_to_direct(&self, protocol_state: &ProtocolState) -> bool {
+        self.direct_fallback_after > 0
+            && protocol_state.consecutive_failed_update_checks >= self.direct_fallback_after
+            && protocol_state.consecutive_proxied_requests >= self.direct_fallback_after
+    }
+
+    /// Move `interval` by `fuzz` (in the range -1.0 to 1.0) of the configured fuzz range.
+    fn fuzz(&self, interval: Duration, fuzz: f64) -> Duration {
+        let range = f64::from(self.fuzz_percentage_range.min(100)) / 100.0;
+        interval.mul_f64(1.0 + fuzz.clamp(-1.0, 1.0) * range)
+    }
+}
+
+/// The data that the [`StandardPolicyEngine`] gathers for the [`StandardPolicy`].
+#[derive(Clone, Debug, PartialEq, TypedBuilder)]
+pub struct StandardPolicyData {
+    /// The current time.
+    #[builder(setter(into))]
+    pub current_time: ComplexTime,
+
+    /// Where in the configured fuzz range the next interval lands, from -1.0 (earliest) to 1.0
+    /// (latest).
+    #[builder(default)]
+    pub interval_fuzz: f64,
+}
+
+/// The data that the [`StandardPolicyEngine`] gathers for the [`StandardPolicy`] to decide whether
+/// something is allowed within a set of maintenance windows.
+#[derive(Clone, Debug)]
+pub struct MaintenanceWindowPolicyData<Z> {
+    /// The current wall time.
+    pub current_time: SystemTime,
+
+    /// The windows to check the current time against.
+    pub windows: MaintenanceWindows,
+
+    /// The time zone that the windows are in.
+    pub time_zone: Z,
+}
+
+impl<Z: TimeZone> MaintenanceWindowPolicyData<Z> {
+    fn is_open(&self) -> bool {
+        self.windows.contains(&self.time_zone, self.current_time)
+    }
+
+    /// Why something had to wait for the windows to open.
+    fn closed_reason(&self, rule_id: u32, message: &str) -> PolicyReason {
+        let reason = PolicyReason::builder().rule_id(rule_id).message(message);
+        match self
+            .windows
+            .next_opening(&self.time_zone, self.current_time)
+            .and_then(|opening| opening.duration_since(self.current_time).ok())
+        {
+            Some(retry_after) => reason.retry_after(retry_after).build(),
+            None => reason.build(),
+        }
+    }
+}
+
+/// A policy for production use, that checks periodically, backs off exponentially on failures,
+/// and honors the interval that the server dictates.  Updates and reboots are restricted to
+/// maintenance windows in the local time zone `Z`.
+///
+/// On-demand update checks are never throttled, and reboot immediately.  Urgent updates ignore the
+/// maintenance windows.
+pub struct StandardPolicy<P: Plan, Z: TimeZone = Local> {
+    _phantom_data: std::marker::PhantomData<(P, Z)>,
+}
+
+impl<P: Plan, Z: TimeZone> StandardPolicy<P, Z> {
+    /// The [`PolicyReason::rule_id`] of updates that are deferred until the update windows open.
+    pub const OUTSIDE_UPDATE_WINDOWS: u32 = 1;
+    /// The [`PolicyReason::rule_id`] of scheduled update checks that are attempted before the
+    /// scheduled time.
+    pub const TOO_SOON: u32 = 6;
+}
+
+impl<P: Plan, Z: TimeZone> Policy for StandardPolicy<P, Z> {
+    type ComputeNextUpdateTimePolicyData = (StandardPolicyConfig, StandardPolicyData);
+    type UpdateCheckAllowedPolicyData = (StandardPolicyConfig, StandardPolicyData);
+    type UpdateCanStartPolicyData = MaintenanceWindowPolicyData<Z>;
+    type RebootPolicyData = MaintenanceWindowPolicyData<Z>;
+    type InstallPlan = P;
+
+    fn compute_next_update_time(
+        (config, policy_data): &Self::ComputeNextUpdateTimePolicyData,
+        _apps: &[App],
+        scheduling: &UpdateCheckSchedule,
+        protocol_state: &ProtocolState,
+    ) -> CheckTiming {
+        let failures = protocol_state.consecutive_failed_update_checks;
+        let (last_time, interval) = if failures > 0 {
+            (
+                scheduling
+                    .last_update_check_time
+                    .or(scheduling.last_update_time),
+                config.backoff(failures),
+            )
+        } else {
+            (scheduling.last_update_time, config.periodic_interval)
+        };
+        let interval = config.fuzz(interval, policy_data.interval_fuzz);
+        // The server may ask for a longer interval, but not a shorter one.
+        let interval = match protocol_state.server_dictated_poll_interval {
+            Some(server_interval) => interval.max(server_interval),
+            None => interval,
+        };
+
+        match last_time {
+            // Nothing has been checked yet, wait out the startup delay.
+            None => CheckTiming::builder()
+                .time(policy_data.current_time + config.startup_delay)
+                .minimum_wait(config.startup_delay)
+                .build(),
+            // The last check was before this boot, so the next one may already be due, but it
+            // still needs to wait out the startup delay.
+            Some(last_time @ PartialComplexTime::Wall(_)) => CheckTiming::builder()
+                .time(last_time + interval)
+                .minimum_wait(config.startup_delay)
+                .build(),
+            Some(last_time) => CheckTiming::builder().time(last_time + interval).build(),
+        }
+    }
+
+    fn update_check_allowed(
+        (config, policy_data): &Self::UpdateCheckAllowedPolicyData,
+        _apps: &[App],
+        scheduling: &UpdateCheckSchedule,
+        protocol_state: &ProtocolState,
+        check_options: &CheckOptions,
+    ) -> CheckDecision {
+        let params = RequestParams {
+            source: check_options.source,
+            use_configured_proxies: !config.falls_back_to_direct(protocol_state),
+            ..RequestParams::default()
+        };
+        match (check_options.source, scheduling.next_update_time) {
+            (InstallSource::OnDemand, _) | (InstallSource::ScheduledTask, None) => {
+                CheckDecision::Ok(params)
+            }
+            (InstallSource::ScheduledTask, Some(next_update_time)) => {
+                if policy_data
+                    .current_time
+                    .is_after_or_eq_any(next_update_time.time)
+                {
+                    CheckDecision::Ok(params)
+                } else {
+                    let reason = PolicyReason::builder()
+                        .rule_id(StandardPolicy::<P, Z>::TOO_SOON)
+                        .message("the next update check isn't scheduled yet");
+                    CheckDecision::TooSoon(
+                        match duration_until(policy_data.current_time, next_update_time.time) {
+                            Some(retry_after) => reason.retry_after(retry_after).build(),
+                            None => reason.build(),
+                        },
+                    )
+                }
+            }
+        }
+    }
+
+    fn update_can_start(
+        policy_data: &Self::UpdateCanStartPolicyData,
+        _proposed_install_plan: &Self::InstallPlan,
+        urgency: Urgency,
+    ) -> UpdateDecision {
+        if urgency == Urgency::Urgent || policy_data.is_open() {
+            UpdateDecision::Ok
+        } else {
+            UpdateDecision::DeferredByPolicy(policy_data.closed_reason(
+                StandardPolicy::<P, Z>::OUTSIDE_UPDATE_WINDOWS,
+                "outside of the update maintenance windows",
+            ))
+        }
+    }
+
+    fn reboot_allowed(
+        policy_data: &Self::RebootPolicyData,
+        check_options: &CheckOptions,
+        urgency: Urgency,
+    ) -> bool {
+        check_options.source == InstallSource::OnDemand
+            || urgency == Urgency::Urgent
+            || policy_data.is_open()
+    }
+
+    fn reboot_needed(_install_plan: &Self::InstallPlan) -> bool {
+        true
+    }
+}
+
+/// How long it is from `now` until `time`, on whichever of its timelines is reached first.
+/// Returns None if `time` has already been reached.
+fn duration_until(now: ComplexTime, time: PartialComplexTime) -> Option<Duration> {
+    let (wall, mono) = time.destructure();
+    let wall = wall.and_then(|wall| wall.duration_since(now.wall).ok());
+    let mono = mono.and_then(|mono| mono.checked_duration_since(now.mono));
+    wall.into_iter().chain(mono).min()
+}
+
+/// A PolicyEngine that gathers the current time and a random fuzz for the [`StandardPolicy`].
+#[derive(Debug)]
+pub struct StandardPolicyEngine<P: Plan, T: TimeSource, Z: TimeZone = Local> {
+    config: StandardPolicyConfig,
+    time_source: T,
+    time_zone: Z,
+    _phantom_data: std::marker::PhantomData<P>,
+}
+
+impl<P, T> StandardPolicyEngine<P, T>
+where
+    T: TimeSource,
+    P: Plan,
+{
+    /// Create a StandardPolicyEngine whose maintenance windows are in the system's time zone.
+    pub fn new(config: StandardPolicyConfig, time_source: T) -> Self {
+        Self::with_time_zone(config, time_source, Local)
+    }
+}
+
+impl<P, T, Z> StandardPolicyEngine<P, T, Z>
+where
+    T: TimeSource,
+    P: Plan,
+    Z: TimeZone,
+{
+    pub fn with_time_zone(config: StandardPolicyConfig, time_source: T, time_zone: Z) -> Self {
+        Self {
+            config,
+            time_source,
+            time_zone,
+            _phantom_data: std::marker::PhantomData,
+        }
+    }
+
+    pub fn config(&self) -> &StandardPolicyConfig {
+        &self.config
+    }
+
+    fn window_policy_data(&self, windows: &MaintenanceWindows) -> MaintenanceWindowPolicyData<Z> {
+        MaintenanceWindowPolicyData {
+            current_time: self.time_source.now_in_walltime(),
+            windows: windows.clone(),
+            time_zone: self.time_zone.clone(),
+        }
+    }
+}
+
+impl<P, T, Z> PolicyEngine for StandardPolicyEngine<P, T, Z>
+where
+    T: TimeSource + Clone,
+    P: Plan,
+    Z: TimeZone,
+{
+    type TimeSource = T;
+    type InstallResult = ();
+    type InstallPlan = P;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        &self.time_source
+    }
+
+    fn compute_next_update_time(
+        &mut self,
+        apps: &[App],
+        scheduling: &UpdateCheckSchedule,
+        protocol_state: &ProtocolState,
+    ) -> BoxFuture<'_, CheckTiming> {
+        let policy_data = StandardPolicyData::builder()
+            .current_time(self.time_source.now())
+            .interval_fuzz(rand::thread_rng().gen_range(-1.0..=1.0))
+            .build();
+        let check_timing = StandardPolicy::<P, Z>::compute_next_update_time(
+            &(self.config.clone(), policy_data),
+            apps,
+            scheduling,
+            protocol_state,
+        );
+        future::ready(check_timing).boxed()
+    }
+
+    fn update_check_allowed(
+        &mut self,
+        apps: &[App],
+        scheduling: &UpdateCheckSchedule,
+        protocol_state: &ProtocolState,
+        check_options: &CheckOptions,
+    ) -> BoxFuture<'_, CheckDecision> {
+        let decision = StandardPolicy::<P, Z>::update_check_allowed(
+            &(
+                self.config.clone(),
+                StandardPolicyData::builder()
+                    .current_time(self.time_source.now())
+                    .build(),
+            ),
+            apps,
+            scheduling,
+            protocol_state,
+            check_options,
+        );
+        future::ready(decision).boxed()
+    }
+
+    fn update_can_start<'p>(
+        &mut self,
+        proposed_install_plan: &'p Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'p, UpdateDecision> {
+        let decision = StandardPolicy::<P, Z>::update_can_start(
+            &self.window_policy_data(&self.config.update_windows),
+            proposed_install_plan,
+            urgency,
+        );
+        future::ready(decision).boxed()
+    }
+
+    fn reboot_allowed(
+        &mut self,
+        check_options: &CheckOptions,
+        _install_result: &Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'_, bool> {
+        let decision = StandardPolicy::<P, Z>::reboot_allowed(
+            &self.window_policy_data(&self.config.reboot_windows),
+            check_options,
+            urgency,
+        );
+        future::ready(decision).boxed()
+    }
+
+    fn next_reboot_allowed_time(
+        &mut self,
+        _check_options: &CheckOptions,
+        _install_result: &Self::InstallResult,
+    ) -> BoxFuture<'_, Option<PartialComplexTime>> {
+        let next_opening = self
+            .config
+            .reboot_windows
+            .next_opening(&self.time_zone, self.time_source.now_in_walltime())
+            .map(PartialComplexTime::Wall);
+        future::ready(next_opening).boxed()
+    }
+
+    fn reboot_needed(&mut self, install_plan: &Self::InstallPlan) -> BoxFuture<'_, bool> {
+        let decision = StandardPolicy::<P, Z>::reboot_needed(install_plan);
+        future::ready(decision).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        installer::stub::StubPlan,
+        policy::{
+            maintenance_window::test_support::{pacific, time, Pacific2024},
+            MaintenanceWindow,
+        },
+        time::MockTimeSource,
+    };
+    use futures::executor::block_on;
+
+    const HOUR: Duration = Duration::from_secs(60 * 60);
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn config() -> StandardPolicyConfig {
+        StandardPolicyConfig {
+            periodic_interval: 4 * HOUR,
+            fuzz_percentage_range: 25,
+            retry_delay: 5 * MINUTE,
+            max_retry_delay: HOUR,
+            startup_delay: MINUTE,
+            ..StandardPolicyConfig::default()
+        }
+    }
+
+    fn make_engine_in_pacific(
+        local_time: SystemTime,
+    ) -> (
+        StandardPolicyEngine<StubPlan, MockTimeSource, Pacific2024>,
+        MockTimeSource,
+    ) {
+        let mock_time = MockTimeSource::new((local_time, std::time::Instant::now()));
+        let config = StandardPolicyConfig {
+            update_windows: vec![MaintenanceWindow::daily(time(2, 0), time(4, 0))].into(),
+            reboot_windows: vec![MaintenanceWindow::daily(time(3, 0), time(4, 0))].into(),
+            ..config()
+        };
+        let engine = StandardPolicyEngine::with_time_zone(config, mock_time.clone(), Pacific2024);
+        (engine, mock_time)
+    }
+
+    fn compute_next_update_time(
+        policy_data: StandardPolicyData,
+        scheduling: &UpdateCheckSchedule,
+        protocol_state: &ProtocolState,
+    ) -> CheckTiming {
+        StandardPolicy::<StubPlan>::compute_next_update_time(
+            &(config(), policy_data),
+            &[],
+            scheduling,
+            protocol_state,
+        )
+    }
+
+    fn update_check_allowed(
+        now: ComplexTime,
+        scheduling: &UpdateCheckSchedule,
+        source: InstallSource,
+    ) -> CheckDecision {
+        StandardPolicy::<StubPlan>::update_check_allowed(
+            &(
+                StandardPolicyConfig::default(),
+                StandardPolicyData::builder().current_time(now).build(),
+            ),
+            &[],
+            scheduling,
+            &ProtocolState::default(),
+            &CheckOptions { source },
+        )
+    }
+
+    #[test]
+    fn test_first_check_waits_for_startup_delay() {
+        let now = MockTimeSource::new_from_now().now();
+        let timing = compute_next_update_time(
+            StandardPolicyData::builder().current_time(now).build(),
+            &UpdateCheckSchedule::default(),
+            &ProtocolState::default(),
+        );
+        assert_eq!(
+            timing,
+            CheckTiming::builder()
+                .time(now + MINUTE)
+                .minimum_wait(MINUTE)
+                .build()
+        );
+    }
+
+    #[test]
+    fn test_check_from_previous_boot_waits_for_startup_delay() {
+        let now = MockTimeSource::new_from_now().now();
+        let last_update_time = now.wall - 5 * HOUR;
+        let timing = compute_next_update_time(
+            StandardPolicyData::builder().current_time(now).build(),
+            &UpdateCheckSchedule::builder()
+                .last_update_time(PartialComplexTime::Wall(last_update_time))
+                .build(),
+            &ProtocolState::default(),
+        );
+        assert_eq!(
+            timing,
+            CheckTiming::builder()
+                .time(PartialComplexTime::Wall(last_update_time + 4 * HOUR))
+                .minimum_wait(MINUTE)
+                .build()
+        );
+    }
+
+    #[test]
+    fn test_periodic_interval_with_fuzz() {
+        let now = MockTimeSource::new_from_now().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now)
+            .last_update_check_time(now)
+            .build();
+        for (fuzz, expected) in [(0.0, 4 * HOUR), (-1.0, 3 * HOUR), (1.0, 5 * HOUR)] {
+            let timing = compute_next_update_time(
+                StandardPolicyData::builder()
+                    .current_time(now)
+                    .interval_fuzz(fuzz)
+                    .build(),
+                &scheduling,
+                &ProtocolState::default(),
+            );
+            assert_eq!(timing, CheckTiming::builder().time(now + expected).build());
+        }
+    }
+
+    #[test]
+    fn test_backoff_on_consecutive_failures() {
+        let now = MockTimeSource::new_from_now().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now - 10 * HOUR)
+            .last_update_check_time(now)
+            .build();
+        for (failures, expected) in [
+            (1, 5 * MINUTE),
+            (2, 10 * MINUTE),
+            (3, 20 * MINUTE),
+            (4, 40 * MINUTE),
+            (5, HOUR),
+            (100, HOUR),
+        ] {
+            let timing = compute_next_update_time(
+                StandardPolicyData::builder().current_time(now).build(),
+                &scheduling,
+                &ProtocolState {
+                    consecutive_failed_update_checks: failures,
+                    ..ProtocolState::default()
+                },
+            );
+            assert_eq!(
+                timing,
+                CheckTiming::builder().time(now + expected).build(),
+                "failures: {failures}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_server_dictated_poll_interval() {
+        let now = MockTimeSource::new_from_now().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now)
+            .last_update_check_time(now)
+            .build();
+
+        // A longer interval from the server replaces the periodic interval, and isn't fuzzed.
+        let timing = compute_next_update_time(
+            StandardPolicyData::builder()
+                .current_time(now)
+                .interval_fuzz(-1.0)
+                .build(),
+            &scheduling,
+            &ProtocolState {
+                server_dictated_poll_interval: Some(8 * HOUR),
+                ..ProtocolState::default()
+            },
+        );
+        assert_eq!(timing, CheckTiming::builder().time(now + 8 * HOUR).build());
+
+        // It also applies to retries.
+        let timing = compute_next_update_time(
+            StandardPolicyData::builder().current_time(now).build(),
+            &scheduling,
+            &ProtocolState {
+                server_dictated_poll_interval: Some(30 * MINUTE),
+                consecutive_failed_update_checks: 1,
+                ..ProtocolState::default()
+            },
+        );
+        assert_eq!(
+            timing,
+            CheckTiming::builder().time(now + 30 * MINUTE).build()
+        );
+
+        // But the client doesn't check more often than configured.
+        let timing = compute_next_update_time(
+            StandardPolicyData::builder().current_time(now).build(),
+            &scheduling,
+            &ProtocolState {
+                server_dictated_poll_interval: Some(MINUTE),
+                ..ProtocolState::default()
+            },
+        );
+        assert_eq!(timing, CheckTiming::builder().time(now + 4 * HOUR).build());
+    }
+
+    #[test]
+    fn test_update_check_allowed_falls_back_to_direct() {
+        let now = MockTimeSource::new_from_now().now();
+        let use_configured_proxies = |config: StandardPolicyConfig, failed, proxied| {
+            let decision = StandardPolicy::<StubPlan>::update_check_allowed(
+                &(
+                    config,
+                    StandardPolicyData::builder().current_time(now).build(),
+                ),
+                &[],
+                &UpdateCheckSchedule::default(),
+                &ProtocolState {
+                    consecutive_failed_update_checks: failed,
+                    consecutive_proxied_requests: proxied,
+                    ..ProtocolState::default()
+                },
+                &CheckOptions::default(),
+            );
+            match decision {
+                CheckDecision::Ok(params) => params.use_configured_proxies,
+                decision => panic!("unexpected decision {decision:?}"),
+            }
+        };
+
+        assert!(use_configured_proxies(config(), 0, 0));
+        assert!(use_configured_proxies(config(), 0, 5));
+        // The last check failed through the proxy.
+        assert!(!use_configured_proxies(config(), 1, 1));
+        // The last check failed without a proxy, so the proxy is tried again.
+        assert!(use_configured_proxies(config(), 3, 0));
+
+        let config = StandardPolicyConfig {
+            direct_fallback_after: 3,
+            ..config()
+        };
+        assert!(use_configured_proxies(config.clone(), 2, 2));
+        assert!(use_configured_proxies(config.clone(), 3, 2));
+        assert!(!use_configured_proxies(config.clone(), 3, 3));
+
+        let config = StandardPolicyConfig {
+            direct_fallback_after: 0,
+            ..config
+        };
+        assert!(use_configured_proxies(config, 10, 10));
+    }
+
+    #[test]
+    fn test_duration_until() {
+        let now = MockTimeSource::new_from_now().now();
+        assert_eq!(
+            duration_until(now, PartialComplexTime::Wall(now.wall + HOUR)),
+            Some(HOUR)
+        );
+        assert_eq!(
+            duration_until(now, PartialComplexTime::Monotonic(now.mono + MINUTE)),
+            Some(MINUTE)
+        );
+        // The earlier of the two timelines is used.
+        assert_eq!(
+            duration_until(
+                now,
+                PartialComplexTime::Complex(ComplexTime {
+                    wall: now.wall + MINUTE,
+                    mono: now.mono + HOUR,
+                })
+            ),
+            Some(MINUTE)
+        );
+        assert_eq!(
+            duration_until(now, PartialComplexTime::Wall(now.wall - HOUR)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_update_check_allowed() {
+        let now = MockTimeSource::new_from_now().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .next_update_time(CheckTiming::builder().time(now + HOUR).build())
+            .build();
+
+        assert_eq!(
+            update_check_allowed(now, &scheduling, InstallSource::ScheduledTask),
+            CheckDecision::TooSoon(
+                PolicyReason::builder()
+                    .rule_id(StandardPolicy::<StubPlan>::TOO_SOON)
+                    .message("the next update check isn't scheduled yet")
+                    .retry_after(HOUR)
+                    .build()
+            )
+        );
+        assert_eq!(
+            update_check_allowed(now + HOUR, &scheduling, InstallSource::ScheduledTask),
+            CheckDecision::Ok(RequestParams {
+                source: InstallSource::ScheduledTask,
+                use_configured_proxies: true,
+                ..RequestParams::default()
+            })
+        );
+        assert_eq!(
+            update_check_allowed(
+                now,
+                &UpdateCheckSchedule::default(),
+                InstallSource::ScheduledTask
+            ),
+            CheckDecision::Ok(RequestParams {
+                source: InstallSource::ScheduledTask,
+                use_configured_proxies: true,
+                ..RequestParams::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_on_demand_check_is_not_throttled() {
+        let now = MockTimeSource::new_from_now().now();
+        let scheduling = UpdateCheckSchedule::builder()
+            .next_update_time(CheckTiming::builder().time(now + HOUR).build())
+            .build();
+        assert_eq!(
+            update_check_allowed(now, &scheduling, InstallSource::OnDemand),
+            CheckDecision::Ok(RequestParams {
+                source: InstallSource::OnDemand,
+                use_configured_proxies: true,
+                ..RequestParams::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_engine_fuzz_stays_in_range() {
+        let mock_time = MockTimeSource::new_from_now();
+        let now = mock_time.now();
+        let mut engine = StandardPolicyEngine::<StubPlan, _>::new(config(), mock_time);
+        let scheduling = UpdateCheckSchedule::builder()
+            .last_update_time(now)
+            .last_update_check_time(now)
+            .build();
+        for _ in 0..100 {
+            let timing = block_on(engine.compute_next_update_time(
+                &[],
+                &scheduling,
+                &ProtocolState::default(),
+            ));
+            let next = timing.time.checked_to_instant().unwrap();
+            assert!(next >= now.mono + 3 * HOUR, "{timing}");
+            assert!(next <= now.mono + 5 * HOUR, "{timing}");
+            assert_eq!(timing.minimum_wait, None);
+        }
+    }
+
+    #[test]
+    fn test_update_can_start_in_window() {
+        let (mut engine, mut mock_time) = make_engine_in_pacific(pacific(6, 3, 1, 0));
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::DeferredByPolicy(
+                PolicyReason::builder()
+                    .rule_id(StandardPolicy::<StubPlan>::OUTSIDE_UPDATE_WINDOWS)
+                    .message("outside of the update maintenance windows")
+                    .retry_after(HOUR)
+                    .build()
+            )
+        );
+        mock_time.advance(HOUR);
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::Ok
+        );
+    }
+
+    #[test]
+    fn test_reboot_allowed_in_window() {
+        let (mut engine, mut mock_time) = make_engine_in_pacific(pacific(6, 3, 1, 0));
+        let scheduled = CheckOptions {
+            source: InstallSource::ScheduledTask,
+        };
+        let on_demand = CheckOptions {
+            source: InstallSource::OnDemand,
+        };
+        assert!(!block_on(engine.reboot_allowed(
+            &scheduled,
+            &(),
+            Urgency::Normal
+        )));
+        assert!(block_on(engine.reboot_allowed(
+            &on_demand,
+            &(),
+            Urgency::Normal
+        )));
+        assert_eq!(
+            block_on(engine.next_reboot_allowed_time(&scheduled, &())),
+            Some(PartialComplexTime::Wall(pacific(6, 3, 3, 0)))
+        );
+
+        mock_time.advance(2 * HOUR + 30 * MINUTE);
+        assert!(block_on(engine.reboot_allowed(
+            &scheduled,
+            &(),
+            Urgency::Normal
+        )));
+        assert_eq!(
+            block_on(engine.next_reboot_allowed_time(&scheduled, &())),
+            Some(PartialComplexTime::Wall(mock_time.now_in_walltime()))
+        );
+    }
+
+    #[test]
+    fn test_no_windows_allow_everything() {
+        let mut engine = StandardPolicyEngine::<StubPlan, _>::new(
+            StandardPolicyConfig::default(),
+            MockTimeSource::new_from_now(),
+        );
+        let options = CheckOptions::default();
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Normal)),
+            UpdateDecision::Ok
+        );
+        assert!(block_on(engine.reboot_allowed(
+            &options,
+            &(),
+            Urgency::Normal
+        )));
+    }
+
+    #[test]
+    fn test_urgent_update_ignores_windows() {
+        let (mut engine, _) = make_engine_in_pacific(pacific(6, 3, 1, 0));
+        let scheduled = CheckOptions {
+            source: InstallSource::ScheduledTask,
+        };
+        assert_eq!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Urgent)),
+            UpdateDecision::Ok
+        );
+        assert!(block_on(engine.reboot_allowed(
+            &scheduled,
+            &(),
+            Urgency::Urgent
+        )));
+    }
+}
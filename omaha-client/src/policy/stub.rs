@@ -10,6 +10,7 @@ use crate::{
     common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
     installer::Plan,
     policy::{CheckDecision, Policy, PolicyData, PolicyEngine, UpdateDecision},
+    protocol::response::Urgency,
     request_builder::RequestParams,
     time::TimeSource,
 };
@@ -56,6 +57,7 @@ impl<P: Plan> Policy for StubPolicy<P> {
     fn update_can_start(
         _policy_data: &Self::UpdateCanStartPolicyData,
         _proposed_install_plan: &Self::InstallPlan,
+        _urgency: Urgency,
     ) -> UpdateDecision {
         UpdateDecision::Ok
     }
@@ -63,6 +65,7 @@ impl<P: Plan> Policy for StubPolicy<P> {
     fn reboot_allowed(
         _policy_data: &Self::RebootPolicyData,
         _check_options: &CheckOptions,
+        _urgency: Urgency,
     ) -> bool {
         true
     }
@@ -143,8 +146,9 @@ where
     fn update_can_start<'p>(
         &mut self,
         proposed_install_plan: &'p Self::InstallPlan,
+        urgency: Urgency,
     ) -> BoxFuture<'p, UpdateDecision> {
-        let decision = StubPolicy::<P>::update_can_start(&(), proposed_install_plan);
+        let decision = StubPolicy::<P>::update_can_start(&(), proposed_install_plan, urgency);
         future::ready(decision).boxed()
     }
 
@@ -152,8 +156,9 @@ where
         &mut self,
         check_options: &CheckOptions,
         _install_result: &Self::InstallResult,
+        urgency: Urgency,
     ) -> BoxFuture<'_, bool> {
-        let decision = StubPolicy::<P>::reboot_allowed(&(), check_options);
+        let decision = StubPolicy::<P>::reboot_allowed(&(), check_options, urgency);
         future::ready(decision).boxed()
     }
 
@@ -230,7 +235,7 @@ mod tests {
 
     #[test]
     fn test_update_can_start() {
-        let result = StubPolicy::update_can_start(&(), &StubPlan);
+        let result = StubPolicy::update_can_start(&(), &StubPlan, Urgency::Normal);
         assert_eq!(result, UpdateDecision::Ok);
     }
 }
@@ -0,0 +1,275 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use crate::{
+    common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
+    policy::{AdminOverrides, CheckDecision, PolicyEngine, PolicyReason, UpdateDecision},
+    protocol::response::Urgency,
+    system_conditions::SystemConditions,
+    time::PartialComplexTime,
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+
+/// Local rules about the [`SystemConditions`] that updates are deferred in.  Conditions that the
+/// platform can't tell are never a reason to defer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SystemConditionsRules {
+    /// Defer updates while the device is on battery, and its charge is below this percentage.
+    pub defer_on_battery_below: Option<u8>,
+
+    /// Defer updates while the device is on a metered network.
+    pub defer_on_metered: bool,
+}
+
+impl SystemConditionsRules {
+    /// The [`PolicyReason::rule_id`] of updates that are deferred because the battery is low.
+    pub const LOW_BATTERY: u32 = 9;
+    /// The [`PolicyReason::rule_id`] of updates that are deferred because the network is metered.
+    pub const METERED_NETWORK: u32 = 10;
+
+    /// Check whether an update may start in the current `conditions`.
+    pub fn check(&self, conditions: &impl SystemConditions) -> Result<(), PolicyReason> {
+        if let (Some(threshold), Some(power_status)) =
+            (self.defer_on_battery_below, conditions.power_status())
+        {
+            if let (false, Some(level)) = (power_status.on_ac, power_status.battery_level) {
+                if level < threshold {
+                    return Err(PolicyReason::builder()
+                        .rule_id(Self::LOW_BATTERY)
+                        .message(format!("battery level {level}% is below {threshold}%"))
+                        .build());
+                }
+            }
+        }
+        if self.defer_on_metered && conditions.is_network_metered() == Some(true) {
+            return Err(PolicyReason::builder()
+                .rule_id(Self::METERED_NETWORK)
+                .message("the network is metered")
+                .build());
+        }
+        Ok(())
+    }
+}
+
+/// A PolicyEngine that defers updates while the [`SystemConditions`] break the
+/// [`SystemConditionsRules`], before asking the wrapped engine whether they can start.  Urgent
+/// updates are never deferred.
+#[derive(Debug)]
+pub struct SystemConditionsPolicyEngine<E, S> {
+    engine: E,
+    conditions: S,
+    rules: SystemConditionsRules,
+}
+
+impl<E: PolicyEngine, S: SystemConditions> SystemConditionsPolicyEngine<E, S> {
+    pub fn new(engine: E, conditions: S, rules: SystemConditionsRules) -> Self {
+        Self {
+            engine,
+            conditions,
+            rules,
+        }
+    }
+}
+
+impl<E: PolicyEngine, S: SystemConditions> PolicyEngine for SystemConditionsPolicyEngine<E, S> {
+    type TimeSource = E::TimeSource;
+    type InstallResult = E::InstallResult;
+    type InstallPlan = E::InstallPlan;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        self.engine.time_source()
+    }
+
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        self.engine.admin_overrides()
+    }
+
+    fn compute_next_update_time<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+    ) -> BoxFuture<'a, CheckTiming> {
+        self.engine
+            .compute_next_update_time(apps, scheduling, protocol_state)
+    }
+
+    fn update_check_allowed<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+        check_options: &'a CheckOptions,
+    ) -> BoxFuture<'a, CheckDecision> {
+        self.engine
+            .update_check_allowed(apps, scheduling, protocol_state, check_options)
+    }
+
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        app: &'a App,
+        offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.engine.update_version_allowed(app, offered_version)
+    }
+
+    fn update_can_start<'a>(
+        &'a mut self,
+        proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        if urgency != Urgency::Urgent {
+            if let Err(reason) = self.rules.check(&self.conditions) {
+                return future::ready(UpdateDecision::DeferredByPolicy(reason)).boxed();
+            }
+        }
+        self.engine.update_can_start(proposed_install_plan, urgency)
+    }
+
+    fn reboot_allowed<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, bool> {
+        self.engine
+            .reboot_allowed(check_options, install_result, urgency)
+    }
+
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        self.engine
+            .next_reboot_allowed_time(check_options, install_result)
+    }
+
+    fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool> {
+        self.engine.reboot_needed(install_plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        installer::stub::StubPlan,
+        policy::StubPolicyEngine,
+        system_conditions::{MockSystemConditions, PowerStatus},
+        time::MockTimeSource,
+    };
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+
+    fn make_engine(
+        conditions: &MockSystemConditions,
+        rules: SystemConditionsRules,
+    ) -> SystemConditionsPolicyEngine<
+        StubPolicyEngine<StubPlan, MockTimeSource>,
+        MockSystemConditions,
+    > {
+        SystemConditionsPolicyEngine::new(
+            StubPolicyEngine::new(MockTimeSource::new_from_now()),
+            conditions.clone(),
+            rules,
+        )
+    }
+
+    fn rule_id(decision: UpdateDecision) -> Option<u32> {
+        match decision {
+            UpdateDecision::Ok => None,
+            UpdateDecision::DeferredByPolicy(reason) => Some(reason.rule_id),
+            decision => panic!("unexpected decision: {decision:?}"),
+        }
+    }
+
+    #[test]
+    fn test_defer_on_battery_below() {
+        let conditions = MockSystemConditions::default();
+        let mut engine = make_engine(
+            &conditions,
+            SystemConditionsRules {
+                defer_on_battery_below: Some(20),
+                ..SystemConditionsRules::default()
+            },
+        );
+        let mut update_can_start = || {
+            rule_id(block_on(
+                engine.update_can_start(&StubPlan, Urgency::Normal),
+            ))
+        };
+
+        // Unknown power status.
+        assert_eq!(update_can_start(), None);
+
+        conditions.set_power_status(PowerStatus {
+            on_ac: false,
+            battery_level: Some(15),
+        });
+        assert_eq!(update_can_start(), Some(SystemConditionsRules::LOW_BATTERY));
+
+        conditions.set_power_status(PowerStatus {
+            on_ac: true,
+            battery_level: Some(15),
+        });
+        assert_eq!(update_can_start(), None);
+
+        conditions.set_power_status(PowerStatus {
+            on_ac: false,
+            battery_level: Some(20),
+        });
+        assert_eq!(update_can_start(), None);
+    }
+
+    #[test]
+    fn test_defer_on_metered() {
+        let conditions = MockSystemConditions::default();
+        let mut engine = make_engine(
+            &conditions,
+            SystemConditionsRules {
+                defer_on_metered: true,
+                ..SystemConditionsRules::default()
+            },
+        );
+        let mut update_can_start = || {
+            rule_id(block_on(
+                engine.update_can_start(&StubPlan, Urgency::Normal),
+            ))
+        };
+
+        assert_eq!(update_can_start(), None);
+
+        conditions.set_network_metered(true);
+        assert_eq!(
+            update_can_start(),
+            Some(SystemConditionsRules::METERED_NETWORK)
+        );
+
+        conditions.set_network_metered(false);
+        assert_eq!(update_can_start(), None);
+    }
+
+    #[test]
+    fn test_urgent_updates_are_not_deferred() {
+        let conditions = MockSystemConditions::default();
+        conditions.set_network_metered(true);
+        let mut engine = make_engine(
+            &conditions,
+            SystemConditionsRules {
+                defer_on_metered: true,
+                ..SystemConditionsRules::default()
+            },
+        );
+        assert_matches!(
+            block_on(engine.update_can_start(&StubPlan, Urgency::Urgent)),
+            UpdateDecision::Ok
+        );
+    }
+}
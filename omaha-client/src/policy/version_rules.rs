@@ -0,0 +1,390 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use crate::{
+    common::{App, CheckOptions, CheckTiming, ProtocolState, UpdateCheckSchedule},
+    policy::{AdminOverrides, CheckDecision, PolicyEngine, PolicyReason, UpdateDecision},
+    protocol::response::Urgency,
+    request_builder::RequestParams,
+    time::PartialComplexTime,
+    version::Version,
+};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::collections::HashMap;
+
+/// Local rules about which versions an app may be updated to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VersionRules {
+    /// Only allow versions that start with these components, e.g. "1.2" allows "1.2.3.4" but not
+    /// "1.20.0.0".  A trailing "." is ignored.
+    pub pinned_prefix: Option<String>,
+
+    /// Versions that are never allowed.
+    pub blocklist: Vec<Version>,
+
+    /// Whether versions older than the installed one are allowed.
+    pub allow_downgrade: bool,
+}
+
+impl VersionRules {
+    /// The [`PolicyReason::rule_id`] of offered versions that couldn't be checked against the
+    /// rules, because they're missing or aren't valid versions.
+    pub const INVALID_VERSION: u32 = 2;
+    /// The [`PolicyReason::rule_id`] of offered versions that don't match the pinned prefix.
+    pub const PINNED_VERSION_PREFIX: u32 = 3;
+    /// The [`PolicyReason::rule_id`] of offered versions that are blocklisted.
+    pub const BLOCKLISTED_VERSION: u32 = 4;
+    /// The [`PolicyReason::rule_id`] of offered versions that are older than the installed one.
+    pub const DOWNGRADE: u32 = 5;
+
+    fn is_unrestricted(&self) -> bool {
+        self.pinned_prefix.is_none() && self.blocklist.is_empty() && self.allow_downgrade
+    }
+
+    /// Ask the server for the pinned prefix, and to allow rollbacks if downgrades are allowed,
+    /// unless the `params` already say otherwise.
+    fn request_params(&self, params: RequestParams) -> RequestParams {
+        RequestParams {
+            target_version_prefix: params
+                .target_version_prefix
+                .or_else(|| self.pinned_prefix.clone()),
+            rollback_allowed: params.rollback_allowed || self.allow_downgrade,
+            ..params
+        }
+    }
+
+    /// Check whether `app` may be updated to `offered_version`.
+    pub fn check(&self, app: &App, offered_version: Option<&str>) -> Result<(), PolicyReason> {
+        if self.is_unrestricted() {
+            return Ok(());
+        }
+        let reason = |rule_id, message: String| {
+            PolicyReason::builder()
+                .rule_id(rule_id)
+                .message(format!("{}: {}", app.id, message))
+                .build()
+        };
+        let version = match offered_version.map(str::parse::<Version>) {
+            Some(Ok(version)) => version,
+            Some(Err(_)) | None => {
+                return Err(reason(
+                    Self::INVALID_VERSION,
+                    format!("can't check offered version {offered_version:?}"),
+                ));
+            }
+        };
+        if let Some(prefix) = &self.pinned_prefix {
+            if !matches_prefix(&version, prefix) {
+                return Err(reason(
+                    Self::PINNED_VERSION_PREFIX,
+                    format!("offered version {version} isn't pinned prefix {prefix}"),
+                ));
+            }
+        }
+        if self.blocklist.contains(&version) {
+            return Err(reason(
+                Self::BLOCKLISTED_VERSION,
+                format!("offered version {version} is blocklisted"),
+            ));
+        }
+        if !self.allow_downgrade && version < app.version {
+            return Err(reason(
+                Self::DOWNGRADE,
+                format!(
+                    "offered version {version} is older than installed version {}",
+                    app.version
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether the numeric components of `version` start with those of `prefix`.  Missing components
+/// are zero, and the pre-release and build of `version` are ignored.
+pub(super) fn matches_prefix(version: &Version, prefix: &str) -> bool {
+    let mut components = version
+        .components()
+        .iter()
+        .copied()
+        .chain(std::iter::repeat(0));
+    prefix
+        .trim_end_matches('.')
+        .split('.')
+        .all(|expected| expected.parse::<u32>().ok() == components.next())
+}
+
+/// A PolicyEngine that checks the offered versions against [`VersionRules`], before asking the
+/// wrapped engine.  Versions that break the rules are denied.
+///
+/// The update checks also ask the server for the pinned prefix of the rules for all apps, and to
+/// allow rollbacks if they allow downgrades.
+#[derive(Debug)]
+pub struct VersionRulesPolicyEngine<E> {
+    engine: E,
+    rules: VersionRules,
+    app_rules: HashMap<String, VersionRules>,
+}
+
+impl<E: PolicyEngine> VersionRulesPolicyEngine<E> {
+    /// Check the versions of all apps against `rules`.
+    pub fn new(engine: E, rules: VersionRules) -> Self {
+        Self {
+            engine,
+            rules,
+            app_rules: HashMap::new(),
+        }
+    }
+
+    /// Check the versions of the app `app_id` against `rules`, instead of the rules for all apps.
+    pub fn with_app_rules(mut self, app_id: impl Into<String>, rules: VersionRules) -> Self {
+        self.app_rules.insert(app_id.into(), rules);
+        self
+    }
+
+    fn rules_for(&self, app: &App) -> &VersionRules {
+        self.app_rules.get(&app.id).unwrap_or(&self.rules)
+    }
+}
+
+impl<E: PolicyEngine> PolicyEngine for VersionRulesPolicyEngine<E> {
+    type TimeSource = E::TimeSource;
+    type InstallResult = E::InstallResult;
+    type InstallPlan = E::InstallPlan;
+
+    fn time_source(&self) -> &Self::TimeSource {
+        self.engine.time_source()
+    }
+
+    fn admin_overrides(&mut self) -> Option<AdminOverrides> {
+        self.engine.admin_overrides()
+    }
+
+    fn compute_next_update_time<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+    ) -> BoxFuture<'a, CheckTiming> {
+        self.engine
+            .compute_next_update_time(apps, scheduling, protocol_state)
+    }
+
+    fn update_check_allowed<'a>(
+        &'a mut self,
+        apps: &'a [App],
+        scheduling: &'a UpdateCheckSchedule,
+        protocol_state: &'a ProtocolState,
+        check_options: &'a CheckOptions,
+    ) -> BoxFuture<'a, CheckDecision> {
+        let rules = &self.rules;
+        self.engine
+            .update_check_allowed(apps, scheduling, protocol_state, check_options)
+            .map(move |decision| match decision {
+                CheckDecision::Ok(params) => CheckDecision::Ok(rules.request_params(params)),
+                CheckDecision::OkUpdateDeferred(params) => {
+                    CheckDecision::OkUpdateDeferred(rules.request_params(params))
+                }
+                decision => decision,
+            })
+            .boxed()
+    }
+
+    fn update_version_allowed<'a>(
+        &'a mut self,
+        app: &'a App,
+        offered_version: Option<&'a str>,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        match self.rules_for(app).check(app, offered_version) {
+            Ok(()) => self.engine.update_version_allowed(app, offered_version),
+            Err(reason) => future::ready(UpdateDecision::DeniedByPolicy(reason)).boxed(),
+        }
+    }
+
+    fn update_can_start<'a>(
+        &'a mut self,
+        proposed_install_plan: &'a Self::InstallPlan,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, UpdateDecision> {
+        self.engine.update_can_start(proposed_install_plan, urgency)
+    }
+
+    fn reboot_allowed<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+        urgency: Urgency,
+    ) -> BoxFuture<'a, bool> {
+        self.engine
+            .reboot_allowed(check_options, install_result, urgency)
+    }
+
+    fn next_reboot_allowed_time<'a>(
+        &'a mut self,
+        check_options: &'a CheckOptions,
+        install_result: &'a Self::InstallResult,
+    ) -> BoxFuture<'a, Option<PartialComplexTime>> {
+        self.engine
+            .next_reboot_allowed_time(check_options, install_result)
+    }
+
+    fn reboot_needed<'a>(&'a mut self, install_plan: &'a Self::InstallPlan) -> BoxFuture<'a, bool> {
+        self.engine.reboot_needed(install_plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{installer::stub::StubPlan, policy::StubPolicyEngine, time::MockTimeSource};
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+
+    fn app(version: [u32; 4]) -> App {
+        App::builder().id("some-app").version(version).build()
+    }
+
+    fn rule_id(result: Result<(), PolicyReason>) -> Option<u32> {
+        result.err().map(|reason| reason.rule_id)
+    }
+
+    #[test]
+    fn test_default_rules_refuse_downgrades() {
+        let rules = VersionRules::default();
+        let app = app([1, 2, 3, 4]);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3.5"))), None);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3.4"))), None);
+        assert_eq!(
+            rule_id(rules.check(&app, Some("1.2.3.3"))),
+            Some(VersionRules::DOWNGRADE)
+        );
+        assert_eq!(
+            rule_id(rules.check(&app, Some("not a version"))),
+            Some(VersionRules::INVALID_VERSION)
+        );
+        assert_eq!(
+            rule_id(rules.check(&app, None)),
+            Some(VersionRules::INVALID_VERSION)
+        );
+    }
+
+    #[test]
+    fn test_unrestricted_rules() {
+        let rules = VersionRules {
+            allow_downgrade: true,
+            ..VersionRules::default()
+        };
+        let app = app([1, 2, 3, 4]);
+        assert_eq!(rule_id(rules.check(&app, Some("0.1"))), None);
+        assert_eq!(rule_id(rules.check(&app, None)), None);
+    }
+
+    #[test]
+    fn test_pinned_prefix() {
+        let rules = VersionRules {
+            pinned_prefix: Some("1.2.".to_string()),
+            ..VersionRules::default()
+        };
+        let app = app([1, 2, 0, 0]);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3.4"))), None);
+        assert_eq!(rule_id(rules.check(&app, Some("1.02.3"))), None);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2"))), None);
+        assert_eq!(
+            rule_id(rules.check(&app, Some("1.20.0.0"))),
+            Some(VersionRules::PINNED_VERSION_PREFIX)
+        );
+        assert_eq!(
+            rule_id(rules.check(&app, Some("2.0"))),
+            Some(VersionRules::PINNED_VERSION_PREFIX)
+        );
+    }
+
+    #[test]
+    fn test_pinned_prefix_with_pre_release() {
+        let rules = VersionRules {
+            pinned_prefix: Some("1.2.3".to_string()),
+            ..VersionRules::default()
+        };
+        let app = app([1, 2, 0, 0]);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3-beta"))), None);
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3.1+build.5"))), None);
+        assert_eq!(
+            rule_id(rules.check(&app, Some("1.2.4-beta"))),
+            Some(VersionRules::PINNED_VERSION_PREFIX)
+        );
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let rules = VersionRules {
+            blocklist: vec![Version::from([1, 2, 3, 5])],
+            ..VersionRules::default()
+        };
+        let app = app([1, 2, 3, 4]);
+        assert_eq!(
+            rule_id(rules.check(&app, Some("1.2.3.5"))),
+            Some(VersionRules::BLOCKLISTED_VERSION)
+        );
+        assert_eq!(rule_id(rules.check(&app, Some("1.2.3.6"))), None);
+    }
+
+    #[test]
+    fn test_engine_sets_request_params() {
+        let mut engine = VersionRulesPolicyEngine::new(
+            StubPolicyEngine::<StubPlan, _>::new(MockTimeSource::new_from_now()),
+            VersionRules {
+                pinned_prefix: Some("1.2.".to_string()),
+                allow_downgrade: true,
+                ..VersionRules::default()
+            },
+        );
+        assert_matches!(
+            block_on(engine.update_check_allowed(
+                &[],
+                &UpdateCheckSchedule::default(),
+                &ProtocolState::default(),
+                &CheckOptions::default(),
+            )),
+            CheckDecision::Ok(RequestParams {
+                target_version_prefix: Some(prefix),
+                rollback_allowed: true,
+                ..
+            }) if prefix == "1.2."
+        );
+    }
+
+    #[test]
+    fn test_engine_uses_app_rules() {
+        let mut engine = VersionRulesPolicyEngine::new(
+            StubPolicyEngine::<StubPlan, _>::new(MockTimeSource::new_from_now()),
+            VersionRules::default(),
+        )
+        .with_app_rules(
+            "other-app",
+            VersionRules {
+                allow_downgrade: true,
+                ..VersionRules::default()
+            },
+        );
+        let some_app = app([1, 2, 3, 4]);
+        let other_app = App::builder().id("other-app").version([1, 2, 3, 4]).build();
+
+        assert_matches!(
+            block_on(engine.update_version_allowed(&some_app, Some("1.0"))),
+            UpdateDecision::DeniedByPolicy(PolicyReason {
+                rule_id: VersionRules::DOWNGRADE,
+                ..
+            })
+        );
+        assert_eq!(
+            block_on(engine.update_version_allowed(&other_app, Some("1.0"))),
+            UpdateDecision::Ok
+        );
+    }
+}
@@ -7,7 +7,7 @@
 // those terms.
 
 use crate::protocol::Cohort;
-use serde::{Serialize, Serializer};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_repr::Serialize_repr;
 use std::collections::HashMap;
 
@@ -112,7 +112,7 @@ pub enum InstallSource {
 /// Information about the platform / operating system.
 ///
 /// See https://github.com/google/omaha/blob/HEAD/doc/ServerProtocolV3.md#os
-#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct OS {
     /// The device platform (e.g. 'Fuchsia')
     pub platform: String,
@@ -207,6 +207,24 @@ pub struct UpdateCheck {
     #[serde(skip_serializing_if = "std::ops::Not::not")]
     #[serde(rename = "sameversionupdate")]
     pub offer_update_if_same_version: bool,
+
+    /// Only offer updates whose version starts with this prefix, e.g. "1.2." to stay on the 1.2
+    /// release line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "targetversionprefix")]
+    pub target_version_prefix: Option<String>,
+
+    /// If true, Omaha may offer an older version than the one the client is running, e.g. to roll
+    /// back to the previous stable version.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(rename = "rollback_allowed")]
+    pub rollback_allowed: bool,
+
+    /// An opaque token that identifies the client as a trusted tester, so that Omaha can offer it
+    /// updates that aren't generally available yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "tttoken")]
+    pub tttoken: Option<String>,
 }
 
 impl UpdateCheck {
@@ -215,7 +233,7 @@ impl UpdateCheck {
     pub fn disabled() -> Self {
         UpdateCheck {
             disabled: true,
-            offer_update_if_same_version: false,
+            ..UpdateCheck::default()
         }
     }
 }
@@ -270,6 +288,14 @@ pub struct Event {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub errorcode: Option<EventErrorCode>,
 
+    /// Additional information about the event, whose meaning is application specific.  For events
+    /// about Policy decisions, this is the id of the rule that made the decision.
+    ///
+    /// This is the extracode1 attribute of the event object.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "extracode1")]
+    pub extra_code1: Option<u32>,
+
     /// The version of the app that was present on the machine at the time of the update-check of
     /// this update flow, regardless of the success or failure of the update operation.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -329,6 +355,9 @@ pub enum EventType {
     /// The application update is complete.
     UpdateComplete = 3,
 
+    /// The application has been removed from the device.
+    Uninstall = 4,
+
     /// The download of the update for the application has started.
     UpdateDownloadStarted = 13,
 
@@ -401,6 +401,30 @@ fn multiple_event_serialization_test() {
     assert_eq!(expected, serde_json::to_value(request).unwrap());
 }
 
+#[test]
+fn update_check_serialization_test() {
+    assert_eq!(
+        serde_json::to_value(UpdateCheck::default()).unwrap(),
+        json!({})
+    );
+    let update_check = UpdateCheck {
+        disabled: false,
+        offer_update_if_same_version: true,
+        target_version_prefix: Some("1.2.".to_string()),
+        rollback_allowed: true,
+        tttoken: Some("some_token".to_string()),
+    };
+    assert_eq!(
+        serde_json::to_value(update_check).unwrap(),
+        json!({
+            "sameversionupdate": true,
+            "targetversionprefix": "1.2.",
+            "rollback_allowed": true,
+            "tttoken": "some_token",
+        })
+    );
+}
+
 #[test]
 fn all_fields_serialization_test() {
     let expected = json!({
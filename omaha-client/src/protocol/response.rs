@@ -40,6 +40,30 @@ pub struct Response {
     pub apps: Vec<App>,
 }
 
+impl Response {
+    /// The urgency of the update in this response, which is urgent if any app's update is.
+    pub fn urgency(&self) -> Urgency {
+        self.apps
+            .iter()
+            .filter_map(|app| app.update_check.as_ref())
+            .filter(|update_check| update_check.status == OmahaStatus::Ok)
+            .map(UpdateCheck::urgency)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// How urgently the server wants an update to be applied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Urgency {
+    /// The update is applied when policy allows it.
+    #[default]
+    Normal,
+    /// The update should be applied and rebooted into as soon as possible, bypassing maintenance
+    /// windows and reboot deferral.
+    Urgent,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct DayStart {
     /// The number of calendar days that have elapsed since January 1st, 2007 in the server's
@@ -158,6 +182,16 @@ impl UpdateCheck {
             .map(|url| url.codebase.as_str())
     }
 
+    /// The urgency of this update, from the `_urgent_update` attribute.  The attribute can be
+    /// either a bool or the string "true".
+    pub fn urgency(&self) -> Urgency {
+        match self.extra_attributes.get("_urgent_update") {
+            Some(Value::Bool(true)) => Urgency::Urgent,
+            Some(Value::String(s)) if s.eq_ignore_ascii_case("true") => Urgency::Urgent,
+            _ => Urgency::Normal,
+        }
+    }
+
     /// Returns an iterator of all packages in this `updatecheck`.
     pub fn get_all_packages(&self) -> impl Iterator<Item = &Package> {
         self.manifest.iter().flat_map(|m| &m.packages.package)
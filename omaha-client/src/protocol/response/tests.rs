@@ -520,3 +520,50 @@ fn test_update_check() {
         ]
     );
 }
+
+#[test]
+fn test_urgency() {
+    let urgent = |value: Value| UpdateCheck {
+        extra_attributes: json!({ "_urgent_update": value })
+            .as_object()
+            .unwrap()
+            .to_owned(),
+        ..UpdateCheck::default()
+    };
+    assert_eq!(UpdateCheck::default().urgency(), Urgency::Normal);
+    assert_eq!(urgent(json!(true)).urgency(), Urgency::Urgent);
+    assert_eq!(urgent(json!("true")).urgency(), Urgency::Urgent);
+    assert_eq!(urgent(json!(false)).urgency(), Urgency::Normal);
+    assert_eq!(urgent(json!("false")).urgency(), Urgency::Normal);
+
+    let app = |id: &str, update_check: UpdateCheck| App {
+        id: id.to_string(),
+        update_check: Some(update_check),
+        ..App::default()
+    };
+    let response = Response {
+        apps: vec![
+            app("normal", UpdateCheck::default()),
+            app("urgent", urgent(json!(true))),
+        ],
+        ..Response::default()
+    };
+    assert_eq!(response.urgency(), Urgency::Urgent);
+
+    // Only apps that have an update count.
+    let response = Response {
+        apps: vec![
+            app("normal", UpdateCheck::default()),
+            app(
+                "no-update",
+                UpdateCheck {
+                    status: OmahaStatus::NoUpdate,
+                    ..urgent(json!(true))
+                },
+            ),
+        ],
+        ..Response::default()
+    };
+    assert_eq!(response.urgency(), Urgency::Normal);
+    assert_eq!(Response::default().urgency(), Urgency::Normal);
+}
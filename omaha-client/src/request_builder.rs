@@ -11,8 +11,10 @@ mod tests;
 
 use crate::{
     common::{App, UserCounting},
+    compression::{CompressionError, ContentEncoding},
     configuration::Config,
     cup_ecdsa::{CupDecorationError, CupRequest, Cupv2RequestHandler, RequestMetadata},
+    http_request::proxy::ProxyRoute,
     protocol::{
         request::{
             Event, InstallSource, Ping, Request, RequestWrapper, UpdateCheck, GUID, HEADER_APP_ID,
@@ -22,6 +24,7 @@ use crate::{
     },
 };
 use http;
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::result;
 use thiserror::Error;
@@ -41,6 +44,9 @@ pub enum Error {
 
     #[error("Error decorating outgoing request with CUPv2 parameters")]
     Cup(#[from] CupDecorationError),
+
+    #[error("Error compressing the request body")]
+    Compression(#[from] CompressionError),
 }
 
 /// The builder's own Result type.
@@ -55,6 +61,9 @@ pub struct RequestParams {
 
     /// If true, the request should use any configured proxies.  This allows the bypassing of
     /// proxies if there are difficulties in communicating with the Omaha service.
+    ///
+    /// The proxies themselves come from `Config::proxy_config`, and the resulting `ProxyRoute` is
+    /// attached to the built http request for the `HttpRequest` implementation to honor.
     pub use_configured_proxies: bool,
 
     /// If true, the request should set the "updatedisabled" property for all apps in the update
@@ -64,6 +73,33 @@ pub struct RequestParams {
     /// If true, the request should set the "sameversionupdate" property for all apps in the update
     /// check request.
     pub offer_update_if_same_version: bool,
+
+    /// If set, the request should set the "targetversionprefix" property for all apps in the
+    /// update check request, so that only updates with this version prefix are offered.
+    pub target_version_prefix: Option<String>,
+
+    /// If true, the request should set the "rollback_allowed" property for all apps in the update
+    /// check request, so that an older version may be offered.
+    pub rollback_allowed: bool,
+
+    /// If set, the request should set the "tttoken" (trusted tester token) property for all apps in
+    /// the update check request.
+    pub tttoken: Option<String>,
+
+    /// Parameters for the update checks of individual apps, by app id.  These apply in addition
+    /// to the parameters above.
+    pub app_params: HashMap<String, AppRequestParams>,
+}
+
+/// The parameters that describe how the update check of a single app should be performed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AppRequestParams {
+    /// If true, the request should set the "updatedisabled" property for the app.
+    pub disable_updates: bool,
+
+    /// If set, the request should set the "targetversionprefix" property for the app, instead of
+    /// the one for all apps.
+    pub target_version_prefix: Option<String>,
 }
 
 /// The AppEntry holds the data for the app whose request is currently being constructed.  An app
@@ -123,7 +159,7 @@ impl From<AppEntry> for ProtocolApp {
         };
         ProtocolApp {
             id: entry.app.id,
-            version: entry.app.version.to_string(),
+            version: entry.app.version.to_omaha_string(),
             fingerprint: entry.app.fingerprint,
             cohort: Some(entry.app.cohort),
             update_check: entry.update_check,
@@ -200,9 +236,16 @@ impl<'a> RequestBuilder<'a> {
     /// an idempotent accumulator, in that it only once adds the App with it's associated Cohort to
     /// the request.  Afterward, it just adds the update check to the App.
     pub fn add_update_check(mut self, app: &App) -> Self {
+        let app_params = self.params.app_params.get(&app.id);
         let update_check = UpdateCheck {
-            disabled: self.params.disable_updates,
+            disabled: self.params.disable_updates
+                || app_params.is_some_and(|params| params.disable_updates),
             offer_update_if_same_version: self.params.offer_update_if_same_version,
+            target_version_prefix: app_params
+                .and_then(|params| params.target_version_prefix.clone())
+                .or_else(|| self.params.target_version_prefix.clone()),
+            rollback_allowed: self.params.rollback_allowed,
+            tttoken: self.params.tttoken.clone(),
         };
 
         self.insert_and_modify_entry(app, |entry| {
@@ -262,10 +305,19 @@ impl<'a> RequestBuilder<'a> {
         {
             info!("Building Request: {}", intermediate);
         }
-        Ok((
-            Into::<Result<http::Request<hyper::Body>>>::into(intermediate)?,
-            request_metadata,
-        ))
+        let mut request = Into::<Result<http::Request<hyper::Body>>>::into(intermediate)?;
+        request.extensions_mut().insert(self.proxy_route());
+        Ok((request, request_metadata))
+    }
+
+    /// The route that requests built by this builder should take, based on the configured proxies
+    /// and whether the request params allow using them.
+    pub fn proxy_route(&self) -> ProxyRoute {
+        ProxyRoute::resolve(
+            self.config.proxy_config.as_ref(),
+            self.params.use_configured_proxies,
+            &self.config.service_url,
+        )
     }
 
     /// Helper function that constructs the request body from the builder.
@@ -296,6 +348,12 @@ impl<'a> RequestBuilder<'a> {
         if let Some(main_app) = self.app_entries.first() {
             headers.push((HEADER_APP_ID, main_app.app.id.clone()));
         }
+        if self.config.compression.decompress_responses {
+            headers.push((
+                http::header::ACCEPT_ENCODING.as_str(),
+                format!("{}, {}", ContentEncoding::Gzip, ContentEncoding::Deflate),
+            ));
+        }
 
         let apps = self
             .app_entries
@@ -311,7 +369,7 @@ impl<'a> RequestBuilder<'a> {
                 request: Request {
                     protocol_version: PROTOCOL_V3.to_string(),
                     updater: self.config.updater.name.clone(),
-                    updater_version: self.config.updater.version.to_string(),
+                    updater_version: self.config.updater.version.to_omaha_string(),
                     install_source: self.params.source,
                     is_machine: true,
                     request_id: self.request_id.clone(),
@@ -320,8 +378,20 @@ impl<'a> RequestBuilder<'a> {
                     apps,
                 },
             },
+            content_encoding: None,
         };
 
+        // The body is only compressed when it's turned into the http request, after CUP has
+        // hashed the uncompressed bytes.
+        let body_len = intermediate.serialize_body()?.len();
+        if let Some(encoding) = self.config.compression.request_encoding_for(body_len) {
+            intermediate.headers.push((
+                http::header::CONTENT_ENCODING.as_str(),
+                encoding.to_string(),
+            ));
+            intermediate.content_encoding = Some(encoding);
+        }
+
         let request_metadata = match cup_handler.as_ref() {
             Some(handler) => Some(handler.decorate_request(&mut intermediate)?),
             _ => None,
@@ -349,6 +419,9 @@ pub struct Intermediate {
 
     /// The request body, still in object form as a RequestWrapper
     pub body: RequestWrapper,
+
+    /// The encoding to compress the serialized body with when building the http request, if any.
+    pub content_encoding: Option<ContentEncoding>,
 }
 
 impl Intermediate {
@@ -377,7 +450,11 @@ impl From<Intermediate> for Result<http::Request<hyper::Body>> {
             builder = builder.header(*key, value);
         }
 
-        let request = builder.body(intermediate.serialize_body()?.into())?;
+        let mut body = intermediate.serialize_body()?;
+        if let Some(encoding) = intermediate.content_encoding {
+            body = encoding.encode(&body)?;
+        }
+        let request = builder.body(body.into())?;
         Ok(request)
     }
 }
@@ -14,6 +14,7 @@ use crate::{
         request::{EventErrorCode, EventResult, EventType},
         Cohort,
     },
+    version::Version,
 };
 use futures::executor::block_on;
 use pretty_assertions::assert_eq;
@@ -53,7 +54,10 @@ fn test_simple_request() {
     let request = intermediate.body.request;
     assert_eq!(request.protocol_version, "3.0");
     assert_eq!(request.updater, config.updater.name);
-    assert_eq!(request.updater_version, config.updater.version.to_string());
+    assert_eq!(
+        request.updater_version,
+        config.updater.version.to_omaha_string()
+    );
     assert_eq!(request.install_source, InstallSource::OnDemand);
     assert_eq!(request.is_machine, true);
     assert_eq!(request.session_id, Some(GUID::from_u128(1)));
@@ -126,7 +130,10 @@ fn test_updates_disabled_request() {
     let request = intermediate.body.request;
     assert_eq!(request.protocol_version, "3.0");
     assert_eq!(request.updater, config.updater.name);
-    assert_eq!(request.updater_version, config.updater.version.to_string());
+    assert_eq!(
+        request.updater_version,
+        config.updater.version.to_omaha_string()
+    );
     assert_eq!(request.install_source, InstallSource::OnDemand);
     assert_eq!(request.is_machine, true);
     assert_eq!(request.session_id, Some(GUID::from_u128(1)));
@@ -152,6 +159,39 @@ fn test_updates_disabled_request() {
     assert_eq!(app.update_check, Some(UpdateCheck::disabled()));
 }
 
+/// Test that a request sets the target version prefix, rollback and trusted tester fields when
+/// configured to do so.
+#[test]
+fn test_target_version_prefix_and_rollback_request() {
+    let config = config_generator();
+
+    let (intermediate, _request_metadata) = RequestBuilder::new(
+        &config,
+        &RequestParams {
+            target_version_prefix: Some("1.2.".to_string()),
+            rollback_allowed: true,
+            tttoken: Some("some_token".to_string()),
+            ..RequestParams::default()
+        },
+    )
+    .add_update_check(&App::builder().id("app id 1").version([1, 2, 3, 4]).build())
+    .add_update_check(&App::builder().id("app id 2").version([5, 6, 7, 8]).build())
+    .session_id(GUID::from_u128(1))
+    .request_id(GUID::from_u128(2))
+    .build_intermediate(None::<&StandardCupv2Handler>)
+    .unwrap();
+
+    let expected = UpdateCheck {
+        target_version_prefix: Some("1.2.".to_string()),
+        rollback_allowed: true,
+        tttoken: Some("some_token".to_string()),
+        ..UpdateCheck::default()
+    };
+    let request = intermediate.body.request;
+    assert_eq!(request.apps[0].update_check, Some(expected.clone()));
+    assert_eq!(request.apps[1].update_check, Some(expected));
+}
+
 /// Test that a request sets the same version update field when configured to do so.
 #[test]
 fn test_same_version_update_request() {
@@ -213,6 +253,27 @@ fn test_same_version_update_request() {
 }
 
 /// Test that a request attaches the extras to the protocol App from the common App.
+/// Test that Omaha-style app versions are sent with four components, and semver-style ones as
+/// they are.
+#[test]
+fn test_app_version_in_request() {
+    let config = config_generator();
+    let (intermediate, _request_metadata) = RequestBuilder::new(&config, &RequestParams::default())
+        .add_update_check(&App::builder().id("app id 1").version([1, 2]).build())
+        .add_update_check(
+            &App::builder()
+                .id("app id 2")
+                .version("1.2.3-beta".parse::<Version>().unwrap())
+                .build(),
+        )
+        .build_intermediate(None::<&StandardCupv2Handler>)
+        .unwrap();
+
+    let apps = &intermediate.body.request.apps;
+    assert_eq!(apps[0].version, "1.2.0.0");
+    assert_eq!(apps[1].version, "1.2.3-beta");
+}
+
 #[test]
 fn test_app_includes_extras() {
     let config = config_generator();
@@ -281,7 +342,7 @@ fn test_single_request() {
         "request": {
             "protocol": "3.0",
             "updater": config.updater.name,
-            "updaterversion": config.updater.version.to_string(),
+            "updaterversion": config.updater.version.to_omaha_string(),
             "installsource": "ondemand",
             "ismachine": true,
             "os": {
@@ -730,3 +791,95 @@ fn test_event_added_to_second_app_update_entry() {
     assert_eq!(event.event_result, EventResult::Success);
     assert_eq!(event.errorcode, Some(EventErrorCode::Installation));
 }
+
+/// Test that the built http request carries the proxy route, and that it follows the request
+/// params.
+#[test]
+fn test_proxy_route_attached_to_request() {
+    use crate::http_request::proxy::{route_for, ProxyConfig};
+
+    let mut config = config_generator();
+    let proxy: crate::http_request::proxy::Proxy = "proxy.example.com:3128".parse().unwrap();
+    config.proxy_config = Some(ProxyConfig::all(proxy.clone()));
+    let app = App::builder().id("app id").version([1, 2, 3, 4]).build();
+
+    let (request, _request_metadata) = RequestBuilder::new(
+        &config,
+        &RequestParams {
+            use_configured_proxies: true,
+            ..RequestParams::default()
+        },
+    )
+    .add_update_check(&app)
+    .build(None::<&StandardCupv2Handler>)
+    .unwrap();
+    assert_eq!(route_for(&request), ProxyRoute::Proxied(proxy));
+
+    let (request, _request_metadata) = RequestBuilder::new(
+        &config,
+        &RequestParams {
+            use_configured_proxies: false,
+            ..RequestParams::default()
+        },
+    )
+    .add_update_check(&app)
+    .build(None::<&StandardCupv2Handler>)
+    .unwrap();
+    assert_eq!(route_for(&request), ProxyRoute::Direct);
+}
+
+/// Test that request bodies above the threshold are compressed, and that CUP hashes the
+/// uncompressed body.
+#[test]
+fn test_compressed_request() {
+    use crate::compression::{CompressionConfig, ContentEncoding};
+
+    let mut config = config_generator();
+    config.compression = CompressionConfig {
+        request_encoding: Some(ContentEncoding::Gzip),
+        request_threshold: 0,
+        decompress_responses: true,
+        ..Default::default()
+    };
+    let app = App::builder().id("app id").version([1, 2, 3, 4]).build();
+    let cup_handler = make_cup_handler_for_test();
+
+    let (request, request_metadata) = RequestBuilder::new(&config, &RequestParams::default())
+        .add_update_check(&app)
+        .build(Some(&cup_handler))
+        .unwrap();
+
+    assert_eq!(request.headers()["Content-Encoding"], "gzip");
+    assert_eq!(request.headers()["Accept-Encoding"], "gzip, deflate");
+    let body = block_on(hyper::body::to_bytes(request)).unwrap();
+    let decoded = ContentEncoding::Gzip.decode(&body, 1 << 20).unwrap();
+    assert_eq!(decoded, request_metadata.unwrap().request_body);
+    let request: serde_json::Value = serde_json::from_slice(&decoded).unwrap();
+    assert_eq!(request["request"]["app"][0]["appid"], "app id");
+}
+
+/// Test that request bodies at or below the threshold aren't compressed.
+#[test]
+fn test_request_below_compression_threshold() {
+    use crate::compression::{CompressionConfig, ContentEncoding};
+
+    let mut config = config_generator();
+    config.compression = CompressionConfig {
+        request_encoding: Some(ContentEncoding::Deflate),
+        request_threshold: 1 << 20,
+        decompress_responses: false,
+        ..Default::default()
+    };
+    let app = App::builder().id("app id").version([1, 2, 3, 4]).build();
+
+    let (request, _request_metadata) = RequestBuilder::new(&config, &RequestParams::default())
+        .add_update_check(&app)
+        .build(None::<&StandardCupv2Handler>)
+        .unwrap();
+
+    assert!(request.headers().get("Content-Encoding").is_none());
+    assert!(request.headers().get("Accept-Encoding").is_none());
+    let body = block_on(hyper::body::to_bytes(request)).unwrap();
+    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(request["request"]["app"][0]["appid"], "app id");
+}
@@ -7,19 +7,22 @@
 // those terms.
 
 use crate::{
-    app_set::{AppSet, AppSetExt as _},
+    app_set::{AppSet, AppSetError, AppSetExt as _, AppUpdate, RegisteredApps},
     async_generator,
+    auth::{self, AuthError, AuthProvider, StubAuthProvider, TokenCache},
     common::{App, CheckOptions, CheckTiming},
+    compression::{self, CompressionError},
     configuration::Config,
     cup_ecdsa::{CupDecorationError, CupVerificationError, Cupv2Handler, RequestMetadata},
-    http_request::{self, HttpRequest},
+    http_request::{self, proxy, HttpRequest},
     installer::{AppInstallResult, Installer, Plan},
     metrics::{ClockType, Metrics, MetricsReporter, UpdateCheckFailureReason},
-    policy::{CheckDecision, PolicyEngine, UpdateDecision},
+    policy::{AdminOverrides, CheckDecision, PolicyEngine, PolicyReason, UpdateDecision},
     protocol::{
         self,
         request::{Event, EventErrorCode, EventResult, EventType, InstallSource, GUID},
-        response::{parse_json_response, OmahaStatus, Response, UpdateCheck},
+        response::{parse_json_response, OmahaStatus, Response, UpdateCheck, Urgency},
+        Cohort,
     },
     request_builder::{self, RequestBuilder, RequestParams},
     storage::{Storage, StorageExt},
@@ -71,7 +74,7 @@ const MAX_OMAHA_REQUEST_ATTEMPTS: u64 = 3;
 /// This is the core state machine for a client's update check.  It is instantiated and used to
 /// perform update checks over time or to perform a single update check process.
 #[derive(Debug)]
-pub struct StateMachine<PE, HR, IN, TM, MR, ST, AS, CH>
+pub struct StateMachine<PE, HR, IN, TM, MR, ST, AS, CH, AP = StubAuthProvider>
 where
     PE: PolicyEngine,
     HR: HttpRequest,
@@ -106,6 +109,15 @@ where
     app_set: Rc<Mutex<AS>>,
 
     cup_handler: Option<CH>,
+
+    /// The source of, and cache for, the bearer tokens attached to requests.
+    auth: TokenCache<AP>,
+
+    /// The admin overrides that were last reported.
+    admin_overrides: Option<AdminOverrides>,
+
+    /// The app ids that apps switch to when their target channel is set to one of these channels.
+    channel_app_ids: HashMap<String, String>,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -142,6 +154,15 @@ pub enum OmahaRequestError {
 
     #[error("HTTP error performing update check: {0}")]
     HttpStatus(hyper::StatusCode),
+
+    #[error("Unable to authenticate update check")]
+    Auth(#[from] AuthError),
+
+    #[error("Unable to decompress the HTTP response body")]
+    Compression(#[from] CompressionError),
+
+    #[error("Unable to compress the HTTP request body")]
+    RequestCompression(#[source] CompressionError),
 }
 
 impl From<request_builder::Error> for OmahaRequestError {
@@ -150,6 +171,7 @@ impl From<request_builder::Error> for OmahaRequestError {
             request_builder::Error::Json(e) => OmahaRequestError::Json(e),
             request_builder::Error::Http(e) => OmahaRequestError::HttpBuilder(e),
             request_builder::Error::Cup(e) => OmahaRequestError::CupDecoration(e),
+            request_builder::Error::Compression(e) => OmahaRequestError::RequestCompression(e),
         }
     }
 }
@@ -204,11 +226,57 @@ impl From<oneshot::Canceled> for StateMachineGone {
     }
 }
 
+/// Errors from asking the state machine to change its apps.
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+pub enum AppChangeError {
+    #[error(transparent)]
+    AppSet(#[from] AppSetError),
+
+    #[error("invalid channel name {0:?}")]
+    InvalidChannel(String),
+
+    #[error("no app id is configured for channel {0:?}")]
+    UnknownChannel(String),
+
+    #[error(transparent)]
+    StateMachineGone(#[from] StateMachineGone),
+}
+
 enum ControlRequest {
     StartUpdateCheck {
         options: CheckOptions,
         responder: oneshot::Sender<StartUpdateCheckResponse>,
     },
+    ChangeApps {
+        change: AppChange,
+        responder: oneshot::Sender<Result<(), AppSetError>>,
+    },
+    SetTargetChannel {
+        app_id: String,
+        channel: String,
+        responder: oneshot::Sender<Result<(), AppChangeError>>,
+    },
+}
+
+/// A change to the apps in the app set, requested through a `ControlHandle`.
+enum AppChange {
+    Register(App),
+    Unregister(String),
+    Update { id: String, update: AppUpdate },
+}
+
+/// A `ControlRequest` that changes the apps, received during an update check.  It's handled once
+/// the check is done.
+enum DeferredRequest {
+    ChangeApps {
+        change: AppChange,
+        responder: oneshot::Sender<Result<(), AppSetError>>,
+    },
+    SetTargetChannel {
+        app_id: String,
+        channel: String,
+        responder: oneshot::Sender<Result<(), AppChangeError>>,
+    },
 }
 
 /// Responses to a request to start an update check now.
@@ -221,8 +289,8 @@ pub enum StartUpdateCheckResponse {
     /// options.
     AlreadyRunning,
 
-    /// The update check was throttled by policy.
-    Throttled,
+    /// The update check was throttled by policy, for the given reason.
+    Throttled(PolicyReason),
 }
 
 impl ControlHandle {
@@ -238,15 +306,71 @@ impl ControlHandle {
             .await?;
         Ok(receive_response.await?)
     }
+
+    /// Add |app| to the apps that the state machine checks for updates.  Its cohort is restored
+    /// from storage if it was registered before.
+    pub async fn register_app(&mut self, app: App) -> Result<(), AppChangeError> {
+        self.change_apps(AppChange::Register(app)).await
+    }
+
+    /// Remove the app with |id| from the apps that the state machine checks for updates, and
+    /// report its uninstallation to Omaha.
+    pub async fn unregister_app(&mut self, id: impl Into<String>) -> Result<(), AppChangeError> {
+        self.change_apps(AppChange::Unregister(id.into())).await
+    }
+
+    /// Change the version, cohort hint or extra fields of the app with |id|.
+    pub async fn update_app(
+        &mut self,
+        id: impl Into<String>,
+        update: AppUpdate,
+    ) -> Result<(), AppChangeError> {
+        self.change_apps(AppChange::Update {
+            id: id.into(),
+            update,
+        })
+        .await
+    }
+
+    /// Move the app with |app_id| to |channel|, switching it to the app id configured for the
+    /// channel if there is one, and then start an on demand update check.  The check is started
+    /// once the ongoing one is done, if there is one.
+    pub async fn set_target_channel(
+        &mut self,
+        app_id: impl Into<String>,
+        channel: impl Into<String>,
+    ) -> Result<(), AppChangeError> {
+        let (responder, receive_response) = oneshot::channel();
+        self.0
+            .send(ControlRequest::SetTargetChannel {
+                app_id: app_id.into(),
+                channel: channel.into(),
+                responder,
+            })
+            .await
+            .map_err(StateMachineGone::from)?;
+        receive_response.await.map_err(StateMachineGone::from)?
+    }
+
+    async fn change_apps(&mut self, change: AppChange) -> Result<(), AppChangeError> {
+        let (responder, receive_response) = oneshot::channel();
+        self.0
+            .send(ControlRequest::ChangeApps { change, responder })
+            .await
+            .map_err(StateMachineGone::from)?;
+        let response = receive_response.await.map_err(StateMachineGone::from)?;
+        Ok(response?)
+    }
 }
 
 #[derive(Debug)]
 enum RebootAfterUpdate<T> {
-    Needed(T),
+    /// Reboot is needed, with the result of the install and the urgency of the update.
+    Needed(T, Urgency),
     NotNeeded,
 }
 
-impl<PE, HR, IN, TM, MR, ST, AS, IR, PL, CH> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH>
+impl<PE, HR, IN, TM, MR, ST, AS, IR, PL, CH, AP> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH, AP>
 where
     PE: PolicyEngine<InstallResult = IR, InstallPlan = PL>,
     HR: HttpRequest,
@@ -256,6 +380,7 @@ where
     ST: Storage,
     AS: AppSet,
     CH: Cupv2Handler,
+    AP: AuthProvider,
     IR: 'static + Send,
     PL: Plan,
 {
@@ -320,6 +445,9 @@ where
 
         let mut should_report_waited_for_reboot_duration = false;
 
+        let mut deferred_requests = vec![];
+        let mut requested_check = None;
+
         let update_finish_time = {
             let storage = self.storage_ref.lock().await;
             let update_finish_time = storage.get_time(UPDATE_FINISH_TIME).await;
@@ -360,16 +488,35 @@ where
                 }
             }
 
-            let (mut options, responder) = {
+            let (mut options, responder) = if let Some(options) = requested_check.take() {
+                (options, None)
+            } else {
                 let check_timing = self.update_next_update_time(&mut co).await;
                 let mut wait_to_next_check = self.make_wait_to_next_check(check_timing).await;
 
                 // Wait for either the next check time or a request to start an update check.  Use
                 // the default check options with the timed check, or those sent with a request.
+                // Changes to the apps are made while waiting, and then the check timing is
+                // recomputed for the new apps.
                 select! {
                     () = wait_to_next_check => (CheckOptions::default(), None),
-                    ControlRequest::StartUpdateCheck{options, responder} = control.select_next_some() => {
-                        (options, Some(responder))
+                    request = control.select_next_some() => match request {
+                        ControlRequest::StartUpdateCheck{options, responder} => {
+                            (options, Some(responder))
+                        }
+                        ControlRequest::ChangeApps{change, responder} => {
+                            self.change_apps(change, responder, &mut co).await;
+                            continue;
+                        }
+                        ControlRequest::SetTargetChannel{app_id, channel, responder} => {
+                            let result = self.set_target_channel(&app_id, channel, &mut co).await;
+                            let changed = result.is_ok();
+                            let _ = responder.send(result);
+                            if !changed {
+                                continue;
+                            }
+                            (CheckOptions { source: InstallSource::OnDemand }, None)
+                        }
                     }
                 }
             };
@@ -397,12 +544,24 @@ where
                     CheckDecision::Ok(rp) | CheckDecision::OkUpdateDeferred(rp) => rp,
 
                     // Negative results, exit early
-                    CheckDecision::TooSoon
-                    | CheckDecision::ThrottledByPolicy
-                    | CheckDecision::DeniedByPolicy => {
-                        info!("The update check is not allowed at this time.");
+                    CheckDecision::TooSoon(reason) => {
+                        info!("The update check is not allowed at this time: {}", reason);
+                        // Only a requested check is declined, a timer that fired early isn't
+                        // worth an event.
+                        if let Some(responder) = responder {
+                            co.yield_(StateMachineEvent::DeclinedByPolicy(reason.clone()))
+                                .await;
+                            let _ = responder.send(StartUpdateCheckResponse::Throttled(reason));
+                        }
+                        continue;
+                    }
+                    CheckDecision::ThrottledByPolicy(reason)
+                    | CheckDecision::DeniedByPolicy(reason) => {
+                        info!("The update check is not allowed by Policy: {}", reason);
+                        co.yield_(StateMachineEvent::DeclinedByPolicy(reason.clone()))
+                            .await;
                         if let Some(responder) = responder {
-                            let _ = responder.send(StartUpdateCheckResponse::Throttled);
+                            let _ = responder.send(StartUpdateCheckResponse::Throttled(reason));
                         }
                         continue;
                     }
@@ -416,29 +575,63 @@ where
                 futures::pin_mut!(update_check);
 
                 // Wait for the update check to complete, handling any control requests that come in
-                // during the check.
+                // during the check.  Changes to the apps and their channels wait until the check is
+                // done.
                 loop {
                     select! {
                         update_check_result = update_check => break update_check_result,
-                        ControlRequest::StartUpdateCheck{
-                            options: new_options,
-                            responder
-                        } = control.select_next_some() => {
-                            if new_options.source == InstallSource::OnDemand {
-                                info!("Got on demand update check request, ensuring ongoing check is on demand");
-                                // TODO(63180): merge CheckOptions in Policy, not here.
-                                options.source = InstallSource::OnDemand;
+                        request = control.select_next_some() => match request {
+                            ControlRequest::StartUpdateCheck{
+                                options: new_options,
+                                responder
+                            } => {
+                                if new_options.source == InstallSource::OnDemand {
+                                    info!("Got on demand update check request, ensuring ongoing check is on demand");
+                                    // TODO(63180): merge CheckOptions in Policy, not here.
+                                    options.source = InstallSource::OnDemand;
+                                }
+
+                                let _ = responder.send(StartUpdateCheckResponse::AlreadyRunning);
+                            }
+                            ControlRequest::ChangeApps{change, responder} => {
+                                deferred_requests.push(DeferredRequest::ChangeApps{change, responder});
+                            }
+                            ControlRequest::SetTargetChannel{app_id, channel, responder} => {
+                                deferred_requests.push(DeferredRequest::SetTargetChannel{
+                                    app_id,
+                                    channel,
+                                    responder,
+                                });
                             }
-
-                            let _ = responder.send(StartUpdateCheckResponse::AlreadyRunning);
                         }
                     }
                 }
             };
 
-            if let RebootAfterUpdate::Needed(install_result) = reboot_after_update {
+            for request in deferred_requests.drain(..) {
+                match request {
+                    DeferredRequest::ChangeApps { change, responder } => {
+                        self.change_apps(change, responder, &mut co).await;
+                    }
+                    DeferredRequest::SetTargetChannel {
+                        app_id,
+                        channel,
+                        responder,
+                    } => {
+                        let result = self.set_target_channel(&app_id, channel, &mut co).await;
+                        if result.is_ok() {
+                            requested_check = Some(CheckOptions {
+                                source: InstallSource::OnDemand,
+                            });
+                        }
+                        let _ = responder.send(result);
+                    }
+                }
+            }
+
+            if let RebootAfterUpdate::Needed(install_result, urgency) = reboot_after_update {
                 Self::yield_state(State::WaitingForReboot, &mut co).await;
-                self.wait_for_reboot(options, &mut control, install_result, &mut co)
+                self.wait_for_reboot(options, &mut control, install_result, urgency, &mut co)
                     .await;
             }
 
@@ -446,20 +639,60 @@ where
         }
     }
 
+    /// Return a future that will wait until reboot is next expected to be allowed, and yield the
+    /// time if the policy engine knows it.  Otherwise, or if that time has already passed, the
+    /// future waits for the polling interval, so that a stale time can't cause a busy loop.
+    async fn make_wait_to_see_if_reboot_allowed(
+        &mut self,
+        options: &CheckOptions,
+        install_result: &IN::InstallResult,
+        co: &mut async_generator::Yield<StateMachineEvent>,
+    ) -> Fuse<BoxFuture<'static, ()>> {
+        match self
+            .policy_engine
+            .next_reboot_allowed_time(options, install_result)
+            .await
+        {
+            Some(time) if !self.time_source.now().is_after_or_eq_any(time) => {
+                info!(
+                    "Reboot not allowed at the moment, will try again at {}",
+                    time
+                );
+                co.yield_(StateMachineEvent::RebootScheduleChange(time))
+                    .await;
+                self.timer.wait_until(time).fuse()
+            }
+            Some(time) => {
+                warn!(
+                    "Reboot not allowed at the moment, though it was expected to be at {}, will \
+                     try again in 30 minutes...",
+                    time
+                );
+                self.timer.wait_for(CHECK_REBOOT_ALLOWED_INTERVAL).fuse()
+            }
+            None => {
+                info!("Reboot not allowed at the moment, will try again in 30 minutes...");
+                self.timer.wait_for(CHECK_REBOOT_ALLOWED_INTERVAL).fuse()
+            }
+        }
+    }
+
     async fn wait_for_reboot(
         &mut self,
         mut options: CheckOptions,
         control: &mut mpsc::Receiver<ControlRequest>,
         install_result: IN::InstallResult,
+        urgency: Urgency,
         co: &mut async_generator::Yield<StateMachineEvent>,
     ) {
         if !self
             .policy_engine
-            .reboot_allowed(&options, &install_result)
+            .reboot_allowed(&options, &install_result, urgency)
             .await
         {
-            let wait_to_see_if_reboot_allowed =
-                self.timer.wait_for(CHECK_REBOOT_ALLOWED_INTERVAL).fuse();
+            let wait_to_see_if_reboot_allowed = self
+                .make_wait_to_see_if_reboot_allowed(&options, &install_result, co)
+                .await;
             futures::pin_mut!(wait_to_see_if_reboot_allowed);
 
             let check_timing = self.update_next_update_time(co).await;
@@ -472,12 +705,12 @@ where
 
                 select! {
                     () = wait_to_see_if_reboot_allowed => {
-                        if self.policy_engine.reboot_allowed(&options, &install_result).await {
+                        if self.policy_engine.reboot_allowed(&options, &install_result, urgency).await {
                             break;
                         }
-                        info!("Reboot not allowed at the moment, will try again in 30 minutes...");
                         wait_to_see_if_reboot_allowed.set(
-                            self.timer.wait_for(CHECK_REBOOT_ALLOWED_INTERVAL).fuse()
+                            self.make_wait_to_see_if_reboot_allowed(&options, &install_result, co)
+                                .await
                         );
                     },
                     () = wait_to_next_ping => {
@@ -485,20 +718,30 @@ where
                         let check_timing = self.update_next_update_time(co).await;
                         wait_to_next_ping.set(self.make_wait_to_next_check(check_timing).await);
                     },
-                    ControlRequest::StartUpdateCheck{
-                        options: new_options,
-                        responder
-                    } = control.select_next_some() => {
-                        let _ = responder.send(StartUpdateCheckResponse::AlreadyRunning);
-                        if new_options.source == InstallSource::OnDemand {
-                            info!("Waiting for reboot, but ensuring that InstallSource is OnDemand");
-                            options.source = InstallSource::OnDemand;
-
-                            if self.policy_engine.reboot_allowed(&options, &install_result).await {
-                                info!("Upgraded update check request to on demand, policy allowed reboot");
-                                break;
-                            }
-                        };
+                    request = control.select_next_some() => match request {
+                        ControlRequest::StartUpdateCheck{
+                            options: new_options,
+                            responder
+                        } => {
+                            let _ = responder.send(StartUpdateCheckResponse::AlreadyRunning);
+                            if new_options.source == InstallSource::OnDemand {
+                                info!("Waiting for reboot, but ensuring that InstallSource is OnDemand");
+                                options.source = InstallSource::OnDemand;
+
+                                if self.policy_engine.reboot_allowed(&options, &install_result, urgency).await {
+                                    info!("Upgraded update check request to on demand, policy allowed reboot");
+                                    break;
+                                }
+                            };
+                        }
+                        ControlRequest::ChangeApps{change, responder} => {
+                            self.change_apps(change, responder, co).await;
+                        }
+                        // The new channel is checked after the reboot.
+                        ControlRequest::SetTargetChannel{app_id, channel, responder} => {
+                            let result = self.set_target_channel(&app_id, channel, co).await;
+                            let _ = responder.send(result);
+                        }
                     }
                 }
             }
@@ -622,6 +865,7 @@ where
         request_params: RequestParams,
         co: &mut async_generator::Yield<StateMachineEvent>,
     ) -> RebootAfterUpdate<IN::InstallResult> {
+        self.apply_admin_overrides(co).await;
         let apps = self.app_set.lock().await.get_apps();
         let result = self.perform_update_check(request_params, apps, co).await;
 
@@ -657,7 +901,6 @@ where
                 }
 
                 (Ok(result), reboot_after_update)
-                // TODO: update consecutive_proxied_requests
             }
             Err(error) => {
                 error!("Update check failed: {:?}", error);
@@ -674,10 +917,14 @@ where
                         OmahaRequestError::Json(_)
                         | OmahaRequestError::HttpBuilder(_)
                         | OmahaRequestError::CupDecoration(_)
-                        | OmahaRequestError::CupValidation(_) => UpdateCheckFailureReason::Internal,
-                        OmahaRequestError::HttpTransport(_) | OmahaRequestError::HttpStatus(_) => {
-                            UpdateCheckFailureReason::Network
+                        | OmahaRequestError::CupValidation(_)
+                        | OmahaRequestError::RequestCompression(_) => {
+                            UpdateCheckFailureReason::Internal
                         }
+                        OmahaRequestError::HttpTransport(e) => transport_failure_reason(e),
+                        OmahaRequestError::HttpStatus(_) => UpdateCheckFailureReason::Network,
+                        OmahaRequestError::Auth(_) => UpdateCheckFailureReason::Auth,
+                        OmahaRequestError::Compression(_) => UpdateCheckFailureReason::Omaha,
                     },
                 };
                 self.report_metrics(Metrics::UpdateCheckFailureReason(failure_reason));
@@ -744,6 +991,183 @@ where
         }
     }
 
+    /// Move apps to the channels that the admin overrides target, and report any changes to the
+    /// overrides.
+    async fn apply_admin_overrides(&mut self, co: &mut async_generator::Yield<StateMachineEvent>) {
+        let overrides = self.policy_engine.admin_overrides();
+        if let Some(overrides) = &overrides {
+            let moves = self
+                .app_set
+                .lock()
+                .await
+                .get_apps()
+                .into_iter()
+                .filter_map(|app| {
+                    let channel = overrides.apps.get(&app.id)?.target_channel.as_ref()?;
+                    (app.get_target_channel() != channel).then(|| (app.id, channel.clone()))
+                })
+                .collect::<Vec<_>>();
+            for (app_id, channel) in moves {
+                info!("Moving {} to channel {} by admin override", app_id, channel);
+                if let Err(e) = self.set_target_channel(&app_id, channel, co).await {
+                    warn!("Unable to apply admin override to {}: {}", app_id, e);
+                }
+            }
+        }
+        if overrides != self.admin_overrides {
+            info!("Admin overrides changed: {:?}", overrides);
+            self.admin_overrides = overrides.clone();
+            co.yield_(StateMachineEvent::AdminOverridesChange(overrides))
+                .await;
+        }
+    }
+
+    /// Make a change to the apps in the app set, and persist it.  The uninstallation of an
+    /// unregistered app is reported to Omaha after responding.
+    async fn change_apps(
+        &mut self,
+        change: AppChange,
+        responder: oneshot::Sender<Result<(), AppSetError>>,
+        co: &mut async_generator::Yield<StateMachineEvent>,
+    ) {
+        let registering = matches!(change, AppChange::Register(_));
+        let unregistering = matches!(change, AppChange::Unregister(_));
+        let result = {
+            let mut storage = self.storage_ref.lock().await;
+            let mut app_set = self.app_set.lock().await;
+            let result = match change {
+                AppChange::Register(mut app) => {
+                    if app.valid() {
+                        app.load(&*storage).await;
+                        app_set.add_app(app.clone()).map(|()| app)
+                    } else {
+                        Err(AppSetError::InvalidApp(app.id))
+                    }
+                }
+                AppChange::Unregister(id) => app_set.remove_app(&id),
+                AppChange::Update { id, update } => app_set.update_app(&id, update),
+            };
+            match &result {
+                Ok(app) if unregistering => {
+                    info!("Unregistered app {}", app.id);
+                    let mut registered_apps = RegisteredApps::load(&*storage).await;
+                    registered_apps.remove(&app.id);
+                    registered_apps.persist(&mut *storage).await;
+                    storage.remove_or_log(&app.id).await;
+                    storage.commit_or_log().await;
+                }
+                Ok(app) => {
+                    info!("Registered or updated app {:?}", app);
+                    let mut registered_apps = RegisteredApps::load(&*storage).await;
+                    if registering {
+                        registered_apps.insert(app);
+                    } else {
+                        registered_apps.replace(&app.id, app);
+                    }
+                    registered_apps.persist(&mut *storage).await;
+                    app.persist(&mut *storage).await;
+                    storage.commit_or_log().await;
+                }
+                Err(e) => warn!("Unable to change apps: {}", e),
+            }
+            result
+        };
+
+        let _ = responder.send(result.clone().map(|_| ()));
+        if let (true, Ok(app)) = (unregistering, result) {
+            self.report_uninstall(&app, co).await;
+        }
+    }
+
+    /// Set the target channel of the app with |app_id|, and persist it.  If app ids are
+    /// configured per channel, the app switches to the app id of the channel, and the channel
+    /// must be one of them.
+    async fn set_target_channel(
+        &mut self,
+        app_id: &str,
+        channel: String,
+        co: &mut async_generator::Yield<StateMachineEvent>,
+    ) -> Result<(), AppChangeError> {
+        if !Cohort::validate_name(&channel) {
+            warn!("Not moving {} to invalid channel {:?}", app_id, channel);
+            return Err(AppChangeError::InvalidChannel(channel));
+        }
+        // Without an app id for the channel, an app that was moved to another channel's app id
+        // would be stuck with it.
+        let new_app_id = match self.channel_app_ids.get(&channel) {
+            Some(new_app_id) => Some(new_app_id.clone()),
+            None if self.channel_app_ids.is_empty() => None,
+            None => {
+                warn!("Not moving {} to unknown channel {:?}", app_id, channel);
+                return Err(AppChangeError::UnknownChannel(channel));
+            }
+        };
+
+        let app_id = {
+            let mut storage = self.storage_ref.lock().await;
+            let mut app_set = self.app_set.lock().await;
+            if !app_set.iter_mut_apps().any(|app| app.id == app_id) {
+                return Err(AppSetError::NotFound(app_id.to_string()).into());
+            }
+            if let Some(new_app_id) = &new_app_id {
+                if new_app_id != app_id && app_set.iter_mut_apps().any(|app| &app.id == new_app_id)
+                {
+                    return Err(AppSetError::AlreadyExists(new_app_id.clone()).into());
+                }
+            }
+            let app = app_set
+                .iter_mut_apps()
+                .find(|app| app.id == app_id)
+                .ok_or_else(|| AppSetError::NotFound(app_id.to_string()))?;
+            info!(
+                "Moving {} from channel {:?} to {:?}",
+                app.id,
+                app.get_target_channel(),
+                channel
+            );
+            app.set_target_channel(Some(channel.clone()), new_app_id);
+            let new_app_id = app.id.clone();
+            if new_app_id != app_id {
+                // The app is persisted under its id, so don't leave it behind under the old one.
+                let mut registered_apps = RegisteredApps::load(&*storage).await;
+                registered_apps.replace(app_id, app);
+                registered_apps.persist(&mut *storage).await;
+                storage.remove_or_log(app_id).await;
+            }
+            app_set.persist(&mut *storage).await;
+            storage.commit_or_log().await;
+            new_app_id
+        };
+
+        co.yield_(StateMachineEvent::ChannelChange { app_id, channel })
+            .await;
+        Ok(())
+    }
+
+    /// Report to Omaha that |app| was removed from the device.
+    async fn report_uninstall(
+        &mut self,
+        app: &App,
+        co: &mut async_generator::Yield<StateMachineEvent>,
+    ) {
+        let request_params = RequestParams::default();
+        let config = self.config.clone();
+        let event = Event {
+            previous_version: Some(app.version.to_omaha_string()),
+            ..Event::success(EventType::Uninstall)
+        };
+        let request_builder = RequestBuilder::new(&config, &request_params)
+            .add_event(app, event)
+            .session_id(GUID::new())
+            .request_id(GUID::new());
+        if let Err(e) = self
+            .do_omaha_request_and_update_context(&request_builder, co)
+            .await
+        {
+            error!("Unable to report uninstall of {}: {:#}", app.id, anyhow!(e));
+        }
+    }
+
     /// Persist all necessary data to storage.
     async fn persist_data(&self) {
         let mut storage = self.storage_ref.lock().await;
@@ -859,6 +1283,22 @@ where
                         break Err(UpdateCheckError::OmahaRequest(e.into()));
                     }
                 }
+                Err(OmahaRequestError::Compression(e)) => {
+                    error!("Unable to decompress HTTP response! {:?}", e);
+                    Self::yield_state(State::ErrorCheckingForUpdate, co).await;
+                    break Err(UpdateCheckError::OmahaRequest(e.into()));
+                }
+                Err(e @ OmahaRequestError::RequestCompression(_)) => {
+                    error!("Unable to compress HTTP request! {:?}", e);
+                    Self::yield_state(State::ErrorCheckingForUpdate, co).await;
+                    break Err(UpdateCheckError::OmahaRequest(e));
+                }
+                Err(OmahaRequestError::Auth(e)) => {
+                    // The token was already refreshed once, don't retry with it again.
+                    error!("Unable to authenticate with Omaha: {:?}", e);
+                    Self::yield_state(State::ErrorCheckingForUpdate, co).await;
+                    break Err(UpdateCheckError::OmahaRequest(e.into()));
+                }
             }
 
             // TODO(https://fxbug.dev/42117854): Move this to Policy.
@@ -877,6 +1317,7 @@ where
             count: omaha_request_attempt,
             successful: loop_result.is_ok(),
         });
+        self.track_proxied_update_check(&request_builder.proxy_route());
 
         let (_parts, data, request_metadata, signature) = loop_result?;
 
@@ -971,18 +1412,43 @@ where
             };
 
             info!("Validating Install Plan with Policy");
-            let install_plan_decision = self.policy_engine.update_can_start(&install_plan).await;
+            let urgency = response.urgency();
+            if urgency == Urgency::Urgent {
+                info!("The update is urgent");
+                co.yield_(StateMachineEvent::UrgentUpdate).await;
+            }
+            let mut install_plan_decision = UpdateDecision::Ok;
+            for app in &apps {
+                if let Some(next_version) = next_versions.get(&app.id) {
+                    install_plan_decision = self
+                        .policy_engine
+                        .update_version_allowed(app, next_version.as_deref())
+                        .await;
+                    if install_plan_decision != UpdateDecision::Ok {
+                        break;
+                    }
+                }
+            }
+            if install_plan_decision == UpdateDecision::Ok {
+                install_plan_decision = self
+                    .policy_engine
+                    .update_can_start(&install_plan, urgency)
+                    .await;
+            }
             match install_plan_decision {
                 UpdateDecision::Ok => {
                     info!("Proceeding with install plan.");
                 }
-                UpdateDecision::DeferredByPolicy => {
-                    info!("Install plan was deferred by Policy.");
+                UpdateDecision::DeferredByPolicy(reason) => {
+                    info!("Install plan was deferred by Policy: {}", reason);
+                    co.yield_(StateMachineEvent::DeclinedByPolicy(reason.clone()))
+                        .await;
                     // Report "error" to Omaha (as this is an event that needs reporting as the
                     // install isn't starting immediately.
                     let event = Event {
                         event_type: EventType::UpdateComplete,
                         event_result: EventResult::UpdateDeferred,
+                        extra_code1: Some(reason.rule_id),
                         ..Event::default()
                     };
                     self.report_omaha_event_and_update_context(
@@ -1003,11 +1469,17 @@ where
                         update_check::Action::DeferredByPolicy,
                     );
                 }
-                UpdateDecision::DeniedByPolicy => {
-                    warn!("Install plan was denied by Policy, see Policy logs for reasoning");
+                UpdateDecision::DeniedByPolicy(reason) => {
+                    warn!("Install plan was denied by Policy: {}", reason);
+                    co.yield_(StateMachineEvent::DeclinedByPolicy(reason.clone()))
+                        .await;
+                    let event = Event {
+                        extra_code1: Some(reason.rule_id),
+                        ..Event::error(EventErrorCode::DeniedByPolicy)
+                    };
                     self.report_omaha_event_and_update_context(
                         &request_params,
-                        Event::error(EventErrorCode::DeniedByPolicy),
+                        event,
                         &apps,
                         &session_id,
                         &next_versions,
@@ -1067,6 +1539,11 @@ where
                     AppInstallResult::Installed | AppInstallResult::Deferred
                 )
             });
+            if urgency == Urgency::Urgent {
+                self.report_metrics(Metrics::UrgentUpdateInstall {
+                    successful: no_apps_failed,
+                });
+            }
             let update_finish_time = self.time_source.now_in_walltime();
             let install_duration = match update_finish_time.duration_since(update_start_time) {
                 Ok(duration) => {
@@ -1108,7 +1585,7 @@ where
                             }
                         };
                         let event = Event {
-                            previous_version: Some(app.version.to_string()),
+                            previous_version: Some(app.version.to_omaha_string()),
                             next_version: response_app.get_manifest_version(),
                             download_time_ms: install_duration
                                 .and_then(|d| d.as_millis().try_into().ok()),
@@ -1220,7 +1697,7 @@ where
             }
 
             let reboot_after_update = if self.policy_engine.reboot_needed(&install_plan).await {
-                RebootAfterUpdate::Needed(install_result)
+                RebootAfterUpdate::Needed(install_result, urgency)
             } else {
                 RebootAfterUpdate::NotNeeded
             };
@@ -1251,7 +1728,7 @@ where
             // Skip apps with no update.
             if let Some(next_version) = next_versions.get(&app.id) {
                 let event = Event {
-                    previous_version: Some(app.version.to_string()),
+                    previous_version: Some(app.version.to_omaha_string()),
                     next_version: next_version.clone(),
                     download_time_ms: install_duration.and_then(|d| d.as_millis().try_into().ok()),
                     ..event.clone()
@@ -1277,8 +1754,7 @@ where
         let request_params = RequestParams {
             source: InstallSource::ScheduledTask,
             use_configured_proxies: true,
-            disable_updates: false,
-            offer_update_if_same_version: false,
+            ..RequestParams::default()
         };
         let config = self.config.clone();
         let mut request_builder = RequestBuilder::new(&config, &request_params);
@@ -1351,8 +1827,17 @@ where
         ),
         OmahaRequestError,
     > {
-        let (request, request_metadata) = builder.build(self.cup_handler.as_ref())?;
-        let response = Self::make_request(&mut self.http, request).await?;
+        let (request, mut request_metadata) = builder.build(self.cup_handler.as_ref())?;
+        let response = match self
+            .send_request(builder, request, &mut request_metadata)
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                self.abandon_cup_request(request_metadata.as_ref());
+                return Err(e);
+            }
+        };
 
         let signature: Option<DerSignature> = if let (Some(handler), Some(metadata)) =
             (self.cup_handler.as_ref(), &request_metadata)
@@ -1361,6 +1846,7 @@ where
                 .verify_response(metadata, &response, metadata.public_key_id)
                 .map_err(|e| {
                     error!("Could not verify response: {:?}", e);
+                    handler.abandon_request(metadata);
                     e
                 })?;
             Some(signature)
@@ -1408,6 +1894,84 @@ where
         }
     }
 
+    /// Send a request built by `builder`, and return the (decompressed) response.
+    ///
+    /// If the request has to be rebuilt and sent again, `request_metadata` is replaced with the
+    /// CUP metadata of the request that the response answers.
+    async fn send_request<'a>(
+        &'a mut self,
+        builder: &RequestBuilder<'a>,
+        mut request: http::Request<hyper::Body>,
+        request_metadata: &mut Option<RequestMetadata>,
+    ) -> Result<HttpResponse<Vec<u8>>, OmahaRequestError> {
+        let authorized = self.prepare_request(&mut request).await?;
+        let mut response = Self::make_request(&mut self.http, request).await?;
+
+        if authorized && auth::is_auth_rejection(response.status()) {
+            // The token may have been revoked before it expired, so refresh it and try once more.
+            // This retry doesn't count against the request attempts of the update check.
+            warn!(
+                "Omaha rejected the auth token with {}, refreshing it",
+                response.status()
+            );
+            self.auth.invalidate();
+            let (mut retry, retry_metadata) = builder.build(self.cup_handler.as_ref())?;
+            // Only the response to the retry may be accepted from now on.
+            self.abandon_cup_request(request_metadata.as_ref());
+            *request_metadata = retry_metadata;
+            self.prepare_request(&mut retry).await?;
+            response = Self::make_request(&mut self.http, retry).await?;
+            if auth::is_auth_rejection(response.status()) {
+                return Err(AuthError::Rejected(response.status()).into());
+            }
+        }
+
+        // CUP signs the uncompressed response, so it has to be decoded before it's verified.  Only
+        // successful responses are decoded, as the bodies of the others are never used, and a
+        // broken one mustn't hide the status and the X-Retry-After header.
+        if self.config.compression.decompress_responses && response.status().is_success() {
+            compression::decode_response(&mut response, self.config.compression.max_decoded_size)?;
+        }
+        Ok(response)
+    }
+
+    /// Tell the CUP handler that the response to a request will never be verified, e.g. because
+    /// the request failed, so that no later response can be verified against it.
+    fn abandon_cup_request(&self, request_metadata: Option<&RequestMetadata>) {
+        if let (Some(handler), Some(metadata)) = (self.cup_handler.as_ref(), request_metadata) {
+            handler.abandon_request(metadata);
+        }
+    }
+
+    /// Track whether the update check went through a proxy.  This is done once per update check,
+    /// not for each of its request attempts, and not for events or pings.
+    fn track_proxied_update_check(&mut self, route: &proxy::ProxyRoute) {
+        if route.is_proxied() {
+            self.context.state.consecutive_proxied_requests = self
+                .context
+                .state
+                .consecutive_proxied_requests
+                .saturating_add(1);
+        } else {
+            self.context.state.consecutive_proxied_requests = 0;
+        }
+    }
+
+    /// Attach an auth token to the request, if there is one.  Returns true if a token was
+    /// attached.
+    async fn prepare_request(
+        &mut self,
+        request: &mut http::Request<hyper::Body>,
+    ) -> Result<bool, AuthError> {
+        self.auth
+            .authorize(request, self.time_source.now_in_monotonic())
+            .await
+            .map_err(|e| {
+                error!("Unable to authorize request: {:?}", e);
+                e
+            })
+    }
+
     /// Make an http request and collect the response body into a Vec of bytes.
     ///
     /// Specifically, this takes the body of the response and concatenates it into a single Vec of
@@ -1522,13 +2086,32 @@ where
     }
 }
 
+/// Map an http transport error to the reason reported in the UpdateCheckFailureReason metric.
+fn transport_failure_reason(error: &http_request::Error) -> UpdateCheckFailureReason {
+    if error.is_proxy() {
+        UpdateCheckFailureReason::Proxy
+    } else if error.is_dns() {
+        UpdateCheckFailureReason::Dns
+    } else if error.is_connection_refused() {
+        UpdateCheckFailureReason::ConnectionRefused
+    } else if error.is_tls() {
+        UpdateCheckFailureReason::Tls
+    } else if error.is_connection_reset() {
+        UpdateCheckFailureReason::ConnectionReset
+    } else if error.is_body_truncated() {
+        UpdateCheckFailureReason::BodyTruncated
+    } else {
+        UpdateCheckFailureReason::Network
+    }
+}
+
 /// Return a random number in [n - range / 2, n - range / 2 + range).
 fn randomize(n: u64, range: u64) -> u64 {
     n - range / 2 + rand::random::<u64>() % range
 }
 
 #[cfg(test)]
-impl<PE, HR, IN, TM, MR, ST, AS, IR, PL, CH> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH>
+impl<PE, HR, IN, TM, MR, ST, AS, IR, PL, CH, AP> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH, AP>
 where
     PE: PolicyEngine<InstallResult = IR, InstallPlan = PL>,
     HR: HttpRequest,
@@ -1538,6 +2121,7 @@ where
     ST: Storage,
     AS: AppSet,
     CH: Cupv2Handler,
+    AP: AuthProvider,
     IR: 'static + Send,
     PL: Plan,
 {
@@ -1573,27 +2157,40 @@ where
 #[cfg(test)]
 mod tests {
     use super::update_check::{
-        Action, CONSECUTIVE_FAILED_UPDATE_CHECKS, LAST_UPDATE_TIME, SERVER_DICTATED_POLL_INTERVAL,
+        Action, CONSECUTIVE_FAILED_UPDATE_CHECKS, CONSECUTIVE_PROXIED_REQUESTS, LAST_UPDATE_TIME,
+        SERVER_DICTATED_POLL_INTERVAL,
     };
     use super::*;
     use crate::{
         app_set::VecAppSet,
+        auth::test_support::MockAuthProvider,
         common::{
             App, CheckOptions, PersistedApp, ProtocolState, UpdateCheckSchedule, UserCounting,
         },
         configuration::Updater,
-        cup_ecdsa::test_support::{make_cup_handler_for_test, MockCupv2Handler},
-        http_request::mock::MockHttpRequest,
+        cup_ecdsa::{
+            test_support::{make_cup_handler_for_test, MockCupv2Handler},
+            CupRequest, Cupv2RequestHandler, Cupv2Verifier, Nonce, PublicKeyId,
+            StandardCupv2Handler,
+        },
+        http_request::{
+            mock::MockHttpRequest,
+            proxy::{ProxyConfig, ProxyRoute},
+        },
         installer::{
             stub::{StubInstallErrors, StubInstaller, StubPlan},
             ProgressObserver,
         },
         metrics::MockMetricsReporter,
-        policy::{MockPolicyEngine, StubPolicyEngine},
+        policy::{
+            AdminOverridesPolicyEngine, MockPolicyEngine, StandardPolicyConfig,
+            StandardPolicyEngine, StubPolicyEngine, VersionRules, VersionRulesPolicyEngine,
+        },
         protocol::{request::OS, response, Cohort},
         storage::MemStorage,
+        test_support::TempDir,
         time::{
-            timers::{BlockingTimer, MockTimer, RequestedWait},
+            timers::{BlockedTimer, BlockingTimer, MockTimer, RequestedWait, StubTimer},
             MockTimeSource, PartialComplexTime,
         },
         version::Version,
@@ -1605,6 +2202,10 @@ mod tests {
     use pretty_assertions::assert_eq;
     use serde_json::json;
     use std::cell::RefCell;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
     use std::time::Duration;
     use tracing::info;
 
@@ -1616,6 +2217,14 @@ mod tests {
             .build()])))
     }
 
+    fn make_policy_reason(rule_id: u32) -> PolicyReason {
+        PolicyReason::builder()
+            .rule_id(rule_id)
+            .message("test policy")
+            .retry_after(Duration::from_secs(60))
+            .build()
+    }
+
     fn make_update_available_response() -> HttpResponse<Vec<u8>> {
         let response = json!({"response":{
           "server": "prod",
@@ -1748,7 +2357,10 @@ mod tests {
             );
             assert_eq!(None, response.app_responses[0].cohort.hint);
 
-            assert_matches!(reboot_after_update, RebootAfterUpdate::Needed(()));
+            assert_matches!(
+                reboot_after_update,
+                RebootAfterUpdate::Needed((), Urgency::Normal)
+            );
         });
     }
 
@@ -1962,7 +2574,10 @@ mod tests {
             assert_eq!(Action::Updated, response.app_responses[1].result);
             assert_eq!("appid_2", response.app_responses[2].app_id);
             assert_eq!(Action::NoUpdate, response.app_responses[2].result);
-            assert_matches!(reboot_after_update, RebootAfterUpdate::Needed(()));
+            assert_matches!(
+                reboot_after_update,
+                RebootAfterUpdate::Needed((), Urgency::Normal)
+            );
 
             let request_params = RequestParams::default();
             let apps = app_set.lock().await.get_apps();
@@ -2004,21 +2619,110 @@ mod tests {
         });
     }
 
-    // Test that our observer can see when there's an installation error, and that it gets
-    // the right error type.
+    fn make_urgent_update_available_response() -> HttpResponse<Vec<u8>> {
+        let response = json!({"response":{
+          "server": "prod",
+          "protocol": "3.0",
+          "app": [{
+            "appid": "{00000000-0000-0000-0000-000000000001}",
+            "status": "ok",
+            "updatecheck": {
+              "status": "ok",
+              "_urgent_update": true
+            }
+          }],
+        }});
+        HttpResponse::new(serde_json::to_vec(&response).unwrap())
+    }
+
     #[test]
-    fn test_observe_installation_error() {
+    fn test_urgent_update() {
         block_on(async {
-            let http = MockHttpRequest::new(make_update_available_response());
-
-            let actual_errors = StateMachineBuilder::new_stub()
+            let http = MockHttpRequest::new(make_urgent_update_available_response());
+            let policy_engine = MockPolicyEngine::default();
+            let update_urgency_received = Rc::clone(&policy_engine.update_urgency_received);
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .policy_engine(policy_engine)
+                .metrics_reporter(MockMetricsReporter::new())
                 .http(http)
-                .installer(StubInstaller { should_fail: true })
-                .oneshot_check()
+                .build()
+                .await;
+
+            let (response, reboot_after_update) = state_machine
+                .oneshot(RequestParams::default())
                 .await
-                .filter_map(|event| {
-                    future::ready(match event {
-                        StateMachineEvent::InstallerError(Some(e)) => {
+                .unwrap();
+            assert_eq!(Action::Updated, response.app_responses[0].result);
+            assert_matches!(
+                reboot_after_update,
+                RebootAfterUpdate::Needed((), Urgency::Urgent)
+            );
+            assert_eq!(*update_urgency_received.borrow(), vec![Urgency::Urgent]);
+            assert!(state_machine
+                .metrics_reporter
+                .metrics
+                .contains(&Metrics::UrgentUpdateInstall { successful: true }));
+        });
+    }
+
+    #[test]
+    fn test_normal_update_is_not_urgent() {
+        block_on(async {
+            let http = MockHttpRequest::new(make_update_available_response());
+            let policy_engine = MockPolicyEngine::default();
+            let update_urgency_received = Rc::clone(&policy_engine.update_urgency_received);
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .policy_engine(policy_engine)
+                .metrics_reporter(MockMetricsReporter::new())
+                .http(http)
+                .build()
+                .await;
+
+            let (_, reboot_after_update) = state_machine
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap();
+            assert_matches!(
+                reboot_after_update,
+                RebootAfterUpdate::Needed((), Urgency::Normal)
+            );
+            assert_eq!(*update_urgency_received.borrow(), vec![Urgency::Normal]);
+            assert!(!state_machine
+                .metrics_reporter
+                .metrics
+                .iter()
+                .any(|m| matches!(m, Metrics::UrgentUpdateInstall { .. })));
+        });
+    }
+
+    #[test]
+    fn test_observe_urgent_update() {
+        block_on(async {
+            let events = StateMachineBuilder::new_stub()
+                .http(MockHttpRequest::new(make_urgent_update_available_response()))
+                .oneshot_check()
+                .await
+                .filter(|event| future::ready(matches!(event, StateMachineEvent::UrgentUpdate)))
+                .count()
+                .await;
+            assert_eq!(events, 1);
+        });
+    }
+    // Test that our observer can see when there's an installation error, and that it gets
+    // the right error type.
+    #[test]
+    fn test_observe_installation_error() {
+        block_on(async {
+            let http = MockHttpRequest::new(make_update_available_response());
+
+            let actual_errors = StateMachineBuilder::new_stub()
+                .http(http)
+                .installer(StubInstaller { should_fail: true })
+                .oneshot_check()
+                .await
+                .filter_map(|event| {
+                    future::ready(match event {
+                        StateMachineEvent::InstallerError(Some(e)) => {
                             Some(*e.downcast::<StubInstallErrors>().unwrap())
                         }
                         _ => None,
@@ -2038,7 +2742,7 @@ mod tests {
             let http = MockHttpRequest::new(make_update_available_response());
 
             let policy_engine = MockPolicyEngine {
-                update_decision: UpdateDecision::DeferredByPolicy,
+                update_decision: UpdateDecision::DeferredByPolicy(make_policy_reason(7)),
                 ..MockPolicyEngine::default()
             };
             let mut state_machine = StateMachineBuilder::new_stub()
@@ -2059,6 +2763,7 @@ mod tests {
             let event = Event {
                 event_type: EventType::UpdateComplete,
                 event_result: EventResult::UpdateDeferred,
+                extra_code1: Some(7),
                 previous_version: Some("1.2.3.4".to_string()),
                 ..Event::default()
             };
@@ -2077,7 +2782,7 @@ mod tests {
             let response = make_update_available_response();
             let http = MockHttpRequest::new(response);
             let policy_engine = MockPolicyEngine {
-                update_decision: UpdateDecision::DeniedByPolicy,
+                update_decision: UpdateDecision::DeniedByPolicy(make_policy_reason(8)),
                 ..MockPolicyEngine::default()
             };
 
@@ -2097,7 +2802,74 @@ mod tests {
             let request_params = RequestParams::default();
             let mut request_builder = RequestBuilder::new(&state_machine.config, &request_params);
             let event = Event {
+                extra_code1: Some(8),
+                previous_version: Some("1.2.3.4".to_string()),
+                ..Event::error(EventErrorCode::DeniedByPolicy)
+            };
+            let apps = state_machine.app_set.lock().await.get_apps();
+            request_builder = request_builder
+                .add_event(&apps[0], event)
+                .session_id(GUID::from_u128(0))
+                .request_id(GUID::from_u128(2));
+            assert_request(&state_machine.http, request_builder).await;
+        });
+    }
+
+    #[test]
+    fn test_report_downgrade_denied_by_version_rules() {
+        block_on(async {
+            let response = json!({"response":{
+              "server": "prod",
+              "protocol": "3.0",
+              "app": [{
+                "appid": "{00000000-0000-0000-0000-000000000001}",
+                "status": "ok",
+                "updatecheck": {
+                  "status": "ok",
+                  "manifest": {
+                      "version": "1.2.3.3",
+                      "actions": {
+                          "action": [],
+                      },
+                      "packages": {
+                          "package": [],
+                      },
+                  }
+                }
+              }],
+            }});
+            let response = serde_json::to_vec(&response).unwrap();
+            let http = MockHttpRequest::new(HttpResponse::new(response));
+            let update_urgency_received = Rc::new(RefCell::new(vec![]));
+            let policy_engine = VersionRulesPolicyEngine::new(
+                MockPolicyEngine {
+                    update_urgency_received: Rc::clone(&update_urgency_received),
+                    ..MockPolicyEngine::default()
+                },
+                VersionRules::default(),
+            );
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .policy_engine(policy_engine)
+                .http(http)
+                .build()
+                .await;
+
+            let (response, reboot_after_update) = state_machine
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap();
+            assert_eq!(Action::DeniedByPolicy, response.app_responses[0].result);
+            assert_matches!(reboot_after_update, RebootAfterUpdate::NotNeeded);
+            // The install plan was never considered.
+            assert_eq!(*update_urgency_received.borrow(), vec![]);
+
+            let request_params = RequestParams::default();
+            let mut request_builder = RequestBuilder::new(&state_machine.config, &request_params);
+            let event = Event {
+                extra_code1: Some(VersionRules::DOWNGRADE),
                 previous_version: Some("1.2.3.4".to_string()),
+                next_version: Some("1.2.3.3".to_string()),
                 ..Event::error(EventErrorCode::DeniedByPolicy)
             };
             let apps = state_machine.app_set.lock().await.get_apps();
@@ -2109,6 +2881,85 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_observe_declined_by_policy() {
+        block_on(async {
+            let policy_engine = MockPolicyEngine {
+                update_decision: UpdateDecision::DeferredByPolicy(make_policy_reason(7)),
+                ..MockPolicyEngine::default()
+            };
+            let reasons = StateMachineBuilder::new_stub()
+                .policy_engine(policy_engine)
+                .http(MockHttpRequest::new(make_update_available_response()))
+                .oneshot_check()
+                .await
+                .filter_map(|event| {
+                    future::ready(match event {
+                        StateMachineEvent::DeclinedByPolicy(reason) => Some(reason),
+                        _ => None,
+                    })
+                })
+                .collect::<Vec<PolicyReason>>()
+                .await;
+
+            assert_eq!(reasons, vec![make_policy_reason(7)]);
+        });
+    }
+
+    #[test]
+    fn test_admin_overrides_move_apps_and_are_reported() {
+        block_on(async {
+            let dir = TempDir::new();
+            let path = dir.join("admin-overrides.json");
+            let overrides = json!({
+                "apps": {
+                    "appid_1": { "target_channel": "beta-channel" },
+                    // Invalid channel names are skipped.
+                    "appid_2": { "target_channel": "" },
+                },
+            });
+            std::fs::write(&path, overrides.to_string()).unwrap();
+            let storage = Rc::new(Mutex::new(MemStorage::new()));
+
+            let app_set = Rc::new(Mutex::new(VecAppSet::new(vec![
+                App::builder().id("appid_1").version([1, 2, 3, 4]).build(),
+                App::builder().id("appid_2").version([1, 2, 3, 4]).build(),
+            ])));
+            let events = StateMachineBuilder::new_stub()
+                .policy_engine(AdminOverridesPolicyEngine::new(
+                    MockPolicyEngine::default(),
+                    &path,
+                ))
+                .app_set(Rc::clone(&app_set))
+                .storage(Rc::clone(&storage))
+                .oneshot_check()
+                .await
+                .filter_map(|event| {
+                    future::ready(match event {
+                        StateMachineEvent::AdminOverridesChange(overrides) => Some(overrides),
+                        _ => None,
+                    })
+                })
+                .collect::<Vec<_>>()
+                .await;
+
+            assert_eq!(
+                events,
+                vec![Some(
+                    AdminOverrides::from_json(overrides.to_string().as_bytes()).unwrap()
+                )]
+            );
+            let apps = app_set.lock().await.get_apps();
+            assert_eq!(apps[0].get_target_channel(), "beta-channel");
+            assert_eq!(apps[1].get_target_channel(), "");
+
+            let persisted: PersistedApp =
+                serde_json::from_str(&storage.lock().await.get_string("appid_1").await.unwrap())
+                    .unwrap();
+            assert_eq!(persisted.cohort.hint, Some("beta-channel".to_string()));
+        });
+    }
+
     #[test]
     fn test_wait_timer() {
         let mut pool = LocalPool::new();
@@ -2384,6 +3235,7 @@ mod tests {
                         event_type: EventType::UpdateComplete,
                         event_result: EventResult::Error,
                         errorcode: Some(EventErrorCode::ParseResponse),
+                        extra_code1: None,
                         previous_version: None,
                         next_version: None,
                         download_time_ms: None,
@@ -2481,6 +3333,7 @@ mod tests {
                         event_type: EventType::UpdateComplete,
                         event_result: EventResult::Error,
                         errorcode: Some(EventErrorCode::ParseResponse),
+                        extra_code1: None,
                         previous_version: None,
                         next_version: None,
                         download_time_ms: None
@@ -2638,100 +3491,403 @@ mod tests {
     }
 
     #[test]
-    fn test_persist_last_update_time() {
+    fn test_metrics_report_update_check_failure_reason_proxy() {
         block_on(async {
-            let storage = Rc::new(Mutex::new(MemStorage::new()));
-
-            StateMachineBuilder::new_stub()
-                .storage(Rc::clone(&storage))
-                .oneshot_check()
-                .await
-                .map(|_| ())
-                .collect::<()>()
+            let mut http = MockHttpRequest::empty();
+            for _ in 0..MAX_OMAHA_REQUEST_ATTEMPTS {
+                http.add_error(http_request::mock_errors::make_proxy_error());
+            }
+            let mut metrics_reporter = MockMetricsReporter::new();
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(http)
+                .metrics_reporter(&mut metrics_reporter)
+                .build()
                 .await;
 
-            let storage = storage.lock().await;
-            storage.get_int(LAST_UPDATE_TIME).await.unwrap();
-            assert!(storage.committed());
+            state_machine.run_once().await;
+
+            assert!(metrics_reporter
+                .metrics
+                .contains(&Metrics::UpdateCheckFailureReason(
+                    UpdateCheckFailureReason::Proxy
+                )));
         });
     }
 
     #[test]
-    fn test_persist_server_dictated_poll_interval() {
-        block_on(async {
-            let response = HttpResponse::builder()
-                .header(X_RETRY_AFTER, 1234)
-                .body(make_noupdate_httpresponse())
-                .unwrap();
-            let http = MockHttpRequest::new(response);
-            let storage = Rc::new(Mutex::new(MemStorage::new()));
+    fn test_metrics_report_update_check_failure_reason_transport_errors() {
+        let cases: [(fn() -> http_request::Error, UpdateCheckFailureReason); 6] = [
+            (
+                http_request::mock_errors::make_dns_error,
+                UpdateCheckFailureReason::Dns,
+            ),
+            (
+                http_request::mock_errors::make_connection_refused_error,
+                UpdateCheckFailureReason::ConnectionRefused,
+            ),
+            (
+                http_request::mock_errors::make_tls_error,
+                UpdateCheckFailureReason::Tls,
+            ),
+            (
+                http_request::mock_errors::make_connection_reset_error,
+                UpdateCheckFailureReason::ConnectionReset,
+            ),
+            (
+                http_request::mock_errors::make_body_truncated_error,
+                UpdateCheckFailureReason::BodyTruncated,
+            ),
+            (
+                http_request::Error::new_timeout,
+                UpdateCheckFailureReason::Network,
+            ),
+        ];
+        for (make_error, reason) in cases {
+            block_on(async {
+                let mut http = MockHttpRequest::empty();
+                for _ in 0..MAX_OMAHA_REQUEST_ATTEMPTS {
+                    http.add_error(make_error());
+                }
+                let mut metrics_reporter = MockMetricsReporter::new();
+                let mut state_machine = StateMachineBuilder::new_stub()
+                    .http(http)
+                    .metrics_reporter(&mut metrics_reporter)
+                    .build()
+                    .await;
 
-            let mut state_machine = StateMachineBuilder::new_stub()
-                .http(http)
-                .storage(Rc::clone(&storage))
-                .build()
-                .await;
-            state_machine
-                .oneshot(RequestParams::default())
-                .await
-                .unwrap();
+                state_machine.run_once().await;
 
-            assert_eq!(
-                state_machine.context.state.server_dictated_poll_interval,
-                Some(Duration::from_secs(1234))
-            );
+                assert!(
+                    metrics_reporter
+                        .metrics
+                        .contains(&Metrics::UpdateCheckFailureReason(reason)),
+                    "{:?}",
+                    metrics_reporter.metrics
+                );
+            });
+        }
+    }
 
-            let storage = storage.lock().await;
-            assert_eq!(
-                storage.get_int(SERVER_DICTATED_POLL_INTERVAL).await,
-                Some(1234000000)
-            );
-            assert!(storage.committed());
-        });
+    fn make_proxied_config() -> Config {
+        Config {
+            proxy_config: Some(ProxyConfig::all(
+                "http://proxy.example.com:3128".parse().unwrap(),
+            )),
+            ..crate::configuration::test_support::config_generator()
+        }
     }
 
     #[test]
-    fn test_persist_server_dictated_poll_interval_http_error() {
+    fn test_consecutive_proxied_requests_increments_and_persists() {
         block_on(async {
-            let response = HttpResponse::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
-                .header(X_RETRY_AFTER, 1234)
-                .body(vec![])
-                .unwrap();
-            let http = MockHttpRequest::new(response);
             let storage = Rc::new(Mutex::new(MemStorage::new()));
+            {
+                let mut storage = storage.lock().await;
+                let _ = storage.set_int(CONSECUTIVE_PROXIED_REQUESTS, 3).await;
+                let _ = storage.commit().await;
+            }
 
             let mut state_machine = StateMachineBuilder::new_stub()
-                .http(http)
+                .config(make_proxied_config())
                 .storage(Rc::clone(&storage))
+                .http(MockHttpRequest::new(HttpResponse::new(
+                    make_noupdate_httpresponse(),
+                )))
                 .build()
                 .await;
-            assert_matches!(
-                state_machine.oneshot(RequestParams::default()).await,
-                Err(UpdateCheckError::OmahaRequest(
-                    OmahaRequestError::HttpStatus(_)
-                ))
-            );
 
-            assert_eq!(
-                state_machine.context.state.server_dictated_poll_interval,
-                Some(Duration::from_secs(1234))
-            );
+            let request_params = RequestParams {
+                use_configured_proxies: true,
+                ..RequestParams::default()
+            };
+            async_generator::generate(move |mut co| async move {
+                state_machine
+                    .start_update_check(request_params, &mut co)
+                    .await;
+                assert_eq!(state_machine.context.state.consecutive_proxied_requests, 4);
+            })
+            .map(|_| ())
+            .collect::<()>()
+            .await;
 
             let storage = storage.lock().await;
-            assert_eq!(
-                storage.get_int(SERVER_DICTATED_POLL_INTERVAL).await,
-                Some(1234000000)
-            );
-            assert!(storage.committed());
+            assert_eq!(storage.get_int(CONSECUTIVE_PROXIED_REQUESTS).await, Some(4));
         });
     }
 
     #[test]
-    fn test_persist_server_dictated_poll_interval_max_duration() {
+    fn test_consecutive_proxied_requests_counts_update_checks_not_attempts() {
         block_on(async {
-            let response = HttpResponse::builder()
-                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+            let mut http = MockHttpRequest::empty();
+            for _ in 0..MAX_OMAHA_REQUEST_ATTEMPTS - 1 {
+                http.add_error(http_request::mock_errors::make_transport_error());
+            }
+            http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+            // The response to the ping below.
+            http.add_response(HttpResponse::new(vec![]));
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .config(make_proxied_config())
+                .http(http)
+                .build()
+                .await;
+
+            state_machine
+                .oneshot(RequestParams {
+                    use_configured_proxies: true,
+                    ..RequestParams::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(state_machine.context.state.consecutive_proxied_requests, 1);
+
+            // Pings aren't update checks, so they don't count either.
+            async_generator::generate(move |mut co| async move {
+                state_machine.ping_omaha(&mut co).await;
+                assert_eq!(state_machine.context.state.consecutive_proxied_requests, 1);
+            })
+            .into_complete()
+            .await;
+        });
+    }
+
+    #[test]
+    fn test_consecutive_proxied_requests_reset_on_direct_request() {
+        block_on(async {
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .config(make_proxied_config())
+                .http(MockHttpRequest::new(HttpResponse::new(vec![])))
+                .build()
+                .await;
+            state_machine.context.state.consecutive_proxied_requests = 5;
+
+            let _ = state_machine
+                .oneshot(RequestParams {
+                    use_configured_proxies: false,
+                    ..RequestParams::default()
+                })
+                .await;
+
+            assert_eq!(state_machine.context.state.consecutive_proxied_requests, 0);
+        });
+    }
+
+    #[test]
+    fn test_consecutive_proxied_requests_not_counted_without_proxy_config() {
+        block_on(async {
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(MockHttpRequest::new(HttpResponse::new(vec![])))
+                .build()
+                .await;
+
+            let _ = state_machine
+                .oneshot(RequestParams {
+                    use_configured_proxies: true,
+                    ..RequestParams::default()
+                })
+                .await;
+
+            assert_eq!(state_machine.context.state.consecutive_proxied_requests, 0);
+        });
+    }
+
+    #[test]
+    fn test_standard_policy_goes_direct_after_proxy_error() {
+        block_on(async {
+            let mut http = MockHttpRequest::empty();
+            for _ in 0..MAX_OMAHA_REQUEST_ATTEMPTS {
+                http.add_error(http_request::mock_errors::make_proxy_error());
+            }
+            http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+            let requests = http.get_request_cell();
+            let policy_engine = StandardPolicyEngine::new(
+                StandardPolicyConfig::default(),
+                MockTimeSource::new_from_now(),
+            );
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .config(make_proxied_config())
+                .policy_engine(policy_engine)
+                .http(http)
+                .build()
+                .await;
+
+            async_generator::generate(move |mut co| async move {
+                for _ in 0..2 {
+                    let decision = state_machine
+                        .policy_engine
+                        .update_check_allowed(
+                            &[],
+                            &state_machine.context.schedule,
+                            &state_machine.context.state,
+                            &CheckOptions::default(),
+                        )
+                        .await;
+                    let request_params = match decision {
+                        CheckDecision::Ok(request_params) => request_params,
+                        decision => panic!("unexpected decision: {decision:?}"),
+                    };
+                    state_machine
+                        .start_update_check(request_params, &mut co)
+                        .await;
+                }
+                assert_eq!(state_machine.context.state.consecutive_proxied_requests, 0);
+            })
+            .into_complete()
+            .await;
+
+            let requests = requests.borrow();
+            let (last, failed) = requests.split_last().unwrap();
+            assert_eq!(failed.len(), MAX_OMAHA_REQUEST_ATTEMPTS as usize);
+            for request in failed {
+                assert_matches!(proxy::route_for(request), ProxyRoute::Proxied(_));
+            }
+            assert_eq!(proxy::route_for(last), ProxyRoute::Direct);
+        });
+    }
+
+    #[test]
+    fn test_update_check_replayed_from_cassette() {
+        block_on(async {
+            let cassette = json!({"interactions": [{
+                "request": {
+                    "method": "POST",
+                    "uri": "http://example.com/",
+                    "body": {"text": serde_json::to_string(&json!({"request": {
+                        "protocol": "3.0",
+                        "updater": "updater",
+                        "updaterversion": "1.2.3.4",
+                        "installsource": "scheduledtask",
+                        "ismachine": true,
+                        "os": {
+                            "platform": "platform",
+                            "version": "0.1.2.3",
+                            "sp": "sp",
+                            "arch": "test_arch",
+                        },
+                        "app": [{
+                            "appid": "{00000000-0000-0000-0000-000000000001}",
+                            "version": "1.2.3.4",
+                            "cohort": "stable-channel",
+                            "updatecheck": {},
+                            "ping": {},
+                        }],
+                    }})).unwrap()},
+                },
+                "response": {
+                    "status": 200,
+                    "body": {"text": String::from_utf8(make_noupdate_httpresponse()).unwrap()},
+                },
+            }]});
+            let cassette = http_request::cassette::Cassette::from_json(
+                &serde_json::to_vec(&cassette).unwrap(),
+            )
+            .unwrap();
+
+            let response = StateMachineBuilder::new_stub()
+                .http(http_request::cassette::ReplayHttpRequest::new(cassette))
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap()
+                .0;
+
+            assert_eq!(Action::NoUpdate, response.app_responses[0].result);
+        });
+    }
+
+    #[test]
+    fn test_persist_last_update_time() {
+        block_on(async {
+            let storage = Rc::new(Mutex::new(MemStorage::new()));
+
+            StateMachineBuilder::new_stub()
+                .storage(Rc::clone(&storage))
+                .oneshot_check()
+                .await
+                .map(|_| ())
+                .collect::<()>()
+                .await;
+
+            let storage = storage.lock().await;
+            storage.get_int(LAST_UPDATE_TIME).await.unwrap();
+            assert!(storage.committed());
+        });
+    }
+
+    #[test]
+    fn test_persist_server_dictated_poll_interval() {
+        block_on(async {
+            let response = HttpResponse::builder()
+                .header(X_RETRY_AFTER, 1234)
+                .body(make_noupdate_httpresponse())
+                .unwrap();
+            let http = MockHttpRequest::new(response);
+            let storage = Rc::new(Mutex::new(MemStorage::new()));
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(http)
+                .storage(Rc::clone(&storage))
+                .build()
+                .await;
+            state_machine
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                state_machine.context.state.server_dictated_poll_interval,
+                Some(Duration::from_secs(1234))
+            );
+
+            let storage = storage.lock().await;
+            assert_eq!(
+                storage.get_int(SERVER_DICTATED_POLL_INTERVAL).await,
+                Some(1234000000)
+            );
+            assert!(storage.committed());
+        });
+    }
+
+    #[test]
+    fn test_persist_server_dictated_poll_interval_http_error() {
+        block_on(async {
+            let response = HttpResponse::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
+                .header(X_RETRY_AFTER, 1234)
+                .body(vec![])
+                .unwrap();
+            let http = MockHttpRequest::new(response);
+            let storage = Rc::new(Mutex::new(MemStorage::new()));
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(http)
+                .storage(Rc::clone(&storage))
+                .build()
+                .await;
+            assert_matches!(
+                state_machine.oneshot(RequestParams::default()).await,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::HttpStatus(_)
+                ))
+            );
+
+            assert_eq!(
+                state_machine.context.state.server_dictated_poll_interval,
+                Some(Duration::from_secs(1234))
+            );
+
+            let storage = storage.lock().await;
+            assert_eq!(
+                storage.get_int(SERVER_DICTATED_POLL_INTERVAL).await,
+                Some(1234000000)
+            );
+            assert!(storage.committed());
+        });
+    }
+
+    #[test]
+    fn test_persist_server_dictated_poll_interval_max_duration() {
+        block_on(async {
+            let response = HttpResponse::builder()
+                .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
                 .header(X_RETRY_AFTER, 123456789)
                 .body(vec![])
                 .unwrap();
@@ -2796,6 +3952,395 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_update_check_retries_injected_faults() {
+        block_on(async {
+            let http = http_request::fault::FaultInjectingHttpRequest::new(
+                MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse())),
+                StubTimer,
+                0,
+            )
+            .inject(
+                http_request::fault::Trigger::OnRequests(vec![0]),
+                http_request::fault::Fault::Status(hyper::StatusCode::SERVICE_UNAVAILABLE),
+            )
+            .inject(
+                http_request::fault::Trigger::OnRequests(vec![1]),
+                http_request::fault::Fault::Error(http_request::Error::new_connection_reset),
+            );
+            let mut metrics_reporter = MockMetricsReporter::new();
+
+            let response = StateMachineBuilder::new_stub()
+                .http(http)
+                .metrics_reporter(&mut metrics_reporter)
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap()
+                .0;
+
+            assert_eq!(Action::NoUpdate, response.app_responses[0].result);
+            assert!(metrics_reporter
+                .metrics
+                .contains(&Metrics::RequestsPerCheck {
+                    count: 3,
+                    successful: true
+                }));
+        });
+    }
+
+    #[test]
+    fn test_http_middleware_sees_requests_and_responses() {
+        block_on(async {
+            let http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
+            let requests = http.get_request_cell();
+            let mut headers = http::HeaderMap::new();
+            headers.insert(
+                "X-Goog-Update-Custom",
+                http::HeaderValue::from_static("value"),
+            );
+
+            let response = StateMachineBuilder::new_stub()
+                .http(http)
+                .http_middleware(http_request::middleware::StaticHeaders::new(headers))
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap()
+                .0;
+
+            assert_eq!(Action::NoUpdate, response.app_responses[0].result);
+            let requests = requests.borrow();
+            assert_eq!(requests[0].headers()["X-Goog-Update-Custom"], "value");
+            // The headers set by the request builder are still there.
+            assert_eq!(requests[0].headers()["X-Goog-Update-Updater"], "updater");
+        });
+    }
+
+    fn make_auth_rejection(status: hyper::StatusCode) -> HttpResponse<Vec<u8>> {
+        HttpResponse::builder().status(status).body(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_auth_token_attached_to_requests() {
+        block_on(async {
+            let mut http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
+            http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+            let requests = http.get_request_cell();
+            let auth_provider = MockAuthProvider::new(["token"]);
+            let fetch_count = auth_provider.fetch_count();
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(http)
+                .auth_provider(auth_provider)
+                .build()
+                .await;
+            state_machine
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap();
+            state_machine
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap();
+
+            let requests = requests.borrow();
+            assert_eq!(requests.len(), 2);
+            for request in requests.iter() {
+                assert_eq!(request.headers()["Authorization"], "Bearer token");
+            }
+            // The token was cached between the requests.
+            assert_eq!(*fetch_count.borrow(), 1);
+        });
+    }
+
+    #[test]
+    fn test_auth_rejection_refreshes_token_and_retries_once() {
+        for status in [
+            hyper::StatusCode::UNAUTHORIZED,
+            hyper::StatusCode::FORBIDDEN,
+        ] {
+            block_on(async {
+                let mut http = MockHttpRequest::new(make_auth_rejection(status));
+                http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+                let requests = http.get_request_cell();
+                let auth_provider = MockAuthProvider::new(["revoked", "fresh"]);
+                let fetch_count = auth_provider.fetch_count();
+                let mut metrics_reporter = MockMetricsReporter::new();
+
+                let response = StateMachineBuilder::new_stub()
+                    .http(http)
+                    .auth_provider(auth_provider)
+                    .metrics_reporter(&mut metrics_reporter)
+                    .oneshot(RequestParams::default())
+                    .await
+                    .unwrap()
+                    .0;
+
+                assert_eq!(Action::NoUpdate, response.app_responses[0].result);
+                let requests = requests.borrow();
+                assert_eq!(requests[0].headers()["Authorization"], "Bearer revoked");
+                assert_eq!(requests[1].headers()["Authorization"], "Bearer fresh");
+                assert_eq!(*fetch_count.borrow(), 2);
+                // The auth retry doesn't count as another request attempt.
+                assert!(metrics_reporter
+                    .metrics
+                    .contains(&Metrics::RequestsPerCheck {
+                        count: 1,
+                        successful: true
+                    }));
+            });
+        }
+    }
+
+    #[test]
+    fn test_auth_retry_counts_as_one_proxied_request() {
+        block_on(async {
+            let mut http =
+                MockHttpRequest::new(make_auth_rejection(hyper::StatusCode::UNAUTHORIZED));
+            http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .config(make_proxied_config())
+                .http(http)
+                .auth_provider(MockAuthProvider::new(["revoked", "fresh"]))
+                .build()
+                .await;
+
+            state_machine
+                .oneshot(RequestParams {
+                    use_configured_proxies: true,
+                    ..RequestParams::default()
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(state_machine.context.state.consecutive_proxied_requests, 1);
+        });
+    }
+
+    #[test]
+    fn test_auth_rejected_after_refresh() {
+        block_on(async {
+            let mut http =
+                MockHttpRequest::new(make_auth_rejection(hyper::StatusCode::UNAUTHORIZED));
+            http.add_response(make_auth_rejection(hyper::StatusCode::UNAUTHORIZED));
+            http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+            let requests = http.get_request_cell();
+            let mut metrics_reporter = MockMetricsReporter::new();
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .http(http)
+                .auth_provider(MockAuthProvider::new(["first", "second"]))
+                .metrics_reporter(&mut metrics_reporter)
+                .build()
+                .await;
+            state_machine.run_once().await;
+
+            // No more requests were made after the retry with the refreshed token.
+            assert_eq!(requests.borrow().len(), 2);
+            assert!(metrics_reporter
+                .metrics
+                .contains(&Metrics::UpdateCheckFailureReason(
+                    UpdateCheckFailureReason::Auth
+                )));
+        });
+    }
+
+    #[test]
+    fn test_auth_error_from_provider() {
+        block_on(async {
+            let mut auth_provider = MockAuthProvider::default();
+            auth_provider.add_result(Err(AuthError::Fetch(anyhow!("no credentials"))));
+            let http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
+            let requests = http.get_request_cell();
+
+            let result = StateMachineBuilder::new_stub()
+                .http(http)
+                .auth_provider(auth_provider)
+                .oneshot(RequestParams::default())
+                .await;
+
+            assert_matches!(
+                result,
+                Err(UpdateCheckError::OmahaRequest(OmahaRequestError::Auth(
+                    AuthError::Fetch(_)
+                )))
+            );
+            assert!(requests.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_unauthorized_without_auth_provider_is_http_status_error() {
+        block_on(async {
+            let http = MockHttpRequest::new(make_auth_rejection(hyper::StatusCode::UNAUTHORIZED));
+            let requests = http.get_request_cell();
+
+            let result = StateMachineBuilder::new_stub()
+                .http(http)
+                .oneshot(RequestParams::default())
+                .await;
+
+            assert_matches!(
+                result,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::HttpStatus(_)
+                ))
+            );
+            assert!(requests.borrow()[0]
+                .headers()
+                .get("Authorization")
+                .is_none());
+        });
+    }
+
+    fn make_gzipped_noupdate_httpresponse() -> HttpResponse<Vec<u8>> {
+        HttpResponse::builder()
+            .header("Content-Encoding", "gzip")
+            .body(
+                compression::ContentEncoding::Gzip
+                    .encode(&make_noupdate_httpresponse())
+                    .unwrap(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compressed_response_is_decompressed() {
+        block_on(async {
+            let config = Config {
+                compression: compression::CompressionConfig {
+                    decompress_responses: true,
+                    ..Default::default()
+                },
+                ..crate::configuration::test_support::config_generator()
+            };
+
+            let response = StateMachineBuilder::new_stub()
+                .config(config)
+                .http(MockHttpRequest::new(make_gzipped_noupdate_httpresponse()))
+                .oneshot(RequestParams::default())
+                .await
+                .unwrap()
+                .0;
+
+            assert_eq!(Action::NoUpdate, response.app_responses[0].result);
+        });
+    }
+
+    #[test]
+    fn test_compressed_response_not_decompressed_when_disabled() {
+        block_on(async {
+            let result = StateMachineBuilder::new_stub()
+                .http(MockHttpRequest::new(make_gzipped_noupdate_httpresponse()))
+                .oneshot(RequestParams::default())
+                .await;
+
+            assert_matches!(result, Err(UpdateCheckError::ResponseParser(_)));
+        });
+    }
+
+    #[test]
+    fn test_compressed_response_too_large() {
+        block_on(async {
+            let config = Config {
+                compression: compression::CompressionConfig {
+                    decompress_responses: true,
+                    max_decoded_size: 16,
+                    ..Default::default()
+                },
+                ..crate::configuration::test_support::config_generator()
+            };
+
+            let result = StateMachineBuilder::new_stub()
+                .config(config)
+                .http(MockHttpRequest::new(make_gzipped_noupdate_httpresponse()))
+                .oneshot(RequestParams::default())
+                .await;
+
+            assert_matches!(
+                result,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::Compression(CompressionError::TooLarge(16))
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn test_unsupported_response_encoding() {
+        block_on(async {
+            let config = Config {
+                compression: compression::CompressionConfig {
+                    decompress_responses: true,
+                    ..Default::default()
+                },
+                ..crate::configuration::test_support::config_generator()
+            };
+            let response = HttpResponse::builder()
+                .header("Content-Encoding", "br")
+                .body(make_noupdate_httpresponse())
+                .unwrap();
+
+            let result = StateMachineBuilder::new_stub()
+                .config(config)
+                .http(MockHttpRequest::new(response))
+                .oneshot(RequestParams::default())
+                .await;
+
+            assert_matches!(
+                result,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::Compression(_)
+                ))
+            );
+        });
+    }
+
+    #[test]
+    fn test_error_response_is_not_decompressed() {
+        block_on(async {
+            let config = Config {
+                compression: compression::CompressionConfig {
+                    decompress_responses: true,
+                    ..Default::default()
+                },
+                ..crate::configuration::test_support::config_generator()
+            };
+            let response = HttpResponse::builder()
+                .status(hyper::StatusCode::SERVICE_UNAVAILABLE)
+                .header("Content-Encoding", "br")
+                .header(X_RETRY_AFTER, 1234)
+                .body(b"not brotli".to_vec())
+                .unwrap();
+
+            let mut state_machine = StateMachineBuilder::new_stub()
+                .config(config)
+                .http(MockHttpRequest::new(response))
+                .build()
+                .await;
+
+            assert_matches!(
+                state_machine.oneshot(RequestParams::default()).await,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::HttpStatus(hyper::StatusCode::SERVICE_UNAVAILABLE)
+                ))
+            );
+            assert_eq!(
+                state_machine.context.state.server_dictated_poll_interval,
+                Some(Duration::from_secs(1234))
+            );
+        });
+    }
+
+    #[test]
+    fn test_request_compression_error_is_not_a_response_error() {
+        assert_matches!(
+            OmahaRequestError::from(request_builder::Error::Compression(
+                CompressionError::TooLarge(16)
+            )),
+            OmahaRequestError::RequestCompression(CompressionError::TooLarge(16))
+        );
+    }
+
     #[test]
     fn test_persist_app() {
         block_on(async {
@@ -3923,58 +5468,205 @@ mod tests {
             );
         });
 
-        // Last update time is updated in storage.
-        pool.run_until(async {
-            let storage = storage_ref.lock().await;
-            let context = update_check::Context::load(&*storage).await;
-            assert_eq!(
-                context.schedule.last_update_time,
-                Some(mock_time.now_in_walltime().into())
-            );
-        });
+        // Last update time is updated in storage.
+        pool.run_until(async {
+            let storage = storage_ref.lock().await;
+            let context = update_check::Context::load(&*storage).await;
+            assert_eq!(
+                context.schedule.last_update_time,
+                Some(mock_time.now_in_walltime().into())
+            );
+        });
+
+        // State machine should be waiting for the next ping.
+        let wait_for_next_ping_timer = pool.run_until(timers.next()).unwrap();
+        assert_eq!(
+            wait_for_next_ping_timer.requested_wait(),
+            RequestedWait::Until(next_update_time.into())
+        );
+
+        // Let state machine check reboot_allowed again, but still don't allow it.
+        wait_for_reboot_timer.unblock();
+        pool.run_until_stalled();
+        assert!(!*reboot_called.borrow());
+
+        // State machine should be waiting for the next reboot.
+        let wait_for_reboot_timer = pool.run_until(timers.next()).unwrap();
+        assert_eq!(
+            wait_for_reboot_timer.requested_wait(),
+            RequestedWait::For(CHECK_REBOOT_ALLOWED_INTERVAL)
+        );
+
+        // Time for a second ping.
+        wait_for_next_ping_timer.unblock();
+        pool.run_until_stalled();
+
+        // Verify that it sends another ping.
+        let mut expected_request_builder = RequestBuilder::new(&config, &request_params)
+            .session_id(GUID::from_u128(7))
+            .request_id(GUID::from_u128(8));
+        for app in &apps {
+            expected_request_builder = expected_request_builder.add_ping(app);
+        }
+        pool.run_until(assert_request(
+            &ping_request_viewer,
+            expected_request_builder,
+        ));
+
+        assert!(!*reboot_called.borrow());
+
+        // Now allow reboot.
+        *reboot_called.borrow_mut() = true;
+        wait_for_reboot_timer.unblock();
+        pool.run_until_stalled();
+        assert!(*reboot_called.borrow());
+    }
+
+    // Verifies that if the policy engine knows when reboot will next be allowed, the state machine
+    // reports that time and waits until then, instead of polling.
+    #[test]
+    fn test_wait_for_reboot_until_next_allowed_time() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let mut http = MockHttpRequest::new(make_update_available_response());
+        // Responses to events.
+        http.add_response(HttpResponse::new(vec![]));
+        http.add_response(HttpResponse::new(vec![]));
+        http.add_response(HttpResponse::new(vec![]));
+        let mut mock_time = MockTimeSource::new_from_now();
+        mock_time.truncate_submicrosecond_walltime();
+        let next_update_time = mock_time.now() + Duration::from_secs(1000);
+        let reboot_time =
+            PartialComplexTime::Wall(mock_time.now_in_walltime()) + Duration::from_secs(5000);
+        let (timer, mut timers) = BlockingTimer::new();
+        let reboot_allowed = Rc::new(RefCell::new(false));
+        let policy_engine = MockPolicyEngine {
+            time_source: mock_time.clone(),
+            reboot_allowed: Rc::clone(&reboot_allowed),
+            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
+            next_reboot_allowed_time: Some(reboot_time),
+            ..MockPolicyEngine::default()
+        };
+        let installer = TestInstaller::builder(mock_time.clone()).build();
+        let reboot_called = Rc::clone(&installer.reboot_called);
+
+        let (_ctl, state_machine) = pool.run_until(
+            StateMachineBuilder::new_stub()
+                .app_set(make_test_app_set())
+                .http(http)
+                .installer(installer)
+                .policy_engine(policy_engine)
+                .timer(timer)
+                .start(),
+        );
+
+        let reboot_schedule = Rc::new(RefCell::new(vec![]));
+        let reboot_schedule_clone = Rc::clone(&reboot_schedule);
+        spawner
+            .spawn_local(async move {
+                futures::pin_mut!(state_machine);
+                while let Some(event) = state_machine.next().await {
+                    if let StateMachineEvent::RebootScheduleChange(time) = event {
+                        reboot_schedule_clone.borrow_mut().push(time);
+                    }
+                }
+            })
+            .unwrap();
 
-        // State machine should be waiting for the next ping.
-        let wait_for_next_ping_timer = pool.run_until(timers.next()).unwrap();
+        // The first wait before update check.
+        let blocked_timer = pool.run_until(timers.next()).unwrap();
         assert_eq!(
-            wait_for_next_ping_timer.requested_wait(),
+            blocked_timer.requested_wait(),
             RequestedWait::Until(next_update_time.into())
         );
-
-        // Let state machine check reboot_allowed again, but still don't allow it.
-        wait_for_reboot_timer.unblock();
+        blocked_timer.unblock();
         pool.run_until_stalled();
-        assert!(!*reboot_called.borrow());
 
-        // State machine should be waiting for the next reboot.
-        let wait_for_reboot_timer = pool.run_until(timers.next()).unwrap();
+        // The timers for reboot and ping.
+        let blocked_timer1 = pool.run_until(timers.next()).unwrap();
+        let blocked_timer2 = pool.run_until(timers.next()).unwrap();
+        let wait_for_reboot_timer =
+            if blocked_timer1.requested_wait() == RequestedWait::Until(reboot_time) {
+                blocked_timer1
+            } else {
+                blocked_timer2
+            };
         assert_eq!(
             wait_for_reboot_timer.requested_wait(),
-            RequestedWait::For(CHECK_REBOOT_ALLOWED_INTERVAL)
+            RequestedWait::Until(reboot_time)
         );
+        assert_eq!(*reboot_schedule.borrow(), vec![reboot_time]);
 
-        // Time for a second ping.
-        wait_for_next_ping_timer.unblock();
+        // Reboot once the time comes.
+        assert!(!*reboot_called.borrow());
+        *reboot_allowed.borrow_mut() = true;
+        wait_for_reboot_timer.unblock();
         pool.run_until_stalled();
+        assert!(*reboot_called.borrow());
+    }
 
-        // Verify that it sends another ping.
-        let mut expected_request_builder = RequestBuilder::new(&config, &request_params)
-            .session_id(GUID::from_u128(7))
-            .request_id(GUID::from_u128(8));
-        for app in &apps {
-            expected_request_builder = expected_request_builder.add_ping(app);
-        }
-        pool.run_until(assert_request(
-            &ping_request_viewer,
-            expected_request_builder,
-        ));
+    // Verifies that if the time that the policy engine expects reboot to be allowed at has already
+    // passed, the state machine polls instead of waiting for it.
+    #[test]
+    fn test_wait_for_reboot_polls_if_next_allowed_time_passed() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
 
-        assert!(!*reboot_called.borrow());
+        let mut http = MockHttpRequest::new(make_update_available_response());
+        // Responses to events.
+        http.add_response(HttpResponse::new(vec![]));
+        http.add_response(HttpResponse::new(vec![]));
+        http.add_response(HttpResponse::new(vec![]));
+        let mock_time = MockTimeSource::new_from_now();
+        let next_update_time = mock_time.now() + Duration::from_secs(1000);
+        let reboot_time =
+            PartialComplexTime::Wall(mock_time.now_in_walltime()) - Duration::from_secs(5000);
+        let (timer, mut timers) = BlockingTimer::new();
+        let policy_engine = MockPolicyEngine {
+            time_source: mock_time.clone(),
+            reboot_allowed: Rc::new(RefCell::new(false)),
+            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
+            next_reboot_allowed_time: Some(reboot_time),
+            ..MockPolicyEngine::default()
+        };
 
-        // Now allow reboot.
-        *reboot_called.borrow_mut() = true;
-        wait_for_reboot_timer.unblock();
+        let (_ctl, state_machine) = pool.run_until(
+            StateMachineBuilder::new_stub()
+                .app_set(make_test_app_set())
+                .http(http)
+                .installer(TestInstaller::builder(mock_time.clone()).build())
+                .policy_engine(policy_engine)
+                .timer(timer)
+                .start(),
+        );
+
+        let reboot_schedule = Rc::new(RefCell::new(vec![]));
+        let reboot_schedule_clone = Rc::clone(&reboot_schedule);
+        spawner
+            .spawn_local(async move {
+                futures::pin_mut!(state_machine);
+                while let Some(event) = state_machine.next().await {
+                    if let StateMachineEvent::RebootScheduleChange(time) = event {
+                        reboot_schedule_clone.borrow_mut().push(time);
+                    }
+                }
+            })
+            .unwrap();
+
+        // The first wait before update check.
+        pool.run_until(timers.next()).unwrap().unblock();
         pool.run_until_stalled();
-        assert!(*reboot_called.borrow());
+
+        // The timers for reboot and ping.
+        let blocked_timers = [
+            pool.run_until(timers.next()).unwrap(),
+            pool.run_until(timers.next()).unwrap(),
+        ];
+        let requested_waits = blocked_timers.each_ref().map(BlockedTimer::requested_wait);
+        assert!(requested_waits.contains(&RequestedWait::For(CHECK_REBOOT_ALLOWED_INTERVAL)));
+        assert!(!requested_waits.contains(&RequestedWait::Until(reboot_time)));
+        assert_eq!(*reboot_schedule.borrow(), vec![]);
     }
 
     #[derive(Debug)]
@@ -4034,16 +5726,22 @@ mod tests {
     #[derive(Debug, Default)]
     struct TestObserver {
         states: Rc<RefCell<Vec<State>>>,
+        declined: Rc<RefCell<Vec<PolicyReason>>>,
     }
 
     impl TestObserver {
         fn observe(&self, s: impl Stream<Item = StateMachineEvent>) -> impl Future<Output = ()> {
             let states = Rc::clone(&self.states);
+            let declined = Rc::clone(&self.declined);
             async move {
                 futures::pin_mut!(s);
                 while let Some(event) = s.next().await {
-                    if let StateMachineEvent::StateChange(state) = event {
-                        states.borrow_mut().push(state);
+                    match event {
+                        StateMachineEvent::StateChange(state) => states.borrow_mut().push(state),
+                        StateMachineEvent::DeclinedByPolicy(reason) => {
+                            declined.borrow_mut().push(reason)
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -4071,6 +5769,10 @@ mod tests {
         fn take_states(&self) -> Vec<State> {
             std::mem::take(&mut *self.states.borrow_mut())
         }
+
+        fn take_declined(&self) -> Vec<PolicyReason> {
+            std::mem::take(&mut *self.declined.borrow_mut())
+        }
     }
 
     #[test]
@@ -4078,164 +5780,529 @@ mod tests {
         let mut pool = LocalPool::new();
         let spawner = pool.spawner();
 
-        let http = MockHttpRequest::new(make_update_available_response());
-        let (send_install, mut recv_install) = mpsc::channel(0);
+        let http = MockHttpRequest::new(make_update_available_response());
+        let (send_install, mut recv_install) = mpsc::channel(0);
+        let (mut ctl, state_machine) = pool.run_until(
+            StateMachineBuilder::new_stub()
+                .http(http)
+                .installer(BlockingInstaller {
+                    on_install: send_install,
+                    on_reboot: None,
+                })
+                .start(),
+        );
+
+        let observer = TestObserver::default();
+        spawner
+            .spawn_local(observer.observe_until_terminal(state_machine))
+            .unwrap();
+
+        let unblock_install = pool.run_until(recv_install.next()).unwrap();
+        pool.run_until_stalled();
+        assert_eq!(
+            observer.take_states(),
+            vec![
+                State::CheckingForUpdates(InstallSource::ScheduledTask),
+                State::InstallingUpdate
+            ]
+        );
+
+        pool.run_until(async {
+            assert_eq!(
+                ctl.start_update_check(CheckOptions::default()).await,
+                Ok(StartUpdateCheckResponse::AlreadyRunning)
+            );
+        });
+        pool.run_until_stalled();
+        assert_eq!(observer.take_states(), vec![]);
+
+        unblock_install
+            .send(vec![AppInstallResult::Installed])
+            .unwrap();
+        pool.run_until_stalled();
+
+        assert_eq!(observer.take_states(), vec![State::WaitingForReboot]);
+    }
+
+    #[test]
+    fn test_start_update_during_timer_starts_update() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let mut mock_time = MockTimeSource::new_from_now();
+        let next_update_time = mock_time.now() + Duration::from_secs(321);
+
+        let (timer, mut timers) = BlockingTimer::new();
+        let policy_engine = MockPolicyEngine {
+            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
+            time_source: mock_time.clone(),
+            ..MockPolicyEngine::default()
+        };
+        let (mut ctl, state_machine) = pool.run_until(
+            StateMachineBuilder::new_stub()
+                .policy_engine(policy_engine)
+                .timer(timer)
+                .start(),
+        );
+
+        let observer = TestObserver::default();
+        spawner
+            .spawn_local(observer.observe(state_machine))
+            .unwrap();
+
+        let blocked_timer = pool.run_until(timers.next()).unwrap();
+        assert_eq!(
+            blocked_timer.requested_wait(),
+            RequestedWait::Until(next_update_time.into())
+        );
+        mock_time.advance(Duration::from_secs(200));
+        assert_eq!(observer.take_states(), vec![]);
+
+        // Nothing happens while the timer is waiting.
+        pool.run_until_stalled();
+        assert_eq!(observer.take_states(), vec![]);
+
+        blocked_timer.unblock();
+        let blocked_timer = pool.run_until(timers.next()).unwrap();
+        assert_eq!(
+            blocked_timer.requested_wait(),
+            RequestedWait::Until(next_update_time.into())
+        );
+        assert_eq!(
+            observer.take_states(),
+            vec![
+                State::CheckingForUpdates(InstallSource::ScheduledTask),
+                State::ErrorCheckingForUpdate,
+                State::Idle
+            ]
+        );
+
+        // Unless a control signal to start an update check comes in.
+        pool.run_until(async {
+            assert_eq!(
+                ctl.start_update_check(CheckOptions::default()).await,
+                Ok(StartUpdateCheckResponse::Started)
+            );
+        });
+        pool.run_until_stalled();
+        assert_eq!(
+            observer.take_states(),
+            vec![
+                State::CheckingForUpdates(InstallSource::ScheduledTask),
+                State::ErrorCheckingForUpdate,
+                State::Idle
+            ]
+        );
+    }
+
+    #[test]
+    fn test_start_update_check_returns_throttled() {
+        for check_decision in [
+            CheckDecision::TooSoon(make_policy_reason(6)),
+            CheckDecision::ThrottledByPolicy(make_policy_reason(9)),
+        ] {
+            let mut pool = LocalPool::new();
+            let spawner = pool.spawner();
+
+            let mut mock_time = MockTimeSource::new_from_now();
+            let next_update_time = mock_time.now() + Duration::from_secs(321);
+
+            let (timer, mut timers) = BlockingTimer::new();
+            let policy_engine = MockPolicyEngine {
+                check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
+                time_source: mock_time.clone(),
+                check_decision: check_decision.clone(),
+                ..MockPolicyEngine::default()
+            };
+            let (mut ctl, state_machine) = pool.run_until(
+                StateMachineBuilder::new_stub()
+                    .policy_engine(policy_engine)
+                    .timer(timer)
+                    .start(),
+            );
+
+            let observer = TestObserver::default();
+            spawner
+                .spawn_local(observer.observe(state_machine))
+                .unwrap();
+
+            let blocked_timer = pool.run_until(timers.next()).unwrap();
+            assert_eq!(
+                blocked_timer.requested_wait(),
+                RequestedWait::Until(next_update_time.into())
+            );
+            mock_time.advance(Duration::from_secs(200));
+            assert_eq!(observer.take_states(), vec![]);
+
+            pool.run_until(async {
+                assert_eq!(
+                    ctl.start_update_check(CheckOptions::default()).await,
+                    Ok(StartUpdateCheckResponse::Throttled(
+                        check_decision.reason().unwrap().clone()
+                    ))
+                );
+            });
+            pool.run_until_stalled();
+            assert_eq!(observer.take_states(), vec![]);
+            assert_eq!(
+                observer.take_declined(),
+                vec![check_decision.reason().unwrap().clone()]
+            );
+        }
+    }
+
+    #[test]
+    fn test_register_update_and_unregister_apps() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let mock_time = MockTimeSource::new_from_now();
+        let next_update_time = mock_time.now() + Duration::from_secs(321);
+        let (timer, mut timers) = BlockingTimer::new();
+        let policy_engine = MockPolicyEngine {
+            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
+            time_source: mock_time,
+            ..MockPolicyEngine::default()
+        };
+        let http = MockHttpRequest::empty();
+        let request_viewer = MockHttpRequest::from_request_cell(http.get_request_cell());
+        let storage_ref = Rc::new(Mutex::new(MemStorage::new()));
+        // The app was registered before, in another cohort.
+        pool.run_until(async {
+            let mut storage = storage_ref.lock().await;
+            App::builder()
+                .id("appid_2")
+                .version([1])
+                .cohort(Cohort {
+                    name: Some("beta-channel".to_string()),
+                    ..Cohort::default()
+                })
+                .build()
+                .persist(&mut *storage)
+                .await;
+        });
+        let apps = make_test_app_set();
+
         let (mut ctl, state_machine) = pool.run_until(
             StateMachineBuilder::new_stub()
+                .app_set(Rc::clone(&apps))
                 .http(http)
-                .installer(BlockingInstaller {
-                    on_install: send_install,
-                    on_reboot: None,
-                })
+                .policy_engine(policy_engine)
+                .timer(timer)
+                .storage(Rc::clone(&storage_ref))
                 .start(),
         );
-
-        let observer = TestObserver::default();
         spawner
-            .spawn_local(observer.observe_until_terminal(state_machine))
+            .spawn_local(state_machine.map(|_| ()).collect())
             .unwrap();
+        let _blocked_timer = pool.run_until(timers.next()).unwrap();
 
-        let unblock_install = pool.run_until(recv_install.next()).unwrap();
-        pool.run_until_stalled();
-        assert_eq!(
-            observer.take_states(),
-            vec![
-                State::CheckingForUpdates(InstallSource::ScheduledTask),
-                State::InstallingUpdate
-            ]
-        );
-
+        let app = App::builder().id("appid_2").version([1, 2]).build();
         pool.run_until(async {
+            assert_eq!(ctl.register_app(app.clone()).await, Ok(()));
             assert_eq!(
-                ctl.start_update_check(CheckOptions::default()).await,
-                Ok(StartUpdateCheckResponse::AlreadyRunning)
+                ctl.register_app(app.clone()).await,
+                Err(AppChangeError::AppSet(AppSetError::AlreadyExists(
+                    "appid_2".to_string()
+                )))
+            );
+            assert_eq!(
+                ctl.register_app(App::builder().id("").version([1]).build())
+                    .await,
+                Err(AppChangeError::AppSet(AppSetError::InvalidApp(
+                    String::new()
+                )))
             );
         });
-        pool.run_until_stalled();
-        assert_eq!(observer.take_states(), vec![]);
+        // The next check is scheduled again, now for both apps.
+        let _blocked_timer = pool.run_until(timers.next()).unwrap();
+        let registered = pool.run_until(apps.lock()).get_apps()[1].clone();
+        assert_eq!(registered.get_current_channel(), "beta-channel");
+
+        let update = AppUpdate {
+            version: Some([2].into()),
+            cohort_hint: Some("stable-channel".to_string()),
+            ..AppUpdate::default()
+        };
+        pool.run_until(async {
+            assert_eq!(ctl.update_app("appid_2", update).await, Ok(()));
+            let storage = storage_ref.lock().await;
+            let persisted: PersistedApp =
+                serde_json::from_str(&storage.get_string("appid_2").await.unwrap()).unwrap();
+            assert_eq!(persisted.cohort.hint, Some("stable-channel".to_string()));
+            assert!(storage.committed());
+        });
+        let updated = pool.run_until(apps.lock()).get_apps()[1].clone();
+        assert_eq!(updated.version, [2].into());
 
-        unblock_install
-            .send(vec![AppInstallResult::Installed])
-            .unwrap();
+        pool.run_until(async {
+            assert_eq!(
+                ctl.unregister_app("{00000000-0000-0000-0000-000000000001}")
+                    .await,
+                Err(AppChangeError::AppSet(AppSetError::SystemApp(
+                    "{00000000-0000-0000-0000-000000000001}".to_string()
+                )))
+            );
+            assert_eq!(ctl.unregister_app("appid_2").await, Ok(()));
+            assert_eq!(storage_ref.lock().await.get_string("appid_2").await, None);
+        });
         pool.run_until_stalled();
+        assert_eq!(pool.run_until(apps.lock()).get_apps().len(), 1);
 
-        assert_eq!(observer.take_states(), vec![State::WaitingForReboot]);
+        // The uninstall is reported to Omaha.
+        let config = crate::configuration::test_support::config_generator();
+        let request_params = RequestParams::default();
+        let event = Event {
+            previous_version: Some("2.0.0.0".to_string()),
+            ..Event::success(EventType::Uninstall)
+        };
+        let request_builder = RequestBuilder::new(&config, &request_params)
+            .add_event(&updated, event)
+            .session_id(GUID::from_u128(0))
+            .request_id(GUID::from_u128(1));
+        pool.run_until(assert_request(&request_viewer, request_builder));
     }
 
     #[test]
-    fn test_start_update_during_timer_starts_update() {
+    fn test_registered_apps_are_added_again_on_restart() {
         let mut pool = LocalPool::new();
         let spawner = pool.spawner();
 
-        let mut mock_time = MockTimeSource::new_from_now();
-        let next_update_time = mock_time.now() + Duration::from_secs(321);
-
         let (timer, mut timers) = BlockingTimer::new();
-        let policy_engine = MockPolicyEngine {
-            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
-            time_source: mock_time.clone(),
-            ..MockPolicyEngine::default()
-        };
+        let storage_ref = Rc::new(Mutex::new(MemStorage::new()));
         let (mut ctl, state_machine) = pool.run_until(
             StateMachineBuilder::new_stub()
-                .policy_engine(policy_engine)
+                .app_set(make_test_app_set())
+                .http(MockHttpRequest::empty())
                 .timer(timer)
+                .storage(Rc::clone(&storage_ref))
                 .start(),
         );
-
-        let observer = TestObserver::default();
         spawner
-            .spawn_local(observer.observe(state_machine))
+            .spawn_local(state_machine.map(|_| ()).collect())
             .unwrap();
+        let _blocked_timer = pool.run_until(timers.next()).unwrap();
 
-        let blocked_timer = pool.run_until(timers.next()).unwrap();
+        let app = App::builder()
+            .id("appid_2")
+            .version([1, 2])
+            .extra_fields([("key".to_string(), "value".to_string())])
+            .build();
+        pool.run_until(async {
+            assert_eq!(ctl.register_app(app).await, Ok(()));
+            let update = AppUpdate {
+                version: Some([2].into()),
+                cohort_hint: Some("stable-channel".to_string()),
+                ..AppUpdate::default()
+            };
+            assert_eq!(ctl.update_app("appid_2", update).await, Ok(()));
+        });
+
+        let restart = |pool: &mut LocalPool| {
+            let apps = make_test_app_set();
+            pool.run_until(
+                StateMachineBuilder::new_stub()
+                    .app_set(Rc::clone(&apps))
+                    .storage(Rc::clone(&storage_ref))
+                    .build(),
+            );
+            let apps = pool.run_until(apps.lock()).get_apps();
+            apps
+        };
+        let apps = restart(&mut pool);
+        assert_eq!(apps.len(), 2);
         assert_eq!(
-            blocked_timer.requested_wait(),
-            RequestedWait::Until(next_update_time.into())
+            apps[1],
+            App::builder()
+                .id("appid_2")
+                .version([2])
+                .cohort(Cohort::from_hint("stable-channel"))
+                .extra_fields([("key".to_string(), "value".to_string())])
+                .build()
         );
-        mock_time.advance(Duration::from_secs(200));
-        assert_eq!(observer.take_states(), vec![]);
 
-        // Nothing happens while the timer is waiting.
+        pool.run_until(async {
+            assert_eq!(ctl.unregister_app("appid_2").await, Ok(()));
+        });
         pool.run_until_stalled();
-        assert_eq!(observer.take_states(), vec![]);
+        assert_eq!(restart(&mut pool).len(), 1);
+    }
 
-        blocked_timer.unblock();
-        let blocked_timer = pool.run_until(timers.next()).unwrap();
-        assert_eq!(
-            blocked_timer.requested_wait(),
-            RequestedWait::Until(next_update_time.into())
-        );
-        assert_eq!(
-            observer.take_states(),
-            vec![
-                State::CheckingForUpdates(InstallSource::ScheduledTask),
-                State::ErrorCheckingForUpdate,
-                State::Idle
-            ]
+    #[test]
+    fn test_set_target_channel_starts_on_demand_check() {
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+
+        let (timer, mut timers) = BlockingTimer::new();
+        let http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
+        let storage_ref = Rc::new(Mutex::new(MemStorage::new()));
+        let apps = make_test_app_set();
+
+        let (mut ctl, state_machine) = pool.run_until(
+            StateMachineBuilder::new_stub()
+                .app_set(Rc::clone(&apps))
+                .http(http)
+                .timer(timer)
+                .storage(Rc::clone(&storage_ref))
+                .channel_app_ids([("beta-channel".to_string(), "beta-app-id".to_string())].into())
+                .start(),
         );
+        let events = Rc::new(RefCell::new(vec![]));
+        let observed = Rc::clone(&events);
+        spawner
+            .spawn_local(state_machine.for_each(move |event| {
+                match event {
+                    event @ StateMachineEvent::ChannelChange { .. } => {
+                        observed.borrow_mut().push(format!("{event:?}"))
+                    }
+                    StateMachineEvent::StateChange(state) => {
+                        observed.borrow_mut().push(format!("{state:?}"))
+                    }
+                    _ => {}
+                }
+                future::ready(())
+            }))
+            .unwrap();
+        let _blocked_timer = pool.run_until(timers.next()).unwrap();
 
-        // Unless a control signal to start an update check comes in.
         pool.run_until(async {
             assert_eq!(
-                ctl.start_update_check(CheckOptions::default()).await,
-                Ok(StartUpdateCheckResponse::Started)
+                ctl.set_target_channel("{00000000-0000-0000-0000-000000000001}", "")
+                    .await,
+                Err(AppChangeError::InvalidChannel(String::new()))
+            );
+            assert_eq!(
+                ctl.set_target_channel("unknown-app-id", "beta-channel")
+                    .await,
+                Err(AppChangeError::AppSet(AppSetError::NotFound(
+                    "unknown-app-id".to_string()
+                )))
+            );
+        });
+        pool.run_until_stalled();
+        assert_eq!(*events.borrow(), Vec::<String>::new());
+
+        pool.run_until(async {
+            assert_eq!(
+                ctl.set_target_channel("{00000000-0000-0000-0000-000000000001}", "beta-channel")
+                    .await,
+                Ok(())
             );
         });
         pool.run_until_stalled();
         assert_eq!(
-            observer.take_states(),
-            vec![
-                State::CheckingForUpdates(InstallSource::ScheduledTask),
-                State::ErrorCheckingForUpdate,
-                State::Idle
+            events.borrow()[..2],
+            [
+                format!(
+                    "{:?}",
+                    StateMachineEvent::ChannelChange {
+                        app_id: "beta-app-id".to_string(),
+                        channel: "beta-channel".to_string(),
+                    }
+                ),
+                format!("{:?}", State::CheckingForUpdates(InstallSource::OnDemand)),
             ]
         );
+
+        let app = pool.run_until(apps.lock()).get_apps()[0].clone();
+        assert_eq!(app.id, "beta-app-id");
+        assert_eq!(app.get_target_channel(), "beta-channel");
+        pool.run_until(async {
+            let storage = storage_ref.lock().await;
+            let persisted: PersistedApp =
+                serde_json::from_str(&storage.get_string("beta-app-id").await.unwrap()).unwrap();
+            assert_eq!(persisted.cohort.hint, Some("beta-channel".to_string()));
+        });
     }
 
     #[test]
-    fn test_start_update_check_returns_throttled() {
+    fn test_set_target_channel_moves_between_app_ids() {
         let mut pool = LocalPool::new();
         let spawner = pool.spawner();
 
-        let mut mock_time = MockTimeSource::new_from_now();
-        let next_update_time = mock_time.now() + Duration::from_secs(321);
-
         let (timer, mut timers) = BlockingTimer::new();
-        let policy_engine = MockPolicyEngine {
-            check_timing: Some(CheckTiming::builder().time(next_update_time).build()),
-            time_source: mock_time.clone(),
-            check_decision: CheckDecision::ThrottledByPolicy,
-            ..MockPolicyEngine::default()
-        };
+        let mut http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
+        http.add_response(HttpResponse::new(make_noupdate_httpresponse()));
+        let storage_ref = Rc::new(Mutex::new(MemStorage::new()));
+        let apps = make_test_app_set();
+        let system_app_id = "{00000000-0000-0000-0000-000000000001}";
+
         let (mut ctl, state_machine) = pool.run_until(
             StateMachineBuilder::new_stub()
-                .policy_engine(policy_engine)
+                .app_set(Rc::clone(&apps))
+                .http(http)
                 .timer(timer)
+                .storage(Rc::clone(&storage_ref))
+                .channel_app_ids(
+                    [
+                        ("stable-channel", system_app_id),
+                        ("beta-channel", "beta-app-id"),
+                        ("other-channel", "other-app-id"),
+                    ]
+                    .into_iter()
+                    .map(|(channel, app_id)| (channel.to_string(), app_id.to_string()))
+                    .collect(),
+                )
                 .start(),
         );
-
-        let observer = TestObserver::default();
         spawner
-            .spawn_local(observer.observe(state_machine))
+            .spawn_local(state_machine.map(|_| ()).collect())
             .unwrap();
+        let _blocked_timer = pool.run_until(timers.next()).unwrap();
+        let persisted_hint = |pool: &mut LocalPool, app_id: &str| {
+            pool.run_until(async {
+                let storage = storage_ref.lock().await;
+                storage.get_string(app_id).await.map(|json| {
+                    serde_json::from_str::<PersistedApp>(&json)
+                        .unwrap()
+                        .cohort
+                        .hint
+                })
+            })
+        };
 
-        let blocked_timer = pool.run_until(timers.next()).unwrap();
+        pool.run_until(async {
+            ctl.register_app(App::builder().id("other-app-id").version([1]).build())
+                .await
+                .unwrap();
+            assert_eq!(
+                ctl.set_target_channel(system_app_id, "alpha-channel").await,
+                Err(AppChangeError::UnknownChannel("alpha-channel".to_string()))
+            );
+            assert_eq!(
+                ctl.set_target_channel(system_app_id, "other-channel").await,
+                Err(AppChangeError::AppSet(AppSetError::AlreadyExists(
+                    "other-app-id".to_string()
+                )))
+            );
+            assert_eq!(
+                ctl.set_target_channel(system_app_id, "beta-channel").await,
+                Ok(())
+            );
+        });
+        pool.run_until_stalled();
         assert_eq!(
-            blocked_timer.requested_wait(),
-            RequestedWait::Until(next_update_time.into())
+            persisted_hint(&mut pool, "beta-app-id"),
+            Some(Some("beta-channel".to_string()))
         );
-        mock_time.advance(Duration::from_secs(200));
-        assert_eq!(observer.take_states(), vec![]);
+        assert_eq!(persisted_hint(&mut pool, system_app_id), None);
 
         pool.run_until(async {
             assert_eq!(
-                ctl.start_update_check(CheckOptions::default()).await,
-                Ok(StartUpdateCheckResponse::Throttled)
+                ctl.set_target_channel("beta-app-id", "stable-channel")
+                    .await,
+                Ok(())
             );
         });
         pool.run_until_stalled();
-        assert_eq!(observer.take_states(), vec![]);
+        let app = pool.run_until(apps.lock()).get_apps()[0].clone();
+        assert_eq!(app.id, system_app_id);
+        assert_eq!(app.get_target_channel(), "stable-channel");
+        assert_eq!(
+            persisted_hint(&mut pool, system_app_id),
+            Some(Some("stable-channel".to_string()))
+        );
+        assert_eq!(persisted_hint(&mut pool, "beta-app-id"), None);
     }
 
     #[test]
@@ -4361,6 +6428,8 @@ mod tests {
             },
             service_url: "http://example.com/".to_string(),
             omaha_public_keys: None,
+            proxy_config: None,
+            compression: Default::default(),
         };
         let metrics_reporter = Rc::new(RefCell::new(MockMetricsReporter::new()));
         let (_ctl, state_machine) = pool.run_until(
@@ -4432,7 +6501,7 @@ mod tests {
             let http = MockHttpRequest::new(HttpResponse::new(make_noupdate_httpresponse()));
 
             let stub_cup_handler = MockCupv2Handler::new()
-                .set_verification_error(|| Some(CupVerificationError::EtagHeaderMissing));
+                .set_verification_error(|| Some(CupVerificationError::ProofHeaderMissing));
 
             assert_matches!(
                 StateMachineBuilder::new_stub()
@@ -4441,7 +6510,7 @@ mod tests {
                     .oneshot(RequestParams::default())
                     .await,
                 Err(UpdateCheckError::OmahaRequest(
-                    OmahaRequestError::CupValidation(CupVerificationError::EtagHeaderMissing)
+                    OmahaRequestError::CupValidation(CupVerificationError::ProofHeaderMissing)
                 ))
             );
 
@@ -4466,4 +6535,168 @@ mod tests {
             info!("update check complete!");
         });
     }
+
+    /// A server that signs its responses, but whose answer to the first request is lost, as if
+    /// the connection dropped.  If `replay` is set, later requests are answered with the signed
+    /// response to the first one, as an attacker who captured it could.
+    struct ReplayingCupServer {
+        signer: crate::cup_ecdsa::Cupv2Signer,
+        replay: bool,
+        first_etag: Option<hyper::header::HeaderValue>,
+        request_count: Arc<AtomicUsize>,
+    }
+
+    impl ReplayingCupServer {
+        fn new(replay: bool) -> Self {
+            use crate::cup_ecdsa::{test_support::*, Cupv2Signer, PrivateKeyAndId, PrivateKeys};
+            Self {
+                signer: Cupv2Signer::new(PrivateKeys {
+                    latest: PrivateKeyAndId {
+                        id: make_default_public_key_id_for_test(),
+                        key: make_default_private_key_for_test(),
+                    },
+                    historical: vec![],
+                }),
+                replay,
+                first_etag: None,
+                request_count: Arc::new(AtomicUsize::new(0)),
+            }
+        }
+    }
+
+    impl HttpRequest for ReplayingCupServer {
+        fn request(
+            &mut self,
+            req: http::Request<hyper::Body>,
+        ) -> BoxFuture<'_, Result<HttpResponse<Vec<u8>>, http_request::Error>> {
+            async move {
+                self.request_count.fetch_add(1, Ordering::SeqCst);
+                let uri = req.uri().clone();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let mut response = HttpResponse::new(make_noupdate_httpresponse());
+                self.signer
+                    .sign_response(&uri, &body, &mut response, false)
+                    .unwrap();
+
+                match &self.first_etag {
+                    None => {
+                        self.first_etag = Some(response.headers()[hyper::header::ETAG].clone());
+                        Err(http_request::Error::new_timeout())
+                    }
+                    Some(first_etag) if self.replay => {
+                        response
+                            .headers_mut()
+                            .insert(hyper::header::ETAG, first_etag.clone());
+                        Ok(response)
+                    }
+                    Some(_) => Ok(response),
+                }
+            }
+            .boxed()
+        }
+    }
+
+    #[test]
+    fn run_cup_verified_after_retry() {
+        block_on(async {
+            let http = ReplayingCupServer::new(false);
+            let request_count = Arc::clone(&http.request_count);
+
+            assert_matches!(
+                StateMachineBuilder::new_stub()
+                    .http(http)
+                    .cup_handler(Some(make_cup_handler_for_test()))
+                    .oneshot(RequestParams::default())
+                    .await,
+                Ok(_)
+            );
+            assert_eq!(request_count.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    /// A CUP handler that gives every retry of a request the nonce of its first attempt, so that
+    /// a replayed response to the first attempt is correctly signed for the retry too, and only
+    /// the check that the nonce is still outstanding can reject it.
+    struct NonceReusingCupHandler {
+        handler: StandardCupv2Handler,
+        first_nonce: RefCell<Option<Nonce>>,
+    }
+
+    impl Cupv2RequestHandler for NonceReusingCupHandler {
+        fn decorate_request(
+            &self,
+            request: &mut impl CupRequest,
+        ) -> Result<RequestMetadata, CupDecorationError> {
+            let mut request_metadata = self.handler.decorate_request(request)?;
+            let mut first_nonce = self.first_nonce.borrow_mut();
+            match *first_nonce {
+                Some(nonce) => {
+                    self.handler.abandon_request(&request_metadata);
+                    request_metadata.nonce = nonce;
+                }
+                None => *first_nonce = Some(request_metadata.nonce),
+            }
+            Ok(request_metadata)
+        }
+
+        fn verify_response(
+            &self,
+            request_metadata: &RequestMetadata,
+            resp: &HttpResponse<Vec<u8>>,
+            public_key_id: PublicKeyId,
+        ) -> Result<DerSignature, CupVerificationError> {
+            self.handler
+                .verify_response(request_metadata, resp, public_key_id)
+        }
+
+        fn abandon_request(&self, request_metadata: &RequestMetadata) {
+            self.handler.abandon_request(request_metadata)
+        }
+    }
+
+    impl Cupv2Verifier for NonceReusingCupHandler {
+        fn verify_response_with_signature(
+            &self,
+            ecdsa_signature: &DerSignature,
+            request_body: &[u8],
+            response_body: &[u8],
+            public_key_id: PublicKeyId,
+            nonce: &Nonce,
+        ) -> Result<(), CupVerificationError> {
+            self.handler.verify_response_with_signature(
+                ecdsa_signature,
+                request_body,
+                response_body,
+                public_key_id,
+                nonce,
+            )
+        }
+    }
+
+    #[test]
+    fn run_cup_rejects_response_replayed_across_retries() {
+        block_on(async {
+            let http = ReplayingCupServer::new(true);
+            let request_count = Arc::clone(&http.request_count);
+            let cup_handler = NonceReusingCupHandler {
+                handler: make_cup_handler_for_test(),
+                first_nonce: RefCell::new(None),
+            };
+
+            // The second attempt has the same body and nonce as the first one, and is answered
+            // with the genuine, signed response to the first attempt. It must not be accepted,
+            // because the first attempt was abandoned.
+            assert_matches!(
+                StateMachineBuilder::new_stub()
+                    .http(http)
+                    .cup_handler(Some(cup_handler))
+                    .oneshot(RequestParams::default())
+                    .await,
+                Err(UpdateCheckError::OmahaRequest(
+                    OmahaRequestError::CupValidation(CupVerificationError::NonceNotOutstanding)
+                ))
+            );
+            assert_eq!(request_count.load(Ordering::SeqCst), 2);
+        });
+    }
 }
@@ -9,9 +9,13 @@
 use crate::{
     app_set::{AppSet, AppSetExt as _},
     async_generator,
+    auth::{AuthProvider, StubAuthProvider, TokenCache},
     configuration::Config,
     cup_ecdsa::Cupv2Handler,
-    http_request::HttpRequest,
+    http_request::{
+        middleware::{Layered, Middleware},
+        HttpRequest,
+    },
     installer::{Installer, Plan},
     metrics::MetricsReporter,
     policy::PolicyEngine,
@@ -21,7 +25,7 @@ use crate::{
     time::Timer,
 };
 use futures::{channel::mpsc, lock::Mutex, prelude::*};
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
 #[cfg(test)]
 use crate::{
@@ -39,7 +43,7 @@ use crate::{
 
 /// Helper type to build/start a [`StateMachine`].
 #[derive(Debug)]
-pub struct StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH>
+pub struct StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH, AP = StubAuthProvider>
 where
     PE: PolicyEngine,
     HR: HttpRequest,
@@ -59,6 +63,8 @@ where
     config: Config,
     app_set: Rc<Mutex<AS>>,
     cup_handler: Option<CH>,
+    auth_provider: AP,
+    channel_app_ids: HashMap<String, String>,
 }
 
 impl<PE, HR, IN, TM, MR, ST, AS, CH> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH>
@@ -95,11 +101,13 @@ where
             config,
             app_set,
             cup_handler,
+            auth_provider: StubAuthProvider,
+            channel_app_ids: HashMap::new(),
         }
     }
 }
 
-impl<PE, HR, IN, TM, MR, ST, AS, CH> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH>
+impl<PE, HR, IN, TM, MR, ST, AS, CH, AP> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH, AP>
 where
     PE: PolicyEngine,
     HR: HttpRequest,
@@ -109,12 +117,13 @@ where
     ST: Storage,
     AS: AppSet,
     CH: Cupv2Handler,
+    AP: AuthProvider,
 {
     /// Configures the state machine to use the provided policy_engine implementation.
     pub fn policy_engine<PE2: PolicyEngine>(
         self,
         policy_engine: PE2,
-    ) -> StateMachineBuilder<PE2, HR, IN, TM, MR, ST, AS, CH> {
+    ) -> StateMachineBuilder<PE2, HR, IN, TM, MR, ST, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine,
             http: self.http,
@@ -125,6 +134,8 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -132,7 +143,7 @@ where
     pub fn http<HR2: HttpRequest>(
         self,
         http: HR2,
-    ) -> StateMachineBuilder<PE, HR2, IN, TM, MR, ST, AS, CH> {
+    ) -> StateMachineBuilder<PE, HR2, IN, TM, MR, ST, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http,
@@ -143,6 +154,29 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
+        }
+    }
+
+    /// Wraps the currently configured http implementation in a layer of middleware.  Middleware
+    /// added later sees requests before, and responses after, middleware added earlier.
+    pub fn http_middleware<M: Middleware>(
+        self,
+        middleware: M,
+    ) -> StateMachineBuilder<PE, Layered<M, HR>, IN, TM, MR, ST, AS, CH, AP> {
+        StateMachineBuilder {
+            policy_engine: self.policy_engine,
+            http: Layered::new(middleware, self.http),
+            installer: self.installer,
+            timer: self.timer,
+            metrics_reporter: self.metrics_reporter,
+            storage: self.storage,
+            config: self.config,
+            app_set: self.app_set,
+            cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -150,7 +184,7 @@ where
     pub fn installer<IN2: Installer>(
         self,
         installer: IN2,
-    ) -> StateMachineBuilder<PE, HR, IN2, TM, MR, ST, AS, CH> {
+    ) -> StateMachineBuilder<PE, HR, IN2, TM, MR, ST, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -161,6 +195,8 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -168,7 +204,7 @@ where
     pub fn timer<TM2: Timer>(
         self,
         timer: TM2,
-    ) -> StateMachineBuilder<PE, HR, IN, TM2, MR, ST, AS, CH> {
+    ) -> StateMachineBuilder<PE, HR, IN, TM2, MR, ST, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -179,6 +215,8 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -186,7 +224,7 @@ where
     pub fn metrics_reporter<MR2: MetricsReporter>(
         self,
         metrics_reporter: MR2,
-    ) -> StateMachineBuilder<PE, HR, IN, TM, MR2, ST, AS, CH> {
+    ) -> StateMachineBuilder<PE, HR, IN, TM, MR2, ST, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -197,6 +235,8 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -204,7 +244,7 @@ where
     pub fn storage<ST2: Storage>(
         self,
         storage: Rc<Mutex<ST2>>,
-    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST2, AS, CH> {
+    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST2, AS, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -215,6 +255,8 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
@@ -224,11 +266,19 @@ where
         self
     }
 
+    /// Configures the app ids that apps switch to when their target channel is set to one of
+    /// these channels.  If any are configured, apps can only be moved to these channels, see
+    /// `ChannelConfigs::channel_app_ids`.
+    pub fn channel_app_ids(mut self, channel_app_ids: HashMap<String, String>) -> Self {
+        self.channel_app_ids = channel_app_ids;
+        self
+    }
+
     /// Configures the state machine to use the provided app_set implementation.
     pub fn app_set<AS2: AppSet>(
         self,
         app_set: Rc<Mutex<AS2>>,
-    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS2, CH> {
+    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS2, CH, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -239,13 +289,15 @@ where
             config: self.config,
             app_set,
             cup_handler: self.cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 
     pub fn cup_handler<CH2: Cupv2Handler>(
         self,
         cup_handler: Option<CH2>,
-    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH2> {
+    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH2, AP> {
         StateMachineBuilder {
             policy_engine: self.policy_engine,
             http: self.http,
@@ -256,11 +308,35 @@ where
             config: self.config,
             app_set: self.app_set,
             cup_handler,
+            auth_provider: self.auth_provider,
+            channel_app_ids: self.channel_app_ids,
+        }
+    }
+
+    /// Configures the state machine to use the provided auth_provider implementation, to attach
+    /// bearer tokens to its requests.
+    pub fn auth_provider<AP2: AuthProvider>(
+        self,
+        auth_provider: AP2,
+    ) -> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH, AP2> {
+        StateMachineBuilder {
+            policy_engine: self.policy_engine,
+            http: self.http,
+            installer: self.installer,
+            timer: self.timer,
+            metrics_reporter: self.metrics_reporter,
+            storage: self.storage,
+            config: self.config,
+            app_set: self.app_set,
+            cup_handler: self.cup_handler,
+            auth_provider,
+            channel_app_ids: self.channel_app_ids,
         }
     }
 }
 
-impl<'a, PE, HR, IN, TM, MR, ST, AS, CH, IR, PL> StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH>
+impl<'a, PE, HR, IN, TM, MR, ST, AS, CH, AP, IR, PL>
+    StateMachineBuilder<PE, HR, IN, TM, MR, ST, AS, CH, AP>
 where
     PE: 'a + PolicyEngine<InstallResult = IR, InstallPlan = PL>,
     HR: 'a + HttpRequest,
@@ -270,10 +346,11 @@ where
     ST: 'a + Storage,
     AS: 'a + AppSet,
     CH: 'a + Cupv2Handler,
+    AP: 'a + AuthProvider,
     IR: 'static + Send,
     PL: 'a + Plan,
 {
-    pub async fn build(self) -> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH> {
+    pub async fn build(self) -> StateMachine<PE, HR, IN, TM, MR, ST, AS, CH, AP> {
         let StateMachineBuilder {
             policy_engine,
             http,
@@ -284,6 +361,8 @@ where
             config,
             app_set,
             cup_handler,
+            auth_provider,
+            channel_app_ids,
         } = self;
 
         let context = {
@@ -311,6 +390,9 @@ where
             context,
             app_set,
             cup_handler,
+            auth: TokenCache::new(auth_provider),
+            admin_overrides: None,
+            channel_app_ids,
         }
     }
 
@@ -9,8 +9,10 @@
 use crate::{
     common::{ProtocolState, UpdateCheckSchedule},
     installer::ProgressObserver,
+    policy::{AdminOverrides, PolicyReason},
     protocol::response::Response,
     state_machine::{update_check, State, UpdateCheckError},
+    time::PartialComplexTime,
 };
 use futures::{channel::mpsc, future::BoxFuture, prelude::*};
 
@@ -19,7 +21,21 @@ use futures::{channel::mpsc, future::BoxFuture, prelude::*};
 pub enum StateMachineEvent {
     StateChange(State),
     ScheduleChange(UpdateCheckSchedule),
+    /// While waiting for reboot, the time at which reboot is next expected to be allowed.
+    RebootScheduleChange(PartialComplexTime),
     ProtocolStateChange(ProtocolState),
+    /// The server marked the update that was found as urgent.
+    UrgentUpdate,
+    /// Policy declined an update check or an update, for this reason.  Scheduled checks that are
+    /// too soon aren't reported, only requested ones.
+    DeclinedByPolicy(PolicyReason),
+    /// The admin overrides of the policy changed, and are now these (if any).
+    AdminOverridesChange(Option<AdminOverrides>),
+    /// The target channel of an app was set, and the app is now known by this id.
+    ChannelChange {
+        app_id: String,
+        channel: String,
+    },
     UpdateCheckResult(Result<update_check::Response, UpdateCheckError>),
     InstallProgressChange(InstallProgress),
     OmahaServerResponse(Response),
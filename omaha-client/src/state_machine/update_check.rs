@@ -20,6 +20,7 @@ use tracing::error;
 
 // These are the keys used to persist data to storage.
 pub const CONSECUTIVE_FAILED_UPDATE_CHECKS: &str = "consecutive_failed_update_checks";
+pub const CONSECUTIVE_PROXIED_REQUESTS: &str = "consecutive_proxied_requests";
 pub const LAST_UPDATE_TIME: &str = "last_update_time";
 pub const SERVER_DICTATED_POLL_INTERVAL: &str = "server_dictated_poll_interval";
 
@@ -57,6 +58,13 @@ impl Context {
             .try_into()
             .unwrap_or_default();
 
+        let consecutive_proxied_requests: u32 = storage
+            .get_int(CONSECUTIVE_PROXIED_REQUESTS)
+            .await
+            .unwrap_or(0)
+            .try_into()
+            .unwrap_or_default();
+
         // last_check_time isn't really last_update_time, but we're not persisting our
         // between-check wall time for reporting, and this is a reasonable-enough proxy.
         Context {
@@ -67,7 +75,7 @@ impl Context {
             state: ProtocolState {
                 server_dictated_poll_interval,
                 consecutive_failed_update_checks,
-                ..Default::default()
+                consecutive_proxied_requests,
             },
         }
     }
@@ -123,6 +131,24 @@ impl Context {
                 CONSECUTIVE_FAILED_UPDATE_CHECKS, e
             );
         }
+
+        let consecutive_proxied_requests_option = {
+            if self.state.consecutive_proxied_requests == 0 {
+                None
+            } else {
+                Some(self.state.consecutive_proxied_requests as i64)
+            }
+        };
+
+        if let Err(e) = storage
+            .set_option_int(
+                CONSECUTIVE_PROXIED_REQUESTS,
+                consecutive_proxied_requests_option,
+            )
+            .await
+        {
+            error!("Unable to persist {}: {}", CONSECUTIVE_PROXIED_REQUESTS, e);
+        }
     }
 }
 
@@ -203,6 +229,10 @@ mod tests {
                 .set_int(CONSECUTIVE_FAILED_UPDATE_CHECKS, 1234)
                 .await
                 .unwrap();
+            storage
+                .set_int(CONSECUTIVE_PROXIED_REQUESTS, 42)
+                .await
+                .unwrap();
 
             let context = Context::load(&storage).await;
 
@@ -213,6 +243,7 @@ mod tests {
                 Some(poll_interval)
             );
             assert_eq!(context.state.consecutive_failed_update_checks, 1234);
+            assert_eq!(context.state.consecutive_proxied_requests, 42);
         });
     }
 
@@ -224,6 +255,7 @@ mod tests {
             assert_eq!(None, context.schedule.last_update_time);
             assert_eq!(None, context.state.server_dictated_poll_interval);
             assert_eq!(0, context.state.consecutive_failed_update_checks);
+            assert_eq!(0, context.state.consecutive_proxied_requests);
         });
     }
 
@@ -234,6 +266,7 @@ mod tests {
             let last_update_time = PartialComplexTime::from_micros_since_epoch(123456789);
             let server_dictated_poll_interval = Some(Duration::from_micros(56789));
             let consecutive_failed_update_checks = 1234;
+            let consecutive_proxied_requests = 42;
             let context = Context {
                 schedule: UpdateCheckSchedule::builder()
                     .last_update_time(last_update_time)
@@ -241,7 +274,7 @@ mod tests {
                 state: ProtocolState {
                     server_dictated_poll_interval,
                     consecutive_failed_update_checks,
-                    ..ProtocolState::default()
+                    consecutive_proxied_requests,
                 },
             };
             context.persist(&mut storage).await;
@@ -254,6 +287,10 @@ mod tests {
                 Some(1234),
                 storage.get_int(CONSECUTIVE_FAILED_UPDATE_CHECKS).await
             );
+            assert_eq!(
+                Some(42),
+                storage.get_int(CONSECUTIVE_PROXIED_REQUESTS).await
+            );
             assert!(!storage.committed());
         });
     }
@@ -271,6 +308,10 @@ mod tests {
                 .set_int(CONSECUTIVE_FAILED_UPDATE_CHECKS, 1234)
                 .await
                 .unwrap();
+            storage
+                .set_int(CONSECUTIVE_PROXIED_REQUESTS, 42)
+                .await
+                .unwrap();
 
             let context = Context {
                 schedule: UpdateCheckSchedule::builder()
@@ -279,7 +320,7 @@ mod tests {
                 state: ProtocolState {
                     server_dictated_poll_interval: None,
                     consecutive_failed_update_checks: 0,
-                    ..ProtocolState::default()
+                    consecutive_proxied_requests: 0,
                 },
             };
             context.persist(&mut storage).await;
@@ -289,6 +330,7 @@ mod tests {
                 None,
                 storage.get_int(CONSECUTIVE_FAILED_UPDATE_CHECKS).await
             );
+            assert_eq!(None, storage.get_int(CONSECUTIVE_PROXIED_REQUESTS).await);
             assert!(!storage.committed());
         });
     }
@@ -0,0 +1,66 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+//! The conditions of the device that a Policy may want to take into account, such as whether it's
+//! running on battery, or on a metered network.
+//!
+//! [`SystemConditionsPolicyEngine`] uses them to defer updates.
+//!
+//! [`SystemConditionsPolicyEngine`]: crate::policy::SystemConditionsPolicyEngine
+
+use std::time::Duration;
+
+/// How the device is powered.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PowerStatus {
+    /// Whether the device is on external (AC) power.
+    pub on_ac: bool,
+
+    /// The charge of the battery, from 0 to 100, if the device has one.
+    pub battery_level: Option<u8>,
+}
+
+/// `SystemConditions` provides the current conditions of the device.  All of them are optional,
+/// as not every platform can tell, and a Policy should treat `None` as unknown.
+pub trait SystemConditions {
+    /// How the device is currently powered.
+    fn power_status(&self) -> Option<PowerStatus>;
+
+    /// Whether the network the device is connected to is metered (billed by usage).
+    fn is_network_metered(&self) -> Option<bool>;
+
+    /// How long the user hasn't interacted with the device.
+    fn user_idle_time(&self) -> Option<Duration>;
+
+    /// The average number of runnable tasks over the last minute, as in `/proc/loadavg`.
+    fn cpu_load(&self) -> Option<f64>;
+}
+
+// Implementations and tests for `SystemConditions`
+pub mod linux;
+pub use linux::LinuxSystemConditions;
+pub mod mock;
+pub use mock::MockSystemConditions;
+
+impl<T> SystemConditions for &T
+where
+    T: SystemConditions,
+{
+    fn power_status(&self) -> Option<PowerStatus> {
+        (*self).power_status()
+    }
+    fn is_network_metered(&self) -> Option<bool> {
+        (*self).is_network_metered()
+    }
+    fn user_idle_time(&self) -> Option<Duration> {
+        (*self).user_idle_time()
+    }
+    fn cpu_load(&self) -> Option<f64> {
+        (*self).cpu_load()
+    }
+}
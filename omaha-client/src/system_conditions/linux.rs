@@ -0,0 +1,245 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use super::{PowerStatus, SystemConditions};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// A `SystemConditions` implementation that reads the power supplies from
+/// `/sys/class/power_supply` and the CPU load from `/proc/loadavg`.
+///
+/// The kernel doesn't know whether the network is metered, or when the user last interacted with
+/// the device, so those are always unknown.
+#[derive(Clone, Debug)]
+pub struct LinuxSystemConditions {
+    power_supply_dir: PathBuf,
+    loadavg_path: PathBuf,
+}
+
+impl Default for LinuxSystemConditions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinuxSystemConditions {
+    pub fn new() -> Self {
+        Self::with_paths("/sys/class/power_supply", "/proc/loadavg")
+    }
+
+    /// Read the power supplies and the load average from somewhere other than the usual places.
+    pub fn with_paths(
+        power_supply_dir: impl Into<PathBuf>,
+        loadavg_path: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            power_supply_dir: power_supply_dir.into(),
+            loadavg_path: loadavg_path.into(),
+        }
+    }
+}
+
+/// Read a single-line attribute of a power supply.
+fn read_attribute(supply: &Path, attribute: &str) -> Option<String> {
+    fs::read_to_string(supply.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+impl SystemConditions for LinuxSystemConditions {
+    fn power_status(&self) -> Option<PowerStatus> {
+        let mut supplies = fs::read_dir(&self.power_supply_dir)
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>();
+        // Report the same battery each time, if there are several.
+        supplies.sort();
+
+        let mut external_supplies = 0;
+        let mut external_online = false;
+        let mut battery = None;
+        for supply in &supplies {
+            match read_attribute(supply, "type").as_deref() {
+                Some("Battery") => {
+                    battery = battery.or(Some(supply));
+                }
+                // "Mains", "USB", "Wireless", ...
+                Some(_) => {
+                    external_supplies += 1;
+                    external_online |= read_attribute(supply, "online").as_deref() == Some("1");
+                }
+                None => {}
+            }
+        }
+
+        let battery_level = battery
+            .and_then(|battery| read_attribute(battery, "capacity"))
+            .and_then(|capacity| capacity.parse::<u8>().ok())
+            .map(|capacity| capacity.min(100));
+        let on_ac = if external_supplies > 0 {
+            external_online
+        } else {
+            // Without any external supplies to look at, the battery's status has to do.
+            match battery.and_then(|battery| read_attribute(battery, "status")) {
+                Some(status) => status != "Discharging",
+                None => return None,
+            }
+        };
+        Some(PowerStatus {
+            on_ac,
+            battery_level,
+        })
+    }
+
+    fn is_network_metered(&self) -> Option<bool> {
+        None
+    }
+
+    fn user_idle_time(&self) -> Option<Duration> {
+        None
+    }
+
+    fn cpu_load(&self) -> Option<f64> {
+        fs::read_to_string(&self.loadavg_path)
+            .ok()?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    /// A fake sysfs and procfs, that is removed when dropped.
+    struct FakeRoot(TempDir);
+
+    impl FakeRoot {
+        fn new() -> Self {
+            let root = TempDir::new();
+            fs::create_dir(root.join("power_supply")).unwrap();
+            Self(root)
+        }
+
+        fn add_supply(&self, name: &str, attributes: &[(&str, &str)]) {
+            let supply = self.0.join("power_supply").join(name);
+            fs::create_dir(&supply).unwrap();
+            for (attribute, value) in attributes {
+                fs::write(supply.join(attribute), format!("{value}\n")).unwrap();
+            }
+        }
+
+        fn conditions(&self) -> LinuxSystemConditions {
+            LinuxSystemConditions::with_paths(self.0.join("power_supply"), self.0.join("loadavg"))
+        }
+    }
+
+    #[test]
+    fn test_power_status_on_ac() {
+        let root = FakeRoot::new();
+        root.add_supply("AC", &[("type", "Mains"), ("online", "1")]);
+        root.add_supply(
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Charging"),
+                ("capacity", "42"),
+            ],
+        );
+        assert_eq!(
+            root.conditions().power_status(),
+            Some(PowerStatus {
+                on_ac: true,
+                battery_level: Some(42)
+            })
+        );
+    }
+
+    #[test]
+    fn test_power_status_on_battery() {
+        let root = FakeRoot::new();
+        root.add_supply("AC", &[("type", "Mains"), ("online", "0")]);
+        root.add_supply("usb", &[("type", "USB"), ("online", "0")]);
+        root.add_supply(
+            "BAT1",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("capacity", "17"),
+            ],
+        );
+        root.add_supply("BAT0", &[("type", "Battery"), ("capacity", "80")]);
+        assert_eq!(
+            root.conditions().power_status(),
+            Some(PowerStatus {
+                on_ac: false,
+                battery_level: Some(80)
+            })
+        );
+    }
+
+    #[test]
+    fn test_power_status_from_battery_status() {
+        let root = FakeRoot::new();
+        root.add_supply(
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("capacity", "5"),
+            ],
+        );
+        assert_eq!(
+            root.conditions().power_status(),
+            Some(PowerStatus {
+                on_ac: false,
+                battery_level: Some(5)
+            })
+        );
+    }
+
+    #[test]
+    fn test_power_status_unknown() {
+        let root = FakeRoot::new();
+        assert_eq!(root.conditions().power_status(), None);
+
+        let conditions = LinuxSystemConditions::with_paths(root.0.join("missing"), "/missing");
+        assert_eq!(conditions.power_status(), None);
+    }
+
+    #[test]
+    fn test_power_status_mains_only() {
+        let root = FakeRoot::new();
+        root.add_supply("AC", &[("type", "Mains"), ("online", "1")]);
+        assert_eq!(
+            root.conditions().power_status(),
+            Some(PowerStatus {
+                on_ac: true,
+                battery_level: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_cpu_load() {
+        let root = FakeRoot::new();
+        assert_eq!(root.conditions().cpu_load(), None);
+
+        fs::write(root.0.join("loadavg"), "0.52 0.58 0.59 1/467 12345\n").unwrap();
+        assert_eq!(root.conditions().cpu_load(), Some(0.52));
+
+        fs::write(root.0.join("loadavg"), "garbage").unwrap();
+        assert_eq!(root.conditions().cpu_load(), None);
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2024 The Fuchsia Authors
+//
+// Licensed under a BSD-style license <LICENSE-BSD>, Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0>, or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your option.
+// This file may not be copied, modified, or distributed except according to
+// those terms.
+
+use super::{PowerStatus, SystemConditions};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+#[derive(Clone, Debug, Default)]
+struct Conditions {
+    power_status: Option<PowerStatus>,
+    is_network_metered: Option<bool>,
+    user_idle_time: Option<Duration>,
+    cpu_load: Option<f64>,
+}
+
+/// A mock `SystemConditions` that can be manipulated as needed.  All conditions start out unknown.
+///
+/// Like the `MockTimeSource`, clones share the same conditions, so that a test can change them
+/// after handing a clone to the code under test.
+///
+/// # Example
+/// ```
+/// use omaha_client::system_conditions::{MockSystemConditions, PowerStatus, SystemConditions};
+/// let mock_conditions = MockSystemConditions::default();
+/// mock_conditions.set_power_status(PowerStatus { on_ac: false, battery_level: Some(15) });
+/// assert_eq!(mock_conditions.power_status().unwrap().battery_level, Some(15));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MockSystemConditions {
+    conditions: Arc<RwLock<Conditions>>,
+}
+
+impl MockSystemConditions {
+    pub fn set_power_status(&self, power_status: impl Into<Option<PowerStatus>>) {
+        self.conditions.write().unwrap().power_status = power_status.into();
+    }
+
+    pub fn set_network_metered(&self, is_network_metered: impl Into<Option<bool>>) {
+        self.conditions.write().unwrap().is_network_metered = is_network_metered.into();
+    }
+
+    pub fn set_user_idle_time(&self, user_idle_time: impl Into<Option<Duration>>) {
+        self.conditions.write().unwrap().user_idle_time = user_idle_time.into();
+    }
+
+    pub fn set_cpu_load(&self, cpu_load: impl Into<Option<f64>>) {
+        self.conditions.write().unwrap().cpu_load = cpu_load.into();
+    }
+}
+
+impl SystemConditions for MockSystemConditions {
+    fn power_status(&self) -> Option<PowerStatus> {
+        self.conditions.read().unwrap().power_status
+    }
+    fn is_network_metered(&self) -> Option<bool> {
+        self.conditions.read().unwrap().is_network_metered
+    }
+    fn user_idle_time(&self) -> Option<Duration> {
+        self.conditions.read().unwrap().user_idle_time
+    }
+    fn cpu_load(&self) -> Option<f64> {
+        self.conditions.read().unwrap().cpu_load
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clones_share_conditions() {
+        let mock_conditions = MockSystemConditions::default();
+        let clone = mock_conditions.clone();
+        assert_eq!(clone.is_network_metered(), None);
+
+        mock_conditions.set_network_metered(true);
+        mock_conditions.set_user_idle_time(Duration::from_secs(300));
+        mock_conditions.set_cpu_load(0.5);
+        assert_eq!(clone.is_network_metered(), Some(true));
+        assert_eq!(clone.user_idle_time(), Some(Duration::from_secs(300)));
+        assert_eq!(clone.cpu_load(), Some(0.5));
+
+        mock_conditions.set_network_metered(None);
+        assert_eq!(clone.is_network_metered(), None);
+    }
+}
@@ -12,16 +12,137 @@ use {
         de::{self, Visitor},
         Deserialize, Deserializer, Serialize, Serializer,
     },
-    std::{fmt, str::FromStr},
+    std::{cmp::Ordering, fmt, str::FromStr},
 };
 
-/// This is a utility wrapper around Omaha-style versions - in the form of A.B.C.D, A.B.C, A.B or A.
-#[derive(Clone, Copy, Eq, Ord, PartialOrd, PartialEq)]
-pub struct Version([u32; 4]);
+/// This is a utility wrapper around Omaha-style versions - in the form of A.B.C.D, A.B.C, A.B or A,
+/// or with any other number of components - and semver-style versions, which may be followed by a
+/// pre-release (`1.2.3-beta.1`) and build metadata (`1.2.3+build.5`).
+///
+/// Missing components are zero, so `1.2` and `1.2.0.0` are the same version.  A pre-release
+/// version comes before the release, and pre-releases are ordered by their identifiers as in
+/// semver.  Build metadata doesn't change the precedence of a version (see
+/// [`Version::cmp_precedence`]), it only orders versions that are otherwise the same.
+///
+/// Leading zeros don't change the value of a component, but a parsed version is displayed and
+/// serialized as it was written, so that `1.02` stays `1.02`.
+#[derive(Clone)]
+pub struct Version {
+    components: Vec<u32>,
+    /// The components as they were parsed, if that differs from their canonical form, e.g.
+    /// because of leading zeros.
+    components_text: Option<Box<str>>,
+    pre_release: Option<Box<str>>,
+    build: Option<Box<str>>,
+}
+
+impl Version {
+    /// The numeric components of the version, as many as it was created with.
+    pub fn components(&self) -> &[u32] {
+        &self.components
+    }
+
+    pub fn pre_release(&self) -> Option<&str> {
+        self.pre_release.as_deref()
+    }
+
+    pub fn build(&self) -> Option<&str> {
+        self.build.as_deref()
+    }
+
+    /// Compare the versions as semver does, ignoring their build metadata.
+    pub fn cmp_precedence(&self, other: &Self) -> Ordering {
+        let len = self.components.len().max(other.components.len());
+        self.padded_components(len)
+            .cmp(other.padded_components(len))
+            .then_with(|| match (&self.pre_release, &other.pre_release) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => a
+                    .split('.')
+                    .map(PreReleaseIdentifier::from)
+                    .cmp(b.split('.').map(PreReleaseIdentifier::from)),
+            })
+    }
+
+    /// The version as it's sent to Omaha.  Omaha-style versions are always sent with at least four
+    /// components, so `1.2` is sent as `1.2.0.0`, but versions with a pre-release or build are
+    /// sent as they are.
+    pub fn to_omaha_string(&self) -> String {
+        if self.pre_release.is_none() && self.build.is_none() {
+            let padding = 4usize.saturating_sub(self.components.len());
+            format!("{}{}", self.components_string(), ".0".repeat(padding))
+        } else {
+            self.to_string()
+        }
+    }
+
+    /// The numeric components, as they were parsed.
+    fn components_string(&self) -> String {
+        match &self.components_text {
+            Some(text) => text.to_string(),
+            None => self.components.iter().format(".").to_string(),
+        }
+    }
+
+    /// The numeric components, followed by as many zeros as needed to have at least |len| of them.
+    fn padded_components(&self, len: usize) -> impl Iterator<Item = u32> + '_ {
+        let padding = len.saturating_sub(self.components.len());
+        self.components
+            .iter()
+            .copied()
+            .chain(std::iter::repeat_n(0, padding))
+    }
+}
+
+/// An identifier of a pre-release, numeric identifiers come before alphanumeric ones.
+#[derive(Eq, Ord, PartialEq, PartialOrd)]
+enum PreReleaseIdentifier<'a> {
+    Numeric(u64),
+    AlphaNumeric(&'a str),
+}
+
+impl<'a> From<&'a str> for PreReleaseIdentifier<'a> {
+    fn from(identifier: &'a str) -> Self {
+        match identifier.parse() {
+            Ok(n) => Self::Numeric(n),
+            Err(_) => Self::AlphaNumeric(identifier),
+        }
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_precedence(other)
+            .then_with(|| self.build.cmp(&other.build))
+    }
+}
 
 impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0.iter().format("."))
+        write!(f, "{}", self.components_string())?;
+        if let Some(pre_release) = &self.pre_release {
+            write!(f, "-{pre_release}")?;
+        }
+        if let Some(build) = &self.build {
+            write!(f, "+{build}")?;
+        }
+        Ok(())
     }
 }
 
@@ -32,46 +153,89 @@ impl fmt::Debug for Version {
     }
 }
 
+const PRE_RELEASE: &str = "pre-release";
+const BUILD: &str = "build";
+
 #[derive(Debug, thiserror::Error)]
-struct TooManyNumbersError;
+#[error("Invalid {kind} identifier {identifier:?} in version.")]
+struct InvalidIdentifierError {
+    kind: &'static str,
+    identifier: String,
+}
 
-impl fmt::Display for TooManyNumbersError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("Too many numbers in version, the maximum is 4.")
+/// Check the dot-separated identifiers of a pre-release or build metadata, which are non-empty
+/// and alphanumeric (or '-').  Numeric pre-release identifiers can't have leading zeros, as they
+/// would compare equal to the identifier without them.
+fn validate_identifiers(kind: &'static str, identifiers: &str) -> Result<(), anyhow::Error> {
+    for identifier in identifiers.split('.') {
+        let valid = !identifier.is_empty()
+            && identifier
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+        let leading_zero = identifier.len() > 1
+            && identifier.starts_with('0')
+            && identifier.bytes().all(|b| b.is_ascii_digit());
+        if !valid || (kind == PRE_RELEASE && leading_zero) {
+            return Err(InvalidIdentifierError {
+                kind,
+                identifier: identifier.to_string(),
+            }
+            .into());
+        }
     }
+    Ok(())
 }
 
 impl FromStr for Version {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let nums = s.split('.').map(|s| s.parse::<u32>());
-
-        let mut array: [u32; 4] = [0; 4];
-        for (i, v) in nums.enumerate() {
-            if i >= 4 {
-                return Err(TooManyNumbersError.into());
+        let (s, build) = match s.split_once('+') {
+            Some((s, build)) => {
+                validate_identifiers(BUILD, build)?;
+                (s, Some(build.into()))
             }
-            array[i] = v?;
-        }
-        Ok(Version(array))
+            None => (s, None),
+        };
+        let (s, pre_release) = match s.split_once('-') {
+            Some((s, pre_release)) => {
+                validate_identifiers(PRE_RELEASE, pre_release)?;
+                (s, Some(pre_release.into()))
+            }
+            None => (s, None),
+        };
+        let components: Vec<u32> = s
+            .split('.')
+            .map(|s| s.parse::<u32>())
+            .collect::<Result<_, _>>()?;
+        let components_text = Some(s)
+            .filter(|s| *s != components.iter().format(".").to_string())
+            .map(Box::from);
+        Ok(Version {
+            components,
+            components_text,
+            pre_release,
+            build,
+        })
     }
 }
 
-macro_rules! impl_from {
-    ($($t:ty),+) => {
-        $(
-            impl From<$t> for Version {
-                fn from(v: $t) -> Self {
-                    let mut array: [u32; 4] = [0; 4];
-                    array.split_at_mut(v.len()).0.copy_from_slice(&v);
-                    Version(array)
-                }
-            }
-        )+
+impl<const N: usize> From<[u32; N]> for Version {
+    fn from(components: [u32; N]) -> Self {
+        components.to_vec().into()
+    }
+}
+
+impl From<Vec<u32>> for Version {
+    fn from(components: Vec<u32>) -> Self {
+        Version {
+            components,
+            components_text: None,
+            pre_release: None,
+            build: None,
+        }
     }
 }
-impl_from!([u32; 1], [u32; 2], [u32; 3], [u32; 4]);
 
 struct VersionVisitor;
 
@@ -79,7 +243,7 @@ impl Visitor<'_> for VersionVisitor {
     type Value = Version;
 
     fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
-        formatter.write_str("a string of the format A.B.C.D")
+        formatter.write_str("a string of the format A.B.C.D or A.B.C-pre.release+build")
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -154,9 +318,30 @@ mod tests {
         assert_eq!("0000999".parse::<Version>().unwrap(), version);
     }
 
+    #[test]
+    fn test_version_leading_zeros_round_trip() {
+        for s in ["1.02", "1.02.003.0004", "06.4.07-rc.1+build.005", "0000999"] {
+            let version = s.parse::<Version>().unwrap();
+            assert_eq!(version.to_string(), s);
+            assert_eq!(
+                serde_json::to_value(&version).unwrap(),
+                serde_json::json!(s)
+            );
+            assert_eq!(
+                serde_json::from_value::<Version>(serde_json::json!(s))
+                    .unwrap()
+                    .to_string(),
+                s
+            );
+        }
+        assert_eq!(
+            "1.02".parse::<Version>().unwrap().to_omaha_string(),
+            "1.02.0.0"
+        );
+    }
+
     #[test]
     fn test_version_parse_error() {
-        assert!("1.2.3.4.5".parse::<Version>().is_err());
         assert!("1.2.".parse::<Version>().is_err());
         assert!(".1.2".parse::<Version>().is_err());
         assert!("-1".parse::<Version>().is_err());
@@ -164,17 +349,91 @@ mod tests {
         assert!(".".parse::<Version>().is_err());
         assert!("".parse::<Version>().is_err());
         assert!("999999999999999999999999".parse::<Version>().is_err());
+        assert!("1.2.3-".parse::<Version>().is_err());
+        assert!("1.2.3+".parse::<Version>().is_err());
+        assert!("1.2.3-beta..1".parse::<Version>().is_err());
+        assert!("1.2.3-beta_1".parse::<Version>().is_err());
+        assert!("1.2.3-01".parse::<Version>().is_err());
+        assert!("1.2.3+build+5".parse::<Version>().is_err());
+        assert!("-beta".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn test_version_parse_many_components() {
+        let version = "1.2.3.4.5".parse::<Version>().unwrap();
+        assert_eq!(version, Version::from([1, 2, 3, 4, 5]));
+        assert_eq!(version.components(), &[1, 2, 3, 4, 5]);
+        assert_eq!(version.to_string(), "1.2.3.4.5");
+        assert_eq!(
+            "1.2.3.4.0.0".parse::<Version>().unwrap(),
+            Version::from([1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn test_version_parse_semver() {
+        let version = "1.2.3-beta.1+build.005".parse::<Version>().unwrap();
+        assert_eq!(version.components(), &[1, 2, 3]);
+        assert_eq!(version.pre_release(), Some("beta.1"));
+        assert_eq!(version.build(), Some("build.005"));
+
+        let version = "1.2.3+build-5".parse::<Version>().unwrap();
+        assert_eq!(version.pre_release(), None);
+        assert_eq!(version.build(), Some("build-5"));
+
+        let version = "1.2.3-rc-1".parse::<Version>().unwrap();
+        assert_eq!(version.pre_release(), Some("rc-1"));
+        assert_eq!(version.build(), None);
+    }
+
+    #[test]
+    fn test_version_round_trip() {
+        for s in [
+            "1.2.3.4",
+            "1.2.3",
+            "1.2",
+            "1.2.3.4.5",
+            "0.0.0.0.0.1",
+            "1.2.3-alpha",
+            "1.2.3-alpha.1+build.5",
+            "1.2.3+20240101",
+            "1.0.0.0.17-rc.0",
+        ] {
+            let version = s.parse::<Version>().unwrap();
+            assert_eq!(version.to_string(), s);
+            assert_eq!(version.to_string().parse::<Version>().unwrap(), version);
+        }
     }
 
     #[test]
     fn test_version_to_string() {
-        assert_eq!(&"1.2".parse::<Version>().unwrap().to_string(), "1.2.0.0");
+        assert_eq!(&"1.2".parse::<Version>().unwrap().to_string(), "1.2");
         assert_eq!(
             &"1.2.3.4".parse::<Version>().unwrap().to_string(),
             "1.2.3.4"
         );
-        assert_eq!(&"1".parse::<Version>().unwrap().to_string(), "1.0.0.0");
-        assert_eq!(&"3.2.1".parse::<Version>().unwrap().to_string(), "3.2.1.0");
+        assert_eq!(&"1".parse::<Version>().unwrap().to_string(), "1");
+        assert_eq!(&"3.2.1".parse::<Version>().unwrap().to_string(), "3.2.1");
+    }
+
+    #[test]
+    fn test_version_to_omaha_string() {
+        assert_eq!(
+            &"1.2".parse::<Version>().unwrap().to_omaha_string(),
+            "1.2.0.0"
+        );
+        assert_eq!(
+            &"1.2.3.4.5".parse::<Version>().unwrap().to_omaha_string(),
+            "1.2.3.4.5"
+        );
+        assert_eq!(
+            &"1.2-rc.1".parse::<Version>().unwrap().to_omaha_string(),
+            "1.2-rc.1"
+        );
+        assert_eq!(
+            &"3.2.1+build".parse::<Version>().unwrap().to_omaha_string(),
+            "3.2.1+build"
+        );
     }
 
     #[test]
@@ -191,6 +450,44 @@ mod tests {
         assert!(Version::from([1]) < Version::from([1, 0, 1, 0]));
         assert!(Version::from([1, 0]) < Version::from([1, 0, 0, 1]));
         assert!(Version::from([1, 0, 0]) > Version::from([0, 1, 2, 0]));
+        assert!(Version::from([1, 2, 3, 4]) < Version::from([1, 2, 3, 4, 1]));
+        assert!(Version::from([1, 2, 3, 4, 0]) == Version::from([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_version_compare_semver() {
+        // The example from https://semver.org/#spec-item-11.
+        let versions = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+        ]
+        .map(|s| s.parse::<Version>().unwrap());
+        for pair in versions.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+        assert!("0.9.9".parse::<Version>().unwrap() < versions[0]);
+        assert_eq!(
+            "1.0-rc.1".parse::<Version>().unwrap(),
+            "1.0.0-rc.1".parse::<Version>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_version_compare_build() {
+        let release = "1.2.3".parse::<Version>().unwrap();
+        let build = "1.2.3+build.5".parse::<Version>().unwrap();
+        let later_build = "1.2.3+build.6".parse::<Version>().unwrap();
+        assert_eq!(release.cmp_precedence(&build), Ordering::Equal);
+        assert_eq!(build.cmp_precedence(&later_build), Ordering::Equal);
+        assert!(release < build);
+        assert!(build < later_build);
+        assert!(later_build < "1.2.4-alpha".parse::<Version>().unwrap());
     }
 
     #[test]
@@ -199,7 +496,9 @@ mod tests {
         assert_eq!(v, Version::from([1, 2, 3, 4]));
         let v: Version = serde_json::from_str(r#""1.2.3""#).unwrap();
         assert_eq!(v, Version::from([1, 2, 3]));
-        serde_json::from_str::<Version>(r#""1.2.3.4.5""#)
+        let v: Version = serde_json::from_str(r#""1.2.3-rc.1+build.5""#).unwrap();
+        assert_eq!(v, "1.2.3-rc.1+build.5".parse().unwrap());
+        serde_json::from_str::<Version>(r#""1.2.3-""#)
             .expect_err("Parsing invalid version should fail");
     }
 
@@ -208,6 +507,8 @@ mod tests {
         let v = Version::from([1, 2, 3, 4]);
         assert_eq!(serde_json::to_string(&v).unwrap(), r#""1.2.3.4""#);
         let v = Version::from([1, 2, 3]);
-        assert_eq!(serde_json::to_string(&v).unwrap(), r#""1.2.3.0""#);
+        assert_eq!(serde_json::to_string(&v).unwrap(), r#""1.2.3""#);
+        let v: Version = "1.2.3-rc.1".parse().unwrap();
+        assert_eq!(serde_json::to_string(&v).unwrap(), r#""1.2.3-rc.1""#);
     }
 }